@@ -0,0 +1,220 @@
+//! Delta catch-up of a site restarting from its database
+//!
+//! A site that restarts reloads its vector clock from its database, so it
+//! knows up to which counter it had seen the transactions of every other site.
+//! Instead of reconciling its whole log, it sends this clock to each of its
+//! neighbours, which answer with their transactions whose source counter is
+//! beyond it. The neighbours send them in Lamport order, which respects their
+//! causal order, one page at a time: the site asks for the next page once it
+//! has applied the previous one.
+
+#![cfg(feature = "server")]
+
+/// Number of transactions sent in a page
+pub const PAGE_SIZE: usize = 128;
+
+/// Payload of the messages of the catch-up
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub enum CatchUpPayload {
+    /// Asks for the transactions the sender has not seen yet
+    Request {
+        /// Vector clock the sender restarted with
+        clock: std::collections::HashMap<String, i64>,
+        /// Last transaction of the previous page, if any
+        after: Option<crate::merkle::TxKey>,
+    },
+    /// Page of transactions, in causal order
+    Page {
+        /// Clock of the request, to ask for the next page
+        clock: std::collections::HashMap<String, i64>,
+        /// The transactions
+        txs: Vec<crate::snapshot::TxSummary>,
+        /// Whether more pages follow
+        more: bool,
+    },
+}
+
+/// Selects the next page of transactions unseen by a clock
+///
+/// The vector clock of each transaction holds the counter of its source node,
+/// a transaction without one is sent in case. The counter is sent along, so
+/// the receiver stores it instead of its own clock.
+pub fn select_page(
+    mut log: Vec<crate::db::Transaction>,
+    clock: &std::collections::HashMap<String, i64>,
+    after: Option<&crate::merkle::TxKey>,
+    size: usize,
+) -> (Vec<crate::snapshot::TxSummary>, bool) {
+    // l'ordre de Lamport, départagé par le site, prolonge l'ordre causal
+    let after = after.map(|(node, lamport)| (*lamport, node.as_str()));
+    log.retain(|tx| {
+        let seen = clock.get(&tx.source_node).copied().unwrap_or(0);
+        let unseen = tx
+            .vector_clock
+            .get(&tx.source_node)
+            .is_none_or(|counter| *counter > seen);
+        unseen && after.is_none_or(|after| (tx.lamport_time, tx.source_node.as_str()) > after)
+    });
+    log.sort_by(|a, b| (a.lamport_time, &a.source_node).cmp(&(b.lamport_time, &b.source_node)));
+    let more = log.len() > size;
+    let page = log
+        .iter()
+        .take(size)
+        .map(crate::snapshot::TxSummary::from)
+        .collect();
+    (page, more)
+}
+
+/// Asks every neighbour for the transactions we missed while we were away
+pub async fn start_catch_up(
+    node: &crate::node::Node,
+    clock: std::collections::HashMap<String, i64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let neighbours = {
+        let state = node.state.lock().await;
+        state.get_connected_nei_addr()
+    };
+    log::info!(
+        "Catching up from our clock {:?} with {} neighbours",
+        clock,
+        neighbours.len()
+    );
+    for addr in neighbours {
+        let request = CatchUpPayload::Request {
+            clock: clock.clone(),
+            after: None,
+        };
+        if let Err(e) = send(node, addr, request).await {
+            log::error!("Cannot ask {} for the missed transactions: {}", addr, e);
+        }
+    }
+    Ok(())
+}
+
+/// Answers a message of the catch-up
+pub async fn handle_catch_up(
+    node: &crate::node::Node,
+    from: std::net::SocketAddr,
+    payload: CatchUpPayload,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match payload {
+        CatchUpPayload::Request { clock, after } => {
+            let log = crate::db::get_local_transaction_log(&node.db)?;
+            let (txs, more) = select_page(log, &clock, after.as_ref(), PAGE_SIZE);
+            log::debug!("Sending {} missed transactions to {}", txs.len(), from);
+            send(node, from, CatchUpPayload::Page { clock, txs, more }).await?;
+        }
+        CatchUpPayload::Page { clock, txs, more } => {
+            log::info!("Received {} missed transactions from {}", txs.len(), from);
+            let failed = crate::db::insert_missing_transactions(&node.db, &txs);
+            if !failed.is_empty() {
+                // absentes de l'arbre de Merkle, la prochaine anti-entropie les redemandera
+                log::warn!(
+                    "{} missed transactions from {} not applied",
+                    failed.len(),
+                    from
+                );
+            }
+            if more && let Some(last) = txs.last() {
+                let request = CatchUpPayload::Request {
+                    clock,
+                    after: Some((last.source_node.clone(), last.lamport_time)),
+                };
+                send(node, from, request).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn send(
+    node: &crate::node::Node,
+    to: std::net::SocketAddr,
+    payload: CatchUpPayload,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (site_addr, site_id, clock) = {
+        let state = node.state.lock().await;
+        (
+            state.get_site_addr(),
+            state.get_site_id(),
+            state.get_clock(),
+        )
+    };
+    crate::network::send_message(
+        node,
+        to,
+        crate::message::MessageInfo::CatchUp(payload),
+        crate::message::NetworkMessageCode::CatchUp,
+        site_addr,
+        &site_id,
+        crate::message::Origin::local(&site_id, site_addr),
+        clock,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tx(node: &str, lamport: i64, counter: Option<i64>) -> crate::db::Transaction {
+        crate::db::Transaction {
+            from_user: "NULL".to_string(),
+            to_user: "alice".to_string(),
+            amount: crate::money::Money::from_cents(100),
+            lamport_time: lamport,
+            source_node: node.to_string(),
+            optional_msg: None,
+            vector_clock: counter.map(|c| (node.to_string(), c)).into_iter().collect(),
+            hlc: crate::clock::Hlc::default(),
+        }
+    }
+
+    fn keys(page: &[crate::snapshot::TxSummary]) -> Vec<(String, i64)> {
+        page.iter()
+            .map(|t| (t.source_node.clone(), t.lamport_time))
+            .collect()
+    }
+
+    #[test]
+    fn test_only_unseen_transactions_are_sent_in_lamport_order() {
+        let mut log = vec![
+            tx("A", 5, Some(3)),
+            tx("B", 2, Some(1)),
+            tx("A", 1, Some(1)),
+            tx("B", 7, Some(4)),
+            tx("C", 3, None),
+        ];
+        log[0].vector_clock.insert("B".to_string(), 2);
+        let clock = std::collections::HashMap::from([("A".to_string(), 1), ("B".to_string(), 4)]);
+        let (page, more) = select_page(log, &clock, None, PAGE_SIZE);
+        assert_eq!(
+            keys(&page),
+            vec![("C".to_string(), 3), ("A".to_string(), 5)]
+        );
+        assert!(page[0].vector_clock.is_empty());
+        assert_eq!(
+            page[1].vector_clock,
+            std::collections::BTreeMap::from([("A".to_string(), 3), ("B".to_string(), 2)])
+        );
+        assert!(!more);
+    }
+
+    #[test]
+    fn test_pages_follow_each_other() {
+        let log: Vec<_> = (1..=5).map(|t| tx("A", t, Some(t))).collect();
+        let clock = std::collections::HashMap::new();
+
+        let (first, more) = select_page(log.clone(), &clock, None, 2);
+        assert_eq!(
+            keys(&first),
+            vec![("A".to_string(), 1), ("A".to_string(), 2)]
+        );
+        assert!(more);
+
+        let last = ("A".to_string(), 4);
+        let (rest, more) = select_page(log, &clock, Some(&last), 2);
+        assert_eq!(keys(&rest), vec![("A".to_string(), 5)]);
+        assert!(!more);
+    }
+}
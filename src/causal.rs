@@ -0,0 +1,321 @@
+//! Causal delivery of the transactions
+//!
+//! The transactions diffused by the waves may reach a site in any order: a
+//! refund can arrive before the transaction it refunds, a transfer before the
+//! creation of its beneficiary. Every transaction carries the vector of the
+//! transactions its initiator had delivered when it issued it, its own
+//! included, and a site holds it in a [`CausalBuffer`] until it has delivered
+//! every one of them.
+//!
+//! The vector only counts transactions, so the other messages do not delay
+//! them. A site learns the count of an initiator from the first transaction it
+//! hears from it: the transactions issued before it joined came with its
+//! synchronisation. The counters are saved with the local state and reloaded
+//! when the site restarts, so that it carries on numbering its transactions
+//! after the ones the other sites already delivered. A transaction whose
+//! dependencies never arrive, because their initiator failed in the middle of
+//! its wave for instance, is given up waiting for after a timeout and delivered
+//! anyway, as an error.
+
+#![cfg(feature = "server")]
+
+/// Default time in milliseconds a transaction waits for its dependencies
+pub const DEFAULT_CAUSAL_TIMEOUT_MS: u64 = 10_000;
+
+/// Number of transactions of each initiator a transaction depends on
+///
+/// Site_id -> number of transactions
+pub type Dependencies = std::collections::BTreeMap<String, u64>;
+
+/// Transaction received before its dependencies
+#[derive(Debug, Clone)]
+pub struct BufferedTransaction {
+    /// ID of the site that issued the transaction
+    pub origin: String,
+    /// Transactions delivered by the initiator when it issued this one
+    pub deps: Dependencies,
+    /// The transaction
+    pub info: crate::message::MessageInfo,
+    /// Clock of the initiator when it issued the transaction
+    pub clock: crate::clock::Clock,
+    /// When the transaction reached the site
+    pub received_at: std::time::Instant,
+}
+
+impl BufferedTransaction {
+    /// Number of the transaction among the ones of its initiator
+    fn number(&self) -> u64 {
+        self.deps.get(&self.origin).copied().unwrap_or(0)
+    }
+}
+
+/// Transactions held until their dependencies are delivered
+pub struct CausalBuffer {
+    /// Number of transactions delivered for each initiator, ours included
+    delivered: std::collections::HashMap<String, u64>,
+    /// Transactions waiting for their dependencies
+    pending: Vec<BufferedTransaction>,
+    /// Time a transaction waits for its dependencies
+    timeout: std::time::Duration,
+    /// Largest number of transactions held at once
+    peak: usize,
+    /// Number of transactions delivered without their dependencies
+    timed_out: u64,
+}
+
+impl CausalBuffer {
+    /// Creates an empty buffer
+    pub fn new() -> Self {
+        Self {
+            delivered: std::collections::HashMap::new(),
+            pending: Vec::new(),
+            timeout: std::time::Duration::from_millis(DEFAULT_CAUSAL_TIMEOUT_MS),
+            peak: 0,
+            timed_out: 0,
+        }
+    }
+
+    /// Set the number of transactions delivered before a restart
+    pub fn init_delivered(&mut self, delivered: Dependencies) {
+        self.delivered = delivered.into_iter().collect();
+    }
+
+    /// Set the time a transaction waits for its dependencies
+    pub fn init_timeout(&mut self, timeout_ms: u64) {
+        self.timeout = std::time::Duration::from_millis(timeout_ms);
+    }
+
+    /// Counts a transaction issued by the site, and returns its dependencies
+    pub fn stamp_local(&mut self, site_id: &str) -> Dependencies {
+        *self.delivered.entry(site_id.to_string()).or_insert(0) += 1;
+        self.delivered
+            .iter()
+            .map(|(site, n)| (site.clone(), *n))
+            .collect()
+    }
+
+    /// Holds a transaction received from another site
+    ///
+    /// Returns the transactions that can be delivered now, in causal order:
+    /// this one if its dependencies are met, followed by the ones it unblocks.
+    /// A transaction older than the ones already delivered is returned right
+    /// away, the database skipping the duplicates.
+    pub fn receive(&mut self, transaction: BufferedTransaction) -> Vec<BufferedTransaction> {
+        let number = transaction.number();
+        match self.delivered.get(&transaction.origin) {
+            Some(delivered) if number <= *delivered => {
+                // Déjà délivrée, ou arrivée après qu'on a abandonné de l'attendre :
+                // la base ignore les doublons
+                log::warn!(
+                    "Transaction {} of {} arrived after its successors",
+                    number,
+                    transaction.origin
+                );
+                return vec![transaction];
+            }
+            Some(_) => {}
+            None => {
+                // Premier message de ce site : ses transactions précédentes
+                // nous sont parvenues avec la synchronisation
+                self.delivered
+                    .insert(transaction.origin.clone(), number.saturating_sub(1));
+            }
+        }
+
+        self.pending.push(transaction);
+        self.peak = self.peak.max(self.pending.len());
+        self.take_deliverable()
+    }
+
+    /// Gives up on the transactions that waited too long for their dependencies
+    ///
+    /// Returns the transactions to deliver anyway, followed by the ones they
+    /// unblock, in causal order.
+    pub fn expire(&mut self, now: std::time::Instant) -> Vec<BufferedTransaction> {
+        let timeout = self.timeout;
+        let (mut expired, pending): (Vec<_>, Vec<_>) = self
+            .pending
+            .drain(..)
+            .partition(|tx| now.saturating_duration_since(tx.received_at) >= timeout);
+        self.pending = pending;
+        if expired.is_empty() {
+            return expired;
+        }
+
+        expired.sort_by(|a, b| (&a.origin, a.number()).cmp(&(&b.origin, b.number())));
+        for tx in &expired {
+            log::error!(
+                "Transaction {} of {} delivered without its dependencies {:?}",
+                tx.number(),
+                tx.origin,
+                tx.deps
+            );
+            self.timed_out += 1;
+            self.mark_delivered(tx);
+        }
+        expired.extend(self.take_deliverable());
+        expired
+    }
+
+    /// Number of transactions waiting for their dependencies
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Largest number of transactions held at once
+    pub fn peak(&self) -> usize {
+        self.peak
+    }
+
+    /// Number of transactions delivered without their dependencies
+    pub fn timed_out(&self) -> u64 {
+        self.timed_out
+    }
+
+    /// Number of transactions delivered for each initiator
+    pub fn delivered(&self) -> Dependencies {
+        self.delivered
+            .iter()
+            .map(|(site, n)| (site.clone(), *n))
+            .collect()
+    }
+
+    /// A transaction can be delivered once it is the next one of its initiator
+    /// and every transaction it depends on is delivered
+    fn deliverable(&self, tx: &BufferedTransaction) -> bool {
+        let delivered = |site: &str| self.delivered.get(site).copied();
+        tx.deps.iter().all(|(site, n)| {
+            if *site == tx.origin {
+                delivered(site).unwrap_or(0) + 1 == *n
+            } else {
+                // sans nouvelles de ce site, ses transactions nous sont
+                // parvenues avec la synchronisation
+                delivered(site).is_none_or(|d| d >= *n)
+            }
+        })
+    }
+
+    fn mark_delivered(&mut self, tx: &BufferedTransaction) {
+        for (site, n) in &tx.deps {
+            let delivered = self.delivered.entry(site.clone()).or_insert(0);
+            *delivered = (*delivered).max(*n);
+        }
+    }
+
+    /// Pops the held transactions whose dependencies are now delivered
+    fn take_deliverable(&mut self) -> Vec<BufferedTransaction> {
+        let mut ready = Vec::new();
+        while let Some(pos) = self.pending.iter().position(|tx| self.deliverable(tx)) {
+            let tx = self.pending.remove(pos);
+            self.mark_delivered(&tx);
+            ready.push(tx);
+        }
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tx(origin: &str, deps: &[(&str, u64)], at: std::time::Instant) -> BufferedTransaction {
+        BufferedTransaction {
+            origin: origin.to_string(),
+            deps: deps.iter().map(|(s, n)| (s.to_string(), *n)).collect(),
+            info: crate::message::MessageInfo::None,
+            clock: crate::clock::Clock::new(),
+            received_at: at,
+        }
+    }
+
+    fn numbers(txs: &[BufferedTransaction]) -> Vec<(String, u64)> {
+        txs.iter().map(|t| (t.origin.clone(), t.number())).collect()
+    }
+
+    #[test]
+    fn test_local_transactions_are_counted() {
+        let mut buffer = CausalBuffer::new();
+        buffer.stamp_local("A");
+        let deps = buffer.stamp_local("A");
+        assert_eq!(deps.get("A"), Some(&2));
+        assert_eq!(buffer.delivered(), deps);
+    }
+
+    #[test]
+    fn test_restarted_site_carries_on_counting() {
+        let mut buffer = CausalBuffer::new();
+        buffer.init_delivered(Dependencies::from([
+            ("A".to_string(), 3),
+            ("B".to_string(), 5),
+        ]));
+        let deps = buffer.stamp_local("A");
+        assert_eq!(deps.get("A"), Some(&4));
+        assert_eq!(deps.get("B"), Some(&5));
+    }
+
+    #[test]
+    fn test_transactions_wait_for_their_dependencies() {
+        let now = std::time::Instant::now();
+        let mut buffer = CausalBuffer::new();
+        buffer.receive(tx("A", &[("A", 1)], now));
+
+        // B refunds the second transaction of A before we received it
+        assert!(
+            buffer
+                .receive(tx("B", &[("A", 2), ("B", 1)], now))
+                .is_empty()
+        );
+        // A third one of A arrives before its second one
+        assert!(buffer.receive(tx("A", &[("A", 3)], now)).is_empty());
+        assert_eq!(buffer.len(), 2);
+
+        let ready = buffer.receive(tx("A", &[("A", 2)], now));
+        assert_eq!(
+            numbers(&ready),
+            vec![
+                ("A".to_string(), 2),
+                ("B".to_string(), 1),
+                ("A".to_string(), 3)
+            ]
+        );
+        assert_eq!(buffer.len(), 0);
+        assert_eq!(buffer.peak(), 3);
+    }
+
+    #[test]
+    fn test_late_transactions_are_not_held() {
+        let now = std::time::Instant::now();
+        let mut buffer = CausalBuffer::new();
+        assert_eq!(buffer.receive(tx("A", &[("A", 4)], now)).len(), 1);
+        assert_eq!(buffer.receive(tx("A", &[("A", 4)], now)).len(), 1);
+        assert_eq!(buffer.receive(tx("A", &[("A", 2)], now)).len(), 1);
+        assert_eq!(buffer.len(), 0);
+        assert_eq!(buffer.delivered().get("A"), Some(&4));
+    }
+
+    #[test]
+    fn test_missing_dependency_times_out() {
+        let now = std::time::Instant::now();
+        let mut buffer = CausalBuffer::new();
+        buffer.init_timeout(100);
+        buffer.receive(tx("A", &[("A", 1)], now));
+        buffer.receive(tx("B", &[("B", 1)], now));
+
+        // The second transaction of A never arrives
+        let later = now + std::time::Duration::from_millis(60);
+        buffer.receive(tx("B", &[("A", 2), ("B", 2)], now));
+        buffer.receive(tx("A", &[("A", 3)], later));
+        assert!(buffer.expire(later).is_empty());
+
+        let expired = buffer.expire(now + std::time::Duration::from_millis(100));
+        assert_eq!(
+            numbers(&expired),
+            vec![("B".to_string(), 2), ("A".to_string(), 3)]
+        );
+        assert_eq!(buffer.timed_out(), 1);
+        assert_eq!(buffer.len(), 0);
+
+        // The lost transaction is still applied if it shows up
+        assert_eq!(buffer.receive(tx("A", &[("A", 2)], later)).len(), 1);
+    }
+}
@@ -1,7 +1,88 @@
 //! Logical clock implementation for distributed synchronization
 //!
 //! This module provides both Lamport and Vector clock implementations for
-//! maintaining causal ordering of events in the distributed system.
+//! maintaining causal ordering of events in the distributed system, and a
+//! hybrid logical clock giving the events a timestamp close to the real time.
+
+/// Hybrid logical clock: physical time in milliseconds and a logical counter
+///
+/// The physical component follows the wall clock of the sites when it moves
+/// forward, and the counter orders the events sharing the same physical
+/// time, so the timestamps respect causality like a Lamport clock while
+/// staying close to the real time of the events.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct Hlc {
+    /// Physical component, milliseconds since the Unix epoch
+    pub physical_ms: i64,
+    /// Logical component
+    pub logical: u32,
+}
+
+impl Hlc {
+    /// Current time of the wall clock in milliseconds since the Unix epoch
+    #[cfg(feature = "server")]
+    pub fn wall_clock_ms() -> i64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or(0)
+    }
+
+    /// Advances the clock for a local or send event
+    pub fn tick(&mut self, now_ms: i64) {
+        if now_ms > self.physical_ms {
+            self.physical_ms = now_ms;
+            self.logical = 0;
+        } else {
+            self.logical += 1;
+        }
+    }
+
+    /// Advances the clock for the reception of a message stamped with `received`
+    pub fn merge(&mut self, received: &Hlc, now_ms: i64) {
+        let physical_ms = self.physical_ms.max(received.physical_ms).max(now_ms);
+        self.logical = match (
+            physical_ms == self.physical_ms,
+            physical_ms == received.physical_ms,
+        ) {
+            (true, true) => self.logical.max(received.logical) + 1,
+            (true, false) => self.logical + 1,
+            (false, true) => received.logical + 1,
+            (false, false) => 0,
+        };
+        self.physical_ms = physical_ms;
+    }
+
+    /// Milliseconds the physical component is ahead of the given time
+    pub fn drift_ms(&self, now_ms: i64) -> i64 {
+        self.physical_ms - now_ms
+    }
+}
+
+impl std::fmt::Display for Hlc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match chrono::DateTime::from_timestamp_millis(self.physical_ms) {
+            Some(date) => write!(f, "{}", date.format("%Y-%m-%d %H:%M:%S%.3f UTC"))?,
+            None => write!(f, "{} ms", self.physical_ms)?,
+        }
+        if self.logical > 0 {
+            write!(f, " +{}", self.logical)?;
+        }
+        Ok(())
+    }
+}
 
 #[cfg(feature = "server")]
 /// Implements logical clocks for distributed synchronization
@@ -16,6 +97,9 @@ pub struct Clock {
     ///
     /// Site_id -> clock value
     vector_clock: std::collections::HashMap<String, i64>,
+    /// Hybrid logical clock, timestamp of the events close to the real time
+    #[serde(default)]
+    hlc: Hlc,
 }
 
 #[cfg(feature = "server")]
@@ -25,6 +109,7 @@ impl Clock {
         Clock {
             lamport_clock: 0,
             vector_clock: std::collections::HashMap::new(),
+            hlc: Hlc::default(),
         }
     }
 
@@ -35,6 +120,7 @@ impl Clock {
         Clock {
             lamport_clock,
             vector_clock,
+            hlc: Hlc::default(),
         }
     }
 
@@ -44,6 +130,7 @@ impl Clock {
         Clock {
             lamport_clock: lamport,
             vector_clock: vector,
+            hlc: Hlc::default(),
         }
     }
 
@@ -63,18 +150,110 @@ impl Clock {
         &self.lamport_clock
     }
 
+    /// Returns a reference to the hybrid logical clock
+    pub fn get_hlc(&self) -> &Hlc {
+        &self.hlc
+    }
+
     /// Returns a reference to the vector clock
     pub fn get_vector_clock_map(&self) -> &std::collections::HashMap<String, i64> {
         &self.vector_clock
     }
 
-    /// Returns the vector clock as a list of values
+    /// Returns the vector clock as a list of values, ordered by site ID
     pub fn get_vector_clock_values(&self) -> Vec<i64> {
-        let mut vc: Vec<i64> = vec![0; self.vector_clock.len()];
-        for (i, clock_value) in self.vector_clock.values().enumerate() {
-            vc[i] = *clock_value;
+        self.get_vector_clock_entries()
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    /// Returns the entries of the vector clock, ordered by site ID
+    pub fn get_vector_clock_entries(&self) -> Vec<(String, i64)> {
+        let mut entries: Vec<(String, i64)> = self
+            .vector_clock
+            .iter()
+            .map(|(site_id, value)| (site_id.clone(), *value))
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    /// Compares two vector clocks entry by entry, a missing entry counting as 0
+    ///
+    /// Returns `Less` if `a` happened before `b`, `Greater` if `b` happened
+    /// before `a`, `Equal` if they are the same and `None` if they are concurrent.
+    pub fn compare_vectors(
+        a: &std::collections::HashMap<String, i64>,
+        b: &std::collections::HashMap<String, i64>,
+    ) -> Option<std::cmp::Ordering> {
+        let mut ordering = std::cmp::Ordering::Equal;
+        for site_id in a.keys().chain(b.keys()) {
+            let entry =
+                |vc: &std::collections::HashMap<String, i64>| vc.get(site_id).copied().unwrap_or(0);
+            match (ordering, entry(a).cmp(&entry(b))) {
+                (_, std::cmp::Ordering::Equal) => {}
+                (std::cmp::Ordering::Equal, site_ordering) => ordering = site_ordering,
+                (ordering, site_ordering) if ordering != site_ordering => return None,
+                _ => {}
+            }
+        }
+        Some(ordering)
+    }
+
+    /// Compares the vector clocks of two events, `None` if they are concurrent
+    pub fn causal_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Self::compare_vectors(&self.vector_clock, &other.vector_clock)
+    }
+
+    /// Returns true if this event happened before the other one
+    pub fn happened_before(&self, other: &Self) -> bool {
+        self.causal_cmp(other) == Some(std::cmp::Ordering::Less)
+    }
+
+    /// Returns true if neither event happened before the other one
+    pub fn concurrent_with(&self, other: &Self) -> bool {
+        self.causal_cmp(other).is_none()
+    }
+
+    /// Returns true if every entry of this clock is at least the one of the other clock
+    pub fn dominates(&self, other: &Self) -> bool {
+        matches!(
+            self.causal_cmp(other),
+            Some(std::cmp::Ordering::Greater | std::cmp::Ordering::Equal)
+        )
+    }
+
+    /// Returns the smallest clock dominating both clocks, entry by entry
+    pub fn join(&self, other: &Self) -> Self {
+        let mut vector_clock = self.vector_clock.clone();
+        for (site_id, value) in &other.vector_clock {
+            let entry = vector_clock.entry(site_id.clone()).or_insert(0);
+            *entry = (*entry).max(*value);
+        }
+        Clock {
+            lamport_clock: self.lamport_clock.max(other.lamport_clock),
+            vector_clock,
+            hlc: self.hlc.max(other.hlc),
+        }
+    }
+
+    /// Clock of a site whose transactions were restored from a snapshot
+    ///
+    /// The Lamport and hybrid clocks and the entry of the site stay ahead of the
+    /// restored transactions, so the next ones do not reuse their timestamps.
+    /// The entries of the other sites are reset: a later catch-up resends their
+    /// transactions rather than skipping some.
+    pub fn restored(&self, site_id: &str, lamport: i64, hlc: Hlc) -> Self {
+        let own = self.vector_clock.get(site_id).copied().unwrap_or(0);
+        Clock {
+            lamport_clock: self.lamport_clock.max(lamport),
+            vector_clock: std::collections::HashMap::from([(
+                site_id.to_string(),
+                own.max(lamport),
+            )]),
+            hlc: self.hlc.max(hlc),
         }
-        vc
     }
 
     /// Updates the vector clock with received values, taking the maximum of local and received values
@@ -98,14 +277,41 @@ impl Clock {
     ///
     /// Then we call update methods to take the maximum of the received clocks if any
     pub fn update_clock(&mut self, local_site_id: &str, received_clock: Option<&Self>) {
+        self.update_clock_at(
+            local_site_id,
+            received_clock,
+            Hlc::wall_clock_ms(),
+            i64::MAX,
+        );
+    }
+
+    /// Same as [`Clock::update_clock`], with the wall clock reading `now_ms`
+    ///
+    /// The hybrid logical clock of a received clock more than `max_drift_ms`
+    /// ahead of the wall clock is not merged, so a site whose wall clock runs
+    /// ahead does not drag the others into the future. Returns false then.
+    pub fn update_clock_at(
+        &mut self,
+        local_site_id: &str,
+        received_clock: Option<&Self>,
+        now_ms: i64,
+        max_drift_ms: i64,
+    ) -> bool {
         if let Some(rc) = received_clock {
             self.update_vector(rc.get_vector_clock_map());
             self.update_lamport(rc.get_lamport());
+            if rc.hlc.drift_ms(now_ms) > max_drift_ms {
+                self.hlc.tick(now_ms);
+                return false;
+            }
+            self.hlc.merge(&rc.hlc, now_ms);
         } else {
             // If the received clock is None, we increment the local lamport clock and the local vector clock
             self.increment_lamport();
             self.increment_vector(local_site_id);
+            self.hlc.tick(now_ms);
         }
+        true
     }
 }
 
@@ -149,9 +355,10 @@ mod tests {
         clock.increment_vector("B");
         clock.increment_vector("B");
 
-        let mut values = clock.get_vector_clock_values();
-        values.sort(); // Order not guaranteed by HashMap
-        assert_eq!(values, vec![1, 2]);
+        clock.increment_vector("C");
+
+        // Ordered by site ID, whatever the order of the HashMap
+        assert_eq!(clock.get_vector_clock_values(), vec![1, 2, 1]);
     }
 
     #[test]
@@ -206,4 +413,185 @@ mod tests {
         assert_eq!(vc.get("A"), Some(&3)); // Incremented locally + merged max
         assert_eq!(vc.get("B"), Some(&2));
     }
+
+    fn vc(entries: &[(&str, i64)]) -> Clock {
+        Clock::new_with_values(
+            0,
+            entries.iter().map(|(s, v)| (s.to_string(), *v)).collect(),
+        )
+    }
+
+    #[test]
+    fn test_causal_comparisons() {
+        let a = vc(&[("A", 1)]);
+        let b = vc(&[("A", 1), ("B", 1)]);
+        let c = vc(&[("A", 2), ("B", 0)]);
+
+        assert!(a.happened_before(&b));
+        assert!(!b.happened_before(&a));
+        assert!(b.dominates(&a) && b.dominates(&b));
+        assert!(b.concurrent_with(&c));
+        assert!(!a.concurrent_with(&c));
+        // A missing entry counts as 0
+        assert_eq!(
+            a.causal_cmp(&vc(&[("A", 1), ("B", 0)])),
+            Some(std::cmp::Ordering::Equal)
+        );
+        assert_eq!(
+            b.join(&c).get_vector_clock_entries(),
+            vec![("A".to_string(), 2), ("B".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_hlc_follows_the_wall_clock() {
+        let mut hlc = Hlc::default();
+        hlc.tick(1_000);
+        assert_eq!((hlc.physical_ms, hlc.logical), (1_000, 0));
+
+        // The wall clock stalls or goes back: the counter orders the events
+        hlc.tick(1_000);
+        hlc.tick(900);
+        assert_eq!((hlc.physical_ms, hlc.logical), (1_000, 2));
+
+        hlc.tick(1_200);
+        assert_eq!((hlc.physical_ms, hlc.logical), (1_200, 0));
+    }
+
+    #[test]
+    fn test_hlc_merge_respects_causality() {
+        let mut local = Hlc {
+            physical_ms: 1_000,
+            logical: 3,
+        };
+        let received = Hlc {
+            physical_ms: 1_500,
+            logical: 7,
+        };
+
+        // A message from a site whose wall clock is ahead
+        local.merge(&received, 1_100);
+        assert!(local > received);
+        assert_eq!((local.physical_ms, local.logical), (1_500, 8));
+
+        // Both behind the wall clock
+        local.merge(&received, 2_000);
+        assert_eq!((local.physical_ms, local.logical), (2_000, 0));
+    }
+
+    #[test]
+    fn test_drifting_clock_is_not_merged() {
+        let mut local = Clock::new();
+        local.update_clock_at("A", None, 1_000, 500);
+
+        let mut remote = Clock::new();
+        remote.update_clock_at("B", None, 5_000, 500);
+
+        assert!(!local.update_clock_at("A", Some(&remote), 1_100, 500));
+        assert_eq!(local.get_hlc().physical_ms, 1_100);
+        // The logical clocks are merged anyway
+        assert_eq!(local.get_vector_clock_map().get("B"), Some(&2));
+
+        assert!(local.update_clock_at("A", Some(&remote), 4_600, 500));
+        assert_eq!(
+            *local.get_hlc(),
+            Hlc {
+                physical_ms: 5_000,
+                logical: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_restored_clock_is_ahead_of_the_snapshot() {
+        let mut clock = Clock::new_with_values(
+            4,
+            std::collections::HashMap::from([("A".to_string(), 3), ("B".to_string(), 9)]),
+        );
+        clock.hlc = Hlc {
+            physical_ms: 2_000,
+            logical: 0,
+        };
+        let snapshot_hlc = Hlc {
+            physical_ms: 3_000,
+            logical: 1,
+        };
+
+        let restored = clock.restored("A", 12, snapshot_hlc);
+        assert_eq!(*restored.get_lamport(), 12);
+        assert_eq!(
+            restored.get_vector_clock_map(),
+            &std::collections::HashMap::from([("A".to_string(), 12)])
+        );
+        assert_eq!(*restored.get_hlc(), snapshot_hlc);
+    }
+
+    #[test]
+    fn test_hlc_display() {
+        let hlc = Hlc {
+            physical_ms: 0,
+            logical: 2,
+        };
+        assert_eq!(hlc.to_string(), "1970-01-01 00:00:00.000 UTC +2");
+    }
+
+    mod lattice {
+        use super::*;
+        use proptest::prelude::*;
+
+        fn clock() -> impl Strategy<Value = Clock> {
+            proptest::collection::hash_map("[A-D]", 0i64..4, 0..4)
+                .prop_map(|vector| Clock::new_with_values(0, vector))
+        }
+
+        proptest! {
+            #[test]
+            fn join_is_commutative(a in clock(), b in clock()) {
+                prop_assert_eq!(a.join(&b).causal_cmp(&b.join(&a)), Some(std::cmp::Ordering::Equal));
+            }
+
+            #[test]
+            fn join_is_associative(a in clock(), b in clock(), c in clock()) {
+                let left = a.join(&b).join(&c);
+                let right = a.join(&b.join(&c));
+                prop_assert_eq!(left.causal_cmp(&right), Some(std::cmp::Ordering::Equal));
+            }
+
+            #[test]
+            fn join_is_idempotent(a in clock()) {
+                prop_assert_eq!(a.join(&a).causal_cmp(&a), Some(std::cmp::Ordering::Equal));
+            }
+
+            #[test]
+            fn join_is_the_least_upper_bound(a in clock(), b in clock(), c in clock()) {
+                let join = a.join(&b);
+                prop_assert!(join.dominates(&a) && join.dominates(&b));
+                if c.dominates(&a) && c.dominates(&b) {
+                    prop_assert!(c.dominates(&join));
+                }
+            }
+
+            #[test]
+            fn order_is_antisymmetric(a in clock(), b in clock()) {
+                if a.dominates(&b) && b.dominates(&a) {
+                    prop_assert_eq!(a.causal_cmp(&b), Some(std::cmp::Ordering::Equal));
+                }
+                prop_assert!(!(a.happened_before(&b) && b.happened_before(&a)));
+            }
+
+            #[test]
+            fn happened_before_is_transitive(a in clock(), b in clock(), c in clock()) {
+                if a.happened_before(&b) && b.happened_before(&c) {
+                    prop_assert!(a.happened_before(&c));
+                }
+            }
+
+            #[test]
+            fn comparison_is_symmetric(a in clock(), b in clock()) {
+                prop_assert_eq!(a.causal_cmp(&b), b.causal_cmp(&a).map(std::cmp::Ordering::reverse));
+                prop_assert_eq!(a.concurrent_with(&b), b.concurrent_with(&a));
+                prop_assert_eq!(a.happened_before(&b), b.dominates(&a) && !a.dominates(&b));
+            }
+        }
+    }
 }
@@ -0,0 +1,180 @@
+//! Length-prefixed framing for peer streams
+//!
+//! TCP is a byte stream: a single `read()` may return half a message or
+//! several messages at once. Every message exchanged between sites is
+//! therefore sent as a frame made of a big-endian `u32` length prefix
+//! followed by the MessagePack payload. This module provides the encoder
+//! used by the writer task and the decoder used by the reader.
+
+#![cfg(feature = "server")]
+
+/// Default maximum size of a frame payload (16 MiB)
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Size of the length prefix in bytes
+const LENGTH_PREFIX_SIZE: usize = 4;
+
+/// Errors raised while encoding or decoding frames
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// The frame payload is bigger than the configured maximum
+    FrameTooLarge { size: usize, max: usize },
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::FrameTooLarge { size, max } => write!(
+                f,
+                "frame of {} bytes exceeds the maximum frame size of {} bytes",
+                size, max
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Length-prefixed frame encoder and decoder
+///
+/// The decoder keeps the bytes it has not consumed yet, so it can be fed
+/// with arbitrary chunks coming from the socket.
+pub struct FrameCodec {
+    /// Maximum accepted payload size
+    max_frame_size: usize,
+    /// Bytes received but not yet decoded
+    buffer: Vec<u8>,
+}
+
+impl FrameCodec {
+    /// Creates a new codec accepting frames up to `max_frame_size` bytes
+    pub fn new(max_frame_size: usize) -> Self {
+        Self {
+            max_frame_size,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Checks that a payload fits in a frame
+    pub fn check_size(&self, size: usize) -> Result<(), FrameError> {
+        if size > self.max_frame_size || size > u32::MAX as usize {
+            return Err(FrameError::FrameTooLarge {
+                size,
+                max: self.max_frame_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// Wraps a payload into a frame ready to be written on the stream
+    pub fn encode(&self, payload: &[u8]) -> Result<Vec<u8>, FrameError> {
+        self.check_size(payload.len())?;
+        let mut frame = Vec::with_capacity(LENGTH_PREFIX_SIZE + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+        Ok(frame)
+    }
+
+    /// Appends bytes read from the stream to the internal buffer
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Extracts the next complete frame payload, if any
+    ///
+    /// Returns `Ok(None)` when more bytes are needed. The length prefix is
+    /// checked as soon as it is available, so an oversized frame is rejected
+    /// before its payload is buffered.
+    pub fn decode(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        if self.buffer.len() < LENGTH_PREFIX_SIZE {
+            return Ok(None);
+        }
+
+        let mut prefix = [0u8; LENGTH_PREFIX_SIZE];
+        prefix.copy_from_slice(&self.buffer[..LENGTH_PREFIX_SIZE]);
+        let size = u32::from_be_bytes(prefix) as usize;
+        self.check_size(size)?;
+
+        if self.buffer.len() < LENGTH_PREFIX_SIZE + size {
+            return Ok(None);
+        }
+
+        let payload = self.buffer[LENGTH_PREFIX_SIZE..LENGTH_PREFIX_SIZE + size].to_vec();
+        self.buffer.drain(..LENGTH_PREFIX_SIZE + size);
+        Ok(Some(payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_single_frame() {
+        let mut codec = FrameCodec::new(DEFAULT_MAX_FRAME_SIZE);
+        let frame = codec.encode(b"hello").unwrap();
+        assert_eq!(frame.len(), LENGTH_PREFIX_SIZE + 5);
+
+        codec.extend(&frame);
+        assert_eq!(codec.decode().unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(codec.decode().unwrap(), None);
+    }
+
+    #[test]
+    fn decode_handles_partial_reads() {
+        let mut codec = FrameCodec::new(DEFAULT_MAX_FRAME_SIZE);
+        let payload = vec![7u8; 3000];
+        let frame = codec.encode(&payload).unwrap();
+
+        // Feed the frame one byte at a time, the prefix included
+        for (i, byte) in frame.iter().enumerate() {
+            codec.extend(&[*byte]);
+            let decoded = codec.decode().unwrap();
+            if i + 1 < frame.len() {
+                assert!(decoded.is_none());
+            } else {
+                assert_eq!(decoded, Some(payload.clone()));
+            }
+        }
+    }
+
+    #[test]
+    fn decode_handles_multiple_frames_in_one_read() {
+        let mut codec = FrameCodec::new(DEFAULT_MAX_FRAME_SIZE);
+        let mut chunk = codec.encode(b"first").unwrap();
+        chunk.extend(codec.encode(b"").unwrap());
+        chunk.extend(codec.encode(b"third").unwrap());
+        // Start of a fourth frame which is not complete yet
+        let fourth = codec.encode(b"fourth").unwrap();
+        chunk.extend(&fourth[..6]);
+
+        codec.extend(&chunk);
+        assert_eq!(codec.decode().unwrap(), Some(b"first".to_vec()));
+        assert_eq!(codec.decode().unwrap(), Some(Vec::new()));
+        assert_eq!(codec.decode().unwrap(), Some(b"third".to_vec()));
+        assert_eq!(codec.decode().unwrap(), None);
+
+        codec.extend(&fourth[6..]);
+        assert_eq!(codec.decode().unwrap(), Some(b"fourth".to_vec()));
+    }
+
+    #[test]
+    fn encode_rejects_oversized_payload() {
+        let codec = FrameCodec::new(8);
+        assert!(codec.encode(&[0u8; 8]).is_ok());
+        assert_eq!(
+            codec.encode(&[0u8; 9]),
+            Err(FrameError::FrameTooLarge { size: 9, max: 8 })
+        );
+    }
+
+    #[test]
+    fn decode_rejects_oversized_prefix_before_payload() {
+        let mut codec = FrameCodec::new(8);
+        codec.extend(&1024u32.to_be_bytes());
+        assert_eq!(
+            codec.decode(),
+            Err(FrameError::FrameTooLarge { size: 1024, max: 8 })
+        );
+    }
+}
@@ -5,14 +5,14 @@
 
 #![cfg(feature = "server")]
 /// Worker that handles critical commands
-pub fn control_worker() {
-    tokio::spawn(async {
-        use crate::state::LOCAL_APP_STATE;
+pub fn control_worker(node: crate::node::Node) {
+    tokio::spawn(async move {
+        use crate::mutex::MutexStatus;
 
         loop {
             // Récupérer Notify sans garder le verrou
             let notify = {
-                let st = LOCAL_APP_STATE.lock().await;
+                let st = node.state.lock().await;
                 st.notify_sc.clone()
             };
 
@@ -21,29 +21,35 @@ pub fn control_worker() {
 
             // Vider la file de tsx en attente
             {
-                let (in_st, waiting, nb_pending) = {
-                    let st = LOCAL_APP_STATE.lock().await;
-                    (st.in_sc, st.waiting_sc, st.pending_commands.len())
+                let (status, nb_pending) = {
+                    let st = node.state.lock().await;
+                    (st.mutex_status(), st.pending_commands.len())
                 };
 
-                if !waiting && nb_pending > 0 && !in_st {
-                    let mut st = LOCAL_APP_STATE.lock().await;
-                    let _ = st.acquire_mutex().await;
+                if status == MutexStatus::Idle && nb_pending > 0 {
+                    let mut st = node.state.lock().await;
+                    let _ = st.acquire_mutex(&node).await;
                     continue;
                 }
 
-                if in_st && nb_pending > 0 {
+                if status == MutexStatus::Held && nb_pending > 0 {
                     log::info!("Début de la section critique");
                     loop {
                         let cmd_opt = {
-                            let mut st = LOCAL_APP_STATE.lock().await;
-                            st.pending_commands.pop_front()
+                            let mut st = node.state.lock().await;
+                            st.pop_runnable_command()
                         };
-                        if let Some(cmd) = cmd_opt {
+                        if let Some(pending) = cmd_opt {
                             log::info!("Execute critical command");
-                            if let Err(e) = crate::control::execute_critical(cmd).await {
+                            let result = crate::control::execute_critical(&node, pending.cmd)
+                                .await
+                                .map_err(|e| e.to_string());
+                            if let Err(e) = &result {
                                 log::error!("Erreur exécution commande critique : {}", e);
                             }
+                            if let Some(done) = pending.done {
+                                let _ = done.send(result);
+                            }
                         } else {
                             break;
                         }
@@ -55,6 +61,76 @@ pub fn control_worker() {
     });
 }
 
+#[cfg(feature = "server")]
+/// Worker that recovers the global mutex from failed sites
+///
+/// At every heartbeat interval it drops the requests of the sites the
+/// membership view reports as gone, and gives up our own request once it has
+/// waited longer than the mutex timeout.
+pub fn mutex_watchdog_worker(node: crate::node::Node) {
+    tokio::spawn(async move {
+        let interval = {
+            let st = node.state.lock().await;
+            st.failure_detector.get_heartbeat_interval()
+        };
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+
+            let mut st = node.state.lock().await;
+            st.forget_departed_requests();
+            if let Err(e) = st.resume_mutex(&node).await {
+                log::error!("Failed to resume the mutual exclusion: {}", e);
+            }
+            if st.mutex_request_expired(std::time::Instant::now()) {
+                if let Err(e) = st.abort_mutex_request(&node).await {
+                    log::error!("Failed to withdraw the mutex request: {}", e);
+                }
+                println!("\x1b[1;31mSECTION CRITIQUE ABANDONNEE !\x1b[0m");
+            }
+        }
+    });
+}
+
+#[cfg(feature = "server")]
+/// Delivers the transactions that waited too long for their dependencies
+pub fn causal_delivery_worker(node: crate::node::Node) {
+    tokio::spawn(async move {
+        let interval = {
+            let st = node.state.lock().await;
+            st.failure_detector.get_heartbeat_interval()
+        };
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+
+            let mut st = node.state.lock().await;
+            let expired = st.causal.expire(std::time::Instant::now());
+            deliver_transactions(&node, expired).await;
+        }
+    });
+}
+
+#[cfg(feature = "server")]
+/// Takes a snapshot of the network at every interval
+pub fn snapshot_worker(node: crate::node::Node, interval: std::time::Duration) {
+    tokio::spawn(async move {
+        let start = tokio::time::Instant::now() + interval;
+        let mut ticker = tokio::time::interval_at(start, interval);
+
+        loop {
+            ticker.tick().await;
+
+            log::info!("Taking the scheduled snapshot");
+            if let Err(e) = run_critical(&node, CriticalCommands::FileSnapshot).await {
+                log::error!("Scheduled snapshot failed: {}", e);
+            }
+        }
+    });
+}
+
 #[cfg(feature = "server")]
 /// Parse a line of input from the CLI and converts it to a Command
 pub fn parse_command(line: Result<Option<String>, std::io::Error>) -> Command {
@@ -74,7 +150,27 @@ pub fn parse_command(line: Result<Option<String>, std::io::Error>) -> Command {
                 "/help" => Command::Help,
                 "/info" => Command::Info,
                 "/start_snapshot" => Command::Snapshot,
-                other => Command::Unknown(other.to_string()),
+                "/start_cl_snapshot" => Command::MarkerSnapshot,
+                "/check_ledger" => Command::CheckLedger,
+                other => {
+                    let mut words = other.split_whitespace();
+                    match words.next() {
+                        Some("/restore_snapshot") => {
+                            Command::RestoreSnapshot(words.collect::<Vec<_>>().join(" "))
+                        }
+                        Some("/diff_snapshots") => {
+                            let (flags, files): (Vec<&str>, Vec<&str>) =
+                                words.partition(|word| *word == "--json");
+                            let file = |i: usize| files.get(i).unwrap_or(&"").to_string();
+                            Command::DiffSnapshots {
+                                left: file(0),
+                                right: file(1),
+                                json: !flags.is_empty(),
+                            }
+                        }
+                        _ => Command::Unknown(other.to_string()),
+                    }
+                }
             };
             command
         }
@@ -121,6 +217,18 @@ pub enum Command {
     Error(String),
     /// Start a system snapshot
     Snapshot,
+    /// Start a Chandy–Lamport snapshot, recording the channels
+    MarkerSnapshot,
+    /// Start a snapshot checking the ledger invariants
+    CheckLedger,
+    /// Rebuild the site from a snapshot file
+    RestoreSnapshot(String),
+    /// Compare two snapshot files, in JSON if asked
+    DiffSnapshots {
+        left: String,
+        right: String,
+        json: bool,
+    },
 }
 
 #[cfg(feature = "server")]
@@ -130,17 +238,26 @@ pub enum CriticalCommands {
     /// Create a new user account
     CreateUser { name: String },
     /// Deposit money into an account
-    Deposit { name: String, amount: f64 },
+    Deposit {
+        name: String,
+        amount: crate::money::Money,
+    },
     /// Withdraw money from an account
-    Withdraw { name: String, amount: f64 },
+    Withdraw {
+        name: String,
+        amount: crate::money::Money,
+    },
     /// Transfer money between accounts
     Transfer {
         from: String,
         to: String,
-        amount: f64,
+        amount: crate::money::Money,
     },
     /// Make a payment
-    Pay { name: String, amount: f64 },
+    Pay {
+        name: String,
+        amount: crate::money::Money,
+    },
     /// Process a refund
     Refund {
         name: String,
@@ -149,25 +266,122 @@ pub enum CriticalCommands {
     },
     /// Request a snapshot to save as a JSON
     FileSnapshot,
-    /// Request a snapshot to update our database
-    SyncSnapshot,
 }
 
 #[cfg(feature = "server")]
-/// Enqueue a critical command
-pub async fn enqueue_critical(cmd: CriticalCommands) -> Result<(), Box<dyn std::error::Error>> {
-    use crate::state::LOCAL_APP_STATE;
-    let mut st = LOCAL_APP_STATE.lock().await;
+impl CriticalCommands {
+    /// Returns the accounts the command reads or writes
+    ///
+    /// The `NULL` account standing for the outside world is never locked.
+    pub fn lock_scope(&self, db: &crate::db::Db) -> crate::state::LockScope {
+        use crate::state::LockScope;
+
+        let accounts = match self {
+            CriticalCommands::CreateUser { name }
+            | CriticalCommands::Deposit { name, .. }
+            | CriticalCommands::Withdraw { name, .. }
+            | CriticalCommands::Pay { name, .. } => vec![name.clone()],
+            CriticalCommands::Transfer { from, to, .. } => vec![from.clone(), to.clone()],
+            CriticalCommands::Refund {
+                name,
+                lamport,
+                node,
+            } => {
+                // The refund gives the money back between the two accounts of the transaction
+                let mut accounts = vec![name.clone()];
+                if let Ok(Some(tx)) = crate::db::get_transaction(db, *lamport, node) {
+                    accounts.push(tx.from_user);
+                    accounts.push(tx.to_user);
+                }
+                accounts
+            }
+            CriticalCommands::FileSnapshot => return LockScope::All,
+        };
+        LockScope::accounts(accounts.into_iter().filter(|a| a != crate::db::NULL))
+    }
+}
 
-    st.pending_commands.push_back(cmd);
+#[cfg(feature = "server")]
+/// Critical command waiting for the global mutex
+#[derive(Debug)]
+pub struct PendingCommand {
+    /// Command to execute in critical section
+    pub cmd: CriticalCommands,
+    /// Accounts the command works on
+    pub scope: crate::state::LockScope,
+    /// Channel reporting the outcome of the command to its caller, if any
+    pub done: Option<tokio::sync::oneshot::Sender<Result<(), String>>>,
+}
+
+#[cfg(feature = "server")]
+impl PendingCommand {
+    /// Creates a pending command, reporting its outcome to `done` if given
+    pub fn new(
+        db: &crate::db::Db,
+        cmd: CriticalCommands,
+        done: Option<tokio::sync::oneshot::Sender<Result<(), String>>>,
+    ) -> Self {
+        Self {
+            scope: cmd.lock_scope(db),
+            cmd,
+            done,
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+/// Enqueue a critical command and wait for its execution
+///
+/// Fails if the command itself fails, or if the global mutex could not be
+/// acquired before the mutex timeout.
+pub async fn run_critical(
+    node: &crate::node::Node,
+    cmd: CriticalCommands,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (done, outcome) = tokio::sync::oneshot::channel();
+    queue_critical(node, PendingCommand::new(&node.db, cmd, Some(done))).await?;
+    match outcome.await {
+        Ok(result) => result.map_err(|e| e.into()),
+        Err(_) => Err("The critical command was dropped before its execution".into()),
+    }
+}
+
+#[cfg(feature = "server")]
+/// Enqueue a critical command typed in the CLI, and report its outcome once executed
+///
+/// The main loop must keep accepting peers and handling Ctrl+C while the
+/// command waits for the mutex, so it does not wait for the command.
+fn spawn_critical(node: &crate::node::Node, cmd: CriticalCommands) {
+    let node = node.clone();
+    tokio::spawn(async move {
+        if let Err(e) = run_critical(&node, cmd).await {
+            log::error!("Error handling a cli command:\n{}", e);
+            println!("❌ {}", e);
+        }
+    });
+}
+
+#[cfg(feature = "server")]
+async fn queue_critical(
+    node: &crate::node::Node,
+    pending: PendingCommand,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use crate::mutex::MutexStatus;
+    let mut st = node.state.lock().await;
+
+    st.pending_commands.push_back(pending);
 
     // si on n’est ni en SC ni déjà en attente → on déclenche la vague
 
-    log::debug!("is in sc {}", !st.in_sc);
-    log::debug!("is waiting {}", !st.waiting_sc);
+    log::debug!("mutex status {:?}", st.mutex_status());
 
-    if !st.in_sc && !st.waiting_sc {
-        st.acquire_mutex().await?;
+    match st.mutex_status() {
+        MutexStatus::Idle => st.acquire_mutex(node).await?,
+        MutexStatus::Waiting => {}
+        MutexStatus::Held => {
+            // la section critique en cours couvre peut-être déjà ses comptes
+            st.notify_sc.notify_waiters();
+        }
     }
     Ok(())
 }
@@ -175,213 +389,247 @@ pub async fn enqueue_critical(cmd: CriticalCommands) -> Result<(), Box<dyn std::
 #[cfg(feature = "server")]
 /// Execute a critical command on our site
 ///
-/// Called by the control worker only when the Mutex is acquired
-pub async fn execute_critical(cmd: CriticalCommands) -> Result<(), Box<dyn std::error::Error>> {
-    use crate::message::{Message, MessageInfo, NetworkMessageCode};
-    use crate::network::diffuse_message;
-    use crate::state::LOCAL_APP_STATE;
-
-    let (clock, site_addr, site_id) = {
-        let mut state = LOCAL_APP_STATE.lock().await;
-        let local_addr = state.get_site_addr();
-        let node = state.get_site_id();
-        let _ = state.update_clock(None);
+/// Called by the control worker only when the Mutex is acquired. A refused
+/// command starts no wave, the mutex is released here instead of at the end
+/// of the wave.
+pub async fn execute_critical(
+    node: &crate::node::Node,
+    cmd: CriticalCommands,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let result = start_critical(node, cmd).await.map_err(|e| e.to_string());
+    if result.is_err() {
+        let mut state = node.state.lock().await;
+        let _ = state.release_mutex_if_done(node).await;
+    }
+    Ok(result?)
+}
+
+#[cfg(feature = "server")]
+/// Applies a critical command locally and starts the wave carrying it
+async fn start_critical(
+    node: &crate::node::Node,
+    cmd: CriticalCommands,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use crate::message::MessageInfo;
+
+    let (clock, site_id) = {
+        let mut state = node.state.lock().await;
+        let site_id = state.get_site_id();
+        state.update_clock(&node.db, None).await;
         let clock = state.get_clock();
-        (clock, local_addr, node)
+        (clock, site_id)
     };
 
-    let msg;
+    let handler: &dyn crate::wave::WaveHandler;
+    let command;
+    let info;
 
     match cmd {
         CriticalCommands::CreateUser { name } => {
             use crate::message::CreateUser;
             if name.is_empty() {
                 log::warn!("Skipping CreateUser command with empty username");
-                return Ok(());
+                return Err("Cannot create a user with an empty name".into());
             }
-            super::db::create_user(&name)?;
-            msg = Message {
-                command: Some(Command::CreateUser),
-                info: MessageInfo::CreateUser(CreateUser::new(name)),
-                code: NetworkMessageCode::Transaction,
-                clock: clock,
-                sender_addr: site_addr,
-                sender_id: site_id.to_string(),
-                message_initiator_id: site_id.to_string(),
-                message_initiator_addr: site_addr,
-            };
+            super::db::create_user(&node.db, &name)?;
+            handler = &TransactionWave;
+            command = Some(Command::CreateUser);
+            info = MessageInfo::CreateUser(CreateUser::new(name));
         }
         CriticalCommands::Deposit { name, amount } => {
             use crate::message::Deposit;
 
             super::db::deposit(
+                &node.db,
                 &name,
                 amount,
-                clock.get_lamport(),
-                site_id.as_str(),
-                clock.get_vector_clock_map(),
+                &super::db::TxStamp::new(&clock, &site_id),
             )?;
 
-            msg = Message {
-                command: Some(Command::Deposit),
-                info: MessageInfo::Deposit(Deposit::new(name, amount)),
-                code: NetworkMessageCode::Transaction,
-                clock: clock,
-                sender_addr: site_addr,
-                sender_id: site_id.to_string(),
-                message_initiator_id: site_id.to_string(),
-                message_initiator_addr: site_addr,
-            };
+            handler = &TransactionWave;
+            command = Some(Command::Deposit);
+            info = MessageInfo::Deposit(Deposit::new(name, amount));
         }
         CriticalCommands::Withdraw { name, amount } => {
             use crate::message::Withdraw;
             super::db::withdraw(
+                &node.db,
                 &name,
                 amount,
-                clock.get_lamport(),
-                site_id.as_str(),
-                clock.get_vector_clock_map(),
+                &super::db::TxStamp::new(&clock, &site_id),
             )?;
 
-            msg = Message {
-                command: Some(Command::Withdraw),
-                info: MessageInfo::Withdraw(Withdraw::new(name, amount)),
-                code: NetworkMessageCode::Transaction,
-                clock: clock,
-                sender_addr: site_addr,
-                sender_id: site_id.to_string(),
-                message_initiator_id: site_id.to_string(),
-                message_initiator_addr: site_addr,
-            };
+            handler = &TransactionWave;
+            command = Some(Command::Withdraw);
+            info = MessageInfo::Withdraw(Withdraw::new(name, amount));
         }
         CriticalCommands::Transfer { from, to, amount } => {
             use crate::message::Transfer;
             super::db::create_transaction(
+                &node.db,
                 &from,
                 &to,
                 amount,
-                clock.get_lamport(),
-                site_id.as_str(),
                 "",
-                clock.get_vector_clock_map(),
+                &super::db::TxStamp::new(&clock, &site_id),
             )?;
-            msg = Message {
-                command: Some(Command::Transfer),
-                info: MessageInfo::Transfer(Transfer::new(from.clone(), to.clone(), amount)),
-                code: NetworkMessageCode::Transaction,
-                clock: clock,
-                sender_addr: site_addr,
-                sender_id: site_id.to_string(),
-                message_initiator_id: site_id.to_string(),
-                message_initiator_addr: site_addr,
-            };
+            handler = &TransactionWave;
+            command = Some(Command::Transfer);
+            info = MessageInfo::Transfer(Transfer::new(from.clone(), to.clone(), amount));
         }
         CriticalCommands::Pay { name, amount } => {
             use crate::message::Pay;
             super::db::create_transaction(
+                &node.db,
                 &name,
                 "NULL",
                 amount,
-                clock.get_lamport(),
-                site_id.as_str(),
                 "",
-                clock.get_vector_clock_map(),
+                &super::db::TxStamp::new(&clock, &site_id),
             )?;
-            msg = Message {
-                command: Some(Command::Pay),
-                info: MessageInfo::Pay(Pay::new(name, amount)),
-                code: NetworkMessageCode::Transaction,
-                clock: clock,
-                sender_addr: site_addr,
-                sender_id: site_id.to_string(),
-                message_initiator_id: site_id.to_string(),
-                message_initiator_addr: site_addr,
-            };
+            handler = &TransactionWave;
+            command = Some(Command::Pay);
+            info = MessageInfo::Pay(Pay::new(name, amount));
         }
         CriticalCommands::Refund {
             name,
             lamport,
-            node,
+            node: origin,
         } => {
             use crate::message::Refund;
             super::db::refund_transaction(
+                &node.db,
                 lamport,
-                &node.as_str(),
-                clock.get_lamport(),
-                site_id.as_str(),
-                clock.get_vector_clock_map(),
+                &origin,
+                &super::db::TxStamp::new(&clock, &site_id),
             )?;
-            msg = Message {
-                command: Some(Command::Refund),
-                info: MessageInfo::Refund(Refund::new(name, lamport, node)),
-                code: NetworkMessageCode::Transaction,
-                clock: clock,
-                sender_addr: site_addr,
-                sender_id: site_id.to_string(),
-                message_initiator_id: site_id.to_string(),
-                message_initiator_addr: site_addr,
-            };
+            handler = &TransactionWave;
+            command = Some(Command::Refund);
+            info = MessageInfo::Refund(Refund::new(name, lamport, origin));
         }
         CriticalCommands::FileSnapshot => {
             use crate::snapshot;
-            snapshot::start_snapshot(snapshot::SnapshotMode::FileMode).await?;
-
-            msg = Message {
-                command: None,
-                code: NetworkMessageCode::SnapshotRequest,
-                info: MessageInfo::None,
-                sender_addr: site_addr,
-                sender_id: site_id.to_string(),
-                message_initiator_id: site_id.to_string(),
-                message_initiator_addr: site_addr,
-                clock: clock.clone(),
-            };
-        }
-        CriticalCommands::SyncSnapshot => {
-            use crate::snapshot;
-            snapshot::start_snapshot(snapshot::SnapshotMode::SyncMode).await?;
-
-            msg = Message {
-                command: None,
-                code: NetworkMessageCode::SnapshotRequest,
-                info: MessageInfo::None,
-                sender_addr: site_addr,
-                sender_id: site_id.to_string(),
-                message_initiator_id: site_id.to_string(),
-                message_initiator_addr: site_addr,
-                clock: clock.clone(),
-            };
+            snapshot::start_snapshot(node, snapshot::SnapshotMode::FileMode).await?;
+
+            handler = &crate::snapshot::SnapshotWave;
+            command = None;
+            info = MessageInfo::None;
         }
     }
 
-    let should_diffuse = {
-        // initialisation des paramètres avant la diffusion d'un message
-        let mut state = LOCAL_APP_STATE.lock().await;
-        let nb_neigh = state.get_nb_connected_neighbours();
-        state.set_parent_addr(site_id.to_string(), site_addr);
-        state.set_nb_nei_for_wave(site_id.to_string(), nb_neigh);
-        nb_neigh > 0
-    };
-
-    if should_diffuse {
-        diffuse_message(&msg).await?;
-    } else {
+    let mut state = node.state.lock().await;
+    let wave = crate::wave::start_wave(node, &mut state, handler, info, command, clock).await?;
+    if wave.is_none() {
         // pas release depuis le réseau si on est tout seul
         // on doit relacher le mutex directement
-        let mut state = LOCAL_APP_STATE.lock().await;
-        let _ = state.release_mutex().await;
+        let _ = state.release_mutex_if_done(node).await;
     };
     Ok(())
 }
 
+#[cfg(feature = "server")]
+/// Wave applying a critical command on every site
+pub struct TransactionWave;
+
+#[cfg(feature = "server")]
+impl crate::wave::WaveHandler for TransactionWave {
+    fn request_code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::Transaction
+    }
+
+    fn answer_code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::TransactionAcknowledgement
+    }
+
+    fn signed_by_initiator(&self) -> bool {
+        true
+    }
+
+    fn stamp(
+        &self,
+        node: &crate::node::Node,
+        state: &mut crate::state::AppState,
+    ) -> Option<crate::causal::Dependencies> {
+        let site_id = state.get_site_id();
+        let deps = state.causal.stamp_local(&site_id);
+        // sauvegardé tout de suite : un numéro réutilisé après un redémarrage
+        // serait pris pour un doublon
+        if let Err(e) = crate::db::update_causal_delivered(&node.db, &deps) {
+            log::error!("Cannot save the causal delivery counters: {}", e);
+        }
+        Some(deps)
+    }
+
+    fn visit<'a>(
+        &'a self,
+        node: &'a crate::node::Node,
+        message: &'a crate::message::Message,
+        _forwarded: bool,
+    ) -> crate::wave::WaveFuture<'a, ()> {
+        Box::pin(async move {
+            if message.command.is_none() {
+                return Err("Command is None for Transaction message".into());
+            }
+            let transaction = crate::causal::BufferedTransaction {
+                origin: message.message_initiator_id.clone(),
+                deps: message.deps.clone().unwrap_or_default(),
+                info: message.info.clone(),
+                clock: message.clock.clone(),
+                received_at: std::time::Instant::now(),
+            };
+            if message.deps.is_none() {
+                // site sans livraison causale : on applique directement
+                deliver_transactions(node, vec![transaction]).await;
+                return Ok(());
+            }
+
+            // Le verrou est gardé pendant la livraison pour que les transactions
+            // débloquées par deux messages ne soient pas appliquées dans le désordre
+            let mut state = node.state.lock().await;
+            let ready = state.causal.receive(transaction);
+            if state.causal.len() > 0 {
+                log::info!(
+                    "{} transaction(s) waiting for their dependencies",
+                    state.causal.len()
+                );
+            }
+            deliver_transactions(node, ready).await;
+            Ok(())
+        })
+    }
+
+    fn complete<'a>(
+        &'a self,
+        node: &'a crate::node::Node,
+        state: &'a mut crate::state::AppState,
+        _aggregate: crate::message::MessageInfo,
+    ) -> crate::wave::WaveFuture<'a, ()> {
+        Box::pin(async move { state.release_mutex_if_done(node).await })
+    }
+}
+
+#[cfg(feature = "server")]
+/// Applies the transactions released by the causal buffer, in order
+async fn deliver_transactions(
+    node: &crate::node::Node,
+    transactions: Vec<crate::causal::BufferedTransaction>,
+) {
+    for tx in transactions {
+        if let Err(e) = process_network_command(node, tx.info, tx.clock, tx.origin.as_str()).await {
+            log::error!("Error handling command:\n{}", e);
+        }
+    }
+}
+
 #[cfg(feature = "server")]
 /// Execute a command from the CLI
 /// Update the clock of the site
 /// Interact with the database
 /// Implement our wave diffusion protocol
-pub async fn process_cli_command(cmd: Command) -> Result<(), Box<dyn std::error::Error>> {
-    use crate::state::LOCAL_APP_STATE;
-
+pub async fn process_cli_command(
+    node: &crate::node::Node,
+    cmd: Command,
+) -> Result<(), Box<dyn std::error::Error>> {
     match cmd {
         Command::CreateUser => {
             let name = prompt("Username");
@@ -389,86 +637,95 @@ pub async fn process_cli_command(cmd: Command) -> Result<(), Box<dyn std::error:
                 println!("❌ Username cannot be empty");
                 return Ok(());
             }
-            enqueue_critical(CriticalCommands::CreateUser { name }).await?;
+            spawn_critical(node, CriticalCommands::CreateUser { name });
         }
 
         Command::UserAccounts => {
-            super::db::print_users()?;
+            super::db::print_users(&node.db)?;
         }
 
         Command::PrintUserTransactions => {
             let name = prompt("Username");
-            super::db::print_transaction_for_user(&name)?;
+            super::db::print_transaction_for_user(&node.db, &name)?;
         }
 
         Command::PrintTransactions => {
-            super::db::print_transactions()?;
+            super::db::print_transactions(&node.db)?;
         }
 
         Command::Deposit => {
             let name = prompt("Username");
-            let amount = prompt_parse::<f64>("Deposit amount");
-            enqueue_critical(CriticalCommands::Deposit {
-                name: name,
-                amount: amount,
-            })
-            .await?;
+            let amount = prompt_parse::<crate::money::Money>("Deposit amount");
+            spawn_critical(
+                node,
+                CriticalCommands::Deposit {
+                    name: name,
+                    amount: amount,
+                },
+            );
         }
 
         Command::Withdraw => {
             let name = prompt("Username");
-            let amount = prompt_parse::<f64>("Withdraw amount");
-            if amount < 0.0 {}
-            enqueue_critical(CriticalCommands::Withdraw {
-                name: name,
-                amount: amount,
-            })
-            .await?;
+            let amount = prompt_parse::<crate::money::Money>("Withdraw amount");
+            spawn_critical(
+                node,
+                CriticalCommands::Withdraw {
+                    name: name,
+                    amount: amount,
+                },
+            );
         }
 
         Command::Transfer => {
             let name = prompt("Username");
 
-            let amount = prompt_parse::<f64>("Transfer amount");
-            let _ = super::db::print_users();
+            let amount = prompt_parse::<crate::money::Money>("Transfer amount");
+            let _ = super::db::print_users(&node.db);
             let beneficiary = prompt("Beneficiary");
 
-            enqueue_critical(CriticalCommands::Transfer {
-                from: name.clone(),
-                to: beneficiary.clone(),
-                amount,
-            })
-            .await?;
+            spawn_critical(
+                node,
+                CriticalCommands::Transfer {
+                    from: name.clone(),
+                    to: beneficiary.clone(),
+                    amount,
+                },
+            );
         }
 
         Command::Pay => {
             let name = prompt("Username");
-            let amount = prompt_parse::<f64>("Payment amount");
+            let amount = prompt_parse::<crate::money::Money>("Payment amount");
 
-            if amount <= 0.0 {
+            if !amount.is_positive() {
                 println!("❌ Amount must be positive");
                 return Ok(());
             }
-            enqueue_critical(CriticalCommands::Pay {
-                name: name.clone(),
-                amount,
-            })
-            .await?;
+            spawn_critical(
+                node,
+                CriticalCommands::Pay {
+                    name: name.clone(),
+                    amount,
+                },
+            );
         }
 
         Command::Refund => {
             let name = prompt("Username");
-            super::db::print_transaction_for_user(&name).unwrap();
+            super::db::print_transaction_for_user(&node.db, &name).unwrap();
 
             let transac_time = prompt_parse::<i64>("Lamport time");
             let transac_node = prompt("Node");
 
-            enqueue_critical(CriticalCommands::Refund {
-                name: name.clone(),
-                lamport: transac_time,
-                node: transac_node.clone(),
-            })
-            .await?;
+            spawn_critical(
+                node,
+                CriticalCommands::Refund {
+                    name: name.clone(),
+                    lamport: transac_time,
+                    node: transac_node.clone(),
+                },
+            );
         }
 
         Command::Help => {
@@ -485,13 +742,64 @@ pub async fn process_cli_command(cmd: Command) -> Result<(), Box<dyn std::error:
             println!("/refund           - Refund a transaction");
             println!("/info             - Show system information");
             println!("/start_snapshot   - Start a snapshot");
+            println!("/start_cl_snapshot - Start a Chandy–Lamport snapshot");
+            println!("/check_ledger     - Check the ledger invariants on a snapshot");
+            println!("/restore_snapshot <file> - Rebuild the site from a snapshot file");
+            println!("/diff_snapshots <file> <file> [--json] - Compare two snapshot files");
             println!("/help             - Show this help message");
             println!("----------------------------------------");
         }
 
         Command::Snapshot => {
             println!("📸 Starting snapshot...");
-            enqueue_critical(CriticalCommands::FileSnapshot).await?;
+            spawn_critical(node, CriticalCommands::FileSnapshot);
+        }
+
+        Command::MarkerSnapshot => {
+            println!("📸 Starting Chandy–Lamport snapshot...");
+            crate::snapshot::start_marker_snapshot(
+                node,
+                crate::snapshot::SnapshotMode::ChandyLamport,
+            )
+            .await?;
+        }
+
+        Command::CheckLedger => {
+            println!("🔎 Starting Chandy–Lamport snapshot to check the ledger...");
+            crate::snapshot::start_marker_snapshot(node, crate::snapshot::SnapshotMode::CheckMode)
+                .await?;
+        }
+
+        Command::RestoreSnapshot(path) => {
+            let path = if path.is_empty() {
+                prompt("Snapshot file")
+            } else {
+                path
+            };
+            let restored = crate::snapshot::restore(node, &path).await?;
+            println!("♻️ {} transactions restored from {}", restored, path);
+        }
+
+        Command::DiffSnapshots { left, right, json } => {
+            let left = if left.is_empty() {
+                prompt("First snapshot file")
+            } else {
+                left
+            };
+            let right = if right.is_empty() {
+                prompt("Second snapshot file")
+            } else {
+                right
+            };
+            let diff = crate::snapshot_diff::diff(
+                &crate::snapshot::load(&left)?,
+                &crate::snapshot::load(&right)?,
+            );
+            if json {
+                println!("{}", serde_json::to_string_pretty(&diff)?);
+            } else {
+                print!("{}", diff);
+            }
         }
 
         Command::Info => {
@@ -504,8 +812,18 @@ pub async fn process_cli_command(cmd: Command) -> Result<(), Box<dyn std::error:
                 connected_neighbours_addrs,
                 parent_addr_for_transaction_wave,
                 attended_neighbours_nb_for_transaction_wave,
+                suspicion_levels,
+                alive_members,
+                members,
+                mutex_algorithm,
+                mutex_stats,
+                causal_held,
+                causal_peak,
+                causal_timed_out,
+                causal_delivered,
+                drifted_messages,
             ) = {
-                let state = LOCAL_APP_STATE.lock().await;
+                let state = node.state.lock().await;
                 (
                     state.get_site_addr(),
                     state.get_site_id().to_string(),
@@ -515,11 +833,26 @@ pub async fn process_cli_command(cmd: Command) -> Result<(), Box<dyn std::error:
                     state.get_connected_nei_addr(),
                     state.get_parent_for_wave_map(),
                     state.get_nb_nei_for_wave(),
+                    state.get_suspicion_levels(),
+                    state.membership.alive_members(),
+                    state.membership.digest(),
+                    state.mutex.algorithm(),
+                    state.mutex_stats.clone(),
+                    state.causal.len(),
+                    state.causal.peak(),
+                    state.causal.timed_out(),
+                    state.causal.delivered(),
+                    state.drifted_messages,
                 )
             };
 
+            let (merkle_len, merkle_root) = {
+                let mut tree = node.db.tree.lock().unwrap();
+                (tree.len(), tree.hash(crate::merkle::Range::ROOT))
+            };
+
             let db_path = {
-                let conn = crate::db::DB_CONN.lock().unwrap();
+                let conn = node.db.lock().unwrap();
                 let path = conn.path().unwrap();
                 // keep only the name of the file (after the last "/")
                 path.to_string().split("/").last().unwrap().to_string()
@@ -537,8 +870,20 @@ pub async fn process_cli_command(cmd: Command) -> Result<(), Box<dyn std::error:
                 "Number of connected neighbors: {:?}",
                 connected_neighbours_addrs
             );
+            for (addr, phi) in suspicion_levels {
+                println!("Suspicion level of {}: {:.2}", addr, phi);
+            }
+            println!("Number of alive members: {}", alive_members.len());
+            for member in members {
+                println!(
+                    "Member {} ({}): {:?}, incarnation {}",
+                    member.addr, member.site_id, member.status, member.incarnation
+                );
+            }
             println!("Vector Clock: {:?}", clock.get_vector_clock_map());
             println!("Lamport Clock: {}", clock.get_lamport());
+            println!("Hybrid Logical Clock: {}", clock.get_hlc());
+            println!("Messages with a drifting clock: {}", drifted_messages);
             println!("--------- Wave diffusion info ------------");
             println!(
                 "Parent addresses for wave (if any): {:?}",
@@ -548,6 +893,34 @@ pub async fn process_cli_command(cmd: Command) -> Result<(), Box<dyn std::error:
                 "Attended neighbours for wave (if any): {:?}",
                 attended_neighbours_nb_for_transaction_wave
             );
+            println!("--------- Mutual exclusion info ----------");
+            println!("Algorithm: {:?}", mutex_algorithm);
+            println!("Critical sections entered: {}", mutex_stats.acquisitions);
+            println!("Messages received: {}", mutex_stats.messages_received);
+            println!("Average wait: {:?}", mutex_stats.average_wait());
+            println!("--------- Causal delivery info ----------");
+            println!("Transactions held: {} (peak {})", causal_held, causal_peak);
+            println!("Delivered without dependencies: {}", causal_timed_out);
+            println!("Delivered per site: {:?}", causal_delivered);
+            println!("--------- Anti-entropy info ----------");
+            println!(
+                "Merkle tree: {} transactions, root {}",
+                merkle_len,
+                crate::merkle::short_hex(&merkle_root)
+            );
+            println!("------------ Snapshots info ------------");
+            {
+                let mgr = node.snapshots.lock().await;
+                println!("Snapshot directory: {}", mgr.index.dir().display());
+                for entry in mgr.index.entries() {
+                    println!(
+                        "{} : {} ({} bytes)",
+                        entry.taken_at.format("%Y-%m-%d %H:%M:%S"),
+                        entry.path.display(),
+                        entry.size
+                    );
+                }
+            }
             println!("----------------------------------------");
         }
 
@@ -568,6 +941,7 @@ pub async fn process_cli_command(cmd: Command) -> Result<(), Box<dyn std::error:
 /// Update the clock of the site
 /// Interact with the database
 pub async fn process_network_command(
+    node: &crate::node::Node,
     msg: crate::message::MessageInfo,
     received_clock: crate::clock::Clock,
     sender_id: &str,
@@ -575,10 +949,9 @@ pub async fn process_network_command(
     use crate::message::MessageInfo;
     use log;
 
-    let message_lamport_time = received_clock.get_lamport();
-    let message_vc_clock = received_clock.get_vector_clock_map();
+    let stamp = super::db::TxStamp::new(&received_clock, sender_id);
 
-    if crate::db::transaction_exists(*message_lamport_time, sender_id)? {
+    if crate::db::transaction_exists(&node.db, stamp.lamport_time, sender_id)? {
         log::info!("Transaction allready exists, skipping");
         return Ok(());
     }
@@ -589,63 +962,41 @@ pub async fn process_network_command(
                 log::warn!("Received CreateUser message with empty username, skipping");
                 return Ok(());
             }
-            if crate::db::user_exists(&create_user.name)? {
+            if crate::db::user_exists(&node.db, &create_user.name)? {
                 log::info!("User already exists, skipping");
                 return Ok(());
             }
-            super::db::create_user(&create_user.name)?;
+            super::db::create_user(&node.db, &create_user.name)?;
         }
         crate::message::MessageInfo::Deposit(deposit) => {
-            super::db::deposit(
-                &deposit.name,
-                deposit.amount,
-                &message_lamport_time,
-                sender_id,
-                &message_vc_clock,
-            )?;
+            super::db::deposit(&node.db, &deposit.name, deposit.amount, &stamp)?;
         }
 
         MessageInfo::Withdraw(withdraw) => {
-            super::db::withdraw(
-                &withdraw.name,
-                withdraw.amount,
-                &message_lamport_time,
-                sender_id,
-                &message_vc_clock,
-            )?;
+            super::db::withdraw(&node.db, &withdraw.name, withdraw.amount, &stamp)?;
         }
 
         MessageInfo::Transfer(transfer) => {
             super::db::create_transaction(
+                &node.db,
                 &transfer.name,
                 &transfer.beneficiary,
                 transfer.amount,
-                &message_lamport_time,
-                sender_id,
                 "",
-                &message_vc_clock,
+                &stamp,
             )?;
         }
 
         MessageInfo::Pay(pay) => {
-            super::db::create_transaction(
-                &pay.name,
-                "NULL",
-                pay.amount,
-                &message_lamport_time,
-                sender_id,
-                "",
-                &message_vc_clock,
-            )?;
+            super::db::create_transaction(&node.db, &pay.name, "NULL", pay.amount, "", &stamp)?;
         }
 
         MessageInfo::Refund(refund) => {
             super::db::refund_transaction(
+                &node.db,
                 refund.transac_time,
                 &refund.transac_node,
-                &message_lamport_time,
-                sender_id,
-                &message_vc_clock,
+                &stamp,
             )?;
         }
         crate::message::MessageInfo::SnapshotResponse(_) => {
@@ -666,6 +1017,18 @@ pub async fn process_network_command(
         crate::message::MessageInfo::Acknowledge(_) => {
             log::error!("Should not process Acknowledge message");
         }
+        crate::message::MessageInfo::Membership(_) => {
+            log::error!("Should not process Membership message");
+        }
+        crate::message::MessageInfo::Mutex(_) => {
+            log::error!("Should not process Mutex message");
+        }
+        crate::message::MessageInfo::AntiEntropy(_) => {
+            log::error!("Should not process AntiEntropy message");
+        }
+        crate::message::MessageInfo::CatchUp(_) => {
+            log::error!("Should not process CatchUp message");
+        }
     }
 
     Ok(())
@@ -686,7 +1049,7 @@ fn prompt(label: &str) -> String {
 /// Prompts the user for input and parses it to a specific type
 fn prompt_parse<T: std::str::FromStr>(label: &str) -> T
 where
-    T::Err: std::fmt::Debug,
+    T::Err: std::fmt::Display,
 {
     use std::io::{self, Write};
     loop {
@@ -696,7 +1059,7 @@ where
         io::stdin().read_line(&mut input).unwrap();
         match input.trim().parse() {
             Ok(value) => return value,
-            Err(e) => println!("Invalid input: {:?}", e),
+            Err(e) => println!("Invalid input: {}", e),
         }
     }
 }
@@ -704,113 +1067,129 @@ where
 #[cfg(feature = "server")]
 #[tokio::test]
 async fn test_mutex_critical_section_high_load() {
+    use crate::mutex::{MutexPayload, MutexStatus};
     use crate::state::{AppState, MutexStamp, MutexTag};
     use std::net::SocketAddr;
 
     let local_addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
-    let mut state = AppState::new(
-        "A".to_string(),
-        vec![
-            "127.0.0.1:9001".parse().unwrap(),
-            "127.0.0.1:9002".parse().unwrap(),
-        ],
-        local_addr,
+    let node = crate::node::Node::new(
+        AppState::new(
+            "A".to_string(),
+            vec![
+                "127.0.0.1:9001".parse().unwrap(),
+                "127.0.0.1:9002".parse().unwrap(),
+            ],
+            local_addr,
+        ),
+        crate::db::Db::in_memory(),
     );
+    let mut state = node.state.lock().await;
 
     // Set manually the number of connected neighbours
     state.set_nb_connected_neighbours(2);
 
     // Simulate remote requests in FIFO before our own
-    state.global_mutex_fifo.insert(
-        "B".to_string(),
-        MutexStamp {
-            tag: MutexTag::Request,
-            date: 1,
-        },
-    );
-
-    state.global_mutex_fifo.insert(
-        "C".to_string(),
-        MutexStamp {
-            tag: MutexTag::Request,
-            date: 2,
-        },
-    );
+    let request = |date| MutexStamp {
+        tag: MutexTag::Request,
+        date,
+        scope: crate::state::LockScope::All,
+    };
+    state.set_global_mutex_fifo(std::collections::HashMap::from([
+        ("B".to_string(), request(1)),
+        ("C".to_string(), request(2)),
+    ]));
 
     // Now request our own access with a higher Lamport (should wait)
     for _ in 0..3 {
-        state.update_clock(None).await;
+        state.update_clock(&node.db, None).await;
     }
-    let _ = state.acquire_mutex().await;
+    let _ = state.acquire_mutex(&node).await;
+
+    // Simulate the end of the wave carrying our request
+    let _ = state
+        .receive_mutex(&node, "A", MutexPayload::Announced)
+        .await;
 
     // Our site should not be in SC yet
-    assert_eq!(state.in_sc, false);
-
-    // Insert ACKs from all peers with lower Lamport (simulate reception)
-    state.global_mutex_fifo.insert(
-        "B".to_string(),
-        MutexStamp {
-            tag: MutexTag::Ack,
-            date: 1,
-        },
-    );
-    state.global_mutex_fifo.insert(
-        "C".to_string(),
-        MutexStamp {
-            tag: MutexTag::Ack,
-            date: 2,
-        },
-    );
+    assert_eq!(state.mutex_status(), MutexStatus::Waiting);
 
-    // Manually call try_enter_sc() to simulate triggering by incoming ack
-    state.try_enter_sc();
+    // Simulate the releases of the older requests
+    let _ = state.receive_mutex(&node, "B", MutexPayload::Release).await;
+    assert_eq!(state.mutex_status(), MutexStatus::Waiting);
+    let _ = state.receive_mutex(&node, "C", MutexPayload::Release).await;
 
     // Now we should be in the section critique
-    assert_eq!(state.in_sc, true);
+    assert_eq!(state.mutex_status(), MutexStatus::Held);
+    assert_eq!(state.mutex_stats.acquisitions, 1);
 
     // Simulate some work and then release
-    let _ = state.release_mutex().await;
+    let _ = state.release_mutex(&node).await;
 
     // After release, should no longer be in critical section
-    assert_eq!(state.in_sc, false);
-    assert_eq!(state.waiting_sc, false);
+    assert_eq!(state.mutex_status(), MutexStatus::Idle);
 
     // All entries should be cleaned up
-    assert!(!state.global_mutex_fifo.contains_key("A"));
+    assert!(state.get_global_mutex_fifo().is_empty());
 
     // Simulate again to check order with large number of requests
-    for i in 0..100 {
-        let site = format!("S{}", i);
-        state.global_mutex_fifo.insert(
-            site.clone(),
-            MutexStamp {
-                tag: MutexTag::Request,
-                date: i,
-            },
-        );
-    }
+    state.set_global_mutex_fifo((0..100).map(|i| (format!("S{}", i), request(i))).collect());
 
     // Now site A requests with date = 50 (should wait since lower stamps exist)
     for _ in 0..50 {
-        state.update_clock(None).await;
+        state.update_clock(&node.db, None).await;
     }
-    let _ = state.acquire_mutex().await;
-    state.try_enter_sc();
-    assert_eq!(state.in_sc, false); // can't enter yet
+    let _ = state.acquire_mutex(&node).await;
+    let _ = state
+        .receive_mutex(&node, "A", MutexPayload::Announced)
+        .await;
+    assert_eq!(state.mutex_status(), MutexStatus::Waiting); // can't enter yet
 
-    // Now convert all others to ACK
+    // Now release all the others
     for i in 0..100 {
-        let site = format!("S{}", i);
-        state.global_mutex_fifo.insert(
-            site.clone(),
-            MutexStamp {
-                tag: MutexTag::Ack,
-                date: i,
-            },
-        );
+        let _ = state
+            .receive_mutex(&node, &format!("S{}", i), MutexPayload::Release)
+            .await;
     }
+    assert_eq!(state.mutex_status(), MutexStatus::Held); // should succeed now
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_lock_scope_of_commands() {
+    use crate::state::LockScope;
+
+    let db = crate::db::Db::in_memory();
+    let deposit = CriticalCommands::Deposit {
+        name: "alice".to_string(),
+        amount: crate::money::Money::from_cents(1000),
+    };
+    assert_eq!(deposit.lock_scope(&db), LockScope::accounts(["alice"]));
 
-    // Try entering again
-    state.try_enter_sc();
-    assert_eq!(state.in_sc, true); // should succeed now
+    let transfer = CriticalCommands::Transfer {
+        from: "bob".to_string(),
+        to: "alice".to_string(),
+        amount: crate::money::Money::from_cents(1000),
+    };
+    assert_eq!(
+        transfer.lock_scope(&db),
+        LockScope::accounts(["alice", "bob"])
+    );
+    assert!(
+        transfer
+            .lock_scope(&db)
+            .conflicts_with(&deposit.lock_scope(&db))
+    );
+
+    // Payments all go to NULL, they must not exclude each other
+    let pay = CriticalCommands::Pay {
+        name: "bob".to_string(),
+        amount: crate::money::Money::from_cents(500),
+    };
+    assert_eq!(pay.lock_scope(&db), LockScope::accounts(["bob"]));
+    assert!(!pay.lock_scope(&db).conflicts_with(&deposit.lock_scope(&db)));
+
+    assert_eq!(
+        CriticalCommands::FileSnapshot.lock_scope(&db),
+        LockScope::All
+    );
 }
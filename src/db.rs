@@ -12,7 +12,7 @@ pub struct Transaction {
     /// Destination user of the transaction
     pub to_user: String,
     /// Transaction amount
-    pub amount: f64,
+    pub amount: crate::money::Money,
     /// Lamport timestamp of the transaction
     pub lamport_time: i64,
     /// ID of the node that created the transaction
@@ -21,98 +21,151 @@ pub struct Transaction {
     pub optional_msg: Option<String>,
     /// Vector clock state at the time of the transaction
     pub vector_clock: std::collections::HashMap<String, i64>,
+    /// Hybrid logical clock of the node when it created the transaction
+    pub hlc: crate::clock::Hlc,
+}
+
+#[cfg(feature = "server")]
+/// When and where a transaction was made
+pub struct TxStamp<'a> {
+    /// Lamport timestamp of the transaction
+    pub lamport_time: i64,
+    /// ID of the node that created the transaction
+    pub source_node: &'a str,
+    /// Vector clock state at the time of the transaction
+    pub vector_clock: &'a std::collections::HashMap<String, i64>,
+    /// Hybrid logical clock of the node when it created the transaction
+    pub hlc: crate::clock::Hlc,
+}
+
+#[cfg(feature = "server")]
+impl<'a> TxStamp<'a> {
+    /// Stamp of a transaction made by `source_node` at the time of `clock`
+    pub fn new(clock: &'a crate::clock::Clock, source_node: &'a str) -> Self {
+        Self {
+            lamport_time: *clock.get_lamport(),
+            source_node,
+            vector_clock: clock.get_vector_clock_map(),
+            hlc: *clock.get_hlc(),
+        }
+    }
 }
 
 #[allow(unused_imports)]
 use clap::Parser;
+
 #[cfg(feature = "server")]
-lazy_static::lazy_static! {
-    pub static ref DB_CONN: std::sync::Mutex<rusqlite::Connection> =
-        std::sync::Mutex::new(rusqlite::Connection::open(format!("peillute_{}.db", super::Args::parse().cli_db_id)).unwrap());
+/// Database of a site, with the Merkle tree of the transactions it stores
+pub struct Db {
+    /// Connection to the SQLite database
+    conn: std::sync::Mutex<rusqlite::Connection>,
+    /// Tree of the stored transactions, see [`crate::merkle`]
+    pub tree: std::sync::Mutex<crate::merkle::MerkleTree>,
 }
 
 #[cfg(feature = "server")]
-/// Special value representing a null user
-const NULL: &str = "NULL";
+impl Db {
+    /// Wraps a connection, the tree is built by [`init_db`]
+    pub fn new(conn: rusqlite::Connection) -> Self {
+        Self {
+            conn: std::sync::Mutex::new(conn),
+            tree: std::sync::Mutex::new(crate::merkle::MerkleTree::new()),
+        }
+    }
+
+    /// Initialized database held in memory, for the tests
+    #[cfg(test)]
+    pub fn in_memory() -> Self {
+        let db = Self::new(rusqlite::Connection::open_in_memory().unwrap());
+        init_db(&db).unwrap();
+        db
+    }
+
+    /// Locks the connection to the database
+    pub fn lock(&self) -> std::sync::LockResult<std::sync::MutexGuard<'_, rusqlite::Connection>> {
+        self.conn.lock()
+    }
+}
 
 #[cfg(feature = "server")]
-/// Initializes the database schema
-pub fn init_db() -> rusqlite::Result<()> {
-    {
-        let conn = DB_CONN.lock().unwrap();
+lazy_static::lazy_static! {
+    /// Database of the site started by `main`
+    pub static ref DB_CONN: std::sync::Arc<Db> =
+        std::sync::Arc::new(Db::new(rusqlite::Connection::open(db_path()).unwrap()));
+}
 
-        // Create VectorClock table for storing vector clock states
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS VectorClock (
-            id INTEGER PRIMARY KEY AUTOINCREMENT
-        );",
-            [],
-        )?;
+#[cfg(feature = "server")]
+/// Path of the database of the site
+pub fn db_path() -> String {
+    format!("peillute_{}.db", super::Args::parse().cli_db_id)
+}
 
-        // Create VectorClockEntry table for storing individual vector clock entries
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS VectorClockEntry (
-                vector_clock_id INTEGER,
-                site_id TEXT,
-                value INTEGER NOT NULL,
-                PRIMARY KEY(vector_clock_id, site_id),
-                FOREIGN KEY(vector_clock_id) REFERENCES VectorClock(id) ON DELETE CASCADE
-            );
-            ",
-            [],
-        )?;
+#[cfg(feature = "server")]
+/// Special value representing a null user
+pub const NULL: &str = "NULL";
 
-        // Create User table for storing user accounts
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS User (
-            unique_name TEXT PRIMARY KEY,
-            solde FLOAT NOT NULL
-        )",
-            [],
-        )?;
+#[cfg(feature = "server")]
+/// Message of the transaction refunding the transaction `node`-`transac_time`
+pub fn refund_msg(node: &str, transac_time: i64) -> String {
+    format!("Refund transaction {}-{}", node, transac_time)
+}
 
-        // Create Transactions table for storing transaction history
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS Transactions (
-                from_user TEXT,
-                to_user TEXT NOT NULL,
-                amount FLOAT NOT NULL,
-                lamport_time INTEGER NOT NULL,
-                vector_clock_id INTEGER NOT NULL,
-                source_node TEXT NOT NULL,
-                optional_msg TEXT,
-                FOREIGN KEY(from_user) REFERENCES User(unique_name),
-                FOREIGN KEY(to_user) REFERENCES User(unique_name),
-                FOREIGN KEY(vector_clock_id) REFERENCES VectorClock(id),
-                PRIMARY KEY(lamport_time, source_node)
-            );",
-            [],
-        )?;
+#[cfg(feature = "server")]
+/// Transaction refunded by a transaction with this message, if any
+pub fn refunded_by(optional_msg: &str) -> Option<crate::merkle::TxKey> {
+    let (node, transac_time) = optional_msg
+        .strip_prefix("Refund transaction ")?
+        .rsplit_once('-')?;
+    Some((node.to_string(), transac_time.parse().ok()?))
+}
 
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS LocalState (
-            site_id TEXT PRIMARY KEY,
-            lamport_time INTEGER NOT NULL,
-            vector_clock_id INTEGER NOT NULL,
-            FOREIGN KEY(vector_clock_id) REFERENCES VectorClock(id)
-        );",
-            [],
-        )?;
+#[cfg(feature = "server")]
+/// Initializes the database schema, or brings it up to date, and builds its tree
+pub fn init_db(db: &Db) -> rusqlite::Result<()> {
+    {
+        let conn = db.lock().unwrap();
+        let applied = crate::migration::migrate(&conn)?;
+        log::debug!(
+            "Database initialized successfully, {} migrations applied.",
+            applied.len()
+        );
     }
-
-    log::debug!("Database initialized successfully.");
+    crate::merkle::reload(db);
     Ok(())
 }
 
+#[cfg(feature = "server")]
+/// Version of the database schema, and the migrations it is waiting for
+///
+/// The database is opened read-only, and never created: `None` when there is
+/// no database yet.
+pub fn pending_migrations()
+-> rusqlite::Result<Option<(u32, Vec<&'static crate::migration::Migration>)>> {
+    let path = db_path();
+    if !std::path::Path::new(&path).exists() {
+        return Ok(None);
+    }
+    let conn =
+        rusqlite::Connection::open_with_flags(&path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    Ok(Some((
+        crate::migration::current_version(&conn)?,
+        crate::migration::pending(&conn)?,
+    )))
+}
+
 #[cfg(feature = "server")]
 /// Update the local state of the site
-pub fn update_local_state(site_id: &str, clock: crate::clock::Clock) -> rusqlite::Result<()> {
+pub fn update_local_state(
+    db: &Db,
+    site_id: &str,
+    clock: crate::clock::Clock,
+) -> rusqlite::Result<()> {
     use rusqlite::params;
 
     let lamport_time = clock.get_lamport();
     let vc_clock = clock.get_vector_clock_map();
 
-    let conn = DB_CONN.lock().unwrap();
+    let conn = db.lock().unwrap();
     conn.execute("INSERT INTO VectorClock DEFAULT VALUES", [])?;
     let vector_clock_id = conn.last_insert_rowid();
 
@@ -123,54 +176,253 @@ pub fn update_local_state(site_id: &str, clock: crate::clock::Clock) -> rusqlite
         stmt.execute(params![vector_clock_id, site, value])?;
     }
     conn.execute(
-        "INSERT OR REPLACE INTO LocalState (site_id, lamport_time, vector_clock_id)
-        VALUES (?1, ?2, ?3)",
+        "INSERT INTO LocalState (site_id, lamport_time, vector_clock_id)
+        VALUES (?1, ?2, ?3)
+        ON CONFLICT(site_id) DO UPDATE SET
+            lamport_time = excluded.lamport_time,
+            vector_clock_id = excluded.vector_clock_id",
         params![site_id, lamport_time, vector_clock_id],
     )?;
     Ok(())
 }
 
 #[cfg(feature = "server")]
-/// Update the database with a snapshot
-pub fn update_db_with_snapshot(
-    snapshot: &crate::snapshot::GlobalSnapshot,
-    vector_clock: &std::collections::HashMap<String, i64>,
-) {
-    log::info!("Applying snapshot to database");
+/// Saves the number of transactions of each site delivered in causal order
+pub fn update_causal_delivered(
+    db: &Db,
+    delivered: &crate::causal::Dependencies,
+) -> rusqlite::Result<()> {
+    use rusqlite::params;
 
-    if snapshot.missing.is_empty() {
-        log::info!("No missing transactions, nothing to do");
-        return;
+    let conn = db.lock().unwrap();
+    let mut stmt = conn.prepare(
+        "INSERT INTO CausalDelivered (site_id, delivered) VALUES (?1, ?2)
+        ON CONFLICT(site_id) DO UPDATE SET delivered = MAX(delivered, excluded.delivered)",
+    )?;
+    for (site, n) in delivered {
+        stmt.execute(params![site, *n as i64])?;
     }
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+/// Number of transactions of each site delivered in causal order before the restart
+pub fn get_causal_delivered(db: &Db) -> rusqlite::Result<crate::causal::Dependencies> {
+    let conn = db.lock().unwrap();
+    let mut stmt = conn.prepare("SELECT site_id, delivered FROM CausalDelivered")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as u64))
+    })?;
+    rows.collect()
+}
+
+#[cfg(feature = "server")]
+/// Persists the public key pinned for a site, keeping the first one
+pub fn pin_key(db: &Db, site_id: &str, public_key: &[u8]) -> rusqlite::Result<()> {
+    use rusqlite::params;
+
+    let conn = db.lock().unwrap();
+    conn.execute(
+        "INSERT OR IGNORE INTO PinnedKey (site_id, public_key) VALUES (?1, ?2)",
+        params![site_id, public_key],
+    )?;
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+/// Public keys pinned for the other sites before the restart
+pub fn get_pinned_keys(db: &Db) -> rusqlite::Result<Vec<(String, Vec<u8>)>> {
+    let conn = db.lock().unwrap();
+    let mut stmt = conn.prepare("SELECT site_id, public_key FROM PinnedKey")?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+#[cfg(feature = "server")]
+/// Loads the signing key of the site, generating and persisting one if needed
+pub fn load_or_create_identity(
+    db: &Db,
+    site_id: &str,
+    clock: &crate::clock::Clock,
+) -> Result<crate::identity::SiteIdentity, Box<dyn std::error::Error>> {
+    use rusqlite::{OptionalExtension, params};
+
+    {
+        let conn = db.lock().unwrap();
+
+        let stored: Option<Option<Vec<u8>>> = conn
+            .query_row(
+                "SELECT signing_key FROM LocalState WHERE site_id = ?1",
+                params![site_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(Some(bytes)) = stored {
+            let secret: [u8; 32] = bytes
+                .as_slice()
+                .try_into()
+                .map_err(|_| "stored signing key is corrupted")?;
+            return Ok(crate::identity::SiteIdentity::from_bytes(&secret));
+        }
+    }
+
+    let identity = crate::identity::SiteIdentity::generate()?;
+    update_local_state(db, site_id, clock.clone())?;
+    {
+        let conn = db.lock().unwrap();
+        conn.execute(
+            "UPDATE LocalState SET signing_key = ?1 WHERE site_id = ?2",
+            params![identity.to_bytes().to_vec(), site_id],
+        )?;
+    }
+    log::info!("New signing key generated for site {}", site_id);
+    Ok(identity)
+}
+
+#[cfg(feature = "server")]
+/// Insert the transactions received from another site that we do not have yet
+///
+/// The balances are not checked again: the transactions were accepted by the
+/// site that created them, and a batch may hold a debit before the credit it
+/// depends on. Each transaction is stored with the vector clock it was created
+/// with, not with our clock, so that a catch-up served from our log and the
+/// causal order of the history stay correct. Returns the keys of the
+/// transactions that could not be stored.
+pub fn insert_missing_transactions(
+    db: &Db,
+    txs: &[crate::snapshot::TxSummary],
+) -> Vec<crate::merkle::TxKey> {
+    log::info!("Applying {} missing transactions to database", txs.len());
 
     // sort tsx actions by lamport time
-    let mut sorted_txs: Vec<_> = snapshot
-        .missing
-        .values()
-        .flat_map(|txs| txs.iter())
-        .collect();
+    let mut sorted_txs: Vec<_> = txs.iter().collect();
     sorted_txs.sort_by_key(|tx| tx.lamport_time);
 
+    let mut failed = Vec::new();
     for tx in sorted_txs {
-        let optional_msg = "";
-
-        let _ = crate::db::create_transaction(
+        let optional_msg = tx
+            .refund_of
+            .as_ref()
+            .map(|(node, time)| refund_msg(node, *time))
+            .unwrap_or_default();
+
+        if transaction_exists(db, tx.lamport_time, &tx.source_node).unwrap_or(false) {
+            continue;
+        }
+        let vector_clock = tx.vector_clock.clone().into_iter().collect();
+        let stamp = TxStamp {
+            lamport_time: tx.lamport_time,
+            source_node: &tx.source_node,
+            vector_clock: &vector_clock,
+            hlc: tx.hlc,
+        };
+        if let Err(e) = insert_transaction(
+            db,
             &tx.from_user,
             &tx.to_user,
-            (tx.amount_in_cent as f64) / 100.0,
-            &tx.lamport_time,
-            &tx.source_node,
+            crate::money::Money::from_cents(tx.amount_in_cent),
             &optional_msg,
-            vector_clock,
+            &stamp,
+        ) {
+            log::error!(
+                "Cannot apply the transaction {}-{}: {}",
+                tx.source_node,
+                tx.lamport_time,
+                e
+            );
+            failed.push((tx.source_node.clone(), tx.lamport_time));
+        }
+    }
+    if !failed.is_empty() {
+        log::warn!(
+            "{} of {} missing transactions could not be applied",
+            failed.len(),
+            txs.len()
         );
     }
+    failed
+}
+
+#[cfg(feature = "server")]
+/// Replaces the users and transactions of the site with the ones of a snapshot
+///
+/// Everything is done in a single SQLite transaction, so a failure leaves the
+/// database untouched. The balances are recomputed from the transactions.
+pub fn restore_transactions(db: &Db, txs: &[crate::snapshot::TxSummary]) -> rusqlite::Result<()> {
+    use rusqlite::params;
+
+    let mut sorted_txs: Vec<_> = txs.iter().collect();
+    sorted_txs
+        .sort_by(|a, b| (a.lamport_time, &a.source_node).cmp(&(b.lamport_time, &b.source_node)));
+
+    let conn = db.lock().unwrap();
+    let db_tx = conn.unchecked_transaction()?;
+    db_tx.execute("DELETE FROM Transactions", [])?;
+    db_tx.execute("DELETE FROM User", [])?;
+    // on garde l'horloge de l'état local, les autres n'appartenaient qu'aux transactions
+    db_tx.execute(
+        "DELETE FROM VectorClockEntry
+        WHERE vector_clock_id NOT IN (SELECT vector_clock_id FROM LocalState)",
+        [],
+    )?;
+    db_tx.execute(
+        "DELETE FROM VectorClock WHERE id NOT IN (SELECT vector_clock_id FROM LocalState)",
+        [],
+    )?;
+
+    for tx in &sorted_txs {
+        for user in [&tx.from_user, &tx.to_user] {
+            if user != NULL {
+                db_tx.execute(
+                    "INSERT OR IGNORE INTO User (unique_name, solde) VALUES (?1, 0)",
+                    params![user],
+                )?;
+            }
+        }
+        db_tx.execute("INSERT INTO VectorClock DEFAULT VALUES", [])?;
+        let vector_clock_id = db_tx.last_insert_rowid();
+        for (site_id, value) in &tx.vector_clock {
+            db_tx.execute(
+                "INSERT INTO VectorClockEntry (vector_clock_id, site_id, value) VALUES (?1, ?2, ?3)",
+                params![vector_clock_id, site_id, value],
+            )?;
+        }
+        db_tx.execute(
+            "INSERT INTO Transactions (from_user, to_user, amount, lamport_time, vector_clock_id, source_node, optional_msg, hlc_physical, hlc_logical)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                tx.from_user,
+                tx.to_user,
+                tx.amount_in_cent,
+                tx.lamport_time,
+                vector_clock_id,
+                tx.source_node,
+                tx.refund_of
+                    .as_ref()
+                    .map(|(node, time)| refund_msg(node, *time))
+                    .unwrap_or_default(),
+                tx.hlc.physical_ms,
+                tx.hlc.logical
+            ],
+        )?;
+    }
+
+    db_tx.execute(
+        "UPDATE User SET solde =
+            IFNULL((SELECT SUM(amount) FROM Transactions WHERE to_user = unique_name), 0) -
+            IFNULL((SELECT SUM(amount) FROM Transactions WHERE from_user = unique_name), 0)",
+        [],
+    )?;
+    db_tx.commit()?;
+    log::info!("Database rebuilt from {} transactions", txs.len());
+    Ok(())
 }
 
 #[cfg(feature = "server")]
 /// Get the local state of the site
-pub fn get_local_state() -> rusqlite::Result<(String, crate::clock::Clock)> {
+pub fn get_local_state(db: &Db) -> rusqlite::Result<(String, crate::clock::Clock)> {
     use rusqlite::params;
-    let conn = DB_CONN.lock().unwrap();
+    let conn = db.lock().unwrap();
     let mut stmt =
         conn.prepare("SELECT site_id, lamport_time, vector_clock_id FROM LocalState LIMIT 1")?;
 
@@ -199,25 +451,12 @@ pub fn get_local_state() -> rusqlite::Result<(String, crate::clock::Clock)> {
     Ok((site_id, c))
 }
 
-#[cfg(feature = "server")]
-/// Checks if the database has been initialized
-pub fn is_database_initialized() -> rusqlite::Result<bool> {
-    {
-        let conn = DB_CONN.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'Transactions')",
-        )?;
-        let exists: bool = stmt.query_row([], |row| row.get(0))?;
-        Ok(exists)
-    }
-}
-
 #[cfg(feature = "server")]
 /// Check if a transaction exists in the database
-pub fn transaction_exists(lamport_time: i64, source_node: &str) -> rusqlite::Result<bool> {
+pub fn transaction_exists(db: &Db, lamport_time: i64, source_node: &str) -> rusqlite::Result<bool> {
     use rusqlite::params;
     {
-        let conn = DB_CONN.lock().unwrap();
+        let conn = db.lock().unwrap();
         let mut stmt = conn.prepare(
             "SELECT EXISTS(SELECT 1 FROM Transactions WHERE lamport_time = ?1 AND source_node = ?2)",
         )?;
@@ -228,10 +467,10 @@ pub fn transaction_exists(lamport_time: i64, source_node: &str) -> rusqlite::Res
 
 #[cfg(feature = "server")]
 /// Checks if a user exists in the database
-pub fn user_exists(name: &str) -> rusqlite::Result<bool> {
+pub fn user_exists(db: &Db, name: &str) -> rusqlite::Result<bool> {
     {
         use rusqlite::params;
-        let conn = DB_CONN.lock().unwrap();
+        let conn = db.lock().unwrap();
         let mut stmt = conn.prepare("SELECT EXISTS(SELECT 1 FROM User WHERE unique_name = ?1)")?;
         let exists: bool = stmt.query_row(params![name], |row| row.get(0))?;
         Ok(exists)
@@ -240,16 +479,16 @@ pub fn user_exists(name: &str) -> rusqlite::Result<bool> {
 
 #[cfg(feature = "server")]
 /// Creates a new user with zero balance
-pub fn create_user(unique_name: &str) -> rusqlite::Result<()> {
+pub fn create_user(db: &Db, unique_name: &str) -> rusqlite::Result<()> {
     use rusqlite::params;
-    if user_exists(unique_name)? {
+    if user_exists(db, unique_name)? {
         log::warn!("User '{}' already exists.", unique_name);
         return Ok(());
     }
 
     {
         log::debug!("Ajout de l'utilisateur {}", unique_name);
-        let conn = DB_CONN.lock().unwrap();
+        let conn = db.lock().unwrap();
         conn.execute(
             "INSERT INTO User (unique_name, solde) VALUES (?1, 0)",
             params![unique_name],
@@ -260,9 +499,9 @@ pub fn create_user(unique_name: &str) -> rusqlite::Result<()> {
 
 #[cfg(feature = "server")]
 /// Deletes a user from the database
-pub fn delete_user(name: &str) -> rusqlite::Result<()> {
+pub fn delete_user(db: &Db, name: &str) -> rusqlite::Result<()> {
     use rusqlite::params;
-    if !user_exists(name)? {
+    if !user_exists(db, name)? {
         let err = rusqlite::Error::SqliteFailure(
             rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
             Some(format!("User '{}' does not exist.", name).into()),
@@ -272,7 +511,7 @@ pub fn delete_user(name: &str) -> rusqlite::Result<()> {
         return Err(err);
     }
     {
-        let conn = DB_CONN.lock().unwrap();
+        let conn = db.lock().unwrap();
         conn.execute("DELETE FROM User WHERE unique_name = ?1", params![name])?;
         Ok(())
     }
@@ -280,10 +519,10 @@ pub fn delete_user(name: &str) -> rusqlite::Result<()> {
 
 #[cfg(feature = "server")]
 /// Calculates the current balance for a user
-pub fn calculate_solde(name: &str) -> rusqlite::Result<f64> {
+pub fn calculate_solde(db: &Db, name: &str) -> rusqlite::Result<crate::money::Money> {
     {
         use rusqlite::params;
-        let conn = DB_CONN.lock().unwrap();
+        let conn = db.lock().unwrap();
         let mut stmt = conn.prepare(
             "SELECT
             IFNULL((SELECT SUM(amount) FROM Transactions WHERE to_user = ?1), 0) -
@@ -294,12 +533,24 @@ pub fn calculate_solde(name: &str) -> rusqlite::Result<f64> {
     }
 }
 
+#[cfg(feature = "server")]
+/// Stored balance of every user, in cents
+pub fn get_balances(db: &Db) -> rusqlite::Result<std::collections::BTreeMap<String, i64>> {
+    let conn = db.lock().unwrap();
+    let mut stmt = conn.prepare("SELECT unique_name, solde FROM User")?;
+    let rows = stmt.query_map([], |row| {
+        let solde: crate::money::Money = row.get(1)?;
+        Ok((row.get(0)?, solde.cents()))
+    })?;
+    rows.collect()
+}
+
 #[cfg(feature = "server")]
 /// Updates the stored balance for a user
-pub fn update_solde(name: &str) -> rusqlite::Result<()> {
+pub fn update_solde(db: &Db, name: &str) -> rusqlite::Result<()> {
     use rusqlite::params;
 
-    if !user_exists(name)? {
+    if !user_exists(db, name)? {
         let err = rusqlite::Error::SqliteFailure(
             rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
             Some(format!("User '{}' does not exist.", name).into()),
@@ -307,9 +558,9 @@ pub fn update_solde(name: &str) -> rusqlite::Result<()> {
 
         return Err(err);
     }
-    let solde = calculate_solde(name)?;
+    let solde = calculate_solde(db, name)?;
     {
-        let conn = DB_CONN.lock().unwrap();
+        let conn = db.lock().unwrap();
         conn.execute(
             "UPDATE User SET solde = ?1 WHERE unique_name = ?2",
             params![solde, name],
@@ -321,9 +572,9 @@ pub fn update_solde(name: &str) -> rusqlite::Result<()> {
 
 #[cfg(feature = "server")]
 /// Ensures a user exists, creating it if necessary
-pub fn ensure_user(name: &str) -> rusqlite::Result<()> {
-    if name != NULL && !user_exists(name)? {
-        create_user(name)?;
+pub fn ensure_user(db: &Db, name: &str) -> rusqlite::Result<()> {
+    if name != NULL && !user_exists(db, name)? {
+        create_user(db, name)?;
     }
     Ok(())
 }
@@ -331,16 +582,14 @@ pub fn ensure_user(name: &str) -> rusqlite::Result<()> {
 #[cfg(feature = "server")]
 /// Creates a new transaction between users
 pub fn create_transaction(
+    db: &Db,
     from_user: &str,
     to_user: &str,
-    amount: f64,
-    lamport_time: &i64,
-    source_node: &str,
+    amount: crate::money::Money,
     optional_msg: &str,
-    vector_clock: &std::collections::HashMap<String, i64>,
+    stamp: &TxStamp,
 ) -> rusqlite::Result<()> {
-    use rusqlite::params;
-    if from_user != NULL && calculate_solde(from_user)? < amount {
+    if from_user != NULL && calculate_solde(db, from_user)? < amount {
         let err = rusqlite::Error::SqliteFailure(
             rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
             Some(
@@ -360,8 +609,25 @@ pub fn create_transaction(
         return Err(err);
     }
 
-    ensure_user(from_user)?;
-    ensure_user(to_user)?;
+    insert_transaction(db, from_user, to_user, amount, optional_msg, stamp)
+}
+
+#[cfg(feature = "server")]
+/// Stores a transaction between users without checking the balance of the sender
+///
+/// Used for the transactions already accepted by the site that created them.
+pub fn insert_transaction(
+    db: &Db,
+    from_user: &str,
+    to_user: &str,
+    amount: crate::money::Money,
+    optional_msg: &str,
+    stamp: &TxStamp,
+) -> rusqlite::Result<()> {
+    use rusqlite::params;
+
+    ensure_user(db, from_user)?;
+    ensure_user(db, to_user)?;
 
     log::debug!(
         "Creating transaction from {} to {} with amount {}",
@@ -371,37 +637,41 @@ pub fn create_transaction(
     );
 
     {
-        let conn = DB_CONN.lock().unwrap();
+        let conn = db.lock().unwrap();
         conn.execute("INSERT INTO VectorClock DEFAULT VALUES", [])?;
         let vector_clock_id = conn.last_insert_rowid();
 
         let mut stmt = conn.prepare(
             "INSERT INTO VectorClockEntry (vector_clock_id, site_id, value) VALUES (?1, ?2, ?3)",
         )?;
-        for (site_id, value) in vector_clock.iter() {
+        for (site_id, value) in stamp.vector_clock.iter() {
             stmt.execute(params![vector_clock_id, site_id, value])?;
         }
 
         conn.execute(
-        "INSERT INTO Transactions (from_user, to_user, amount, lamport_time, vector_clock_id, source_node, optional_msg)
-        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        "INSERT INTO Transactions (from_user, to_user, amount, lamport_time, vector_clock_id, source_node, optional_msg, hlc_physical, hlc_logical)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
         params![
             from_user,
             to_user,
             amount,
-            lamport_time,
+            stamp.lamport_time,
             vector_clock_id,
-            source_node,
-            optional_msg
+            stamp.source_node,
+            optional_msg,
+            stamp.hlc.physical_ms,
+            stamp.hlc.logical
         ],
     )?;
     }
 
+    crate::merkle::record(db, stamp.source_node, stamp.lamport_time);
+
     if from_user != NULL {
-        update_solde(from_user)?;
+        update_solde(db, from_user)?;
     }
     if to_user != NULL {
-        update_solde(to_user)?;
+        update_solde(db, to_user)?;
     }
 
     Ok(())
@@ -409,13 +679,12 @@ pub fn create_transaction(
 
 #[cfg(feature = "server")]
 pub fn deposit(
+    db: &Db,
     user: &str,
-    amount: f64,
-    lamport_time: &i64,
-    source_node: &str,
-    vector_clock: &std::collections::HashMap<String, i64>,
+    amount: crate::money::Money,
+    stamp: &TxStamp,
 ) -> rusqlite::Result<()> {
-    if !user_exists(user)? {
+    if !user_exists(db, user)? {
         let err = rusqlite::Error::SqliteFailure(
             rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
             Some(format!("Unknown User: {}", user).into()),
@@ -425,7 +694,7 @@ pub fn deposit(
         return Err(err);
     }
 
-    if amount < 0.0 {
+    if amount.is_negative() {
         let err = rusqlite::Error::SqliteFailure(
             rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
             Some(format!("Negative deposit amount: {}", amount).into()),
@@ -437,26 +706,17 @@ pub fn deposit(
 
     log::debug!("Depositing {} to {}", amount, user);
 
-    create_transaction(
-        NULL,
-        user,
-        amount,
-        lamport_time,
-        source_node,
-        "Deposit",
-        vector_clock,
-    )
+    create_transaction(db, NULL, user, amount, "Deposit", stamp)
 }
 
 #[cfg(feature = "server")]
 pub fn withdraw(
+    db: &Db,
     user: &str,
-    amount: f64,
-    lamport_time: &i64,
-    source_node: &str,
-    vector_clock: &std::collections::HashMap<String, i64>,
+    amount: crate::money::Money,
+    stamp: &TxStamp,
 ) -> rusqlite::Result<()> {
-    if amount < 0.0 {
+    if amount.is_negative() {
         let err = rusqlite::Error::SqliteFailure(
             rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
             Some(format!("Negative withdrawal amount: {}", amount).into()),
@@ -464,7 +724,7 @@ pub fn withdraw(
         log::error!("Negative withdrawal amount: {}", amount);
         return Err(err);
     }
-    if !user_exists(user)? {
+    if !user_exists(db, user)? {
         let err = rusqlite::Error::SqliteFailure(
             rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
             Some(format!("Unknown user: {}", user).into()),
@@ -472,7 +732,7 @@ pub fn withdraw(
         log::error!("Unknown user: {}", user);
         return Err(err);
     }
-    if calculate_solde(user)? < amount {
+    if calculate_solde(db, user)? < amount {
         let err = rusqlite::Error::SqliteFailure(
             rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
             Some(format!("User {} not enough money", user).into()),
@@ -483,26 +743,18 @@ pub fn withdraw(
 
     log::debug!("Withdrawing {} from {}", amount, user);
 
-    create_transaction(
-        user,
-        NULL,
-        amount,
-        lamport_time,
-        source_node,
-        "Withdraw",
-        vector_clock,
-    )
+    create_transaction(db, user, NULL, amount, "Withdraw", stamp)
 }
 
 #[cfg(feature = "server")]
-pub fn has_been_refunded(transac_time: i64, node: &str) -> rusqlite::Result<bool> {
+pub fn has_been_refunded(db: &Db, transac_time: i64, node: &str) -> rusqlite::Result<bool> {
     use rusqlite::params;
     {
-        let conn = DB_CONN.lock().unwrap();
+        let conn = db.lock().unwrap();
         let mut stmt =
             conn.prepare("SELECT EXISTS(SELECT 1 FROM Transactions WHERE optional_msg = ?1)")?;
 
-        let optional_msg = format!("Refund transaction {}-{}", node, transac_time);
+        let optional_msg = refund_msg(node, transac_time);
         let exists: bool = stmt.query_row(params![optional_msg], |row| row.get(0))?;
 
         Ok(exists)
@@ -511,14 +763,13 @@ pub fn has_been_refunded(transac_time: i64, node: &str) -> rusqlite::Result<bool
 
 #[cfg(feature = "server")]
 pub fn refund_transaction(
+    db: &Db,
     transac_time: i64,
     node: &str,
-    lamport_time: &i64,
-    source_node: &str,
-    vector_clock: &std::collections::HashMap<String, i64>,
+    stamp: &TxStamp,
 ) -> rusqlite::Result<()> {
-    if let Some(tx) = get_transaction(transac_time, node)? {
-        if calculate_solde(&tx.to_user)? < tx.amount {
+    if let Some(tx) = get_transaction(db, transac_time, node)? {
+        if calculate_solde(db, &tx.to_user)? < tx.amount {
             let err = rusqlite::Error::SqliteFailure(
                 rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
                 Some(format!("User {} has not enough money to give back", &tx.to_user).into()),
@@ -527,7 +778,7 @@ pub fn refund_transaction(
             return Err(err);
         }
 
-        if tx.optional_msg.is_some() && tx.optional_msg.unwrap().starts_with("Refund transaction") {
+        if tx.optional_msg.as_deref().and_then(refunded_by).is_some() {
             let err = rusqlite::Error::SqliteFailure(
                 rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
                 Some(
@@ -546,7 +797,7 @@ pub fn refund_transaction(
             return Err(err);
         }
 
-        if has_been_refunded(transac_time, node)? {
+        if has_been_refunded(db, transac_time, node)? {
             let err = rusqlite::Error::SqliteFailure(
                 rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
                 Some(format!("Transaction {}-{} already refunded", node, transac_time).into()),
@@ -556,13 +807,12 @@ pub fn refund_transaction(
         }
 
         create_transaction(
+            db,
             &tx.to_user,
             &tx.from_user,
             tx.amount,
-            lamport_time,
-            source_node,
-            &format!("Refund transaction {}-{}", node, transac_time),
-            vector_clock,
+            &refund_msg(node, transac_time),
+            stamp,
         )?;
     } else {
         let err = rusqlite::Error::SqliteFailure(
@@ -587,23 +837,31 @@ pub fn refund_transaction(
 }
 
 #[cfg(feature = "server")]
-pub fn get_transaction(transac_time: i64, node: &str) -> rusqlite::Result<Option<Transaction>> {
+pub fn get_transaction(
+    db: &Db,
+    transac_time: i64,
+    node: &str,
+) -> rusqlite::Result<Option<Transaction>> {
     use rusqlite::params;
     {
-        let conn = DB_CONN.lock().unwrap();
+        let conn = db.lock().unwrap();
         let mut stmt = conn.prepare(
-            "SELECT from_user, to_user, amount, lamport_time, source_node, optional_msg, vector_clock_id
+            "SELECT from_user, to_user, amount, lamport_time, source_node, optional_msg, vector_clock_id, hlc_physical, hlc_logical
         FROM Transactions WHERE lamport_time = ?1 AND source_node = ?2",
         )?;
 
         match stmt.query_row(params![transac_time, node], |row| {
             let from_user: String = row.get(0)?;
             let to_user: String = row.get(1)?;
-            let amount: f64 = row.get(2)?;
+            let amount: crate::money::Money = row.get(2)?;
             let lamport_time: i64 = row.get(3)?;
             let source_node: String = row.get(4)?;
             let optional_msg: Option<String> = row.get(5)?;
             let vector_clock_id: i64 = row.get(6)?;
+            let hlc = crate::clock::Hlc {
+                physical_ms: row.get(7)?,
+                logical: row.get(8)?,
+            };
 
             let mut clock_map = std::collections::HashMap::new();
             let mut vc_stmt = conn.prepare(
@@ -624,6 +882,7 @@ pub fn get_transaction(transac_time: i64, node: &str) -> rusqlite::Result<Option
                 source_node,
                 optional_msg,
                 vector_clock: clock_map,
+                hlc,
             })
         }) {
             Ok(tx) => Ok(Some(tx)),
@@ -634,27 +893,30 @@ pub fn get_transaction(transac_time: i64, node: &str) -> rusqlite::Result<Option
 }
 
 #[cfg(feature = "server")]
-pub fn print_users() -> rusqlite::Result<()> {
+pub fn print_users(db: &Db) -> rusqlite::Result<()> {
     {
-        let conn = DB_CONN.lock().unwrap();
+        let conn = db.lock().unwrap();
         let mut stmt = conn.prepare("SELECT unique_name, solde FROM User")?;
         let users = stmt.query_map([], |row| {
-            Ok((row.get::<_, String>(0)?, row.get::<_, f64>(1)?))
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, crate::money::Money>(1)?,
+            ))
         })?;
 
         println!("-- Users --");
         for user in users {
             let (name, solde) = user?;
-            println!("{}: {:.2}", name, solde);
+            println!("{}: {}", name, solde);
         }
         Ok(())
     }
 }
 
 #[cfg(feature = "server")]
-pub fn get_users() -> rusqlite::Result<Vec<String>> {
+pub fn get_users(db: &Db) -> rusqlite::Result<Vec<String>> {
     {
-        let conn = DB_CONN.lock().unwrap();
+        let conn = db.lock().unwrap();
         let mut stmt = conn.prepare("SELECT unique_name FROM User")?;
         let users = stmt.query_map([], |row| Ok(row.get::<_, String>(0)?))?;
         let mut users_vec = Vec::new();
@@ -666,9 +928,9 @@ pub fn get_users() -> rusqlite::Result<Vec<String>> {
 }
 
 #[cfg(feature = "server")]
-pub fn print_transactions() -> rusqlite::Result<()> {
+pub fn print_transactions(db: &Db) -> rusqlite::Result<()> {
     {
-        let conn = DB_CONN.lock().unwrap();
+        let conn = db.lock().unwrap();
         let mut stmt = conn.prepare(
             "SELECT from_user, to_user, amount, lamport_time, source_node, optional_msg, vector_clock_id FROM Transactions",
         )?;
@@ -676,7 +938,7 @@ pub fn print_transactions() -> rusqlite::Result<()> {
             Ok((
                 row.get::<_, String>(0)?,
                 row.get::<_, String>(1)?,
-                row.get::<_, f64>(2)?,
+                row.get::<_, crate::money::Money>(2)?,
                 row.get::<_, i64>(3)?,
                 row.get::<_, String>(4)?,
                 row.get::<_, Option<String>>(5)?,
@@ -710,7 +972,7 @@ pub fn print_transactions() -> rusqlite::Result<()> {
             }
 
             println!(
-                "│ {:<15} │ {:<15} │ {:<10.2} │ {:<10} │ {:<15} │ {:<20} │ {:<20?} │",
+                "│ {:<15} │ {:<15} │ {:<10} │ {:<10} │ {:<15} │ {:<20} │ {:<20?} │",
                 from,
                 to,
                 amount,
@@ -729,10 +991,10 @@ pub fn print_transactions() -> rusqlite::Result<()> {
 }
 
 #[cfg(feature = "server")]
-pub fn print_transaction_for_user(name: &str) -> rusqlite::Result<()> {
+pub fn print_transaction_for_user(db: &Db, name: &str) -> rusqlite::Result<()> {
     use rusqlite::params;
     {
-        let conn = DB_CONN.lock().unwrap();
+        let conn = db.lock().unwrap();
         let mut stmt = conn.prepare(
             "SELECT from_user, to_user, amount, lamport_time, source_node, optional_msg, vector_clock_id
         FROM Transactions WHERE from_user = ?1 OR to_user = ?1",
@@ -742,7 +1004,7 @@ pub fn print_transaction_for_user(name: &str) -> rusqlite::Result<()> {
             Ok((
                 row.get::<_, String>(0)?,
                 row.get::<_, String>(1)?,
-                row.get::<_, f64>(2)?,
+                row.get::<_, crate::money::Money>(2)?,
                 row.get::<_, i64>(3)?,
                 row.get::<_, String>(4)?,
                 row.get::<_, Option<String>>(5)?,
@@ -775,7 +1037,7 @@ pub fn print_transaction_for_user(name: &str) -> rusqlite::Result<()> {
                 clock_map.insert(site_id, value);
             }
             println!(
-                "│ {:<15} │ {:<15} │ {:<10.2} │ {:<10} │ {:<15} │ {:<20} │ {:<20?} │",
+                "│ {:<15} │ {:<15} │ {:<10} │ {:<10} │ {:<15} │ {:<20} │ {:<20?} │",
                 from,
                 to,
                 amount,
@@ -794,12 +1056,12 @@ pub fn print_transaction_for_user(name: &str) -> rusqlite::Result<()> {
 }
 
 #[cfg(feature = "server")]
-pub fn get_transactions_for_user(name: &str) -> rusqlite::Result<Vec<Transaction>> {
+pub fn get_transactions_for_user(db: &Db, name: &str) -> rusqlite::Result<Vec<Transaction>> {
     use rusqlite::params;
     {
-        let conn = DB_CONN.lock().unwrap();
+        let conn = db.lock().unwrap();
         let mut stmt = conn.prepare(
-            "SELECT from_user, to_user, amount, lamport_time, source_node, optional_msg, vector_clock_id
+            "SELECT from_user, to_user, amount, lamport_time, source_node, optional_msg, vector_clock_id, hlc_physical, hlc_logical
         FROM Transactions WHERE from_user = ?1 OR to_user = ?1",
         )?;
 
@@ -807,17 +1069,21 @@ pub fn get_transactions_for_user(name: &str) -> rusqlite::Result<Vec<Transaction
             Ok((
                 row.get::<_, String>(0)?,
                 row.get::<_, String>(1)?,
-                row.get::<_, f64>(2)?,
+                row.get::<_, crate::money::Money>(2)?,
                 row.get::<_, i64>(3)?,
                 row.get::<_, String>(4)?,
                 row.get::<_, Option<String>>(5)?,
                 row.get::<_, i64>(6)?,
+                crate::clock::Hlc {
+                    physical_ms: row.get(7)?,
+                    logical: row.get(8)?,
+                },
             ))
         })?;
 
         let mut txs_vec = Vec::new();
         for tx in txs {
-            let (from, to, amount, time, node, msg, vector_clock_id) = tx?;
+            let (from, to, amount, time, node, msg, vector_clock_id, hlc) = tx?;
             let mut clock_map = std::collections::HashMap::new();
             let mut vc_stmt = conn.prepare(
                 "SELECT site_id, value FROM VectorClockEntry WHERE vector_clock_id = ?1",
@@ -837,6 +1103,7 @@ pub fn get_transactions_for_user(name: &str) -> rusqlite::Result<Vec<Transaction
                 source_node: node,
                 optional_msg: msg,
                 vector_clock: clock_map,
+                hlc,
             });
         }
         Ok(txs_vec)
@@ -844,10 +1111,32 @@ pub fn get_transactions_for_user(name: &str) -> rusqlite::Result<Vec<Transaction
 }
 
 #[cfg(feature = "server")]
-pub fn get_local_transaction_log() -> rusqlite::Result<Vec<Transaction>> {
-    let conn = DB_CONN.lock().unwrap();
+/// Get every transaction stored in the database, with its vector clock
+pub fn get_local_transaction_log(db: &Db) -> rusqlite::Result<Vec<Transaction>> {
+    let conn = db.lock().unwrap();
+    let mut clocks: std::collections::HashMap<i64, std::collections::HashMap<String, i64>> =
+        std::collections::HashMap::new();
+    let mut vc_stmt = conn.prepare(
+        "SELECT e.vector_clock_id, e.site_id, e.value
+        FROM VectorClockEntry e JOIN Transactions t ON t.vector_clock_id = e.vector_clock_id",
+    )?;
+    let entries = vc_stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, i64>(2)?,
+        ))
+    })?;
+    for entry in entries {
+        let (vector_clock_id, site_id, value) = entry?;
+        clocks
+            .entry(vector_clock_id)
+            .or_default()
+            .insert(site_id, value);
+    }
+
     let mut stmt = conn.prepare(
-    "SELECT from_user, to_user, amount, lamport_time, source_node, optional_msg, vector_clock_id
+    "SELECT from_user, to_user, amount, lamport_time, source_node, optional_msg, hlc_physical, hlc_logical, vector_clock_id
         FROM Transactions")?;
     let rows = stmt.query_map([], |row| {
         Ok(Transaction {
@@ -857,7 +1146,14 @@ pub fn get_local_transaction_log() -> rusqlite::Result<Vec<Transaction>> {
             lamport_time: row.get(3)?,
             source_node: row.get(4)?,
             optional_msg: row.get(5)?,
-            vector_clock: std::collections::HashMap::new(),
+            vector_clock: clocks
+                .get(&row.get::<_, i64>(8)?)
+                .cloned()
+                .unwrap_or_default(),
+            hlc: crate::clock::Hlc {
+                physical_ms: row.get(6)?,
+                logical: row.get(7)?,
+            },
         })
     })?;
 
@@ -0,0 +1,286 @@
+//! Heartbeat-based failure detection for neighbours
+//!
+//! Every site periodically sends a `Heartbeat` message to its connected
+//! neighbours, carrying its membership view. Any message received from a
+//! neighbour counts as a sign of life. A phi-accrual detector turns the time
+//! elapsed since the last sign of life into a suspicion level, and neighbours
+//! whose level exceeds the configured threshold are removed from the network
+//! as if they had disconnected.
+
+#![cfg(feature = "server")]
+
+/// Default interval between two heartbeats, in milliseconds
+pub const DEFAULT_HEARTBEAT_INTERVAL_MS: u64 = 1000;
+
+/// Default suspicion level above which a neighbour is considered dead
+pub const DEFAULT_PHI_THRESHOLD: f64 = 8.0;
+
+/// Number of inter-arrival samples kept per neighbour
+const MAX_SAMPLES: usize = 100;
+
+/// Arrival history of a single neighbour
+struct HeartbeatHistory {
+    /// Last time we heard from the neighbour
+    last_arrival: std::time::Instant,
+    /// Recent inter-arrival times, in milliseconds
+    intervals: std::collections::VecDeque<f64>,
+}
+
+/// Phi-accrual failure detector
+///
+/// Inter-arrival times are modelled with an exponential distribution, so the
+/// suspicion level is `phi = elapsed / mean * log10(e)`. A phi of 1 means a
+/// 10% chance of being wrong when suspecting the neighbour, 2 means 1%, etc.
+pub struct FailureDetector {
+    /// Expected interval between two heartbeats, used before any sample is known
+    heartbeat_interval: std::time::Duration,
+    /// Suspicion level above which a neighbour is suspected
+    phi_threshold: f64,
+    /// Arrival history for each monitored neighbour
+    histories: std::collections::HashMap<std::net::SocketAddr, HeartbeatHistory>,
+}
+
+impl FailureDetector {
+    /// Creates a detector for the given heartbeat interval and threshold
+    pub fn new(heartbeat_interval: std::time::Duration, phi_threshold: f64) -> Self {
+        Self {
+            heartbeat_interval,
+            phi_threshold,
+            histories: std::collections::HashMap::new(),
+        }
+    }
+
+    /// Returns the interval between two heartbeats
+    pub fn get_heartbeat_interval(&self) -> std::time::Duration {
+        self.heartbeat_interval
+    }
+
+    /// Starts monitoring a neighbour if it is not monitored yet
+    pub fn watch(&mut self, addr: std::net::SocketAddr, now: std::time::Instant) {
+        self.histories
+            .entry(addr)
+            .or_insert_with(|| HeartbeatHistory {
+                last_arrival: now,
+                intervals: std::collections::VecDeque::new(),
+            });
+    }
+
+    /// Records a sign of life from a neighbour
+    pub fn heartbeat(&mut self, addr: std::net::SocketAddr, now: std::time::Instant) {
+        match self.histories.get_mut(&addr) {
+            Some(history) => {
+                let interval = now.saturating_duration_since(history.last_arrival);
+                history.last_arrival = now;
+                if history.intervals.len() == MAX_SAMPLES {
+                    history.intervals.pop_front();
+                }
+                history.intervals.push_back(interval.as_secs_f64() * 1000.0);
+            }
+            None => self.watch(addr, now),
+        }
+    }
+
+    /// Stops monitoring a neighbour
+    pub fn remove(&mut self, addr: &std::net::SocketAddr) {
+        self.histories.remove(addr);
+    }
+
+    /// Stops monitoring every neighbour not present in `addrs`
+    pub fn retain(&mut self, addrs: &[std::net::SocketAddr]) {
+        self.histories.retain(|addr, _| addrs.contains(addr));
+    }
+
+    /// Returns the suspicion level of a neighbour, 0 if it is not monitored
+    pub fn phi(&self, addr: &std::net::SocketAddr, now: std::time::Instant) -> f64 {
+        let Some(history) = self.histories.get(addr) else {
+            return 0.0;
+        };
+
+        let expected = self.heartbeat_interval.as_secs_f64() * 1000.0;
+        let mean = if history.intervals.is_empty() {
+            expected
+        } else {
+            let sum: f64 = history.intervals.iter().sum();
+            // Never trust a mean smaller than the heartbeat interval, bursts of
+            // messages would otherwise make the detector far too eager
+            (sum / history.intervals.len() as f64).max(expected)
+        };
+
+        let elapsed = now
+            .saturating_duration_since(history.last_arrival)
+            .as_secs_f64()
+            * 1000.0;
+        elapsed / mean * std::f64::consts::LOG10_E
+    }
+
+    /// Returns the monitored neighbours whose suspicion level exceeds the threshold
+    pub fn suspected(&self, now: std::time::Instant) -> Vec<std::net::SocketAddr> {
+        self.histories
+            .keys()
+            .filter(|addr| self.phi(addr, now) > self.phi_threshold)
+            .copied()
+            .collect()
+    }
+
+    /// Returns the suspicion level of every monitored neighbour
+    pub fn suspicion_levels(&self, now: std::time::Instant) -> Vec<(std::net::SocketAddr, f64)> {
+        let mut levels: Vec<_> = self
+            .histories
+            .keys()
+            .map(|addr| (*addr, self.phi(addr, now)))
+            .collect();
+        levels.sort_by_key(|(addr, _)| *addr);
+        levels
+    }
+}
+
+/// Worker that sends heartbeats and removes suspected neighbours
+pub fn heartbeat_worker(node: crate::node::Node) {
+    tokio::spawn(async move {
+        use crate::message::{MessageInfo, NetworkMessageCode};
+
+        let interval = {
+            let st = node.state.lock().await;
+            st.failure_detector.get_heartbeat_interval()
+        };
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+
+            let (local_addr, site_id, clock, neighbours, suspected, membership, unpinned) = {
+                let mut st = node.state.lock().await;
+                let now = std::time::Instant::now();
+                let neighbours = st.get_connected_nei_addr();
+                st.failure_detector.retain(&neighbours);
+                for addr in &neighbours {
+                    st.failure_detector.watch(*addr, now);
+                }
+                (
+                    st.get_site_addr(),
+                    st.get_site_id(),
+                    st.get_clock(),
+                    neighbours,
+                    st.failure_detector.suspected(now),
+                    st.get_membership_payload(),
+                    st.unpinned_members(),
+                )
+            };
+
+            for addr in &suspected {
+                let addr = *addr;
+                log::warn!("Neighbour {} is suspected to have failed", addr);
+                {
+                    let mut st = node.state.lock().await;
+                    st.remove_peer(&node, addr).await;
+                    st.membership
+                        .mark(addr, crate::membership::MemberStatus::Suspect);
+                }
+                println!("\x1b[1;31mSITE {} SUSPECTED DOWN !\x1b[0m", addr);
+            }
+
+            // Heartbeats are not events: the clock is sent as is, without being incremented.
+            // They piggyback the membership view, which is how it is gossiped.
+            for addr in neighbours.into_iter().filter(|a| !suspected.contains(a)) {
+                if let Err(e) = crate::network::send_message(
+                    &node,
+                    addr,
+                    MessageInfo::Membership(membership.clone()),
+                    NetworkMessageCode::Heartbeat,
+                    local_addr,
+                    &site_id,
+                    crate::message::Origin::local(&site_id, local_addr),
+                    clock.clone(),
+                )
+                .await
+                {
+                    log::debug!("Failed to send heartbeat to {}: {}", addr, e);
+                }
+            }
+
+            // The members we never met send us their key directly, until it is pinned
+            for addr in unpinned {
+                if let Err(e) = crate::network::send_message(
+                    &node,
+                    addr,
+                    MessageInfo::None,
+                    NetworkMessageCode::KeyRequest,
+                    local_addr,
+                    &site_id,
+                    crate::message::Origin::local(&site_id, local_addr),
+                    clock.clone(),
+                )
+                .await
+                {
+                    log::debug!("Failed to ask {} for its key: {}", addr, e);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> std::net::SocketAddr {
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn unknown_neighbour_is_not_suspected() {
+        let detector = FailureDetector::new(std::time::Duration::from_millis(100), 8.0);
+        assert_eq!(detector.phi(&addr(1), std::time::Instant::now()), 0.0);
+    }
+
+    #[test]
+    fn phi_grows_with_silence() {
+        let mut detector = FailureDetector::new(std::time::Duration::from_millis(100), 8.0);
+        let start = std::time::Instant::now();
+        detector.watch(addr(1), start);
+
+        let early = detector.phi(&addr(1), start + std::time::Duration::from_millis(100));
+        let late = detector.phi(&addr(1), start + std::time::Duration::from_millis(1000));
+        assert!(early < late);
+        assert!(
+            detector
+                .suspected(start + std::time::Duration::from_millis(1000))
+                .is_empty()
+        );
+        assert_eq!(
+            detector.suspected(start + std::time::Duration::from_millis(2000)),
+            vec![addr(1)]
+        );
+    }
+
+    #[test]
+    fn heartbeats_reset_suspicion() {
+        let mut detector = FailureDetector::new(std::time::Duration::from_millis(100), 8.0);
+        let start = std::time::Instant::now();
+        detector.watch(addr(1), start);
+        detector.watch(addr(2), start);
+
+        // Only the first neighbour keeps sending heartbeats
+        let mut now = start;
+        for _ in 0..30 {
+            now += std::time::Duration::from_millis(100);
+            detector.heartbeat(addr(1), now);
+        }
+
+        assert!(detector.phi(&addr(1), now) < 1.0);
+        assert_eq!(detector.suspected(now), vec![addr(2)]);
+    }
+
+    #[test]
+    fn retain_forgets_removed_neighbours() {
+        let mut detector = FailureDetector::new(std::time::Duration::from_millis(100), 8.0);
+        let start = std::time::Instant::now();
+        detector.watch(addr(1), start);
+        detector.watch(addr(2), start);
+
+        detector.retain(&[addr(2)]);
+        let levels = detector.suspicion_levels(start);
+        assert_eq!(levels.len(), 1);
+        assert_eq!(levels[0].0, addr(2));
+    }
+}
@@ -0,0 +1,315 @@
+//! Ed25519 identities of the sites
+//!
+//! Every site owns a signing key persisted in the `LocalState` table. Each
+//! message leaves the site wrapped in a [`SignedMessage`] envelope carrying
+//! the signature of its encoded bytes. The public keys are exchanged with the
+//! `Discovery` and `Acknowledgment` messages, and a site asks the members of
+//! the network that are not its neighbours for their key with a `KeyRequest`,
+//! answered by a `KeyAnnouncement`. A key is only taken from a message that
+//! its owner sent directly and signed with it, never from the gossip of a
+//! third site. The first key seen for a site id is pinned, in the `PinnedKey`
+//! table so that it survives a restart, and from then on every message
+//! claiming to come from this site must be signed with it.
+//!
+//! The envelope is checked hop by hop against `sender_id`, a message forwarded
+//! by a wave being signed again by each site that relays it. A relay could
+//! still change what it forwards, so the initiator of a transaction wave also
+//! signs what it diffuses: the signature travels unchanged with the wave, and
+//! every site checks it against `message_initiator_id` before applying the
+//! transaction. A wave whose initiator has no pinned key yet is rejected.
+
+#![cfg(feature = "server")]
+
+/// Message as written on the wire: the encoded `Message` and its signature
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct SignedMessage {
+    /// MessagePack encoding of the `Message`
+    pub message: Vec<u8>,
+    /// Public key of the sender, only sent with discovery messages
+    pub public_key: Option<Vec<u8>>,
+    /// Ed25519 signature of `message`
+    pub signature: Vec<u8>,
+}
+
+/// Reasons for rejecting a received message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureError {
+    /// No public key is known for the sender yet
+    UnknownSite(String),
+    /// The sender announced a key different from the one pinned for it
+    KeyMismatch(String),
+    /// The signature does not match the pinned key of the sender
+    InvalidSignature(String),
+    /// The key or the signature does not have the Ed25519 format
+    Malformed(String),
+    /// The message lacks the signature of the site that initiated it
+    Unsigned(String),
+}
+
+impl std::fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignatureError::UnknownSite(site) => write!(f, "no public key known for site {}", site),
+            SignatureError::KeyMismatch(site) => {
+                write!(
+                    f,
+                    "site {} announced a key different from its pinned one",
+                    site
+                )
+            }
+            SignatureError::InvalidSignature(site) => {
+                write!(f, "invalid signature for a message from site {}", site)
+            }
+            SignatureError::Malformed(site) => {
+                write!(f, "malformed key or signature from site {}", site)
+            }
+            SignatureError::Unsigned(site) => {
+                write!(
+                    f,
+                    "message initiated by site {} without its signature",
+                    site
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// Signing key of the local site
+pub struct SiteIdentity {
+    signing_key: ed25519_dalek::SigningKey,
+}
+
+impl SiteIdentity {
+    /// Generates a new random identity
+    pub fn generate() -> Result<Self, getrandom::Error> {
+        let mut secret = [0u8; ed25519_dalek::SECRET_KEY_LENGTH];
+        getrandom::getrandom(&mut secret)?;
+        Ok(Self::from_bytes(&secret))
+    }
+
+    /// Restores an identity from its secret key
+    pub fn from_bytes(secret: &[u8; ed25519_dalek::SECRET_KEY_LENGTH]) -> Self {
+        Self {
+            signing_key: ed25519_dalek::SigningKey::from_bytes(secret),
+        }
+    }
+
+    /// Returns the secret key, to be persisted
+    pub fn to_bytes(&self) -> [u8; ed25519_dalek::SECRET_KEY_LENGTH] {
+        self.signing_key.to_bytes()
+    }
+
+    /// Returns the public key announced to the other sites
+    pub fn public_key(&self) -> [u8; ed25519_dalek::PUBLIC_KEY_LENGTH] {
+        self.signing_key.verifying_key().to_bytes()
+    }
+
+    /// Signs an encoded message, attaching the public key if asked to
+    pub fn sign(&self, message: Vec<u8>, with_public_key: bool) -> SignedMessage {
+        use ed25519_dalek::Signer;
+
+        let signature = self.signing_key.sign(&message).to_bytes().to_vec();
+        SignedMessage {
+            message,
+            public_key: with_public_key.then(|| self.public_key().to_vec()),
+            signature,
+        }
+    }
+}
+
+/// Public keys of the other sites, indexed by site id
+#[derive(Default)]
+pub struct KeyRing {
+    keys: std::collections::HashMap<String, ed25519_dalek::VerifyingKey>,
+}
+
+impl KeyRing {
+    /// Creates an empty key ring
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pins the key of a site, or checks it against the already pinned one
+    ///
+    /// Returns true if the key was not pinned yet.
+    pub fn pin(&mut self, site_id: &str, public_key: &[u8]) -> Result<bool, SignatureError> {
+        let key = <[u8; ed25519_dalek::PUBLIC_KEY_LENGTH]>::try_from(public_key)
+            .ok()
+            .and_then(|bytes| ed25519_dalek::VerifyingKey::from_bytes(&bytes).ok())
+            .ok_or_else(|| SignatureError::Malformed(site_id.to_string()))?;
+
+        match self.keys.get(site_id) {
+            Some(pinned) if *pinned != key => Err(SignatureError::KeyMismatch(site_id.to_string())),
+            Some(_) => Ok(false),
+            None => {
+                log::info!("Public key of site {} pinned", site_id);
+                self.keys.insert(site_id.to_string(), key);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Whether a key is pinned for a site
+    pub fn is_pinned(&self, site_id: &str) -> bool {
+        self.keys.contains_key(site_id)
+    }
+
+    /// Checks that an envelope was signed by the pinned key of `sender_id`
+    pub fn verify(&self, sender_id: &str, signed: &SignedMessage) -> Result<(), SignatureError> {
+        use ed25519_dalek::Verifier;
+
+        let key = self
+            .keys
+            .get(sender_id)
+            .ok_or_else(|| SignatureError::UnknownSite(sender_id.to_string()))?;
+        let signature = ed25519_dalek::Signature::from_slice(&signed.signature)
+            .map_err(|_| SignatureError::Malformed(sender_id.to_string()))?;
+        key.verify(&signed.message, &signature)
+            .map_err(|_| SignatureError::InvalidSignature(sender_id.to_string()))
+    }
+
+    /// Checks that a wave message carries the signature of its initiator
+    pub fn verify_initiator(
+        &self,
+        message: &crate::message::Message,
+    ) -> Result<(), SignatureError> {
+        let initiator = &message.message_initiator_id;
+        let signature = message
+            .initiator_signature
+            .clone()
+            .ok_or_else(|| SignatureError::Unsigned(initiator.clone()))?;
+        let payload = message
+            .initiator_payload()
+            .map_err(|_| SignatureError::Malformed(initiator.clone()))?;
+        self.verify(
+            initiator,
+            &SignedMessage {
+                message: payload,
+                public_key: None,
+                signature,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_message_verifies_with_pinned_key() {
+        let identity = SiteIdentity::generate().unwrap();
+        let mut ring = KeyRing::new();
+        ring.pin("A", &identity.public_key()).unwrap();
+
+        let signed = identity.sign(b"transfer".to_vec(), false);
+        assert!(signed.public_key.is_none());
+        assert_eq!(ring.verify("A", &signed), Ok(()));
+    }
+
+    #[test]
+    fn unknown_sender_is_rejected() {
+        let identity = SiteIdentity::generate().unwrap();
+        let ring = KeyRing::new();
+        let signed = identity.sign(b"transfer".to_vec(), true);
+        assert_eq!(
+            ring.verify("A", &signed),
+            Err(SignatureError::UnknownSite("A".to_string()))
+        );
+    }
+
+    #[test]
+    fn spoofed_sender_is_rejected() {
+        let honest = SiteIdentity::generate().unwrap();
+        let spoofer = SiteIdentity::generate().unwrap();
+        let mut ring = KeyRing::new();
+        ring.pin("A", &honest.public_key()).unwrap();
+
+        // The spoofer cannot replace the pinned key nor sign in the name of A
+        assert_eq!(
+            ring.pin("A", &spoofer.public_key()),
+            Err(SignatureError::KeyMismatch("A".to_string()))
+        );
+        let forged = spoofer.sign(b"refund".to_vec(), false);
+        assert_eq!(
+            ring.verify("A", &forged),
+            Err(SignatureError::InvalidSignature("A".to_string()))
+        );
+    }
+
+    #[test]
+    fn tampered_message_is_rejected() {
+        let identity = SiteIdentity::generate().unwrap();
+        let mut ring = KeyRing::new();
+        ring.pin("A", &identity.public_key()).unwrap();
+
+        let mut signed = identity.sign(b"deposit 10".to_vec(), false);
+        signed.message = b"deposit 99".to_vec();
+        assert_eq!(
+            ring.verify("A", &signed),
+            Err(SignatureError::InvalidSignature("A".to_string()))
+        );
+    }
+
+    #[test]
+    fn initiator_signature_is_checked_after_relays() {
+        let initiator = SiteIdentity::generate().unwrap();
+        let relay = SiteIdentity::generate().unwrap();
+        let mut ring = KeyRing::new();
+        ring.pin("A", &initiator.public_key()).unwrap();
+        ring.pin("B", &relay.public_key()).unwrap();
+
+        let mut message = crate::message::Message {
+            sender_id: "A".to_string(),
+            sender_addr: "127.0.0.1:8080".parse().unwrap(),
+            message_initiator_id: "A".to_string(),
+            message_initiator_addr: "127.0.0.1:8080".parse().unwrap(),
+            wave_seq: 1,
+            clock: crate::clock::Clock::new(),
+            command: Some(crate::control::Command::Pay),
+            info: crate::message::MessageInfo::Pay(crate::message::Pay::new(
+                "alice".to_string(),
+                crate::money::Money::from_cents(500),
+            )),
+            code: crate::message::NetworkMessageCode::Transaction,
+            deps: None,
+            initiator_signature: None,
+        };
+        assert_eq!(
+            ring.verify_initiator(&message),
+            Err(SignatureError::Unsigned("A".to_string()))
+        );
+        let payload = message.initiator_payload().unwrap();
+        message.initiator_signature = Some(initiator.sign(payload, false).signature);
+
+        // B relays the wave: the signature of A still holds
+        message.sender_id = "B".to_string();
+        assert_eq!(ring.verify_initiator(&message), Ok(()));
+
+        // B cannot change the payment, nor sign it in the name of A
+        message.info = crate::message::MessageInfo::Pay(crate::message::Pay::new(
+            "alice".to_string(),
+            crate::money::Money::from_cents(50_000),
+        ));
+        assert_eq!(
+            ring.verify_initiator(&message),
+            Err(SignatureError::InvalidSignature("A".to_string()))
+        );
+        let payload = message.initiator_payload().unwrap();
+        message.initiator_signature = Some(relay.sign(payload, false).signature);
+        assert_eq!(
+            ring.verify_initiator(&message),
+            Err(SignatureError::InvalidSignature("A".to_string()))
+        );
+    }
+
+    #[test]
+    fn identity_survives_persistence() {
+        let identity = SiteIdentity::generate().unwrap();
+        let restored = SiteIdentity::from_bytes(&identity.to_bytes());
+        assert_eq!(identity.public_key(), restored.public_key());
+        assert!(KeyRing::new().pin("A", &[0u8; 3]).is_err());
+    }
+}
@@ -0,0 +1,403 @@
+//! Ledger invariants checked on a global snapshot
+//!
+//! Money only enters the network through deposits and leaves it through
+//! withdrawals and payments, both made with the `NULL` user: across a
+//! consistent cut, the money deposited minus the money taken out must be the
+//! sum of the balances. The check runs on a Chandy–Lamport snapshot, where
+//! every site reports its own log and the balances stored in its database, and
+//! they are checked against the transactions it holds. A site of the snapshot
+//! without balances is reported as not checked. The transactions of the cut are
+//! also replayed in Lamport order, which respects their causal order, to find
+//! the balances that went negative, and the refunds of a transaction that is
+//! not in the cut or that was already refunded.
+
+#![cfg(feature = "server")]
+
+/// Broken invariant, with the transactions at fault
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub enum Violation {
+    /// The balances stored by a site do not add up to the money it saw entering and leaving
+    Conservation {
+        /// The site
+        site_id: String,
+        /// Money deposited minus the money taken out, in cents
+        expected: i64,
+        /// Sum of the balances stored by the site, in cents
+        balances: i64,
+        /// Transactions of the users whose stored balance is wrong
+        txs: Vec<crate::snapshot::TxSummary>,
+    },
+    /// A balance went below zero
+    NegativeBalance {
+        /// The user
+        user: String,
+        /// Balance after the transaction, in cents
+        balance: i64,
+        /// Transaction that made the balance negative
+        tx: crate::snapshot::TxSummary,
+    },
+    /// Refund of a transaction that is not in the snapshot
+    UnknownRefund {
+        /// The refund
+        refund: crate::snapshot::TxSummary,
+    },
+    /// Transaction refunded more than once
+    DoubleRefund {
+        /// The refunded transaction
+        refunded: crate::snapshot::TxSummary,
+        /// Its refunds
+        refunds: Vec<crate::snapshot::TxSummary>,
+    },
+}
+
+/// Outcome of the check of a global snapshot
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct LedgerReport {
+    /// Money deposited, in cents
+    pub deposits: i64,
+    /// Money withdrawn or paid, in cents
+    pub outflows: i64,
+    /// Sum of the balances of the cut, in cents
+    pub balances: i64,
+    /// Broken invariants
+    pub violations: Vec<Violation>,
+    /// Sites of the snapshot whose balances were not checked, for lack of them
+    pub unchecked: Vec<String>,
+}
+
+/// Checks the ledger invariants on a global snapshot
+pub fn check(snapshot: &crate::snapshot::GlobalSnapshot) -> LedgerReport {
+    let mut txs: Vec<&crate::snapshot::TxSummary> = snapshot.all_transactions.iter().collect();
+    txs.sort_by(|a, b| (a.lamport_time, &a.source_node).cmp(&(b.lamport_time, &b.source_node)));
+    let mut violations = Vec::new();
+
+    // Sites: money entering and leaving against the stored balances
+    let mut sites: Vec<_> = snapshot.balances.iter().collect();
+    sites.sort_by_key(|(site_id, _)| *site_id);
+    let empty = std::collections::HashSet::new();
+    for (site_id, stored) in sites {
+        let missing = snapshot.missing.get(site_id).unwrap_or(&empty);
+        let seen: Vec<_> = txs
+            .iter()
+            .copied()
+            .filter(|tx| !missing.contains(*tx))
+            .collect();
+        let (deposits, outflows) = flows(&seen);
+        let total: i64 = stored.values().sum();
+        if deposits - outflows != total {
+            let replayed = crate::snapshot_diff::balances(seen.iter().copied());
+            let wrong: std::collections::HashSet<&String> = stored
+                .keys()
+                .chain(replayed.keys())
+                .filter(|user| stored.get(*user) != replayed.get(*user))
+                .collect();
+            violations.push(Violation::Conservation {
+                site_id: site_id.clone(),
+                expected: deposits - outflows,
+                balances: total,
+                txs: seen
+                    .iter()
+                    .filter(|tx| wrong.contains(&tx.from_user) || wrong.contains(&tx.to_user))
+                    .map(|tx| (*tx).clone())
+                    .collect(),
+            });
+        }
+    }
+
+    // Replay of the cut
+    let by_key: std::collections::HashMap<crate::merkle::TxKey, &crate::snapshot::TxSummary> = txs
+        .iter()
+        .map(|tx| ((tx.source_node.clone(), tx.lamport_time), *tx))
+        .collect();
+    let mut refunds: std::collections::BTreeMap<
+        &crate::merkle::TxKey,
+        Vec<crate::snapshot::TxSummary>,
+    > = std::collections::BTreeMap::new();
+    let mut balances: std::collections::BTreeMap<&str, i64> = std::collections::BTreeMap::new();
+    let mut negative = std::collections::HashSet::new();
+    for tx in &txs {
+        if let Some(key) = &tx.refund_of {
+            if by_key.contains_key(key) {
+                refunds.entry(key).or_default().push((*tx).clone());
+            } else {
+                violations.push(Violation::UnknownRefund {
+                    refund: (*tx).clone(),
+                });
+            }
+        }
+        if tx.to_user != crate::db::NULL {
+            *balances.entry(&tx.to_user).or_insert(0) += tx.amount_in_cent;
+        }
+        if tx.from_user != crate::db::NULL {
+            let balance = balances.entry(&tx.from_user).or_insert(0);
+            *balance -= tx.amount_in_cent;
+            // une seule alerte par utilisateur, les suivantes en découlent
+            if *balance < 0 && negative.insert(&tx.from_user) {
+                violations.push(Violation::NegativeBalance {
+                    user: tx.from_user.clone(),
+                    balance: *balance,
+                    tx: (*tx).clone(),
+                });
+            }
+        }
+    }
+    for (key, refunds) in refunds {
+        if refunds.len() > 1 {
+            violations.push(Violation::DoubleRefund {
+                refunded: by_key[key].clone(),
+                refunds,
+            });
+        }
+    }
+
+    let sites: std::collections::BTreeSet<&String> = snapshot
+        .missing
+        .keys()
+        .chain(snapshot.channels.iter().flat_map(|c| [&c.from, &c.to]))
+        .collect();
+    let unchecked = sites
+        .into_iter()
+        .filter(|site_id| !snapshot.balances.contains_key(*site_id))
+        .cloned()
+        .collect();
+
+    let (deposits, outflows) = flows(&txs);
+    LedgerReport {
+        deposits,
+        outflows,
+        balances: balances.values().sum(),
+        violations,
+        unchecked,
+    }
+}
+
+/// Money deposited, and money withdrawn or paid, in cents
+fn flows(txs: &[&crate::snapshot::TxSummary]) -> (i64, i64) {
+    let mut deposits = 0;
+    let mut outflows = 0;
+    for tx in txs {
+        if tx.from_user == crate::db::NULL && tx.to_user != crate::db::NULL {
+            deposits += tx.amount_in_cent;
+        }
+        if tx.from_user != crate::db::NULL && tx.to_user == crate::db::NULL {
+            outflows += tx.amount_in_cent;
+        }
+    }
+    (deposits, outflows)
+}
+
+fn cents(amount: i64) -> crate::money::Money {
+    crate::money::Money::from_cents(amount)
+}
+
+fn write_tx(f: &mut std::fmt::Formatter<'_>, tx: &crate::snapshot::TxSummary) -> std::fmt::Result {
+    writeln!(
+        f,
+        "    {}-{} : {} -> {}, {}",
+        tx.source_node,
+        tx.lamport_time,
+        tx.from_user,
+        tx.to_user,
+        cents(tx.amount_in_cent)
+    )
+}
+
+impl std::fmt::Display for LedgerReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Deposits {} - withdrawals and payments {} = balances {}",
+            cents(self.deposits),
+            cents(self.outflows),
+            cents(self.balances)
+        )?;
+        if !self.unchecked.is_empty() {
+            writeln!(
+                f,
+                "Sites not checked, without their balances: {}",
+                self.unchecked.join(", ")
+            )?;
+        }
+        if self.violations.is_empty() {
+            return writeln!(f, "No violation found.");
+        }
+
+        for violation in &self.violations {
+            match violation {
+                Violation::Conservation {
+                    site_id,
+                    expected,
+                    balances,
+                    txs,
+                } => {
+                    writeln!(
+                        f,
+                        "Balances of {} add up to {} instead of {}:",
+                        site_id,
+                        cents(*balances),
+                        cents(*expected)
+                    )?;
+                    for tx in txs {
+                        write_tx(f, tx)?;
+                    }
+                }
+                Violation::NegativeBalance { user, balance, tx } => {
+                    writeln!(f, "Balance of {} went down to {}:", user, cents(*balance))?;
+                    write_tx(f, tx)?;
+                }
+                Violation::UnknownRefund { refund } => {
+                    writeln!(f, "Refund of a transaction not in the snapshot:")?;
+                    write_tx(f, refund)?;
+                }
+                Violation::DoubleRefund { refunded, refunds } => {
+                    writeln!(
+                        f,
+                        "Transaction {}-{} refunded {} times:",
+                        refunded.source_node,
+                        refunded.lamport_time,
+                        refunds.len()
+                    )?;
+                    for tx in refunds {
+                        write_tx(f, tx)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tx(
+        node: &str,
+        lamport: i64,
+        from: &str,
+        to: &str,
+        amount_in_cent: i64,
+    ) -> crate::snapshot::TxSummary {
+        crate::snapshot::TxSummary {
+            lamport_time: lamport,
+            source_node: node.to_string(),
+            from_user: from.to_string(),
+            to_user: to.to_string(),
+            amount_in_cent,
+            hlc: crate::clock::Hlc::default(),
+            refund_of: None,
+            vector_clock: Default::default(),
+        }
+    }
+
+    fn refund(
+        node: &str,
+        lamport: i64,
+        of: &crate::snapshot::TxSummary,
+    ) -> crate::snapshot::TxSummary {
+        crate::snapshot::TxSummary {
+            refund_of: Some((of.source_node.clone(), of.lamport_time)),
+            ..tx(node, lamport, &of.to_user, &of.from_user, of.amount_in_cent)
+        }
+    }
+
+    fn snapshot(txs: Vec<crate::snapshot::TxSummary>) -> crate::snapshot::GlobalSnapshot {
+        crate::snapshot::GlobalSnapshot {
+            all_transactions: txs.into_iter().collect(),
+            missing: std::collections::HashMap::new(),
+            channels: Vec::new(),
+            balances: std::collections::HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_sound_ledger() {
+        let deposit = tx("A", 1, "NULL", "alice", 1000);
+        let transfer = tx("A", 2, "alice", "bob", 400);
+        let pay = tx("B", 3, "bob", "NULL", 100);
+        let undo = refund("B", 4, &pay);
+        let mut s = snapshot(vec![deposit, transfer, pay, undo]);
+        s.balances.insert(
+            "A".to_string(),
+            std::collections::BTreeMap::from([
+                ("alice".to_string(), 600),
+                ("bob".to_string(), 400),
+            ]),
+        );
+
+        let report = check(&s);
+        assert_eq!(report.violations, Vec::new());
+        assert_eq!(
+            (report.deposits, report.outflows, report.balances),
+            (1100, 100, 1000)
+        );
+    }
+
+    #[test]
+    fn test_sites_without_balances_are_reported() {
+        let deposit = tx("A", 1, "NULL", "alice", 1000);
+        let mut s = snapshot(vec![deposit.clone()]);
+        s.missing
+            .insert("B".to_string(), [deposit].into_iter().collect());
+        s.channels.push(crate::snapshot::ChannelState {
+            from: "A".to_string(),
+            to: "C".to_string(),
+            messages: Vec::new(),
+        });
+        s.balances.insert(
+            "A".to_string(),
+            std::collections::BTreeMap::from([("alice".to_string(), 1000)]),
+        );
+
+        let report = check(&s);
+        assert!(report.violations.is_empty());
+        assert_eq!(report.unchecked, vec!["B".to_string(), "C".to_string()]);
+    }
+
+    #[test]
+    fn test_violations_are_reported() {
+        let deposit = tx("A", 1, "NULL", "alice", 1000);
+        let overdraft = tx("B", 2, "alice", "NULL", 1500);
+        let first = refund("A", 3, &deposit);
+        let second = refund("B", 4, &deposit);
+        let ghost = refund("B", 5, &tx("C", 9, "bob", "NULL", 50));
+        let mut s = snapshot(vec![
+            deposit.clone(),
+            overdraft.clone(),
+            first.clone(),
+            second.clone(),
+            ghost.clone(),
+        ]);
+        // B did not record the deposit of alice
+        s.missing
+            .insert("B".to_string(), [deposit.clone()].into_iter().collect());
+        s.balances.insert(
+            "B".to_string(),
+            std::collections::BTreeMap::from([("alice".to_string(), -1000)]),
+        );
+
+        let violations = check(&s).violations;
+        assert_eq!(violations.len(), 4);
+        assert!(matches!(
+            &violations[0],
+            Violation::Conservation { site_id, expected, balances: -1000, txs }
+                if site_id == "B" && *expected == -3450 && txs.len() == 4
+        ));
+        assert_eq!(
+            violations[1],
+            Violation::NegativeBalance {
+                user: "alice".to_string(),
+                balance: -500,
+                tx: overdraft,
+            }
+        );
+        assert_eq!(violations[2], Violation::UnknownRefund { refund: ghost });
+        assert_eq!(
+            violations[3],
+            Violation::DoubleRefund {
+                refunded: deposit,
+                refunds: vec![first, second],
+            }
+        );
+    }
+}
@@ -6,14 +6,33 @@
 
 #![allow(non_snake_case)]
 
+mod catch_up;
+mod causal;
 mod clock;
+mod codec;
 mod control;
 mod db;
+mod heartbeat;
+mod identity;
+mod ledger;
+mod membership;
+mod merkle;
 mod message;
+mod migration;
+mod money;
+mod mutex;
 mod network;
+mod node;
+mod reconnect;
+mod simulation;
 mod snapshot;
+mod snapshot_diff;
+mod snapshot_index;
 mod state;
+mod tls;
+mod transport;
 mod utils;
+mod wave;
 
 /// Command-line arguments for configuring the Peillute application
 #[derive(clap::Parser, Debug)]
@@ -38,32 +57,116 @@ struct Args {
     /// ID for the batabase path
     #[arg(long, default_value_t = 0)]
     cli_db_id: u16,
+
+    /// Print the pending migrations of the database and exit, without applying them
+    #[arg(long)]
+    cli_migrate_dry_run: bool,
+
+    /// Maximum size in bytes of a message exchanged with a peer
+    #[arg(long, default_value_t = 16 * 1024 * 1024)]
+    cli_max_frame_size: usize,
+
+    /// Interval in milliseconds between two heartbeats sent to the neighbours
+    #[arg(long, default_value_t = 1000)]
+    cli_heartbeat_interval_ms: u64,
+
+    /// Suspicion level above which a silent neighbour is removed
+    #[arg(long, default_value_t = 8.0)]
+    cli_phi_threshold: f64,
+
+    /// Mutual exclusion algorithm, the same on every site of the network
+    #[cfg(feature = "server")]
+    #[arg(long, value_enum, default_value_t = mutex::MutexAlgorithm::Wave)]
+    cli_mutex: mutex::MutexAlgorithm,
+
+    /// Time in milliseconds after which a command waiting for the global mutex fails
+    #[arg(long, default_value_t = 30_000)]
+    cli_mutex_timeout_ms: u64,
+
+    /// Time in milliseconds a transaction waits for the transactions it depends on
+    #[arg(long, default_value_t = 10_000)]
+    cli_causal_timeout_ms: u64,
+
+    /// Milliseconds a received clock may be ahead of the local wall clock before it is flagged
+    #[arg(long, default_value_t = 60_000)]
+    cli_max_clock_drift_ms: i64,
+
+    /// Address of a site of the network to bootstrap the membership from, when no peers are given
+    #[arg(long)]
+    cli_seed: Option<String>,
+
+    /// PEM file of the certificate authority trusted to sign the peers certificates
+    #[arg(long, requires_all = ["cli_tls_cert", "cli_tls_key"])]
+    cli_tls_ca: Option<String>,
+
+    /// PEM file of the certificate chain of this site
+    #[arg(long, requires_all = ["cli_tls_ca", "cli_tls_key"])]
+    cli_tls_cert: Option<String>,
+
+    /// PEM file of the private key of this site
+    #[arg(long, requires_all = ["cli_tls_ca", "cli_tls_cert"])]
+    cli_tls_key: Option<String>,
+
+    /// Directory the snapshots are written to
+    #[arg(long, default_value_t = String::from("snapshots"))]
+    cli_snapshot_dir: String,
+
+    /// Interval in seconds between two automatic snapshots, 0 to disable them
+    #[arg(long, default_value_t = 0)]
+    cli_snapshot_interval_s: u64,
+
+    /// Number of latest snapshots kept, 0 to disable this rule
+    #[arg(long, default_value_t = 0)]
+    cli_snapshot_keep_last: usize,
+
+    /// Number of days whose latest snapshot is kept, 0 to disable this rule
+    #[arg(long, default_value_t = 0)]
+    cli_snapshot_keep_daily: usize,
 }
 
 #[cfg(feature = "server")]
 #[tokio::main]
 async fn main() -> rusqlite::Result<(), Box<dyn std::error::Error>> {
-    use crate::state::LOCAL_APP_STATE;
     use clap::Parser;
     use std::io::{self as std_io, Write};
     use std::net::SocketAddr;
     use tokio::io::{self as tokio_io, AsyncBufReadExt, BufReader};
-    use tokio::net::TcpListener;
 
     const LOW_PORT: u16 = 10000;
     const HIGH_PORT: u16 = 11000;
     const PORT_OFFSET: u16 = HIGH_PORT - LOW_PORT + 1;
 
-    if !db::is_database_initialized()? {
-        let _ = db::init_db();
-    }
-
-    control::control_worker();
     // Init the logger
     env_logger::init();
 
     let args = Args::parse();
 
+    if args.cli_migrate_dry_run {
+        let Some((version, pending)) = db::pending_migrations()? else {
+            println!(
+                "No database at {}: it will be created with the {} migrations.",
+                db::db_path(),
+                migration::MIGRATIONS.len()
+            );
+            return Ok(());
+        };
+        println!("Database schema version: {}", version);
+        if pending.is_empty() {
+            println!("No pending migration.");
+        }
+        for migration in pending {
+            println!(
+                "Pending migration {}: {}",
+                migration.version, migration.description
+            );
+        }
+        return Ok(());
+    }
+    let node = node::Node::global();
+    db::init_db(&node.db)?;
+
+    control::control_worker(node.clone());
+
     let port_range = LOW_PORT..=HIGH_PORT;
     let selected_port = if args.cli_port == 0 {
         port_range
@@ -84,7 +187,13 @@ async fn main() -> rusqlite::Result<(), Box<dyn std::error::Error>> {
         .filter_map(|peer| peer.parse::<SocketAddr>().ok())
         .collect();
 
-    let (final_site_id, final_clock, needs_sync) = match utils::reload_existing_site().await {
+    let final_seed_addr: Option<SocketAddr> = match &args.cli_seed {
+        Some(seed) => Some(seed.parse()?),
+        None => None,
+    };
+
+    let (final_site_id, final_clock, needs_sync) = match utils::reload_existing_site(&node.db).await
+    {
         Ok((site_id_from_db, clock_from_db)) => (site_id_from_db, clock_from_db, true),
         Err(_) => {
             let generated_site_id = if args.cli_site_id.is_empty() {
@@ -96,20 +205,60 @@ async fn main() -> rusqlite::Result<(), Box<dyn std::error::Error>> {
         }
     };
 
+    let site_identity = db::load_or_create_identity(&node.db, &final_site_id, &final_clock)?;
+
     {
-        let mut state = LOCAL_APP_STATE.lock().await;
+        let mut state = node.state.lock().await;
         state.init_site_id(final_site_id.clone());
         state.init_site_addr(final_site_addr);
+        if needs_sync {
+            state.init_restart_clock(final_clock.get_vector_clock_map().clone());
+        }
         state.init_clock(final_clock);
-        state.init_parent_addr_for_transaction_wave();
         state.init_cli_peer_addrs(final_cli_peers_addrs);
+        let incarnation = membership::initial_incarnation();
+        state.init_membership(incarnation);
+        state.init_wave_seq(incarnation);
         state.init_sync(needs_sync);
+        state.init_failure_detector(args.cli_heartbeat_interval_ms, args.cli_phi_threshold);
+        state.init_mutex(args.cli_mutex);
+        state.init_mutex_timeout(args.cli_mutex_timeout_ms);
+        state.init_causal_timeout(args.cli_causal_timeout_ms);
+        state.init_causal_delivered(db::get_causal_delivered(&node.db)?);
+        state.init_key_ring(db::get_pinned_keys(&node.db)?);
+        state.init_max_clock_drift(args.cli_max_clock_drift_ms);
+    }
+
+    {
+        let mut manager = node.network.lock().await;
+        manager.init_max_frame_size(args.cli_max_frame_size);
+        manager.init_identity(site_identity);
+    }
+
+    {
+        let mut mgr = node.snapshots.lock().await;
+        mgr.index.init_dir(args.cli_snapshot_dir.clone().into())?;
+        mgr.index.init_retention(snapshot_index::RetentionPolicy {
+            keep_last: args.cli_snapshot_keep_last,
+            keep_daily: args.cli_snapshot_keep_daily,
+        });
     }
 
     // Create the network listener
-    let network_listener_local_addr = final_site_addr.clone();
-    let listener: TcpListener = TcpListener::bind(network_listener_local_addr).await?;
-    log::debug!("Listening on: {}", network_listener_local_addr);
+    let tls_context = match (&args.cli_tls_ca, &args.cli_tls_cert, &args.cli_tls_key) {
+        (Some(ca), Some(cert), Some(key)) => {
+            log::info!("Mutual TLS enabled for peer connections");
+            Some(tls::TlsContext::from_pem_files(ca, cert, key)?)
+        }
+        _ => None,
+    };
+    let transport: std::sync::Arc<dyn transport::Transport> =
+        std::sync::Arc::new(transport::TcpTransport::bind(final_site_addr, tls_context).await?);
+    {
+        let mut manager = node.network.lock().await;
+        manager.init_transport(transport.clone());
+    }
+    log::debug!("Listening on: {}", transport.local_addr());
 
     // Create the web app listener
     let router = axum::Router::new().serve_dioxus_application(ServeConfigBuilder::default(), App);
@@ -124,7 +273,7 @@ async fn main() -> rusqlite::Result<(), Box<dyn std::error::Error>> {
     let mut lines: tokio_io::Lines<_> = reader.lines();
 
     // Announce our presence to the network
-    network::announce(&args.cli_ip, LOW_PORT, HIGH_PORT, selected_port).await;
+    network::announce(node, final_seed_addr).await;
 
     println!(
         "\n\
@@ -141,14 +290,26 @@ async fn main() -> rusqlite::Result<(), Box<dyn std::error::Error>> {
     print!("> ");
     std_io::stdout().flush().unwrap();
 
-    let main_loop_app_state = LOCAL_APP_STATE.clone();
+    // Start watching the neighbours once the discovery phase is over
+    heartbeat::heartbeat_worker(node.clone());
+    // Do not let a failed site block the critical section forever
+    control::mutex_watchdog_worker(node.clone());
+    control::causal_delivery_worker(node.clone());
+    // Keep the peers given in arguments connected, even if they restart
+    reconnect::reconnection_worker(node.clone());
+    if args.cli_snapshot_interval_s > 0 {
+        control::snapshot_worker(
+            node.clone(),
+            std::time::Duration::from_secs(args.cli_snapshot_interval_s),
+        );
+    }
 
     // Spawn the web server
     let server_task = tokio::spawn(async move {
         axum::serve(backend_listener, router).await.unwrap();
     });
 
-    main_loop(main_loop_app_state, &mut lines, listener).await;
+    main_loop(node, &mut lines, transport).await;
 
     // Ensure the server task finishes cleanly if ever reached
     server_task.await?;
@@ -158,9 +319,9 @@ async fn main() -> rusqlite::Result<(), Box<dyn std::error::Error>> {
 
 #[cfg(feature = "server")]
 async fn main_loop(
-    _state: std::sync::Arc<tokio::sync::Mutex<crate::state::AppState>>,
+    node: &crate::node::Node,
     lines: &mut tokio::io::Lines<tokio::io::BufReader<tokio::io::Stdin>>,
-    listener: tokio::net::TcpListener,
+    transport: std::sync::Arc<dyn crate::transport::Transport>,
 ) {
     use crate::control::{parse_command, process_cli_command};
     use std::io::{self as std_io, Write};
@@ -170,17 +331,18 @@ async fn main_loop(
         select! {
             line = lines.next_line() => {
                 let command = parse_command(line);
-                if let Err(e) = process_cli_command(command).await{
+                if let Err(e) = process_cli_command(node, command).await{
                     log::error!("Error handling a cli command:\n{}", e);
+                    println!("❌ {}", e);
                 }
                 print!("> ");
                 std_io::stdout().flush().unwrap();
             }
-            Ok((stream, addr)) = listener.accept() => {
-                let _ = crate::network::start_listening(stream, addr).await;
+            Ok((stream, addr)) = transport.accept() => {
+                let _ = crate::network::start_listening(node.clone(), stream, addr).await;
             }
             _ = tokio::signal::ctrl_c() => {
-                disconnect().await;
+                disconnect(node).await;
                 std::process::exit(0);
             }
         }
@@ -188,13 +350,13 @@ async fn main_loop(
 }
 
 #[cfg(feature = "server")]
-async fn disconnect() {
+async fn disconnect(node: &crate::node::Node) {
     use crate::message::{MessageInfo, NetworkMessageCode};
-    use crate::state::LOCAL_APP_STATE;
     use log::{error, info};
 
     let (local_addr, site_id, connected_nei_addr) = {
-        let state = LOCAL_APP_STATE.lock().await;
+        let mut state = node.state.lock().await;
+        state.membership.leave();
         (
             state.get_site_addr(),
             state.get_site_id().to_string(),
@@ -206,20 +368,19 @@ async fn disconnect() {
     for peer_addr in connected_nei_addr {
         // increment the clock for every deconnection
         let clock = {
-            let mut state = LOCAL_APP_STATE.lock().await;
-            state.update_clock(None).await;
+            let mut state = node.state.lock().await;
+            state.update_clock(&node.db, None).await;
             state.get_clock().clone()
         };
 
         if let Err(e) = crate::network::send_message(
+            node,
             peer_addr,
             MessageInfo::None,
-            None,
             NetworkMessageCode::Disconnect,
             local_addr,
             &site_id,
-            &site_id,
-            local_addr,
+            crate::message::Origin::local(&site_id, local_addr),
             clock.clone(),
         )
         .await
@@ -326,4 +487,33 @@ mod tests {
         assert_eq!(args.cli_port, 8080);
         assert_eq!(args.cli_peers.len(), 0);
     }
+
+    #[test]
+    fn test_args_parsing_max_frame_size() {
+        use super::Args;
+        let args = Args::parse_from(vec!["my_program"]);
+        assert_eq!(args.cli_max_frame_size, 16 * 1024 * 1024);
+
+        let args = Args::parse_from(vec!["my_program", "--cli-max-frame-size", "4096"]);
+        assert_eq!(args.cli_max_frame_size, 4096);
+    }
+
+    #[test]
+    fn test_args_parsing_tls_files_go_together() {
+        use super::Args;
+        let args = Args::parse_from(vec![
+            "my_program",
+            "--cli-tls-ca",
+            "ca.pem",
+            "--cli-tls-cert",
+            "node.pem",
+            "--cli-tls-key",
+            "node.key",
+        ]);
+        assert_eq!(args.cli_tls_ca.as_deref(), Some("ca.pem"));
+        assert_eq!(args.cli_tls_cert.as_deref(), Some("node.pem"));
+        assert_eq!(args.cli_tls_key.as_deref(), Some("node.key"));
+
+        assert!(Args::try_parse_from(vec!["my_program", "--cli-tls-ca", "ca.pem"]).is_err());
+    }
 }
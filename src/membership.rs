@@ -0,0 +1,347 @@
+//! Gossip-based membership of the network
+//!
+//! Every site keeps a view of all the sites of the network, not only of its
+//! neighbours. The view is disseminated the SWIM way: it is piggybacked on the
+//! `Discovery` and `Acknowledgment` messages and on every heartbeat, and each
+//! site merges the views it receives into its own.
+//!
+//! Each member carries an incarnation number that only the member itself can
+//! increase. For a given incarnation `Left` overrides `Suspect`, which
+//! overrides `Alive`, and a higher incarnation overrides everything. A site
+//! that hears it is suspected refutes it by gossiping itself `Alive` with a
+//! higher incarnation. Incarnations start at the launch time of the site, so a
+//! site that restarts overrides the `Left` entry of its previous run.
+
+#![cfg(feature = "server")]
+
+/// Status of a member in the view
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberStatus {
+    /// The member is part of the network
+    Alive,
+    /// A neighbour of the member stopped hearing from it
+    Suspect,
+    /// The member disconnected properly
+    Left,
+}
+
+impl MemberStatus {
+    /// Precedence of the status between two updates of the same incarnation
+    fn rank(self) -> u8 {
+        match self {
+            MemberStatus::Alive => 0,
+            MemberStatus::Suspect => 1,
+            MemberStatus::Left => 2,
+        }
+    }
+}
+
+/// Entry of the view, as exchanged between sites
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MemberUpdate {
+    /// Address the member listens on
+    pub addr: std::net::SocketAddr,
+    /// Site id of the member
+    pub site_id: String,
+    /// Incarnation of the member, increased by the member itself only
+    pub incarnation: u64,
+    /// Status of the member at this incarnation
+    pub status: MemberStatus,
+}
+
+/// Membership view of the network, including the local site
+pub struct MembershipView {
+    /// Address of the local site
+    local_addr: std::net::SocketAddr,
+    /// Every known member, indexed by address
+    members: std::collections::HashMap<std::net::SocketAddr, MemberUpdate>,
+}
+
+impl MembershipView {
+    /// Creates a view containing only the local site
+    pub fn new(local_addr: std::net::SocketAddr, site_id: String, incarnation: u64) -> Self {
+        let mut members = std::collections::HashMap::new();
+        members.insert(
+            local_addr,
+            MemberUpdate {
+                addr: local_addr,
+                site_id,
+                incarnation,
+                status: MemberStatus::Alive,
+            },
+        );
+        Self {
+            local_addr,
+            members,
+        }
+    }
+
+    /// Returns the entries of the view, to be gossiped
+    pub fn digest(&self) -> Vec<MemberUpdate> {
+        let mut members: Vec<_> = self.members.values().cloned().collect();
+        members.sort_by_key(|m| m.addr);
+        members
+    }
+
+    /// Returns the addresses of the other members believed alive
+    pub fn alive_members(&self) -> Vec<std::net::SocketAddr> {
+        let mut alive: Vec<_> = self
+            .members
+            .values()
+            .filter(|m| m.status == MemberStatus::Alive && m.addr != self.local_addr)
+            .map(|m| m.addr)
+            .collect();
+        alive.sort();
+        alive
+    }
+
+    /// Returns the ids of the sites believed alive, the local one included, sorted
+    pub fn alive_sites(&self) -> Vec<String> {
+        let mut alive: Vec<_> = self
+            .members
+            .values()
+            .filter(|m| m.status == MemberStatus::Alive)
+            .map(|m| m.site_id.clone())
+            .collect();
+        alive.sort();
+        alive.dedup();
+        alive
+    }
+
+    /// Returns the address of a site believed alive
+    pub fn alive_addr(&self, site_id: &str) -> Option<std::net::SocketAddr> {
+        self.members
+            .values()
+            .find(|m| m.status == MemberStatus::Alive && m.site_id == site_id)
+            .map(|m| m.addr)
+    }
+
+    /// Returns the ids of the other sites believed alive, sorted
+    pub fn alive_peers(&self) -> Vec<String> {
+        let mut alive: Vec<_> = self
+            .members
+            .values()
+            .filter(|m| m.status == MemberStatus::Alive && m.addr != self.local_addr)
+            .map(|m| m.site_id.clone())
+            .collect();
+        alive.sort();
+        alive.dedup();
+        alive
+    }
+
+    /// Returns the ids of the sites believed failed or gone
+    ///
+    /// A site id that is alive at another address, after a restart on
+    /// another port for instance, is not reported.
+    pub fn departed_sites(&self) -> Vec<String> {
+        let alive: std::collections::HashSet<&str> = self
+            .members
+            .values()
+            .filter(|m| m.status == MemberStatus::Alive)
+            .map(|m| m.site_id.as_str())
+            .collect();
+        let mut departed: Vec<_> = self
+            .members
+            .values()
+            .filter(|m| m.status != MemberStatus::Alive && !alive.contains(m.site_id.as_str()))
+            .map(|m| m.site_id.clone())
+            .collect();
+        departed.sort();
+        departed.dedup();
+        departed
+    }
+
+    /// Merges a gossiped view into the local one
+    ///
+    /// Returns the members that became alive in the local view.
+    pub fn merge(&mut self, updates: &[MemberUpdate]) -> Vec<std::net::SocketAddr> {
+        let mut joined = Vec::new();
+        for update in updates {
+            if update.addr == self.local_addr {
+                self.refute(update);
+                continue;
+            }
+
+            let newer = match self.members.get(&update.addr) {
+                None => true,
+                Some(current) => {
+                    update.incarnation > current.incarnation
+                        || (update.incarnation == current.incarnation
+                            && update.status.rank() > current.status.rank())
+                }
+            };
+            if !newer {
+                continue;
+            }
+
+            let was_alive = self
+                .members
+                .get(&update.addr)
+                .is_some_and(|m| m.status == MemberStatus::Alive);
+            if update.status == MemberStatus::Alive && !was_alive {
+                joined.push(update.addr);
+            }
+            if update.status != MemberStatus::Alive && was_alive {
+                log::info!("Member {} is now {:?}", update.addr, update.status);
+            }
+            self.members.insert(update.addr, update.clone());
+        }
+        joined
+    }
+
+    /// Marks a member with a new status at its current incarnation
+    pub fn mark(&mut self, addr: std::net::SocketAddr, status: MemberStatus) {
+        if addr == self.local_addr {
+            return;
+        }
+        if let Some(member) = self.members.get_mut(&addr)
+            && status.rank() > member.status.rank()
+        {
+            member.status = status;
+        }
+    }
+
+    /// Answers a rumour about the local site by gossiping a higher incarnation
+    fn refute(&mut self, update: &MemberUpdate) {
+        let Some(local) = self.members.get_mut(&self.local_addr) else {
+            return;
+        };
+        // A leaving site has nothing to refute
+        if local.status == MemberStatus::Alive
+            && update.status != MemberStatus::Alive
+            && update.incarnation >= local.incarnation
+        {
+            local.incarnation = update.incarnation + 1;
+            log::info!(
+                "Refuting {:?} rumour with incarnation {}",
+                update.status,
+                local.incarnation
+            );
+        }
+    }
+
+    /// Marks the local site as leaving the network
+    pub fn leave(&mut self) {
+        if let Some(local) = self.members.get_mut(&self.local_addr) {
+            local.status = MemberStatus::Left;
+        }
+    }
+}
+
+/// Incarnation of a site starting now
+pub fn initial_incarnation() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> std::net::SocketAddr {
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    fn view(port: u16) -> MembershipView {
+        MembershipView::new(addr(port), format!("site{}", port), 1)
+    }
+
+    #[test]
+    fn joining_site_learns_the_full_view_from_its_seed() {
+        let mut seed = view(1);
+        seed.merge(&view(2).digest());
+        seed.merge(&view(3).digest());
+
+        let mut newcomer = view(4);
+        let joined = newcomer.merge(&seed.digest());
+        assert_eq!(joined, vec![addr(1), addr(2), addr(3)]);
+        assert_eq!(newcomer.alive_members(), vec![addr(1), addr(2), addr(3)]);
+
+        // The seed learns the newcomer from the view sent with the discovery
+        seed.merge(&newcomer.digest());
+        assert_eq!(seed.alive_members(), vec![addr(2), addr(3), addr(4)]);
+    }
+
+    #[test]
+    fn status_precedence_within_an_incarnation() {
+        let mut local = view(1);
+        local.merge(&view(2).digest());
+
+        let mut suspect = view(2).digest();
+        suspect[0].status = MemberStatus::Suspect;
+        local.merge(&suspect);
+        assert!(local.alive_members().is_empty());
+
+        // A stale alive entry does not override the suspicion
+        local.merge(&view(2).digest());
+        assert!(local.alive_members().is_empty());
+    }
+
+    #[test]
+    fn suspected_site_refutes_with_a_higher_incarnation() {
+        let mut observer = view(1);
+        let mut suspected = view(2);
+        observer.merge(&suspected.digest());
+        observer.mark(addr(2), MemberStatus::Suspect);
+        assert!(observer.alive_members().is_empty());
+
+        suspected.merge(&observer.digest());
+        let joined = observer.merge(&suspected.digest());
+        assert_eq!(joined, vec![addr(2)]);
+        assert_eq!(observer.alive_members(), vec![addr(2)]);
+    }
+
+    #[test]
+    fn restarted_site_overrides_its_left_entry() {
+        let mut observer = view(1);
+        let mut leaving = view(2);
+        leaving.leave();
+        observer.merge(&leaving.digest());
+        assert!(observer.alive_members().is_empty());
+
+        let restarted = MembershipView::new(addr(2), "site2".to_string(), 2);
+        assert_eq!(observer.merge(&restarted.digest()), vec![addr(2)]);
+    }
+
+    #[test]
+    fn departed_sites_skip_restarted_ids() {
+        let mut local = view(1);
+        local.merge(&view(2).digest());
+        local.merge(&view(3).digest());
+        assert!(local.departed_sites().is_empty());
+
+        local.mark(addr(2), MemberStatus::Suspect);
+        local.mark(addr(3), MemberStatus::Left);
+        assert_eq!(local.departed_sites(), vec!["site2", "site3"]);
+
+        // site3 came back on another port
+        local.merge(&MembershipView::new(addr(4), "site3".to_string(), 2).digest());
+        assert_eq!(local.departed_sites(), vec!["site2"]);
+    }
+
+    #[test]
+    fn views_converge_through_gossip_on_a_line() {
+        // 1 - 2 - 3 - 4 - 5, each site only gossips with its neighbours
+        let mut views: Vec<_> = (1..=5).map(view).collect();
+        for i in 0..4 {
+            let digest = views[i].digest();
+            views[i + 1].merge(&digest);
+        }
+        views[4].leave();
+        for _ in 0..4 {
+            for i in 0..4 {
+                let right = views[i + 1].digest();
+                views[i].merge(&right);
+                let left = views[i].digest();
+                views[i + 1].merge(&left);
+            }
+        }
+
+        for v in &views {
+            assert_eq!(v.digest(), views[0].digest());
+        }
+        assert_eq!(views[0].alive_members(), vec![addr(2), addr(3), addr(4)]);
+    }
+}
@@ -0,0 +1,500 @@
+//! Merkle-tree anti-entropy between neighbours
+//!
+//! A site that joins the network used to receive the whole transaction log of
+//! every other site, whatever it already had. Each site now keeps a
+//! [`MerkleTree`] over the keys `(source_node, lamport_time)` of its
+//! transactions: the keys are spread over the leaves by their hash, and a
+//! node of the tree hashes the nodes below it. Two neighbours compare their
+//! roots, then only the children of the nodes that differ, down to the leaves
+//! where they exchange their keys, so only the missing transactions are sent.
+//!
+//! The exchange is symmetric: each side sends the transactions the other one
+//! lacks. A side whose range is empty does not descend into it, the other one
+//! sends every transaction of the range right away, which is what a site
+//! joining with an empty database gets.
+
+#![cfg(feature = "server")]
+
+/// Number of children of an inner node of the tree
+pub const FANOUT: u32 = 16;
+
+/// Depth of the leaves, the root being at depth 0
+pub const DEPTH: u8 = 3;
+
+/// Number of transactions or leaves sent in a single message
+const BATCH_SIZE: usize = 256;
+
+/// Hash of a node of the tree
+pub type Digest = [u8; 32];
+
+/// Hash of a node without any transaction below it
+pub const EMPTY: Digest = [0; 32];
+
+/// Key of a transaction: the node that created it and its Lamport time
+pub type TxKey = (String, i64);
+
+/// Node of the tree, covering a range of its leaves
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
+pub struct Range {
+    /// Depth of the node, 0 for the root
+    pub depth: u8,
+    /// Position of the node among the nodes of its depth
+    pub index: u32,
+}
+
+impl Range {
+    /// The whole tree
+    pub const ROOT: Range = Range { depth: 0, index: 0 };
+
+    /// Whether the node is a leaf
+    pub fn is_leaf(&self) -> bool {
+        self.depth == DEPTH
+    }
+
+    /// Children of the node, none for a leaf
+    pub fn children(&self) -> impl Iterator<Item = Range> {
+        let depth = self.depth + 1;
+        let first = self.index * FANOUT;
+        let count = if self.is_leaf() { 0 } else { FANOUT };
+        (first..first + count).map(move |index| Range { depth, index })
+    }
+
+    /// Leaves covered by the node
+    fn leaves(&self) -> std::ops::Range<usize> {
+        let width = FANOUT.pow((DEPTH - self.depth) as u32) as usize;
+        let first = self.index as usize * width;
+        first..first + width
+    }
+}
+
+/// Payload of the messages exchanged to reconcile two trees
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub enum AntiEntropyPayload {
+    /// Hashes of nodes of the sender's tree
+    Compare(Vec<(Range, Digest)>),
+    /// Keys of the sender in leaves whose hashes differ
+    Keys(Vec<(Range, Vec<TxKey>)>),
+    /// Transactions the sender asks for
+    Fetch(Vec<TxKey>),
+    /// Transactions the receiver is missing
+    Transactions(Vec<crate::snapshot::TxSummary>),
+}
+
+/// Outcome of the comparison of hashes received from a neighbour
+#[derive(Debug, Default, PartialEq)]
+pub struct Comparison {
+    /// Nodes to compare next, with our hashes
+    pub descend: Vec<(Range, Digest)>,
+    /// Our keys in the leaves that differ
+    pub keys: Vec<(Range, Vec<TxKey>)>,
+    /// Our transactions in the ranges where the neighbour has none
+    pub push: Vec<TxKey>,
+}
+
+/// Merkle tree over the keys of the transactions known by the site
+pub struct MerkleTree {
+    /// Keys of each leaf
+    leaves: Vec<std::collections::BTreeSet<TxKey>>,
+    /// Hashes of the nodes of each depth, None until computed or after a change below
+    hashes: Vec<Vec<Option<Digest>>>,
+    /// Number of keys in the tree
+    len: usize,
+}
+
+impl MerkleTree {
+    /// Creates an empty tree
+    pub fn new() -> Self {
+        Self {
+            leaves: vec![std::collections::BTreeSet::new(); FANOUT.pow(DEPTH as u32) as usize],
+            hashes: (0..=DEPTH)
+                .map(|depth| vec![None; FANOUT.pow(depth as u32) as usize])
+                .collect(),
+            len: 0,
+        }
+    }
+
+    /// Creates a tree holding the given keys
+    pub fn from_keys(keys: impl IntoIterator<Item = TxKey>) -> Self {
+        let mut tree = Self::new();
+        for key in keys {
+            tree.insert(key);
+        }
+        tree
+    }
+
+    /// Adds a key, returns false if it was already there
+    pub fn insert(&mut self, key: TxKey) -> bool {
+        let leaf = leaf_of(&key);
+        if !self.leaves[leaf].insert(key) {
+            return false;
+        }
+        self.len += 1;
+        // le chemin de la feuille à la racine doit être recalculé
+        let mut index = leaf;
+        for depth in (0..=DEPTH as usize).rev() {
+            self.hashes[depth][index] = None;
+            index /= FANOUT as usize;
+        }
+        true
+    }
+
+    /// Number of keys in the tree
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Hash of a node, [`EMPTY`] if no key is below it
+    pub fn hash(&mut self, range: Range) -> Digest {
+        use sha2::Digest as _;
+
+        if let Some(digest) = self.hashes[range.depth as usize][range.index as usize] {
+            return digest;
+        }
+        let digest = if range.is_leaf() {
+            let keys = &self.leaves[range.index as usize];
+            if keys.is_empty() {
+                EMPTY
+            } else {
+                let mut hasher = sha2::Sha256::new();
+                for (node, lamport) in keys {
+                    hasher.update((node.len() as u64).to_be_bytes());
+                    hasher.update(node.as_bytes());
+                    hasher.update(lamport.to_be_bytes());
+                }
+                hasher.finalize().into()
+            }
+        } else {
+            let children: Vec<Digest> = range.children().map(|child| self.hash(child)).collect();
+            if children.iter().all(|child| *child == EMPTY) {
+                EMPTY
+            } else {
+                let mut hasher = sha2::Sha256::new();
+                for child in children {
+                    hasher.update(child);
+                }
+                hasher.finalize().into()
+            }
+        };
+        self.hashes[range.depth as usize][range.index as usize] = Some(digest);
+        digest
+    }
+
+    /// Keys below a node
+    pub fn keys(&self, range: Range) -> Vec<TxKey> {
+        self.leaves[range.leaves()]
+            .iter()
+            .flat_map(|leaf| leaf.iter().cloned())
+            .collect()
+    }
+
+    /// Compares the hashes of a neighbour with ours
+    pub fn compare(&mut self, theirs: &[(Range, Digest)]) -> Comparison {
+        let mut comparison = Comparison::default();
+        for (range, their_hash) in theirs {
+            let our_hash = self.hash(*range);
+            if our_hash == *their_hash {
+                continue;
+            }
+            if *their_hash == EMPTY {
+                comparison.push.extend(self.keys(*range));
+            } else if our_hash == EMPTY {
+                // nous n'avons rien ici : c'est à l'autre d'envoyer toute la plage
+                comparison.descend.push((*range, EMPTY));
+            } else if range.is_leaf() {
+                comparison.keys.push((*range, self.keys(*range)));
+            } else {
+                for child in range.children() {
+                    let hash = self.hash(child);
+                    comparison.descend.push((child, hash));
+                }
+            }
+        }
+        comparison
+    }
+
+    /// Compares the keys of a neighbour with ours, leaf by leaf
+    ///
+    /// Returns the keys the neighbour lacks, then the ones we lack.
+    pub fn reconcile(&self, theirs: &[(Range, Vec<TxKey>)]) -> (Vec<TxKey>, Vec<TxKey>) {
+        let mut they_lack = Vec::new();
+        let mut we_lack = Vec::new();
+        for (range, their_keys) in theirs {
+            let ours: std::collections::BTreeSet<TxKey> = self.keys(*range).into_iter().collect();
+            let theirs: std::collections::BTreeSet<TxKey> = their_keys.iter().cloned().collect();
+            they_lack.extend(ours.difference(&theirs).cloned());
+            we_lack.extend(theirs.difference(&ours).cloned());
+        }
+        (they_lack, we_lack)
+    }
+}
+
+/// Leaf holding a key
+fn leaf_of(key: &TxKey) -> usize {
+    use sha2::Digest as _;
+
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(key.0.as_bytes());
+    hasher.update(key.1.to_be_bytes());
+    let digest = hasher.finalize();
+    let prefix = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]);
+    (prefix % FANOUT.pow(DEPTH as u32)) as usize
+}
+
+/// Short hexadecimal form of a hash, for the logs
+pub fn short_hex(digest: &Digest) -> String {
+    digest[..4].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Tree of the transactions stored in a database
+fn load_tree(db: &crate::db::Db) -> MerkleTree {
+    match crate::db::get_local_transaction_log(db) {
+        Ok(txs) => {
+            MerkleTree::from_keys(txs.into_iter().map(|tx| (tx.source_node, tx.lamport_time)))
+        }
+        Err(e) => {
+            log::error!("Cannot build the Merkle tree from the database: {}", e);
+            MerkleTree::new()
+        }
+    }
+}
+
+/// Rebuilds the tree after the database was replaced
+pub fn reload(db: &crate::db::Db) {
+    *db.tree.lock().unwrap() = load_tree(db);
+}
+
+/// Adds a transaction stored in the database to the tree
+pub fn record(db: &crate::db::Db, source_node: &str, lamport_time: i64) {
+    db.tree
+        .lock()
+        .unwrap()
+        .insert((source_node.to_string(), lamport_time));
+}
+
+/// Starts the reconciliation of our transactions with every neighbour
+pub async fn start_anti_entropy(
+    node: &crate::node::Node,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let root = node.db.tree.lock().unwrap().hash(Range::ROOT);
+    let neighbours = {
+        let state = node.state.lock().await;
+        state.get_connected_nei_addr()
+    };
+    log::info!(
+        "Comparing our transactions (root {}) with {} neighbours",
+        short_hex(&root),
+        neighbours.len()
+    );
+    for addr in neighbours {
+        if let Err(e) = send(
+            node,
+            addr,
+            AntiEntropyPayload::Compare(vec![(Range::ROOT, root)]),
+        )
+        .await
+        {
+            log::error!("Cannot start the anti-entropy with {}: {}", addr, e);
+        }
+    }
+    Ok(())
+}
+
+/// Answers a message of the anti-entropy protocol
+pub async fn handle_anti_entropy(
+    node: &crate::node::Node,
+    from: std::net::SocketAddr,
+    payload: AntiEntropyPayload,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match payload {
+        AntiEntropyPayload::Compare(theirs) => {
+            let comparison = node.db.tree.lock().unwrap().compare(&theirs);
+            for batch in comparison.descend.chunks(BATCH_SIZE) {
+                send(node, from, AntiEntropyPayload::Compare(batch.to_vec())).await?;
+            }
+            for batch in comparison.keys.chunks(BATCH_SIZE) {
+                send(node, from, AntiEntropyPayload::Keys(batch.to_vec())).await?;
+            }
+            send_transactions(node, from, &comparison.push).await?;
+        }
+        AntiEntropyPayload::Keys(theirs) => {
+            let (they_lack, we_lack) = node.db.tree.lock().unwrap().reconcile(&theirs);
+            send_transactions(node, from, &they_lack).await?;
+            for batch in we_lack.chunks(BATCH_SIZE) {
+                send(node, from, AntiEntropyPayload::Fetch(batch.to_vec())).await?;
+            }
+        }
+        AntiEntropyPayload::Fetch(keys) => {
+            send_transactions(node, from, &keys).await?;
+        }
+        AntiEntropyPayload::Transactions(txs) => {
+            log::info!("Received {} missing transactions from {}", txs.len(), from);
+            let failed = crate::db::insert_missing_transactions(&node.db, &txs);
+            // une seule nouvelle demande par transaction, pour ne pas boucler
+            // sur une erreur de la base qui persiste
+            let refetch: Vec<TxKey> = {
+                let mut state = node.state.lock().await;
+                failed
+                    .into_iter()
+                    .filter(|key| state.refetched.insert(key.clone()))
+                    .collect()
+            };
+            if !refetch.is_empty() {
+                log::info!("Asking {} again for {} transactions", from, refetch.len());
+                for batch in refetch.chunks(BATCH_SIZE) {
+                    send(node, from, AntiEntropyPayload::Fetch(batch.to_vec())).await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sends our transactions with the given keys, in batches
+async fn send_transactions(
+    node: &crate::node::Node,
+    to: std::net::SocketAddr,
+    keys: &[TxKey],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut txs = Vec::new();
+    for (site, lamport) in keys {
+        match crate::db::get_transaction(&node.db, *lamport, site)? {
+            Some(tx) => txs.push(crate::snapshot::TxSummary::from(&tx)),
+            None => log::warn!("Transaction {}-{} asked for but not found", site, lamport),
+        }
+    }
+    for batch in txs.chunks(BATCH_SIZE) {
+        send(node, to, AntiEntropyPayload::Transactions(batch.to_vec())).await?;
+    }
+    Ok(())
+}
+
+async fn send(
+    node: &crate::node::Node,
+    to: std::net::SocketAddr,
+    payload: AntiEntropyPayload,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (site_addr, site_id, clock) = {
+        let state = node.state.lock().await;
+        (
+            state.get_site_addr(),
+            state.get_site_id(),
+            state.get_clock(),
+        )
+    };
+    crate::network::send_message(
+        node,
+        to,
+        crate::message::MessageInfo::AntiEntropy(payload),
+        crate::message::NetworkMessageCode::AntiEntropy,
+        site_addr,
+        &site_id,
+        crate::message::Origin::local(&site_id, site_addr),
+        clock,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(node: &str, times: std::ops::Range<i64>) -> Vec<TxKey> {
+        times.map(|t| (node.to_string(), t)).collect()
+    }
+
+    /// Runs the protocol between two trees, returns the keys each one sent
+    fn exchange(a: &mut MerkleTree, b: &mut MerkleTree) -> (Vec<TxKey>, Vec<TxKey>, usize) {
+        let mut sent = (Vec::new(), Vec::new());
+        let mut messages = 0;
+        let mut inbox = vec![(
+            true,
+            AntiEntropyPayload::Compare(vec![(Range::ROOT, a.hash(Range::ROOT))]),
+        )];
+        while let Some((to_b, payload)) = inbox.pop() {
+            messages += 1;
+            let (tree, tree_sent) = if to_b {
+                (&mut *b, &mut sent.1)
+            } else {
+                (&mut *a, &mut sent.0)
+            };
+            match payload {
+                AntiEntropyPayload::Compare(theirs) => {
+                    let c = tree.compare(&theirs);
+                    if !c.descend.is_empty() {
+                        inbox.push((!to_b, AntiEntropyPayload::Compare(c.descend)));
+                    }
+                    if !c.keys.is_empty() {
+                        inbox.push((!to_b, AntiEntropyPayload::Keys(c.keys)));
+                    }
+                    tree_sent.extend(c.push);
+                }
+                AntiEntropyPayload::Keys(theirs) => {
+                    let (they_lack, we_lack) = tree.reconcile(&theirs);
+                    tree_sent.extend(they_lack);
+                    if !we_lack.is_empty() {
+                        inbox.push((!to_b, AntiEntropyPayload::Fetch(we_lack)));
+                    }
+                }
+                AntiEntropyPayload::Fetch(keys) => tree_sent.extend(keys),
+                AntiEntropyPayload::Transactions(_) => unreachable!(),
+            }
+        }
+        let (mut from_a, mut from_b) = sent;
+        from_a.sort();
+        from_b.sort();
+        (from_a, from_b, messages)
+    }
+
+    #[test]
+    fn test_hash_does_not_depend_on_insertion_order() {
+        let mut a = MerkleTree::from_keys(keys("A", 0..50));
+        let mut b = MerkleTree::from_keys(keys("A", 0..50).into_iter().rev());
+        assert_eq!(a.hash(Range::ROOT), b.hash(Range::ROOT));
+        assert_eq!(a.len(), 50);
+        assert!(!b.insert(("A".to_string(), 3)));
+
+        b.insert(("B".to_string(), 1));
+        assert_ne!(a.hash(Range::ROOT), b.hash(Range::ROOT));
+        assert_eq!(MerkleTree::new().hash(Range::ROOT), EMPTY);
+    }
+
+    #[test]
+    fn test_identical_trees_exchange_nothing() {
+        let mut a = MerkleTree::from_keys(keys("A", 0..100));
+        let mut b = MerkleTree::from_keys(keys("A", 0..100));
+        assert_eq!(exchange(&mut a, &mut b), (vec![], vec![], 1));
+    }
+
+    #[test]
+    fn test_only_missing_transactions_are_sent() {
+        let mut a = MerkleTree::from_keys(keys("A", 0..1000));
+        let mut b = MerkleTree::from_keys(keys("A", 0..1000));
+        a.insert(("A".to_string(), 1000));
+        b.insert(("B".to_string(), 7));
+        b.insert(("B".to_string(), 8));
+
+        let (from_a, from_b, messages) = exchange(&mut a, &mut b);
+        assert_eq!(from_a, vec![("A".to_string(), 1000)]);
+        assert_eq!(from_b, keys("B", 7..9));
+        // on ne descend que dans les branches qui diffèrent
+        assert!(messages <= 2 * (DEPTH as usize + 2));
+    }
+
+    #[test]
+    fn test_empty_site_gets_everything_at_once() {
+        let mut a = MerkleTree::new();
+        let mut b = MerkleTree::from_keys(keys("B", 0..300));
+        let (from_a, from_b, messages) = exchange(&mut a, &mut b);
+        assert!(from_a.is_empty());
+        assert_eq!(from_b, keys("B", 0..300));
+        assert_eq!(messages, 1);
+
+        // and the other way around
+        let (from_b, from_a, messages) = exchange(&mut b, &mut a);
+        assert!(from_a.is_empty());
+        assert_eq!(from_b, keys("B", 0..300));
+        assert_eq!(messages, 2);
+    }
+}
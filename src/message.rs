@@ -46,6 +46,69 @@ pub enum NetworkMessageCode {
     AckGlobalMutex,
     /// Acknowledgment of the global mutex acquisition
     AckReleaseGlobalMutex,
+    /// Periodic sign of life sent to the neighbours
+    Heartbeat,
+    /// Message of a mutual exclusion algorithm sent to a single site
+    Mutex,
+    /// Message of the anti-entropy protocol sent to a neighbour
+    AntiEntropy,
+    /// Message of the catch-up of a restarting site
+    CatchUp,
+    /// Marker of a Chandy–Lamport snapshot, sent on every channel
+    SnapshotMarker,
+    /// Local snapshot of a site sent to the initiator of a Chandy–Lamport snapshot
+    SnapshotRecorded,
+    /// Request for the public key of a site that is not a neighbour
+    KeyRequest,
+    /// Public key of a site, sent directly to the site that asked for it
+    KeyAnnouncement,
+}
+
+#[cfg(feature = "server")]
+/// Identifier of a diffusion wave
+///
+/// An initiator numbers its waves, so the waves it runs at the same time, a
+/// mutex request and a transaction for instance, keep separate parents and
+/// counters on every site.
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
+pub struct WaveId {
+    /// ID of the node that initiated the wave
+    pub initiator_id: String,
+    /// Sequence number of the wave for this initiator
+    pub seq: u64,
+}
+
+#[cfg(feature = "server")]
+impl std::fmt::Display for WaveId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}#{}", self.initiator_id, self.seq)
+    }
+}
+
+#[cfg(feature = "server")]
+/// Site that initiated a message, and the wave carrying it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Origin {
+    /// Wave carrying the message, numbered 0 outside of waves
+    pub wave: WaveId,
+    /// Network address of the initiator
+    pub initiator_addr: std::net::SocketAddr,
+}
+
+#[cfg(feature = "server")]
+impl Origin {
+    /// Message initiated by the site itself, outside of any wave
+    pub fn local(site_id: &str, site_addr: std::net::SocketAddr) -> Self {
+        Self {
+            wave: WaveId {
+                initiator_id: site_id.to_string(),
+                seq: 0,
+            },
+            initiator_addr: site_addr,
+        }
+    }
 }
 
 #[cfg(feature = "server")]
@@ -58,6 +121,8 @@ pub struct Message {
     pub message_initiator_id: String,
     /// Network address of the node that initiated the message
     pub message_initiator_addr: std::net::SocketAddr,
+    /// Sequence number of the wave carrying the message, 0 outside of waves
+    pub wave_seq: u64,
     /// Network address of the sending node
     pub sender_addr: std::net::SocketAddr,
     /// Logical clock state of the sending node
@@ -68,6 +133,46 @@ pub struct Message {
     pub info: MessageInfo,
     /// Type of the message
     pub code: NetworkMessageCode,
+    /// Transactions delivered by the initiator of a transaction, for its causal delivery
+    #[serde(default)]
+    pub deps: Option<crate::causal::Dependencies>,
+    /// Signature by the initiator of what its wave diffuses, relayed unchanged
+    #[serde(default)]
+    pub initiator_signature: Option<Vec<u8>>,
+}
+
+#[cfg(feature = "server")]
+impl Message {
+    /// Returns the identifier of the wave carrying the message
+    pub fn wave_id(&self) -> WaveId {
+        WaveId {
+            initiator_id: self.message_initiator_id.clone(),
+            seq: self.wave_seq,
+        }
+    }
+
+    /// Initiator and wave of the message, to answer it within the same wave
+    pub fn origin(&self) -> Origin {
+        Origin {
+            wave: self.wave_id(),
+            initiator_addr: self.message_initiator_addr,
+        }
+    }
+
+    /// Bytes signed by the initiator of a wave
+    ///
+    /// Only what the initiator diffuses is covered, not the fields the sites
+    /// relaying the wave rewrite.
+    pub fn initiator_payload(&self) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+        rmp_serde::encode::to_vec(&(
+            &self.message_initiator_id,
+            self.wave_seq,
+            &self.code,
+            &self.command,
+            &self.info,
+            &self.deps,
+        ))
+    }
 }
 
 #[cfg(feature = "server")]
@@ -96,6 +201,14 @@ pub enum MessageInfo {
     ReleaseMutex(ReleaseMutexPayload),
     /// Acknowledge a critical section
     AckMutex(AckMutexPayload),
+    /// Membership view of the sender
+    Membership(MembershipPayload),
+    /// Message of the mutual exclusion algorithm
+    Mutex(crate::mutex::MutexPayload),
+    /// Message of the anti-entropy protocol
+    AntiEntropy(crate::merkle::AntiEntropyPayload),
+    /// Message of the catch-up of a restarting site
+    CatchUp(crate::catch_up::CatchUpPayload),
     /// No payload
     None,
 }
@@ -106,12 +219,25 @@ pub enum MessageInfo {
 pub struct AcknowledgePayload {
     /// Logical clock state of the acknowledging node
     pub global_fifo: std::collections::HashMap<String, crate::state::MutexStamp>,
+    /// Membership view of the acknowledging node
+    pub members: Vec<crate::membership::MemberUpdate>,
+}
+
+#[cfg(feature = "server")]
+/// Payload gossiping the membership view
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct MembershipPayload {
+    /// Every member known by the sender, the sender included
+    pub members: Vec<crate::membership::MemberUpdate>,
 }
 
 #[cfg(feature = "server")]
 /// Payload for the AcquireMutex message
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
-pub struct AcquireMutexPayload;
+pub struct AcquireMutexPayload {
+    /// Accounts the requesting site wants to lock
+    pub scope: crate::state::LockScope,
+}
 
 #[cfg(feature = "server")]
 /// Payload for the ReleaseMutex message
@@ -137,6 +263,12 @@ pub struct SnapshotResponse {
     pub clock: crate::clock::Clock,
     /// Transaction log summary
     pub tx_log: Vec<crate::snapshot::TxSummary>,
+    /// State of the channels towards the responding node, in Chandy–Lamport mode
+    #[serde(default)]
+    pub channels: Vec<crate::snapshot::ChannelState>,
+    /// Stored balance of every user in cents, unless the log is the one of a subtree
+    #[serde(default)]
+    pub balances: Option<std::collections::BTreeMap<String, i64>>,
 }
 
 #[cfg(feature = "server")]
@@ -162,13 +294,13 @@ pub struct Deposit {
     /// Name of the account
     pub name: String,
     /// Amount to deposit
-    pub amount: f64,
+    pub amount: crate::money::Money,
 }
 
 #[cfg(feature = "server")]
 impl Deposit {
     /// Creates a new Deposit request
-    pub fn new(name: String, amount: f64) -> Self {
+    pub fn new(name: String, amount: crate::money::Money) -> Self {
         Self { name, amount }
     }
 }
@@ -180,13 +312,13 @@ pub struct Withdraw {
     /// Name of the account
     pub name: String,
     /// Amount to withdraw
-    pub amount: f64,
+    pub amount: crate::money::Money,
 }
 
 #[cfg(feature = "server")]
 impl Withdraw {
     /// Creates a new Withdraw request
-    pub fn new(name: String, amount: f64) -> Self {
+    pub fn new(name: String, amount: crate::money::Money) -> Self {
         Self { name, amount }
     }
 }
@@ -200,13 +332,13 @@ pub struct Transfer {
     /// Name of the destination account
     pub beneficiary: String,
     /// Amount to transfer
-    pub amount: f64,
+    pub amount: crate::money::Money,
 }
 
 #[cfg(feature = "server")]
 impl Transfer {
     /// Creates a new Transfer request
-    pub fn new(name: String, beneficiary: String, amount: f64) -> Self {
+    pub fn new(name: String, beneficiary: String, amount: crate::money::Money) -> Self {
         Self {
             name,
             beneficiary,
@@ -222,13 +354,13 @@ pub struct Pay {
     /// Name of the account
     pub name: String,
     /// Amount to pay
-    pub amount: f64,
+    pub amount: crate::money::Money,
 }
 
 #[cfg(feature = "server")]
 impl Pay {
     /// Creates a new Pay request
-    pub fn new(name: String, amount: f64) -> Self {
+    pub fn new(name: String, amount: crate::money::Money) -> Self {
         Self { name, amount }
     }
 }
@@ -285,11 +417,71 @@ mod tests {
             sender_addr: "127.0.0.1:8080".parse().unwrap(),
             message_initiator_id: "A".to_string(),
             message_initiator_addr: "127.0.0.1:8080".parse().unwrap(),
+            wave_seq: 0,
             clock: clock,
             command: None,
             info: MessageInfo::None,
             code: NetworkMessageCode::Transaction,
+            deps: None,
+            initiator_signature: None,
         };
         assert!(format!("{:?}", message).contains("Message { sender_id: \"A\""));
     }
+
+    #[test]
+    fn test_wave_id_of_message() {
+        let mut message = Message {
+            sender_id: "B".to_string(),
+            sender_addr: "127.0.0.1:8081".parse().unwrap(),
+            message_initiator_id: "A".to_string(),
+            message_initiator_addr: "127.0.0.1:8080".parse().unwrap(),
+            wave_seq: 3,
+            clock: crate::clock::Clock::new(),
+            command: None,
+            info: MessageInfo::None,
+            code: NetworkMessageCode::AcquireMutex,
+            deps: None,
+            initiator_signature: None,
+        };
+        let first = message.wave_id();
+        assert_eq!(first.to_string(), "A#3");
+
+        // Another wave of the same initiator is another key
+        message.wave_seq = 4;
+        assert_ne!(message.wave_id(), first);
+    }
+
+    #[test]
+    fn test_initiator_payload_ignores_the_relays() {
+        let message = Message {
+            sender_id: "A".to_string(),
+            sender_addr: "127.0.0.1:8080".parse().unwrap(),
+            message_initiator_id: "A".to_string(),
+            message_initiator_addr: "127.0.0.1:8080".parse().unwrap(),
+            wave_seq: 1,
+            clock: crate::clock::Clock::new(),
+            command: Some(crate::control::Command::Deposit),
+            info: MessageInfo::Deposit(Deposit::new(
+                "alice".to_string(),
+                crate::money::Money::from_cents(1000),
+            )),
+            code: NetworkMessageCode::Transaction,
+            deps: None,
+            initiator_signature: None,
+        };
+        let payload = message.initiator_payload().unwrap();
+
+        let mut relayed = message.clone();
+        relayed.sender_id = "B".to_string();
+        relayed.sender_addr = "127.0.0.1:8081".parse().unwrap();
+        relayed.clock = crate::clock::Clock::new_with_values(5, Default::default());
+        assert_eq!(relayed.initiator_payload().unwrap(), payload);
+
+        let mut tampered = message.clone();
+        tampered.info = MessageInfo::Deposit(Deposit::new(
+            "alice".to_string(),
+            crate::money::Money::from_cents(99_000),
+        ));
+        assert_ne!(tampered.initiator_payload().unwrap(), payload);
+    }
 }
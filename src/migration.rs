@@ -0,0 +1,354 @@
+//! Versioned migrations of the database schema
+//!
+//! The version of the schema is recorded in the `schema_version` table, one
+//! row per migration applied. At startup, the migrations above this version
+//! are applied in order, each in its own transaction, so that a database
+//! written by an older version of Peillute is brought up to date instead of
+//! breaking on a missing column. Migrations only go forward: a new column or
+//! table is a new migration appended to [`MIGRATIONS`], never a change to one
+//! that was released.
+//!
+//! The databases created before this table have no version. The first
+//! migrations tolerate the changes these databases already went through, so
+//! all of them are applied from version 0.
+
+#![cfg(feature = "server")]
+
+/// Change of the database schema
+pub struct Migration {
+    /// Version of the schema once the migration is applied
+    pub version: u32,
+    /// What the migration changes
+    pub description: &'static str,
+    /// Applies the migration, inside a transaction
+    apply: fn(&rusqlite::Connection) -> rusqlite::Result<()>,
+}
+
+/// Migrations of the schema, in order
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create the vector clocks, users, transactions and local state tables",
+        apply: initial_schema,
+    },
+    Migration {
+        version: 2,
+        description: "Add the hybrid logical clock of the transactions",
+        apply: hybrid_logical_clocks,
+    },
+    Migration {
+        version: 3,
+        description: "Add the signing key of the site",
+        apply: signing_key,
+    },
+    Migration {
+        version: 4,
+        description: "Store the amounts and balances in cents",
+        apply: amounts_in_cents,
+    },
+    Migration {
+        version: 5,
+        description: "Add the causal delivery counters",
+        apply: causal_counters,
+    },
+    Migration {
+        version: 6,
+        description: "Add the public keys pinned for the other sites",
+        apply: pinned_keys,
+    },
+];
+
+/// Version of the schema of the database, 0 if no migration was applied
+pub fn current_version(conn: &rusqlite::Connection) -> rusqlite::Result<u32> {
+    let versioned: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !versioned {
+        return Ok(0);
+    }
+    conn.query_row(
+        "SELECT IFNULL(MAX(version), 0) FROM schema_version",
+        [],
+        |row| row.get(0),
+    )
+}
+
+/// Migrations not applied to the database yet, in order
+pub fn pending(conn: &rusqlite::Connection) -> rusqlite::Result<Vec<&'static Migration>> {
+    let version = current_version(conn)?;
+    let latest = MIGRATIONS.last().map_or(0, |m| m.version);
+    if version > latest {
+        let err = rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::ErrorCode::Unknown as i32),
+            Some(format!(
+                "Database schema version {} is newer than the latest known version {}",
+                version, latest
+            )),
+        );
+        log::error!("{}", err);
+        return Err(err);
+    }
+    Ok(MIGRATIONS.iter().filter(|m| m.version > version).collect())
+}
+
+/// Applies the pending migrations, and returns them
+pub fn migrate(conn: &rusqlite::Connection) -> rusqlite::Result<Vec<&'static Migration>> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    let pending = pending(conn)?;
+    for migration in &pending {
+        log::info!(
+            "Migrating the database to version {}: {}",
+            migration.version,
+            migration.description
+        );
+        let tx = conn.unchecked_transaction()?;
+        (migration.apply)(&tx)?;
+        tx.execute(
+            "INSERT INTO schema_version (version, description, applied_at)
+            VALUES (?1, ?2, datetime('now'))",
+            rusqlite::params![migration.version, migration.description],
+        )?;
+        tx.commit()?;
+    }
+    Ok(pending)
+}
+
+/// Version 1: the schema before the migrations
+fn initial_schema(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS VectorClock (
+            id INTEGER PRIMARY KEY AUTOINCREMENT
+        );
+        CREATE TABLE IF NOT EXISTS VectorClockEntry (
+            vector_clock_id INTEGER,
+            site_id TEXT,
+            value INTEGER NOT NULL,
+            PRIMARY KEY(vector_clock_id, site_id),
+            FOREIGN KEY(vector_clock_id) REFERENCES VectorClock(id) ON DELETE CASCADE
+        );
+        CREATE TABLE IF NOT EXISTS User (
+            unique_name TEXT PRIMARY KEY,
+            solde FLOAT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS Transactions (
+            from_user TEXT,
+            to_user TEXT NOT NULL,
+            amount FLOAT NOT NULL,
+            lamport_time INTEGER NOT NULL,
+            vector_clock_id INTEGER NOT NULL,
+            source_node TEXT NOT NULL,
+            optional_msg TEXT,
+            FOREIGN KEY(from_user) REFERENCES User(unique_name),
+            FOREIGN KEY(to_user) REFERENCES User(unique_name),
+            FOREIGN KEY(vector_clock_id) REFERENCES VectorClock(id),
+            PRIMARY KEY(lamport_time, source_node)
+        );
+        CREATE TABLE IF NOT EXISTS LocalState (
+            site_id TEXT PRIMARY KEY,
+            lamport_time INTEGER NOT NULL,
+            vector_clock_id INTEGER NOT NULL,
+            FOREIGN KEY(vector_clock_id) REFERENCES VectorClock(id)
+        );",
+    )
+}
+
+/// Version 2: hybrid logical clock of the transactions
+fn hybrid_logical_clocks(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    add_missing_column(
+        conn,
+        "Transactions",
+        "hlc_physical",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    add_missing_column(
+        conn,
+        "Transactions",
+        "hlc_logical",
+        "INTEGER NOT NULL DEFAULT 0",
+    )
+}
+
+/// Version 3: signing key of the site
+fn signing_key(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    add_missing_column(conn, "LocalState", "signing_key", "BLOB")
+}
+
+/// Version 4: amounts and balances in cents instead of floats
+///
+/// SQLite cannot change the type of a column: the tables are copied to new
+/// ones, which replace them.
+fn amounts_in_cents(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    // bases déjà converties avant la table des versions
+    let in_float: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM pragma_table_info('Transactions')
+            WHERE name = 'amount' AND type = 'FLOAT')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !in_float {
+        return Ok(());
+    }
+
+    conn.execute_batch(
+        "CREATE TABLE UserInCents (
+            unique_name TEXT PRIMARY KEY,
+            solde INTEGER NOT NULL
+        );
+        INSERT INTO UserInCents (unique_name, solde)
+            SELECT unique_name, CAST(ROUND(solde * 100) AS INTEGER) FROM User;
+        CREATE TABLE TransactionsInCents (
+            from_user TEXT,
+            to_user TEXT NOT NULL,
+            amount INTEGER NOT NULL,
+            lamport_time INTEGER NOT NULL,
+            vector_clock_id INTEGER NOT NULL,
+            source_node TEXT NOT NULL,
+            optional_msg TEXT,
+            hlc_physical INTEGER NOT NULL DEFAULT 0,
+            hlc_logical INTEGER NOT NULL DEFAULT 0,
+            FOREIGN KEY(from_user) REFERENCES User(unique_name),
+            FOREIGN KEY(to_user) REFERENCES User(unique_name),
+            FOREIGN KEY(vector_clock_id) REFERENCES VectorClock(id),
+            PRIMARY KEY(lamport_time, source_node)
+        );
+        INSERT INTO TransactionsInCents
+            SELECT from_user, to_user, CAST(ROUND(amount * 100) AS INTEGER), lamport_time,
+                vector_clock_id, source_node, optional_msg, hlc_physical, hlc_logical
+            FROM Transactions;
+        DROP TABLE Transactions;
+        DROP TABLE User;
+        ALTER TABLE UserInCents RENAME TO User;
+        ALTER TABLE TransactionsInCents RENAME TO Transactions;",
+    )
+}
+
+/// Version 5: number of transactions of each site delivered in causal order
+fn causal_counters(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS CausalDelivered (
+            site_id TEXT PRIMARY KEY,
+            delivered INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Version 6: public keys pinned for the other sites
+fn pinned_keys(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS PinnedKey (
+            site_id TEXT PRIMARY KEY,
+            public_key BLOB NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Adds a column to a table, unless a database without version already has it
+fn add_missing_column(
+    conn: &rusqlite::Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> rusqlite::Result<()> {
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2)",
+        rusqlite::params![table, column],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns(conn: &rusqlite::Connection, table: &str) -> Vec<(String, String)> {
+        let mut stmt = conn
+            .prepare("SELECT name, type FROM pragma_table_info(?1)")
+            .unwrap();
+        stmt.query_map([table], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_migrations_are_ordered() {
+        for (position, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version as usize, position + 1);
+        }
+    }
+
+    #[test]
+    fn test_new_database_is_migrated_once() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        assert_eq!(current_version(&conn).unwrap(), 0);
+        assert_eq!(pending(&conn).unwrap().len(), MIGRATIONS.len());
+
+        assert_eq!(migrate(&conn).unwrap().len(), MIGRATIONS.len());
+        assert_eq!(current_version(&conn).unwrap(), 6);
+        assert!(pending(&conn).unwrap().is_empty());
+        assert!(migrate(&conn).unwrap().is_empty());
+
+        assert!(columns(&conn, "Transactions").contains(&("amount".into(), "INTEGER".into())));
+        assert!(columns(&conn, "LocalState").contains(&("signing_key".into(), "BLOB".into())));
+    }
+
+    #[test]
+    fn test_database_without_version_is_migrated() {
+        // base créée avant les horloges hybrides, avec des montants en flottants
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        initial_schema(&conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO User VALUES ('alice', 0.29);
+            INSERT INTO Transactions VALUES ('NULL', 'alice', 0.29, 1, 1, 'A', 'Deposit');",
+        )
+        .unwrap();
+
+        let applied: Vec<u32> = migrate(&conn).unwrap().iter().map(|m| m.version).collect();
+        assert_eq!(applied, vec![1, 2, 3, 4, 5, 6]);
+
+        let (solde, amount, hlc): (crate::money::Money, crate::money::Money, i64) = conn
+            .query_row(
+                "SELECT solde, amount, hlc_physical FROM User, Transactions",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(solde, crate::money::Money::from_cents(29));
+        assert_eq!(amount, crate::money::Money::from_cents(29));
+        assert_eq!(hlc, 0);
+    }
+
+    #[test]
+    fn test_newer_database_is_rejected() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        conn.execute(
+            "INSERT INTO schema_version VALUES (99, 'From the future', datetime('now'))",
+            [],
+        )
+        .unwrap();
+        assert!(pending(&conn).is_err());
+        assert!(migrate(&conn).is_err());
+    }
+}
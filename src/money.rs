@@ -0,0 +1,224 @@
+//! Amounts of money
+//!
+//! A float cannot hold most amounts of cents exactly: added up, converted to
+//! cents for a snapshot or summed by SQLite, the amounts drift apart by a
+//! cent. [`Money`] stores an integer number of cents instead, from the forms
+//! and the CLI down to the database. The amounts typed by the users are
+//! parsed from their decimal text, without going through a float, and the
+//! ones that are not a number, are infinite or hold a fraction of a cent are
+//! rejected there. Amounts are only added and multiplied with checked
+//! arithmetic, so that a crafted amount gives an error instead of a panic or a
+//! wrapped total.
+
+/// Amount of money, in cents
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(transparent)]
+pub struct Money(i64);
+
+impl Money {
+    /// No money
+    pub const ZERO: Money = Money(0);
+
+    /// Amount of the given number of cents
+    pub const fn from_cents(cents: i64) -> Self {
+        Money(cents)
+    }
+
+    /// Number of cents of the amount
+    pub fn cents(self) -> i64 {
+        self.0
+    }
+
+    /// Whether the amount is below zero
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    /// Whether the amount is above zero
+    pub fn is_positive(self) -> bool {
+        self.0 > 0
+    }
+
+    /// Sum of two amounts, or an error if it does not fit in the ledger
+    pub fn checked_add(self, other: Money) -> Result<Money, MoneyError> {
+        self.0
+            .checked_add(other.0)
+            .map(Money)
+            .ok_or_else(|| MoneyError::Overflow(format!("{} + {}", self, other)))
+    }
+
+    /// Amount times a quantity, or an error if it does not fit in the ledger
+    pub fn checked_mul(self, quantity: u32) -> Result<Money, MoneyError> {
+        self.0
+            .checked_mul(quantity as i64)
+            .map(Money)
+            .ok_or_else(|| MoneyError::Overflow(format!("{} * {}", self, quantity)))
+    }
+
+    /// Sum of amounts, or an error if it does not fit in the ledger
+    pub fn total<I: IntoIterator<Item = Money>>(amounts: I) -> Result<Money, MoneyError> {
+        amounts
+            .into_iter()
+            .try_fold(Money::ZERO, |total, amount| total.checked_add(amount))
+    }
+}
+
+impl std::fmt::Display for Money {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let cents = self.0.unsigned_abs();
+        f.pad(&format!("{}{}.{:02}", sign, cents / 100, cents % 100))
+    }
+}
+
+/// Reason an amount was rejected
+#[derive(Debug, Clone, PartialEq)]
+pub enum MoneyError {
+    /// The amount is not a number
+    NotANumber(String),
+    /// The amount is infinite
+    Infinite,
+    /// The amount holds a fraction of a cent
+    SubCent(String),
+    /// The amount does not fit in the ledger
+    Overflow(String),
+}
+
+impl std::fmt::Display for MoneyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MoneyError::NotANumber(input) => write!(f, "'{}' is not an amount", input),
+            MoneyError::Infinite => write!(f, "an amount cannot be infinite"),
+            MoneyError::SubCent(input) => {
+                write!(f, "'{}' holds a fraction of a cent", input)
+            }
+            MoneyError::Overflow(input) => write!(f, "'{}' is too large", input),
+        }
+    }
+}
+
+impl std::error::Error for MoneyError {}
+
+impl std::str::FromStr for Money {
+    type Err = MoneyError;
+
+    /// Parses a decimal amount such as `12`, `-3.5` or `0.07`
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let text = input.trim();
+        let (negative, unsigned) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text.strip_prefix('+').unwrap_or(text)),
+        };
+        if ["inf", "infinity"].contains(&unsigned.to_ascii_lowercase().as_str()) {
+            return Err(MoneyError::Infinite);
+        }
+
+        let (units, decimals) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+        let is_number = |digits: &str| digits.chars().all(|c| c.is_ascii_digit());
+        if (units.is_empty() && decimals.is_empty()) || !is_number(units) || !is_number(decimals) {
+            return Err(MoneyError::NotANumber(input.to_string()));
+        }
+        let decimals = decimals.trim_end_matches('0');
+        if decimals.len() > 2 {
+            return Err(MoneyError::SubCent(input.to_string()));
+        }
+
+        let overflow = || MoneyError::Overflow(input.to_string());
+        let units: i64 = match units {
+            "" => 0,
+            units => units.parse().map_err(|_| overflow())?,
+        };
+        let fraction: i64 = format!("{:0<2}", decimals).parse().unwrap_or(0);
+        let cents = units
+            .checked_mul(100)
+            .and_then(|cents| cents.checked_add(fraction))
+            .ok_or_else(overflow)?;
+        Ok(Money(if negative { -cents } else { cents }))
+    }
+}
+
+#[cfg(feature = "server")]
+impl rusqlite::types::ToSql for Money {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        Ok(self.0.into())
+    }
+}
+
+#[cfg(feature = "server")]
+impl rusqlite::types::FromSql for Money {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        i64::column_result(value).map(Money)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Result<Money, MoneyError> {
+        input.parse()
+    }
+
+    #[test]
+    fn test_amounts_are_parsed_exactly() {
+        assert_eq!(parse("12"), Ok(Money::from_cents(1200)));
+        assert_eq!(parse(" 0.29 "), Ok(Money::from_cents(29)));
+        assert_eq!(parse("-3.5"), Ok(Money::from_cents(-350)));
+        assert_eq!(parse(".07"), Ok(Money::from_cents(7)));
+        assert_eq!(parse("+1.230"), Ok(Money::from_cents(123)));
+        assert_eq!(parse("4."), Ok(Money::from_cents(400)));
+    }
+
+    #[test]
+    fn test_invalid_amounts_are_rejected() {
+        assert!(matches!(parse("NaN"), Err(MoneyError::NotANumber(_))));
+        assert!(matches!(parse(""), Err(MoneyError::NotANumber(_))));
+        assert!(matches!(parse("1e3"), Err(MoneyError::NotANumber(_))));
+        assert!(matches!(parse("1.2.3"), Err(MoneyError::NotANumber(_))));
+        assert_eq!(parse("inf"), Err(MoneyError::Infinite));
+        assert_eq!(parse("-Infinity"), Err(MoneyError::Infinite));
+        assert!(matches!(parse("0.001"), Err(MoneyError::SubCent(_))));
+        assert!(matches!(
+            parse("99999999999999999999"),
+            Err(MoneyError::Overflow(_))
+        ));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Money::from_cents(1205).to_string(), "12.05");
+        assert_eq!(Money::from_cents(-7).to_string(), "-0.07");
+        assert_eq!(Money::ZERO.to_string(), "0.00");
+    }
+
+    #[test]
+    fn test_arithmetic_is_checked() {
+        let three = Money::from_cents(120).checked_mul(3).unwrap();
+        assert_eq!(
+            Money::total([Money::from_cents(150), three]),
+            Ok(Money::from_cents(510))
+        );
+        let max = Money::from_cents(i64::MAX);
+        assert!(matches!(
+            max.checked_add(Money::from_cents(1)),
+            Err(MoneyError::Overflow(_))
+        ));
+        assert!(matches!(max.checked_mul(2), Err(MoneyError::Overflow(_))));
+        assert!(matches!(
+            Money::total([max, max]),
+            Err(MoneyError::Overflow(_))
+        ));
+    }
+}
@@ -0,0 +1,1222 @@
+//! Mutual exclusion algorithms guarding the critical section
+//!
+//! The critical commands of a site run while it holds the distributed mutex.
+//! Three algorithms implement [`MutualExclusion`], the one a site runs is
+//! chosen at launch with `--cli-mutex`, and every site of a network must run
+//! the same one:
+//!
+//! - [`WaveQueue`], the default: the request and the release of a site are
+//!   diffused by echo waves and every site keeps the queue of the requests.
+//!   A site enters once its request has reached the whole network and no older
+//!   request on the same accounts is queued. It works on any topology.
+//! - [`RicartAgrawala`]: a site asks every other site for its permission and
+//!   enters once all of them replied; a site delays its reply while it holds,
+//!   or asks first for, the same accounts.
+//! - [`SuzukiKasami`]: a single token moves between the sites, a site asks
+//!   every other site for it and enters when it receives it.
+//!
+//! The two last ones send their messages directly to every alive member of
+//! the membership view, opening a connection to the ones that are not
+//! neighbours, so they do not depend on the topology given with `--cli-peers`.
+//! The token of Suzuki–Kasami locks every account.
+//!
+//! The algorithms are state machines: a step returns the [`MutexEffect`]s the
+//! site must apply, the messages to send, and [`crate::state::AppState`]
+//! applies them. They can be compared on the same topologies with the message
+//! counts and waiting times of [`MutexStats`].
+
+#![cfg(feature = "server")]
+
+use crate::state::{LockScope, MutexStamp, MutexTag};
+
+/// Algorithm run by the sites to share the critical section
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MutexAlgorithm {
+    /// Queue of the requests diffused by echo waves
+    Wave,
+    /// Permissions of every other site, Ricart–Agrawala
+    RicartAgrawala,
+    /// Token moving between the sites, Suzuki–Kasami
+    SuzukiKasami,
+}
+
+impl MutexAlgorithm {
+    /// Creates the state of the algorithm for a site
+    pub fn build(self) -> Box<dyn MutualExclusion> {
+        match self {
+            MutexAlgorithm::Wave => Box::new(WaveQueue::new()),
+            MutexAlgorithm::RicartAgrawala => Box::new(RicartAgrawala::new()),
+            MutexAlgorithm::SuzukiKasami => Box::new(SuzukiKasami::new()),
+        }
+    }
+}
+
+/// Where a site stands regarding the critical section
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MutexStatus {
+    /// Neither asked for nor holding the critical section
+    Idle,
+    /// Asked for the critical section, not granted yet
+    Waiting,
+    /// In the critical section
+    Held,
+}
+
+/// What a site knows of the network when running a step of the algorithm
+pub struct MutexContext {
+    /// ID of the local site
+    pub site_id: String,
+    /// Lamport time of the local site
+    pub lamport: i64,
+    /// IDs of the other sites taking part in the algorithm, the alive members
+    /// of the membership view whether they are neighbours or not
+    pub peers: Vec<String>,
+    /// IDs of the sites alive in the membership view, ours included
+    pub members: Vec<String>,
+}
+
+/// Messages exchanged by the algorithms
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub enum MutexPayload {
+    /// Request of a site, dated with its Lamport time or its request number
+    Request { date: i64, scope: LockScope },
+    /// Release of a site
+    Release,
+    /// Permission given by a site
+    Reply,
+    /// Token of Suzuki–Kasami
+    Token(Token),
+    /// Question of the site in charge of the token to every other site, before regenerating it
+    Probe { generation: u64 },
+    /// Answer to a probe: whether the site holds the token, and its last request served
+    ProbeReply {
+        generation: u64,
+        holds: bool,
+        served: i64,
+    },
+    /// Local event: the wave carrying our request went through every site
+    Announced,
+}
+
+/// Step the site must take for the algorithm
+#[derive(Clone, Debug)]
+pub enum MutexEffect {
+    /// Diffuse our request to the whole network with a wave
+    DiffuseRequest(LockScope),
+    /// Diffuse our release to the whole network with a wave
+    DiffuseRelease,
+    /// Send a message to another site
+    Send { to: String, payload: MutexPayload },
+}
+
+/// Mutual exclusion algorithm run by a site
+pub trait MutualExclusion: Send + Sync {
+    /// Returns the algorithm implemented
+    fn algorithm(&self) -> MutexAlgorithm;
+
+    /// Returns where the site stands regarding the critical section
+    fn status(&self) -> MutexStatus;
+
+    /// Returns the accounts locked by the site, while it holds the critical section
+    fn held_scope(&self) -> Option<&LockScope>;
+
+    /// Asks for the critical section on the given accounts
+    fn request(&mut self, ctx: &MutexContext, scope: LockScope) -> Vec<MutexEffect>;
+
+    /// Leaves the critical section, or withdraws the pending request
+    fn release(&mut self, ctx: &MutexContext) -> Vec<MutexEffect>;
+
+    /// Handles a message of the algorithm sent by another site
+    fn receive(
+        &mut self,
+        ctx: &MutexContext,
+        from: &str,
+        payload: MutexPayload,
+    ) -> Vec<MutexEffect>;
+
+    /// Forgets a site that failed or left the network
+    ///
+    /// Returns true if the site was taking part in the algorithm.
+    fn forget_site(&mut self, ctx: &MutexContext, site_id: &str) -> bool;
+
+    /// Carries on once departed sites were forgotten, with what they held recovered
+    fn resume(&mut self, _ctx: &MutexContext) -> Vec<MutexEffect> {
+        Vec::new()
+    }
+
+    /// Returns the queue of the requests known by the site, sent to the sites joining
+    fn queue(&self) -> std::collections::HashMap<String, MutexStamp> {
+        std::collections::HashMap::new()
+    }
+
+    /// Adopts the queue of the requests sent by a neighbour when joining
+    fn adopt_queue(&mut self, _queue: std::collections::HashMap<String, MutexStamp>) {}
+}
+
+/// Message counts and waiting times of the critical section on a site
+#[derive(Clone, Debug, Default)]
+pub struct MutexStats {
+    /// Number of times the site entered the critical section
+    pub acquisitions: u64,
+    /// Number of messages of the algorithm received by the site
+    pub messages_received: u64,
+    /// Time spent waiting for the critical section
+    pub total_wait: std::time::Duration,
+}
+
+impl MutexStats {
+    /// Average time spent waiting for the critical section
+    pub fn average_wait(&self) -> std::time::Duration {
+        if self.acquisitions == 0 {
+            return std::time::Duration::ZERO;
+        }
+        self.total_wait / self.acquisitions as u32
+    }
+}
+
+/// Queue of the requests diffused by echo waves
+pub struct WaveQueue {
+    /// Last request or release heard from each site, ours included
+    pub fifo: std::collections::HashMap<String, MutexStamp>,
+    /// Waiting for the critical section
+    pub waiting: bool,
+    /// In the critical section
+    pub in_sc: bool,
+    /// The wave carrying our request went through every site
+    pub announced: bool,
+    /// Accounts of our pending request
+    pub scope: Option<LockScope>,
+}
+
+impl WaveQueue {
+    /// Creates an empty queue
+    pub fn new() -> Self {
+        Self {
+            fifo: std::collections::HashMap::new(),
+            waiting: false,
+            in_sc: false,
+            announced: false,
+            scope: None,
+        }
+    }
+
+    /// Enters the critical section if our request is the oldest on its accounts
+    ///
+    /// Must only be called once our request has reached every site, otherwise
+    /// we could enter before a site has queued it.
+    pub fn try_enter_sc(&mut self, site_id: &str) {
+        // Pour respecter l'algo du poly il faut que la vague soit complete
+        // c'est à dire que tout le monde ait répondu ACK pour appeller cette fonction
+        // sinon on va entrer en section critique à un moment sans qu'un des peers ait noté notre demande
+        let my_stamp = match self.fifo.get(site_id) {
+            Some(s) => s.clone(),
+            None => return, // No local request found
+        };
+        let me = (my_stamp.date, site_id.to_string());
+
+        // ici on compara les stamps des autres demandes, est-ce qu'on est le suivant dans la FIFO ?
+        // seules les demandes portant sur les mêmes comptes nous concernent
+        // si oui on peut entrer en section critique
+        let ok = self.fifo.iter().all(|(id, stamp)| {
+            if id == site_id || !stamp.scope.conflicts_with(&my_stamp.scope) {
+                true
+            } else {
+                match stamp.tag {
+                    MutexTag::Request => me <= (stamp.date, id.clone()),
+                    _ => true,
+                }
+            }
+        });
+
+        if ok {
+            self.waiting = false;
+            self.in_sc = true;
+            // We remove obsolete Releases
+            self.fifo.retain(|_, s| s.tag != MutexTag::Release);
+        } else {
+            println!("\x1b[1;31mSECTION CRITIQUE REFUSEE !\x1b[0m");
+            // print fifo order
+            println!("FIFO order:");
+            for (id, stamp) in &self.fifo {
+                println!("{}: {:?} - {:?}", id, stamp.tag, stamp.date);
+            }
+            // print my stamp
+            println!("{}: {:?} - {:?}", site_id, my_stamp.tag, my_stamp.date);
+        }
+    }
+}
+
+impl MutualExclusion for WaveQueue {
+    fn algorithm(&self) -> MutexAlgorithm {
+        MutexAlgorithm::Wave
+    }
+
+    fn status(&self) -> MutexStatus {
+        if self.in_sc {
+            MutexStatus::Held
+        } else if self.waiting {
+            MutexStatus::Waiting
+        } else {
+            MutexStatus::Idle
+        }
+    }
+
+    fn held_scope(&self) -> Option<&LockScope> {
+        if !self.in_sc {
+            return None;
+        }
+        self.scope.as_ref()
+    }
+
+    fn request(&mut self, ctx: &MutexContext, scope: LockScope) -> Vec<MutexEffect> {
+        self.fifo.insert(
+            ctx.site_id.clone(),
+            MutexStamp {
+                tag: MutexTag::Request,
+                date: ctx.lamport,
+                scope: scope.clone(),
+            },
+        );
+        self.in_sc = false;
+        self.waiting = true;
+        self.announced = false;
+        self.scope = Some(scope.clone());
+        vec![MutexEffect::DiffuseRequest(scope)]
+    }
+
+    fn release(&mut self, ctx: &MutexContext) -> Vec<MutexEffect> {
+        self.fifo.remove(&ctx.site_id);
+        self.in_sc = false;
+        self.waiting = false;
+        self.announced = false;
+        self.scope = None;
+        vec![MutexEffect::DiffuseRelease]
+    }
+
+    fn receive(
+        &mut self,
+        ctx: &MutexContext,
+        from: &str,
+        payload: MutexPayload,
+    ) -> Vec<MutexEffect> {
+        match payload {
+            MutexPayload::Request { date, scope } => {
+                self.fifo.insert(
+                    from.to_string(),
+                    MutexStamp {
+                        tag: MutexTag::Request,
+                        date,
+                        scope,
+                    },
+                );
+            }
+            MutexPayload::Release => {
+                self.fifo.remove(from);
+                if self.waiting && self.announced {
+                    self.try_enter_sc(&ctx.site_id);
+                }
+            }
+            MutexPayload::Announced => {
+                self.announced = true;
+                if self.waiting {
+                    self.try_enter_sc(&ctx.site_id);
+                }
+            }
+            MutexPayload::Reply
+            | MutexPayload::Token(_)
+            | MutexPayload::Probe { .. }
+            | MutexPayload::ProbeReply { .. } => {
+                log::error!("Unexpected mutex message from {} for the wave queue", from);
+            }
+        }
+        Vec::new()
+    }
+
+    fn forget_site(&mut self, ctx: &MutexContext, site_id: &str) -> bool {
+        if site_id == ctx.site_id || self.fifo.remove(site_id).is_none() {
+            return false;
+        }
+        // Our own request may have been waiting for this one
+        if self.waiting && self.announced {
+            self.try_enter_sc(&ctx.site_id);
+        }
+        true
+    }
+
+    fn queue(&self) -> std::collections::HashMap<String, MutexStamp> {
+        self.fifo.clone()
+    }
+
+    fn adopt_queue(&mut self, queue: std::collections::HashMap<String, MutexStamp>) {
+        if self.fifo.len() >= queue.len() {
+            return; // Do not overwrite if the new FIFO is smaller or equal
+        }
+        self.fifo = queue;
+    }
+}
+
+/// Ricart–Agrawala: the permission of every other site
+pub struct RicartAgrawala {
+    /// Date and accounts of our pending or granted request
+    request: Option<(i64, LockScope)>,
+    /// In the critical section
+    in_sc: bool,
+    /// Sites whose permission we still wait for
+    awaiting: std::collections::BTreeSet<String>,
+    /// Sites whose request we answer when leaving the critical section
+    deferred: Vec<String>,
+}
+
+impl RicartAgrawala {
+    /// Creates a site neither asking for nor holding the critical section
+    pub fn new() -> Self {
+        Self {
+            request: None,
+            in_sc: false,
+            awaiting: std::collections::BTreeSet::new(),
+            deferred: Vec::new(),
+        }
+    }
+}
+
+impl MutualExclusion for RicartAgrawala {
+    fn algorithm(&self) -> MutexAlgorithm {
+        MutexAlgorithm::RicartAgrawala
+    }
+
+    fn status(&self) -> MutexStatus {
+        match (&self.request, self.in_sc) {
+            (_, true) => MutexStatus::Held,
+            (Some(_), false) => MutexStatus::Waiting,
+            (None, false) => MutexStatus::Idle,
+        }
+    }
+
+    fn held_scope(&self) -> Option<&LockScope> {
+        if !self.in_sc {
+            return None;
+        }
+        self.request.as_ref().map(|(_, scope)| scope)
+    }
+
+    fn request(&mut self, ctx: &MutexContext, scope: LockScope) -> Vec<MutexEffect> {
+        self.request = Some((ctx.lamport, scope.clone()));
+        self.awaiting = ctx.peers.iter().cloned().collect();
+        self.in_sc = self.awaiting.is_empty();
+        ctx.peers
+            .iter()
+            .map(|peer| MutexEffect::Send {
+                to: peer.clone(),
+                payload: MutexPayload::Request {
+                    date: ctx.lamport,
+                    scope: scope.clone(),
+                },
+            })
+            .collect()
+    }
+
+    fn release(&mut self, _ctx: &MutexContext) -> Vec<MutexEffect> {
+        self.request = None;
+        self.in_sc = false;
+        self.awaiting.clear();
+        self.deferred
+            .drain(..)
+            .map(|site| MutexEffect::Send {
+                to: site,
+                payload: MutexPayload::Reply,
+            })
+            .collect()
+    }
+
+    fn receive(
+        &mut self,
+        ctx: &MutexContext,
+        from: &str,
+        payload: MutexPayload,
+    ) -> Vec<MutexEffect> {
+        match payload {
+            MutexPayload::Request { date, scope } => {
+                // The oldest of two conflicting requests goes first
+                let defer = match &self.request {
+                    Some((my_date, my_scope)) if my_scope.conflicts_with(&scope) => {
+                        self.in_sc || (*my_date, ctx.site_id.as_str()) < (date, from)
+                    }
+                    _ => false,
+                };
+                if defer {
+                    self.deferred.push(from.to_string());
+                    Vec::new()
+                } else {
+                    vec![MutexEffect::Send {
+                        to: from.to_string(),
+                        payload: MutexPayload::Reply,
+                    }]
+                }
+            }
+            MutexPayload::Reply => {
+                self.awaiting.remove(from);
+                if self.request.is_some() && self.awaiting.is_empty() {
+                    self.in_sc = true;
+                }
+                Vec::new()
+            }
+            MutexPayload::Release
+            | MutexPayload::Token(_)
+            | MutexPayload::Announced
+            | MutexPayload::Probe { .. }
+            | MutexPayload::ProbeReply { .. } => {
+                log::error!("Unexpected mutex message from {} for Ricart–Agrawala", from);
+                Vec::new()
+            }
+        }
+    }
+
+    fn forget_site(&mut self, _ctx: &MutexContext, site_id: &str) -> bool {
+        let awaited = self.awaiting.remove(site_id);
+        let len = self.deferred.len();
+        self.deferred.retain(|site| site != site_id);
+        if awaited && self.request.is_some() && self.awaiting.is_empty() {
+            self.in_sc = true;
+        }
+        awaited || self.deferred.len() != len
+    }
+}
+
+/// Token of Suzuki–Kasami
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Token {
+    /// Number of the last request of each site that was served
+    pub last_served: std::collections::HashMap<String, i64>,
+    /// Sites waiting for the token, in their order of service
+    pub queue: std::collections::VecDeque<String>,
+    /// Number of times the token was regenerated after being lost
+    #[serde(default)]
+    pub generation: u64,
+}
+
+/// Suzuki–Kasami: a single token moving between the sites
+///
+/// The token is created by the site with the smallest ID among the alive
+/// members of the membership view, the first time a site asks for it. The
+/// view is gossiped to every site, so they agree on it once it has spread. A
+/// site joining later learns the token exists from a request that is not the
+/// first one of its site.
+///
+/// When a member fails or leaves, it may have taken the token with it. The
+/// smallest alive member then probes every other site with a new generation
+/// of the token: a site holding the token moves it to this generation, and a
+/// site that answered drops the tokens of an older generation it receives
+/// later. If no site holds the token once every one answered, the token is
+/// lost and the site regenerates it from the requests served the sites
+/// reported, so that there is never more than one token.
+pub struct SuzukiKasami {
+    /// Number of the last request heard from each site, ours included
+    requests: std::collections::HashMap<String, i64>,
+    /// The token, when the site has it
+    token: Option<Token>,
+    /// The site created the token, received it, or heard of a site it served
+    token_created: bool,
+    /// Highest generation of the token seen by the site
+    generation: u64,
+    /// Members seen alive, whose failure may have lost the token
+    members_seen: std::collections::BTreeSet<String>,
+    /// A member failed, the site must probe the others if it is in charge of the token
+    probe_needed: bool,
+    /// Probe in progress, when the site is in charge of the token
+    probe: Option<TokenProbe>,
+    /// Asked for the token
+    waiting: bool,
+    /// In the critical section
+    in_sc: bool,
+}
+
+/// Answers collected by the site probing the others for the token
+struct TokenProbe {
+    /// Sites that did not answer yet
+    awaiting: std::collections::BTreeSet<String>,
+    /// A site answered that it holds the token
+    found: bool,
+    /// Last request served of each site that answered
+    served: std::collections::HashMap<String, i64>,
+}
+
+impl SuzukiKasami {
+    /// Every account is locked by the token
+    const SCOPE: LockScope = LockScope::All;
+
+    /// Creates a site without the token
+    pub fn new() -> Self {
+        Self {
+            requests: std::collections::HashMap::new(),
+            token: None,
+            token_created: false,
+            generation: 0,
+            members_seen: std::collections::BTreeSet::new(),
+            probe_needed: false,
+            probe: None,
+            waiting: false,
+            in_sc: false,
+        }
+    }
+
+    /// Whether the site is the smallest alive member, in charge of the token
+    fn is_creator(ctx: &MutexContext) -> bool {
+        ctx.members.iter().all(|member| ctx.site_id <= *member)
+    }
+
+    /// Remembers the alive members, the ones that may later take the token away
+    fn see_members(&mut self, ctx: &MutexContext) {
+        self.members_seen.extend(ctx.members.iter().cloned());
+    }
+
+    /// Number of the last request of the site that was served
+    fn served(&self, ctx: &MutexContext) -> i64 {
+        let number = self.requests.get(&ctx.site_id).copied().unwrap_or(0);
+        if self.waiting { number - 1 } else { number }
+    }
+
+    /// Creates the token if this site is the one in charge of it
+    fn create_token(&mut self, ctx: &MutexContext) {
+        if !self.token_created && Self::is_creator(ctx) {
+            log::info!("Creating the token of the critical section");
+            self.token = Some(Token::default());
+            self.token_created = true;
+        }
+    }
+
+    /// Asks every other site whether it holds the token, under a new generation
+    fn start_probe(&mut self, ctx: &MutexContext) -> Vec<MutexEffect> {
+        self.probe_needed = false;
+        if self.token.is_some() {
+            return Vec::new();
+        }
+        self.generation += 1;
+        // pas de nouveau jeton créé pendant la recherche
+        self.token_created = true;
+        log::warn!(
+            "Looking for the token of the critical section, generation {}",
+            self.generation
+        );
+        self.probe = Some(TokenProbe {
+            awaiting: ctx.peers.iter().cloned().collect(),
+            found: false,
+            served: std::collections::HashMap::new(),
+        });
+        let generation = self.generation;
+        let mut effects: Vec<_> = ctx
+            .peers
+            .iter()
+            .map(|peer| MutexEffect::Send {
+                to: peer.clone(),
+                payload: MutexPayload::Probe { generation },
+            })
+            .collect();
+        effects.extend(self.end_probe(ctx));
+        effects
+    }
+
+    /// Regenerates the token once every site answered that it does not hold it
+    fn end_probe(&mut self, ctx: &MutexContext) -> Vec<MutexEffect> {
+        if !self.probe.as_ref().is_some_and(|p| p.awaiting.is_empty()) {
+            return Vec::new();
+        }
+        let probe = self.probe.take().unwrap();
+        if probe.found || self.token.is_some() {
+            return Vec::new();
+        }
+        log::warn!(
+            "Regenerating the token of the critical section, generation {}",
+            self.generation
+        );
+        let mut last_served = probe.served;
+        last_served.insert(ctx.site_id.clone(), self.served(ctx));
+        self.token = Some(Token {
+            last_served,
+            queue: std::collections::VecDeque::new(),
+            generation: self.generation,
+        });
+        if self.waiting {
+            self.waiting = false;
+            self.in_sc = true;
+            Vec::new()
+        } else {
+            self.pass_token(ctx)
+        }
+    }
+
+    /// Sends the idle token to the next site waiting for it
+    fn pass_token(&mut self, ctx: &MutexContext) -> Vec<MutexEffect> {
+        let Some(token) = &mut self.token else {
+            return Vec::new();
+        };
+        let mut waiting: Vec<_> = self
+            .requests
+            .iter()
+            .filter(|(site, n)| {
+                **site != ctx.site_id
+                    && **n == token.last_served.get(*site).copied().unwrap_or(0) + 1
+                    && !token.queue.contains(site)
+            })
+            .map(|(site, _)| site.clone())
+            .collect();
+        waiting.sort();
+        token.queue.extend(waiting);
+
+        match token.queue.pop_front() {
+            Some(next) => vec![MutexEffect::Send {
+                to: next,
+                payload: MutexPayload::Token(self.token.take().unwrap()),
+            }],
+            None => Vec::new(),
+        }
+    }
+}
+
+impl MutualExclusion for SuzukiKasami {
+    fn algorithm(&self) -> MutexAlgorithm {
+        MutexAlgorithm::SuzukiKasami
+    }
+
+    fn status(&self) -> MutexStatus {
+        if self.in_sc {
+            MutexStatus::Held
+        } else if self.waiting {
+            MutexStatus::Waiting
+        } else {
+            MutexStatus::Idle
+        }
+    }
+
+    fn held_scope(&self) -> Option<&LockScope> {
+        self.in_sc.then_some(&Self::SCOPE)
+    }
+
+    fn request(&mut self, ctx: &MutexContext, _scope: LockScope) -> Vec<MutexEffect> {
+        self.see_members(ctx);
+        self.create_token(ctx);
+        if self.token.is_some() {
+            self.in_sc = true;
+            return Vec::new();
+        }
+
+        let number = self.requests.entry(ctx.site_id.clone()).or_insert(0);
+        *number += 1;
+        let date = *number;
+        self.waiting = true;
+        ctx.peers
+            .iter()
+            .map(|peer| MutexEffect::Send {
+                to: peer.clone(),
+                payload: MutexPayload::Request {
+                    date,
+                    scope: Self::SCOPE,
+                },
+            })
+            .collect()
+    }
+
+    fn release(&mut self, ctx: &MutexContext) -> Vec<MutexEffect> {
+        self.see_members(ctx);
+        self.waiting = false;
+        if !self.in_sc {
+            return Vec::new();
+        }
+        self.in_sc = false;
+        let served = self.served(ctx);
+        if let Some(token) = &mut self.token {
+            token.last_served.insert(ctx.site_id.clone(), served);
+        }
+        self.pass_token(ctx)
+    }
+
+    fn receive(
+        &mut self,
+        ctx: &MutexContext,
+        from: &str,
+        payload: MutexPayload,
+    ) -> Vec<MutexEffect> {
+        self.see_members(ctx);
+        match payload {
+            MutexPayload::Request { date, .. } => {
+                // une demande qui n'est pas la première : le jeton existe déjà
+                if date > 1 {
+                    self.token_created = true;
+                }
+                self.create_token(ctx);
+                let number = self.requests.entry(from.to_string()).or_insert(0);
+                *number = (*number).max(date);
+                if self.in_sc {
+                    Vec::new()
+                } else {
+                    self.pass_token(ctx)
+                }
+            }
+            MutexPayload::Token(token) => {
+                if token.generation < self.generation {
+                    log::warn!(
+                        "Dropping a token of generation {} from {}, the current one is {}",
+                        token.generation,
+                        from,
+                        self.generation
+                    );
+                    return Vec::new();
+                }
+                self.generation = token.generation;
+                self.token_created = true;
+                self.token = Some(token);
+                if let Some(probe) = &mut self.probe {
+                    probe.found = true;
+                }
+                if self.waiting {
+                    self.waiting = false;
+                    self.in_sc = true;
+                    Vec::new()
+                } else {
+                    // Our request was withdrawn in the meantime, or the token
+                    // was regenerated from an older state
+                    let served = self.served(ctx);
+                    if let Some(token) = &mut self.token {
+                        token.last_served.insert(ctx.site_id.clone(), served);
+                    }
+                    self.pass_token(ctx)
+                }
+            }
+            MutexPayload::Probe { generation } => {
+                // les jetons plus anciens que la recherche seront jetés
+                self.generation = self.generation.max(generation);
+                self.token_created = true;
+                if let Some(token) = &mut self.token {
+                    token.generation = self.generation;
+                }
+                vec![MutexEffect::Send {
+                    to: from.to_string(),
+                    payload: MutexPayload::ProbeReply {
+                        generation,
+                        holds: self.token.is_some(),
+                        served: self.served(ctx),
+                    },
+                }]
+            }
+            MutexPayload::ProbeReply {
+                generation,
+                holds,
+                served,
+            } => {
+                if generation != self.generation {
+                    return Vec::new();
+                }
+                let Some(probe) = &mut self.probe else {
+                    return Vec::new();
+                };
+                probe.awaiting.remove(from);
+                probe.found |= holds;
+                probe.served.insert(from.to_string(), served);
+                self.end_probe(ctx)
+            }
+            MutexPayload::Release | MutexPayload::Reply | MutexPayload::Announced => {
+                log::error!("Unexpected mutex message from {} for Suzuki–Kasami", from);
+                Vec::new()
+            }
+        }
+    }
+
+    fn forget_site(&mut self, _ctx: &MutexContext, site_id: &str) -> bool {
+        let known = self.requests.remove(site_id).is_some();
+        if let Some(token) = &mut self.token {
+            token.queue.retain(|site| site != site_id);
+            token.last_served.remove(site_id);
+        }
+        if let Some(probe) = &mut self.probe {
+            probe.awaiting.remove(site_id);
+        }
+        if self.members_seen.remove(site_id) {
+            log::warn!(
+                "Site {} is gone, it may have taken the token of the critical section",
+                site_id
+            );
+            self.probe_needed = true;
+        }
+        known
+    }
+
+    fn resume(&mut self, ctx: &MutexContext) -> Vec<MutexEffect> {
+        if self.probe_needed && Self::is_creator(ctx) {
+            return self.start_probe(ctx);
+        }
+        self.probe_needed = false;
+        let mut effects = self.end_probe(ctx);
+        if !self.in_sc {
+            effects.extend(self.pass_token(ctx));
+        }
+        effects
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stamp(date: i64, scope: LockScope) -> MutexStamp {
+        MutexStamp {
+            tag: MutexTag::Request,
+            date,
+            scope,
+        }
+    }
+
+    /// Sites of a full mesh exchanging the messages of an algorithm
+    struct Mesh {
+        sites: Vec<Box<dyn MutualExclusion>>,
+        messages: std::collections::VecDeque<(String, String, MutexPayload)>,
+        sent: usize,
+        failed: std::collections::BTreeSet<usize>,
+    }
+
+    impl Mesh {
+        fn new(algorithm: MutexAlgorithm, nb_sites: usize) -> Self {
+            Self {
+                sites: (0..nb_sites).map(|_| algorithm.build()).collect(),
+                messages: std::collections::VecDeque::new(),
+                sent: 0,
+                failed: std::collections::BTreeSet::new(),
+            }
+        }
+
+        fn ctx(&self, site: usize, lamport: i64) -> MutexContext {
+            let alive = (0..self.sites.len()).filter(|i| !self.failed.contains(i));
+            MutexContext {
+                site_id: format!("S{}", site),
+                lamport,
+                peers: alive
+                    .clone()
+                    .filter(|i| *i != site)
+                    .map(|i| format!("S{}", i))
+                    .collect(),
+                members: alive.map(|i| format!("S{}", i)).collect(),
+            }
+        }
+
+        /// Crashes a site, then lets every other one forget it
+        fn fail(&mut self, site: usize) {
+            let algorithm = self.sites[site].algorithm();
+            self.sites[site] = algorithm.build();
+            self.failed.insert(site);
+            for i in 0..self.sites.len() {
+                if self.failed.contains(&i) {
+                    continue;
+                }
+                let ctx = self.ctx(i, 0);
+                self.sites[i].forget_site(&ctx, &format!("S{}", site));
+                let effects = self.sites[i].resume(&ctx);
+                self.post(i, effects);
+            }
+        }
+
+        fn post(&mut self, from: usize, effects: Vec<MutexEffect>) {
+            for effect in effects {
+                match effect {
+                    MutexEffect::Send { to, payload } => {
+                        self.sent += 1;
+                        self.messages.push_back((format!("S{}", from), to, payload));
+                    }
+                    other => panic!("Unexpected effect {:?}", other),
+                }
+            }
+        }
+
+        fn request(&mut self, site: usize, lamport: i64, scope: LockScope) {
+            let ctx = self.ctx(site, lamport);
+            let effects = self.sites[site].request(&ctx, scope);
+            self.post(site, effects);
+        }
+
+        fn release(&mut self, site: usize) {
+            let ctx = self.ctx(site, 0);
+            let effects = self.sites[site].release(&ctx);
+            self.post(site, effects);
+        }
+
+        /// Delivers every message in transit, checking the mutual exclusion after each one
+        fn deliver_all(&mut self) {
+            while let Some((from, to, payload)) = self.messages.pop_front() {
+                let site: usize = to[1..].parse().unwrap();
+                if self.failed.contains(&site) {
+                    continue;
+                }
+                let ctx = self.ctx(site, 0);
+                let effects = self.sites[site].receive(&ctx, &from, payload);
+                self.post(site, effects);
+                self.assert_exclusive();
+            }
+        }
+
+        fn holders(&self) -> Vec<usize> {
+            (0..self.sites.len())
+                .filter(|i| self.sites[*i].status() == MutexStatus::Held)
+                .collect()
+        }
+
+        fn assert_exclusive(&self) {
+            let held: Vec<_> = self
+                .sites
+                .iter()
+                .filter_map(|site| site.held_scope())
+                .collect();
+            for (i, a) in held.iter().enumerate() {
+                for b in &held[i + 1..] {
+                    assert!(!a.conflicts_with(b), "{:?} and {:?} both held", a, b);
+                }
+            }
+        }
+
+        /// Serves every pending request one after the other, returns the order of service
+        fn serve_all(&mut self) -> Vec<usize> {
+            let mut order = Vec::new();
+            self.deliver_all();
+            while let Some(&holder) = self.holders().first() {
+                order.push(holder);
+                self.release(holder);
+                self.deliver_all();
+            }
+            order
+        }
+    }
+
+    #[test]
+    fn test_wave_requests_on_other_accounts_do_not_wait() {
+        let mut queue = WaveQueue::new();
+        queue.waiting = true;
+        queue
+            .fifo
+            .insert("B".to_string(), stamp(1, LockScope::accounts(["bob"])));
+        queue
+            .fifo
+            .insert("A".to_string(), stamp(2, LockScope::accounts(["alice"])));
+        queue.try_enter_sc("A");
+        assert_eq!(queue.status(), MutexStatus::Held);
+
+        // An older request on alice goes first
+        queue.in_sc = false;
+        queue.waiting = true;
+        queue.fifo.insert(
+            "C".to_string(),
+            stamp(1, LockScope::accounts(["alice", "bob"])),
+        );
+        queue.try_enter_sc("A");
+        assert_eq!(queue.status(), MutexStatus::Waiting);
+    }
+
+    #[test]
+    fn test_wave_crossed_transfers_do_not_deadlock() {
+        // A transfers alice -> bob while B transfers bob -> alice, with the same date
+        let mut queues: Vec<_> = ["A", "B"]
+            .iter()
+            .map(|id| {
+                let mut queue = WaveQueue::new();
+                queue.waiting = true;
+                queue.fifo.insert(
+                    "A".to_string(),
+                    stamp(3, LockScope::accounts(["alice", "bob"])),
+                );
+                queue.fifo.insert(
+                    "B".to_string(),
+                    stamp(3, LockScope::accounts(["bob", "alice"])),
+                );
+                queue.try_enter_sc(id);
+                queue
+            })
+            .collect();
+        assert_eq!(queues[0].status(), MutexStatus::Held);
+        assert_eq!(queues[1].status(), MutexStatus::Waiting);
+
+        // Once A is done, B goes
+        queues[1].fifo.remove("A");
+        queues[1].try_enter_sc("B");
+        assert_eq!(queues[1].status(), MutexStatus::Held);
+    }
+
+    #[test]
+    fn test_ricart_agrawala_serves_the_oldest_request_first() {
+        let mut mesh = Mesh::new(MutexAlgorithm::RicartAgrawala, 4);
+        for (site, date) in [(2, 1), (0, 3), (3, 2), (1, 3)] {
+            mesh.request(site, date, LockScope::All);
+        }
+        assert_eq!(mesh.serve_all(), vec![2, 3, 0, 1]);
+        // A request and a reply per other site, for each of the four entries
+        assert_eq!(mesh.sent, 4 * 2 * 3);
+        assert!(mesh.sites.iter().all(|s| s.status() == MutexStatus::Idle));
+    }
+
+    #[test]
+    fn test_ricart_agrawala_other_accounts_enter_together() {
+        let mut mesh = Mesh::new(MutexAlgorithm::RicartAgrawala, 3);
+        mesh.request(0, 1, LockScope::accounts(["alice"]));
+        mesh.request(1, 1, LockScope::accounts(["bob"]));
+        mesh.request(2, 2, LockScope::accounts(["alice", "bob"]));
+        mesh.deliver_all();
+        assert_eq!(mesh.holders(), vec![0, 1]);
+
+        mesh.release(0);
+        mesh.deliver_all();
+        assert_eq!(mesh.holders(), vec![1]);
+        mesh.release(1);
+        mesh.deliver_all();
+        assert_eq!(mesh.holders(), vec![2]);
+    }
+
+    #[test]
+    fn test_ricart_agrawala_forgets_a_failed_site() {
+        let mut mesh = Mesh::new(MutexAlgorithm::RicartAgrawala, 3);
+        mesh.request(0, 1, LockScope::All);
+        // S2 never answers
+        mesh.messages.retain(|(_, to, _)| to != "S2");
+        mesh.deliver_all();
+        assert_eq!(mesh.sites[0].status(), MutexStatus::Waiting);
+
+        let ctx = mesh.ctx(0, 0);
+        assert!(mesh.sites[0].forget_site(&ctx, "S2"));
+        assert_eq!(mesh.sites[0].status(), MutexStatus::Held);
+    }
+
+    #[test]
+    fn test_suzuki_kasami_passes_the_token_around() {
+        let mut mesh = Mesh::new(MutexAlgorithm::SuzukiKasami, 4);
+        for site in [2, 1, 3] {
+            mesh.request(site, 0, LockScope::accounts(["alice"]));
+        }
+        // S0 creates the token on the first request it hears of
+        let order = mesh.serve_all();
+        assert_eq!(order.len(), 3);
+        assert_eq!(order[0], 2);
+        // Three requests to every other site, and the token once for each of them
+        assert_eq!(mesh.sent, 3 * 3 + 3);
+
+        // The last holder keeps the token and enters without a message
+        let last = order[2];
+        let sent = mesh.sent;
+        mesh.request(last, 0, LockScope::All);
+        assert_eq!(mesh.holders(), vec![last]);
+        assert_eq!(mesh.sent, sent);
+        mesh.release(last);
+
+        // Every site can enter again
+        for site in 0..4 {
+            mesh.request(site, 0, LockScope::All);
+        }
+        let mut order = mesh.serve_all();
+        order.sort();
+        assert_eq!(order, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_suzuki_kasami_regenerates_a_lost_token() {
+        let mut mesh = Mesh::new(MutexAlgorithm::SuzukiKasami, 3);
+        mesh.request(2, 0, LockScope::All);
+        mesh.deliver_all();
+        assert_eq!(mesh.holders(), vec![2]);
+
+        // S2 fails with the token, S1 asks for it meanwhile
+        mesh.request(1, 0, LockScope::All);
+        mesh.fail(2);
+        mesh.deliver_all();
+        assert_eq!(mesh.holders(), vec![1]);
+    }
+
+    #[test]
+    fn test_suzuki_kasami_token_passed_by_another_site_is_regenerated() {
+        let mut mesh = Mesh::new(MutexAlgorithm::SuzukiKasami, 4);
+        mesh.request(1, 0, LockScope::All);
+        mesh.deliver_all();
+        assert_eq!(mesh.holders(), vec![1]);
+
+        // S1, which is not in charge of the token, hands it to S2
+        mesh.request(2, 0, LockScope::All);
+        mesh.deliver_all();
+        mesh.release(1);
+        mesh.deliver_all();
+        assert_eq!(mesh.holders(), vec![2]);
+
+        // S2 fails with it, S3 and S1 wait for the token
+        mesh.request(3, 0, LockScope::All);
+        mesh.request(1, 0, LockScope::All);
+        mesh.fail(2);
+        let mut order = mesh.serve_all();
+        order.sort();
+        assert_eq!(order, vec![1, 3]);
+
+        // Every site can still enter
+        for site in [0, 1, 3] {
+            mesh.request(site, 0, LockScope::All);
+        }
+        let mut order = mesh.serve_all();
+        order.sort();
+        assert_eq!(order, vec![0, 1, 3]);
+    }
+
+    #[test]
+    fn test_suzuki_kasami_probe_keeps_a_token_still_held() {
+        let mut mesh = Mesh::new(MutexAlgorithm::SuzukiKasami, 4);
+        mesh.request(1, 0, LockScope::All);
+        mesh.deliver_all();
+        assert_eq!(mesh.holders(), vec![1]);
+
+        // S3 leaves while S1 holds the token: S0 must not create another one
+        mesh.request(2, 0, LockScope::All);
+        mesh.fail(3);
+        mesh.deliver_all();
+        assert_eq!(mesh.holders(), vec![1]);
+
+        mesh.release(1);
+        mesh.deliver_all();
+        assert_eq!(mesh.holders(), vec![2]);
+        mesh.request(0, 0, LockScope::All);
+        assert_eq!(mesh.serve_all(), vec![2, 0]);
+    }
+
+    #[test]
+    fn test_suzuki_kasami_drops_a_token_of_an_older_generation() {
+        let mut mesh = Mesh::new(MutexAlgorithm::SuzukiKasami, 4);
+        mesh.request(2, 0, LockScope::All);
+        mesh.deliver_all();
+        mesh.request(1, 0, LockScope::All);
+        mesh.deliver_all();
+
+        // S2 hands the token to S1, but it is still in transit when S3 fails
+        mesh.release(2);
+        let late = mesh.messages.pop_front().unwrap();
+        assert!(matches!(late.2, MutexPayload::Token(_)));
+        mesh.fail(3);
+        mesh.deliver_all();
+        // Nobody held it: S0 regenerated it for S1
+        assert_eq!(mesh.holders(), vec![1]);
+
+        // The old token arrives and is dropped
+        mesh.messages.push_back(late);
+        mesh.deliver_all();
+        assert_eq!(mesh.holders(), vec![1]);
+        mesh.request(0, 0, LockScope::All);
+        mesh.request(2, 0, LockScope::All);
+        mesh.release(1);
+        let mut order = mesh.serve_all();
+        order.sort();
+        assert_eq!(order, vec![0, 2]);
+    }
+
+    #[test]
+    fn test_suzuki_kasami_token_is_created_by_the_smallest_member() {
+        let mut mesh = Mesh::new(MutexAlgorithm::SuzukiKasami, 2);
+        // S1 does not know S0 as a neighbour yet, the membership view does
+        let mut ctx = mesh.ctx(1, 0);
+        ctx.peers.clear();
+        let effects = mesh.sites[1].request(&ctx, LockScope::All);
+        assert!(effects.is_empty());
+        assert_eq!(mesh.sites[1].status(), MutexStatus::Waiting);
+    }
+
+    #[test]
+    fn test_suzuki_kasami_locks_every_account() {
+        let mut mesh = Mesh::new(MutexAlgorithm::SuzukiKasami, 2);
+        mesh.request(0, 0, LockScope::accounts(["alice"]));
+        assert_eq!(mesh.sites[0].held_scope(), Some(&LockScope::All));
+    }
+}
@@ -17,6 +17,12 @@ pub struct NetworkManager {
     pub nb_active_connections: u16,
     /// Pool of active peer connections
     pub connection_pool: std::collections::HashMap<std::net::SocketAddr, PeerConnection>,
+    /// Maximum size of a frame payload sent or received on a peer stream
+    pub max_frame_size: usize,
+    /// Transport used to reach the other sites, set at launch
+    pub transport: Option<std::sync::Arc<dyn crate::transport::Transport>>,
+    /// Key used to sign every outgoing message
+    pub identity: crate::identity::SiteIdentity,
 }
 
 #[cfg(feature = "server")]
@@ -26,9 +32,28 @@ impl NetworkManager {
         Self {
             nb_active_connections: 0,
             connection_pool: std::collections::HashMap::new(),
+            max_frame_size: crate::codec::DEFAULT_MAX_FRAME_SIZE,
+            transport: None,
+            identity: crate::identity::SiteIdentity::generate()
+                .expect("no randomness available to generate the site key"),
         }
     }
 
+    /// Sets the maximum frame size at initialization
+    pub fn init_max_frame_size(&mut self, max_frame_size: usize) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    /// Sets the persisted identity of the site at initialization
+    pub fn init_identity(&mut self, identity: crate::identity::SiteIdentity) {
+        self.identity = identity;
+    }
+
+    /// Sets the transport used to reach the other sites at initialization
+    pub fn init_transport(&mut self, transport: std::sync::Arc<dyn crate::transport::Transport>) {
+        self.transport = Some(transport);
+    }
+
     /// Adds a new peer connection to the connection pool
     fn add_connection(
         &mut self,
@@ -45,12 +70,15 @@ impl NetworkManager {
         &mut self,
         site_addr: std::net::SocketAddr,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        use tokio::net::TcpStream;
         use tokio::sync::mpsc;
 
-        let stream = TcpStream::connect(site_addr).await?;
+        let transport = self
+            .transport
+            .clone()
+            .ok_or("no transport configured to reach the other sites")?;
+        let stream = transport.connect(site_addr).await?;
         let (tx, rx) = mpsc::channel(256);
-        spawn_writer_task(stream, rx).await;
+        spawn_writer_task(stream, rx, self.max_frame_size).await;
         self.add_connection(site_addr, tx);
         Ok(())
     }
@@ -77,16 +105,31 @@ lazy_static::lazy_static! {
 
 #[cfg(feature = "server")]
 /// Spawns a task to handle writing messages to a peer connection
-pub async fn spawn_writer_task(
-    stream: tokio::net::TcpStream,
+///
+/// Each message is written as a length-prefixed frame
+pub async fn spawn_writer_task<S>(
+    stream: S,
     mut rx: tokio::sync::mpsc::Receiver<Vec<u8>>,
-) {
+    max_frame_size: usize,
+) where
+    S: tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    use crate::codec::FrameCodec;
     use tokio::io::AsyncWriteExt;
 
     tokio::spawn(async move {
         let mut stream = stream;
+        let codec = FrameCodec::new(max_frame_size);
         while let Some(data) = rx.recv().await {
-            if stream.write_all(&data).await.is_err() {
+            let frame = match codec.encode(&data) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    log::error!("Dropping outgoing message: {}", e);
+                    continue;
+                }
+            };
+            // TLS buffers the records, flush so the frame leaves right away
+            if stream.write_all(&frame).await.is_err() || stream.flush().await.is_err() {
                 log::error!("Failed to send message");
                 break;
             }
@@ -98,18 +141,19 @@ pub async fn spawn_writer_task(
 #[cfg(feature = "server")]
 /// Announces this node's presence to potential peers in the network.
 /// If the user gave peers in args, we will only connect to those peers.
-/// If not, we will scan the port range and try connecting to all sockets.
-pub async fn announce(ip: &str, start_port: u16, end_port: u16, selected_port: u16) {
+/// If not, we bootstrap from the seed: its acknowledgment carries the
+/// membership view, and we then connect to every member of this view.
+pub async fn announce(node: &crate::node::Node, seed: Option<std::net::SocketAddr>) {
     use crate::message::{MessageInfo, NetworkMessageCode};
-    use crate::state::LOCAL_APP_STATE;
 
-    let (local_addr, site_id, clocks, cli_peers) = {
-        let state = LOCAL_APP_STATE.lock().await;
+    let (local_addr, site_id, clocks, cli_peers, membership) = {
+        let state = node.state.lock().await;
         (
             state.get_site_addr(),
             state.get_site_id(),
             state.get_clock(),
             state.get_cli_peers_addrs(),
+            state.get_membership_payload(),
         )
     };
 
@@ -118,18 +162,15 @@ pub async fn announce(ip: &str, start_port: u16, end_port: u16, selected_port: u
         log::debug!("Manually connecting to peers based on args");
         cli_peers
     } else {
-        log::debug!("Looking for all ports to find potential peers");
-        (start_port..=end_port)
-            .filter(|&port| port != selected_port)
-            .map(|port| format!("{}:{}", ip, port).parse().unwrap())
-            .collect()
+        log::debug!("Bootstrapping the membership from the seed {:?}", seed);
+        seed.into_iter().collect()
     };
 
     //If there are no peers, we don't need to do anything
     if peer_to_ping.is_empty() {
         return;
     } else {
-        let mut state = LOCAL_APP_STATE.lock().await;
+        let mut state = node.state.lock().await;
         state.init_sync(true); // we need to sync with other sites
     }
 
@@ -145,17 +186,18 @@ pub async fn announce(ip: &str, start_port: u16, end_port: u16, selected_port: u
         let clocks = clocks.clone();
         let local_addr = local_addr.clone();
         let success_count = Arc::clone(&success_count);
+        let membership = membership.clone();
+        let node = node.clone();
 
         let handle = tokio::spawn(async move {
             let result = send_message(
+                &node,
                 addr,
-                MessageInfo::None,
-                None,
+                MessageInfo::Membership(membership),
                 NetworkMessageCode::Discovery,
                 local_addr,
                 &site_id,
-                &site_id,
-                local_addr,
+                crate::message::Origin::local(&site_id, local_addr),
                 clocks,
             )
             .await;
@@ -174,36 +216,53 @@ pub async fn announce(ip: &str, start_port: u16, end_port: u16, selected_port: u
 
     // Update the number of attended neighbours
     {
-        let mut state = LOCAL_APP_STATE.lock().await;
+        let mut state = node.state.lock().await;
         state.init_nb_first_attended_neighbours(success_count.load(Ordering::SeqCst) as i64);
     }
 }
 
 #[cfg(feature = "server")]
 /// Starts listening for messages from a new peer
-pub async fn start_listening(stream: tokio::net::TcpStream, addr: std::net::SocketAddr) {
-    log::debug!("Accepted connection from: {}", addr);
+pub async fn start_listening(
+    node: crate::node::Node,
+    stream: crate::transport::PeerReader,
+    addr: std::net::SocketAddr,
+) {
+    log::debug!("Listening to the messages of: {}", addr);
 
     tokio::spawn(async move {
-        if let Err(e) = handle_network_message(stream, addr).await {
+        if let Err(e) = handle_network_message(&node, stream, addr).await {
             log::error!("Error handling connection from {}: {}", addr, e);
         }
     });
 }
 
 #[cfg(feature = "server")]
-/// Handles incoming messages from a peer
-/// Implement our wave diffusion protocol
-pub async fn handle_network_message(
-    mut stream: tokio::net::TcpStream,
+/// Reads the frames sent by a peer and handles every decoded message
+///
+/// A single read can contain a partial frame or several frames, the codec
+/// buffers the bytes until complete frames are available.
+pub async fn handle_network_message<S>(
+    node: &crate::node::Node,
+    mut stream: S,
     socket_of_the_sender: std::net::SocketAddr,
-) -> Result<(), Box<dyn std::error::Error>> {
-    use crate::message::{Message, MessageInfo, NetworkMessageCode};
-    use crate::state::LOCAL_APP_STATE;
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    S: tokio::io::AsyncRead + Unpin,
+{
+    use crate::codec::FrameCodec;
+    use crate::identity::SignedMessage;
+    use crate::message::Message;
     use rmp_serde::decode;
     use tokio::io::AsyncReadExt;
 
-    let mut buf = vec![0; 1024];
+    let max_frame_size = {
+        let manager = node.network.lock().await;
+        manager.max_frame_size
+    };
+    let mut codec = FrameCodec::new(max_frame_size);
+
+    let mut buf = vec![0; 4096];
     loop {
         let n = stream.read(&mut buf).await?;
 
@@ -212,7 +271,7 @@ pub async fn handle_network_message(
             // Here we should remove the site from the network in the app state
             {
                 log::debug!("Removing {} from the peers", socket_of_the_sender);
-                let mut state = LOCAL_APP_STATE.lock().await;
+                let mut state = node.state.lock().await;
                 state
                     .remove_peer_from_socket_closed(socket_of_the_sender)
                     .await;
@@ -221,946 +280,475 @@ pub async fn handle_network_message(
         }
 
         log::debug!("Received {} bytes from {}", n, socket_of_the_sender);
+        codec.extend(&buf[..n]);
+
+        loop {
+            let frame = match codec.decode() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    // The stream cannot be resynchronized after an invalid frame
+                    log::error!(
+                        "Invalid frame from {}, closing the connection: {}",
+                        socket_of_the_sender,
+                        e
+                    );
+                    let mut state = node.state.lock().await;
+                    state
+                        .remove_peer_from_socket_closed(socket_of_the_sender)
+                        .await;
+                    return Err(e.into());
+                }
+            };
 
-        let message: Message = match decode::from_slice(&buf[..n]) {
-            Ok(msg) => msg,
-            Err(e) => {
-                log::error!("Error decoding message: {}", e);
+            let signed: SignedMessage = match decode::from_slice(&frame) {
+                Ok(signed) => signed,
+                Err(e) => {
+                    log::error!("Error decoding signed envelope: {}", e);
+                    continue;
+                }
+            };
+            let message: Message = match decode::from_slice(&signed.message) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    log::error!("Error decoding message: {}", e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = verify_message(node, &signed, &message).await {
+                reject_message(&message, e);
                 continue;
             }
-        };
 
-        log::debug!(
-            "Message received from site {} : {:?}",
-            message.sender_addr,
-            message.clone()
-        );
+            handle_message(node, message, socket_of_the_sender).await?;
+        }
+    }
+}
 
-        {
-            let mut state = LOCAL_APP_STATE.lock().await;
-            state.add_site_id(
-                message.message_initiator_id.clone(),
-                message.message_initiator_addr.clone(),
+#[cfg(feature = "server")]
+/// Checks the signature of a received message against the key pinned for its sender
+///
+/// The key announced by a `Discovery`, an `Acknowledgment` or a key exchange
+/// is pinned first, so the very first message of a site can be verified.
+pub async fn verify_message(
+    node: &crate::node::Node,
+    signed: &crate::identity::SignedMessage,
+    message: &crate::message::Message,
+) -> Result<(), crate::identity::SignatureError> {
+    use crate::message::NetworkMessageCode;
+
+    let mut state = node.state.lock().await;
+    if let Some(public_key) = &signed.public_key
+        && matches!(
+            message.code,
+            NetworkMessageCode::Discovery
+                | NetworkMessageCode::Acknowledgment
+                | NetworkMessageCode::KeyRequest
+                | NetworkMessageCode::KeyAnnouncement
+        )
+    {
+        state.pin_key(&node.db, &message.sender_id, public_key)?;
+    }
+    state.key_ring.verify(&message.sender_id, signed)
+}
+
+#[cfg(feature = "server")]
+/// Drops a message whose signature could not be verified
+///
+/// The message never reaches `handle_message`, so no command it carries is applied.
+pub fn reject_message(message: &crate::message::Message, error: crate::identity::SignatureError) {
+    use crate::identity::SignatureError;
+
+    match &error {
+        SignatureError::KeyMismatch(_)
+        | SignatureError::InvalidSignature(_)
+        | SignatureError::Unsigned(_) => {
+            println!(
+                "\x1b[1;31mREJECTED FORGED MESSAGE {:?} CLAIMING TO COME FROM {} !\x1b[0m",
+                message.code, message.sender_id
             );
         }
+        SignatureError::UnknownSite(_) | SignatureError::Malformed(_) => {}
+    }
+    log::error!(
+        "Rejected {:?} message from {}: {}",
+        message.code,
+        message.sender_addr,
+        error
+    );
+}
 
-        match message.code {
-            NetworkMessageCode::AcquireMutex => {
-                // We store the request
-                {
-                    let mut st = LOCAL_APP_STATE.lock().await;
-                    st.global_mutex_fifo.insert(
-                        message.message_initiator_id.clone(),
-                        crate::state::MutexStamp {
-                            tag: crate::state::MutexTag::Request,
-                            date: message.clock.get_lamport().clone(),
-                        },
-                    );
-                }
-                // wave diffusion
-                let mut diffuse = false;
-                let (local_site_id, local_site_addr) = {
-                    let mut state = LOCAL_APP_STATE.lock().await;
-                    let parent_id = state
-                        .parent_addr_for_transaction_wave
-                        .get(&message.message_initiator_id)
-                        .unwrap_or(&"0.0.0.0:0".parse().unwrap())
-                        .to_string();
-                    if parent_id == "0.0.0.0:0" {
-                        state.set_parent_addr(
-                            message.message_initiator_id.clone(),
-                            message.sender_addr,
-                        );
-
-                        let nb_neighbours = state.get_nb_connected_neighbours();
-                        let current_value = state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .get(&message.message_initiator_id)
-                            .copied()
-                            .unwrap_or(nb_neighbours);
-
-                        state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .insert(message.message_initiator_id.clone(), current_value - 1);
-
-                        log::debug!("Nombre de voisin : {}", current_value - 1);
-
-                        diffuse = state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .get(&message.message_initiator_id)
-                            .copied()
-                            .unwrap_or(0)
-                            > 0;
-                    }
-                    (state.get_site_id(), state.get_site_addr())
-                };
-
-                if diffuse {
-                    let mut snd_msg = message.clone();
-                    snd_msg.sender_id = local_site_id.to_string();
-                    snd_msg.sender_addr = local_site_addr;
-                    diffuse_message(&snd_msg).await?;
-                } else {
-                    let (parent_addr, local_addr, site_id) = {
-                        let state = LOCAL_APP_STATE.lock().await;
-                        (
-                            state.get_parent_addr_for_wave(message.message_initiator_id.clone()),
-                            &state.get_site_addr(),
-                            &state.get_site_id().to_string(),
-                        )
-                    };
-                    // Acquit message to parent
-                    log::debug!(
-                        "Réception d'un message de d'acquisition de mutex, on est sur une feuille, on acquite, envoie à {}",
-                        message.sender_addr.to_string().as_str()
-                    );
-                    send_message(
-                        message.sender_addr,
-                        MessageInfo::AckMutex(crate::message::AckMutexPayload {
-                            clock: message.clock.get_lamport().clone(),
-                        }),
-                        None,
-                        NetworkMessageCode::AckGlobalMutex,
-                        *local_addr,
-                        site_id,
-                        &message.message_initiator_id,
-                        message.message_initiator_addr,
-                        message.clock.clone(),
-                    )
-                    .await?;
+#[cfg(feature = "server")]
+/// Handles a single message received from a peer
+/// Implement our wave diffusion protocol
+pub async fn handle_message(
+    node: &crate::node::Node,
+    message: crate::message::Message,
+    socket_of_the_sender: std::net::SocketAddr,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use crate::message::{MessageInfo, NetworkMessageCode};
 
-                    if message.sender_addr == parent_addr {
-                        // réinitialisation s'il s'agit de la remontée après réception des rouges de tous les fils
-                        let mut state = LOCAL_APP_STATE.lock().await;
-                        let peer_count = state.get_nb_connected_neighbours();
-                        state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .insert(message.message_initiator_id.clone(), peer_count as i64);
-                        state.parent_addr_for_transaction_wave.insert(
-                            message.message_initiator_id.clone(),
-                            "0.0.0.0:0".parse().unwrap(),
-                        );
-                    }
-                }
-            }
+    log::debug!(
+        "Message received from site {} : {:?}",
+        message.sender_addr,
+        message.clone()
+    );
 
-            NetworkMessageCode::AckGlobalMutex => {
-                // Message rouge
-                let mut state = LOCAL_APP_STATE.lock().await;
+    {
+        let mut state = node.state.lock().await;
+        state.add_site_id(
+            message.message_initiator_id.clone(),
+            message.message_initiator_addr.clone(),
+        );
+        // Any message is a sign of life from the neighbour that sent it
+        state
+            .failure_detector
+            .heartbeat(message.sender_addr, std::time::Instant::now());
+        if matches!(
+            message.code,
+            NetworkMessageCode::AcquireMutex
+                | NetworkMessageCode::AckGlobalMutex
+                | NetworkMessageCode::ReleaseGlobalMutex
+                | NetworkMessageCode::AckReleaseGlobalMutex
+                | NetworkMessageCode::Mutex
+        ) {
+            state.mutex_stats.messages_received += 1;
+        }
+    }
 
-                let nb_neighbours = state.get_nb_connected_neighbours();
-                let current_value = state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .get(&message.message_initiator_id)
-                    .copied()
-                    .unwrap_or(nb_neighbours);
-                state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .insert(message.message_initiator_id.clone(), current_value - 1);
+    if message.code == NetworkMessageCode::Transaction {
+        crate::snapshot::record_in_flight(node, &message).await;
+    }
 
-                if state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .get(&message.message_initiator_id.clone())
-                    .copied()
-                    .unwrap_or(-1)
-                    == 0
-                {
-                    if state
-                        .parent_addr_for_transaction_wave
-                        .get(&message.message_initiator_id.clone())
-                        .copied()
-                        .unwrap_or("99.99.99.99:0".parse().unwrap())
-                        == state.get_site_addr()
-                    {
-                        // on est chez le parent
-                        // diffusion terminée
-                        // Réinitialisation
-
-                        println!("\x1b[1;31mDiffusion terminée et réussie !\x1b[0m");
-                        state.try_enter_sc();
-                    } else {
-                        log::debug!(
-                            "On est de le noeud {}. On a reçu un rouge de tous nos fils: on acquite au parent {}",
-                            state.get_site_addr(),
-                            state
-                                .get_parent_addr_for_wave(message.message_initiator_id.clone())
-                                .to_string()
-                                .as_str()
-                        );
-                        send_message(
-                            state.get_parent_addr_for_wave(message.message_initiator_id.clone()),
-                            MessageInfo::AckMutex(crate::message::AckMutexPayload {
-                                clock: message.clock.get_lamport().clone(),
-                            }),
-                            None,
-                            NetworkMessageCode::AckGlobalMutex,
-                            state.get_site_addr(),
-                            &state.get_site_id().to_string(),
-                            &message.message_initiator_id,
-                            message.message_initiator_addr,
-                            state.get_clock().clone(),
-                        )
-                        .await?;
-                    }
+    match message.code {
+        NetworkMessageCode::AcquireMutex
+        | NetworkMessageCode::AckGlobalMutex
+        | NetworkMessageCode::ReleaseGlobalMutex
+        | NetworkMessageCode::AckReleaseGlobalMutex
+        | NetworkMessageCode::Transaction
+        | NetworkMessageCode::TransactionAcknowledgement
+        | NetworkMessageCode::SnapshotRequest
+        | NetworkMessageCode::SnapshotResponse => {
+            crate::wave::handle_wave_message(node, &message).await?;
+        }
 
-                    let peer_count = state.get_nb_connected_neighbours();
-                    state
-                        .attended_neighbours_nb_for_transaction_wave
-                        .insert(message.message_initiator_id.clone(), peer_count as i64);
-                    state.parent_addr_for_transaction_wave.insert(
-                        message.message_initiator_id.clone(),
-                        "0.0.0.0:0".parse().unwrap(),
-                    );
-                }
+        NetworkMessageCode::Mutex => {
+            if let MessageInfo::Mutex(payload) = &message.info {
+                let mut state = node.state.lock().await;
+                state
+                    .receive_mutex(node, &message.sender_id, payload.clone())
+                    .await?;
             }
+        }
 
-            NetworkMessageCode::AckReleaseGlobalMutex => {
-                // Message rouge
-                let mut state = LOCAL_APP_STATE.lock().await;
-
-                let nb_neighbours = state.get_nb_connected_neighbours();
-                let current_value = state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .get(&message.message_initiator_id)
-                    .copied()
-                    .unwrap_or(nb_neighbours);
-                state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .insert(message.message_initiator_id.clone(), current_value - 1);
+        NetworkMessageCode::AntiEntropy => {
+            if let MessageInfo::AntiEntropy(payload) = message.info.clone() {
+                crate::merkle::handle_anti_entropy(node, message.sender_addr, payload).await?;
+            }
+        }
 
-                if state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .get(&message.message_initiator_id.clone())
-                    .copied()
-                    .unwrap_or(-1)
-                    == 0
-                {
-                    if state
-                        .parent_addr_for_transaction_wave
-                        .get(&message.message_initiator_id.clone())
-                        .copied()
-                        .unwrap_or("99.99.99.99:0".parse().unwrap())
-                        == state.get_site_addr()
-                    {
-                        // on est chez le parent
-                        // diffusion terminée
-                        // Réinitialisation
-
-                        println!("\x1b[1;31mDiffusion terminée et réussie !\x1b[0m");
-                        // On vient de release la section critique, on peut essayer d'y entrer à nouveau
-                        state.try_enter_sc();
-                    } else {
-                        log::debug!(
-                            "On est de le noeud {}. On a reçu un rouge de tous nos fils: on acquite au parent {}",
-                            state.get_site_addr(),
-                            state
-                                .get_parent_addr_for_wave(message.message_initiator_id.clone())
-                                .to_string()
-                                .as_str()
-                        );
-                        send_message(
-                            state.get_parent_addr_for_wave(message.message_initiator_id.clone()),
-                            MessageInfo::None,
-                            None,
-                            NetworkMessageCode::AckReleaseGlobalMutex,
-                            state.get_site_addr(),
-                            &state.get_site_id().to_string(),
-                            &message.message_initiator_id,
-                            message.message_initiator_addr,
-                            state.get_clock().clone(),
-                        )
-                        .await?;
-                    }
+        NetworkMessageCode::SnapshotMarker => {
+            crate::snapshot::receive_marker(node, &message).await?;
+        }
 
-                    let peer_count = state.get_nb_connected_neighbours();
-                    state
-                        .attended_neighbours_nb_for_transaction_wave
-                        .insert(message.message_initiator_id.clone(), peer_count as i64);
-                    state.parent_addr_for_transaction_wave.insert(
-                        message.message_initiator_id.clone(),
-                        "0.0.0.0:0".parse().unwrap(),
-                    );
-                }
+        NetworkMessageCode::SnapshotRecorded => {
+            if let MessageInfo::SnapshotResponse(resp) = message.info.clone() {
+                let site_id = node.state.lock().await.get_site_id();
+                crate::snapshot::collect_recorded(node, resp, &site_id).await?;
             }
+        }
 
-            NetworkMessageCode::ReleaseGlobalMutex => {
-                // A node is releasing the critical section
-                {
-                    let mut st = LOCAL_APP_STATE.lock().await;
-                    st.global_mutex_fifo.remove(&message.message_initiator_id);
-                    st.try_enter_sc();
-                }
-                // wave diffusion
-                let mut diffuse = false;
-                let (local_site_id, local_site_addr) = {
-                    let mut state = LOCAL_APP_STATE.lock().await;
-                    let parent_id = state
-                        .parent_addr_for_transaction_wave
-                        .get(&message.message_initiator_id)
-                        .unwrap_or(&"0.0.0.0:0".parse().unwrap())
-                        .to_string();
-                    if parent_id == "0.0.0.0:0" {
-                        state.set_parent_addr(
-                            message.message_initiator_id.clone(),
-                            message.sender_addr,
-                        );
-
-                        let nb_neighbours = state.get_nb_connected_neighbours();
-                        let current_value = state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .get(&message.message_initiator_id)
-                            .copied()
-                            .unwrap_or(nb_neighbours);
-
-                        state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .insert(message.message_initiator_id.clone(), current_value - 1);
-
-                        log::debug!("Nombre de voisin : {}", current_value - 1);
-
-                        diffuse = state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .get(&message.message_initiator_id)
-                            .copied()
-                            .unwrap_or(0)
-                            > 0;
-                    }
-                    (state.get_site_id(), state.get_site_addr())
-                };
+        NetworkMessageCode::CatchUp => {
+            if let MessageInfo::CatchUp(payload) = message.info.clone() {
+                crate::catch_up::handle_catch_up(node, message.sender_addr, payload).await?;
+            }
+        }
 
-                if diffuse {
-                    let mut snd_msg = message.clone();
-                    snd_msg.sender_id = local_site_id.to_string();
-                    snd_msg.sender_addr = local_site_addr;
-                    diffuse_message(&snd_msg).await?;
-                } else {
-                    let (parent_addr, local_addr, site_id) = {
-                        let state = LOCAL_APP_STATE.lock().await;
-                        (
-                            state.get_parent_addr_for_wave(message.message_initiator_id.clone()),
-                            &state.get_site_addr(),
-                            &state.get_site_id().to_string(),
-                        )
-                    };
-                    // Acquit message to parent
-                    log::debug!(
-                        "Réception d'un message de relachement de mutex global, on est sur une feuille, on acquite, envoie à {}",
-                        message.sender_addr.to_string().as_str()
-                    );
-                    send_message(
-                        message.sender_addr,
-                        MessageInfo::None,
-                        None,
-                        NetworkMessageCode::AckReleaseGlobalMutex,
-                        *local_addr,
-                        site_id,
-                        &message.message_initiator_id,
-                        message.message_initiator_addr,
-                        message.clock.clone(),
-                    )
-                    .await?;
+        NetworkMessageCode::Discovery => {
+            let mut state = node.state.lock().await;
 
-                    if message.sender_addr == parent_addr {
-                        // réinitialisation s'il s'agit de la remontée après réception des rouges de tous les fils
-                        let mut state = LOCAL_APP_STATE.lock().await;
-                        let peer_count = state.get_nb_connected_neighbours();
-                        state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .insert(message.message_initiator_id.clone(), peer_count as i64);
-                        state.parent_addr_for_transaction_wave.insert(
-                            message.message_initiator_id.clone(),
-                            "0.0.0.0:0".parse().unwrap(),
-                        );
-                    }
-                }
+            if let MessageInfo::Membership(payload) = &message.info {
+                state.membership.merge(&payload.members);
             }
 
-            NetworkMessageCode::Discovery => {
-                let mut state = LOCAL_APP_STATE.lock().await;
-
-                // Try to add this new site as a new peer
-                state.add_incomming_peer(
-                    message.message_initiator_addr,
-                    socket_of_the_sender,
-                    message.clock.clone(),
-                );
+            // Try to add this new site as a new peer
+            state.add_incomming_peer(
+                message.message_initiator_addr,
+                socket_of_the_sender,
+                message.clock.clone(),
+            );
 
-                // Return ack message if this we are connected to the site
+            // Return ack message if this we are connected to the site
+            if state
+                .get_connected_nei_addr()
+                .iter()
+                .find(|addr| addr == &&message.sender_addr)
+                .is_some()
+            {
                 if state
                     .get_connected_nei_addr()
                     .iter()
                     .find(|addr| addr == &&message.sender_addr)
-                    .is_some()
+                    .is_none()
                 {
-                    if state
-                        .get_connected_nei_addr()
-                        .iter()
-                        .find(|addr| addr == &&message.sender_addr)
-                        .is_none()
-                    {
-                        state.add_connected_neighbour(message.sender_addr);
-                    }
-                    send_message(
-                        message.sender_addr,
-                        MessageInfo::Acknowledge(crate::message::AcknowledgePayload {
-                            global_fifo: state.get_global_mutex_fifo().clone(),
-                        }),
-                        None,
-                        NetworkMessageCode::Acknowledgment,
-                        state.get_site_addr(),
-                        state.get_site_id().as_str(),
-                        &message.message_initiator_id.clone(),
-                        message.message_initiator_addr,
-                        state.get_clock(),
-                    )
-                    .await?;
-                }
-            }
-
-            NetworkMessageCode::Acknowledgment => {
-                let ready_to_sync = {
-                    let mut state = LOCAL_APP_STATE.lock().await;
-                    // If the site received an acknoledgement from a site,
-                    // It can be a site that is not in the network anymore
-                    state.add_incomming_peer(
-                        message.sender_addr,
-                        socket_of_the_sender,
-                        message.clock.clone(),
-                    );
-                    if message.message_initiator_addr == state.get_site_addr() {
-                        for (site_id, nb_a_i) in state.get_nb_nei_for_wave().iter() {
-                            state
-                                .attended_neighbours_nb_for_transaction_wave
-                                .insert(site_id.clone(), *nb_a_i + 1);
-                        }
-                    }
-                    // If we are in sync mode, we can start the sync process
-                    // And we have received all the responses from the first attended neighbours counter
-                    // We can start the sync process by starting a snapshot with sync mode
-                    state.get_sync()
-                        && state.get_nb_first_attended_neighbours()
-                            == state.get_nb_connected_neighbours()
-                };
-
-                // Récupérer le global_fifo envoyé dans l'acknowledgment
-                let global_fifo = match &message.info {
-                    MessageInfo::Acknowledge(payload) => Some(payload.global_fifo.clone()),
-                    _ => None,
-                };
-
-                if let Some(global_fifo) = global_fifo {
-                    let mut state = LOCAL_APP_STATE.lock().await;
-                    state.set_global_mutex_fifo(global_fifo);
-                }
-
-                if ready_to_sync {
-                    log::info!("All neighbours have responded, starting synchronization");
-                    crate::control::enqueue_critical(
-                        crate::control::CriticalCommands::SyncSnapshot,
-                    )
-                    .await?;
+                    state.add_connected_neighbour(message.sender_addr);
                 }
+                send_message(
+                    node,
+                    message.sender_addr,
+                    MessageInfo::Acknowledge(crate::message::AcknowledgePayload {
+                        global_fifo: state.get_global_mutex_fifo().clone(),
+                        members: state.membership.digest(),
+                    }),
+                    NetworkMessageCode::Acknowledgment,
+                    state.get_site_addr(),
+                    state.get_site_id().as_str(),
+                    crate::message::Origin {
+                        wave: crate::message::WaveId {
+                            initiator_id: message.message_initiator_id.clone(),
+                            seq: 0,
+                        },
+                        initiator_addr: message.message_initiator_addr,
+                    },
+                    state.get_clock(),
+                )
+                .await?;
             }
+        }
 
-            NetworkMessageCode::Transaction => {
-                // messages bleus
-                if message.command.is_some() {
-                    if let Err(e) = crate::control::process_network_command(
-                        message.info.clone(),
-                        message.clock.clone(),
-                        message.message_initiator_id.as_str(),
-                    )
-                    .await
-                    {
-                        log::error!("Error handling command:\n{}", e);
-                    }
-                    // wave diffusion
-                    let mut diffuse = false;
-                    let (local_site_id, local_site_addr) = {
-                        let mut state = LOCAL_APP_STATE.lock().await;
-                        let parent_id = state
-                            .parent_addr_for_transaction_wave
-                            .get(&message.message_initiator_id.clone())
-                            .unwrap_or(&"0.0.0.0:0".parse().unwrap())
-                            .to_string();
-                        if parent_id == "0.0.0.0:0" {
-                            state.set_parent_addr(
-                                message.message_initiator_id.clone(),
-                                message.sender_addr,
-                            );
-
-                            let nb_neighbours = state.get_nb_connected_neighbours();
-                            let current_value = state
-                                .attended_neighbours_nb_for_transaction_wave
-                                .get(&message.message_initiator_id)
-                                .copied()
-                                .unwrap_or(nb_neighbours);
-
-                            state
-                                .attended_neighbours_nb_for_transaction_wave
-                                .insert(message.message_initiator_id.clone(), current_value - 1);
-
-                            log::debug!("Nombre de voisin : {}", current_value - 1);
-
-                            diffuse = state
-                                .attended_neighbours_nb_for_transaction_wave
-                                .get(&message.message_initiator_id.clone())
-                                .copied()
-                                .unwrap_or(0)
-                                > 0;
-                        }
-                        (state.get_site_id(), state.get_site_addr())
-                    };
-
-                    if diffuse {
-                        let mut snd_msg = message.clone();
-                        snd_msg.sender_id = local_site_id.to_string();
-                        snd_msg.sender_addr = local_site_addr;
-                        diffuse_message(&snd_msg).await?;
-                    } else {
-                        let (parent_addr, local_addr, site_id) = {
-                            let state = LOCAL_APP_STATE.lock().await;
-                            (
-                                state
-                                    .get_parent_addr_for_wave(message.message_initiator_id.clone()),
+        NetworkMessageCode::Acknowledgment => {
+            let (ready_to_sync, restart_clock) = {
+                let mut state = node.state.lock().await;
+                // If the site received an acknoledgement from a site,
+                // It can be a site that is not in the network anymore
+                state.add_incomming_peer(
+                    message.sender_addr,
+                    socket_of_the_sender,
+                    message.clock.clone(),
+                );
+                // Without configured peers, we connect to every member the seed told us about
+                if let MessageInfo::Acknowledge(payload) = &message.info {
+                    let joined = state.membership.merge(&payload.members);
+                    if state.get_cli_peers_addrs().is_empty() {
+                        for addr in joined {
+                            if state.get_connected_nei_addr().contains(&addr) {
+                                continue;
+                            }
+                            let result = send_message(
+                                node,
+                                addr,
+                                MessageInfo::Membership(state.get_membership_payload()),
+                                NetworkMessageCode::Discovery,
                                 state.get_site_addr(),
-                                state.get_site_id().to_string(),
+                                state.get_site_id().as_str(),
+                                crate::message::Origin::local(
+                                    state.get_site_id().as_str(),
+                                    state.get_site_addr(),
+                                ),
+                                state.get_clock(),
                             )
-                        };
-                        // Acquit message to parent
-                        log::debug!(
-                            "Réception d'un message de transaction, on est sur une feuille, on acquite, envoie à {}",
-                            message.sender_addr.to_string().as_str()
-                        );
-                        send_message(
-                            message.sender_addr,
-                            MessageInfo::None,
-                            None,
-                            NetworkMessageCode::TransactionAcknowledgement,
-                            local_addr,
-                            site_id.as_str(),
-                            &message.message_initiator_id.clone(),
-                            message.message_initiator_addr,
-                            message.clock.clone(),
-                        )
-                        .await?;
-
-                        if message.sender_addr == parent_addr {
-                            // réinitialisation s'il s'agit de la remontée après réception des rouges de tous les fils
-                            let mut state = LOCAL_APP_STATE.lock().await;
-                            let peer_count = state.get_nb_connected_neighbours();
-                            state
-                                .attended_neighbours_nb_for_transaction_wave
-                                .insert(message.message_initiator_id.clone(), peer_count as i64);
-                            state
-                                .parent_addr_for_transaction_wave
-                                .insert(message.message_initiator_id, "0.0.0.0:0".parse().unwrap());
+                            .await;
+                            match result {
+                                Ok(()) => {
+                                    // One more acknowledgment to wait for before synchronizing
+                                    let expected = state.get_nb_first_attended_neighbours() + 1;
+                                    state.init_nb_first_attended_neighbours(expected);
+                                }
+                                Err(e) => log::warn!("Cannot reach member {}: {}", addr, e),
+                            }
                         }
                     }
-                } else {
-                    log::error!("Command is None for Transaction message");
                 }
-            }
-            NetworkMessageCode::TransactionAcknowledgement => {
-                let mut should_reset = false;
-
-                // Message rouge
-                let mut state = LOCAL_APP_STATE.lock().await;
-
-                let nb_neighbours = state.get_nb_connected_neighbours();
-                let current_value = state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .get(&message.message_initiator_id)
-                    .copied()
-                    .unwrap_or(nb_neighbours);
-                state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .insert(message.message_initiator_id.clone(), current_value - 1);
 
-                if state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .get(&message.message_initiator_id)
-                    .copied()
-                    .unwrap_or(-1)
-                    == 0
-                {
-                    if state
-                        .parent_addr_for_transaction_wave
-                        .get(&message.message_initiator_id)
-                        .copied()
-                        .unwrap_or("99.99.99.99:0".parse().unwrap())
-                        == state.get_site_addr()
-                    {
-                        // on est chez le parent
-                        // diffusion terminée
-                        // Réinitialisation
-
-                        println!("\x1b[1;31mDiffusion terminée et réussie !\x1b[0m");
-                        should_reset = true;
-                    } else {
-                        log::debug!(
-                            "On est dans le noeud {}. On a reçu un rouge de tous nos fils: on acquite au parent {}",
-                            state.get_site_addr().to_string().as_str(),
-                            state
-                                .get_parent_addr_for_wave(message.message_initiator_id.clone())
-                                .to_string()
-                                .as_str()
-                        );
-                        send_message(
-                            state.get_parent_addr_for_wave(message.message_initiator_id.clone()),
-                            MessageInfo::None,
-                            None,
-                            NetworkMessageCode::TransactionAcknowledgement,
-                            state.get_site_addr(),
-                            &state.get_site_id().to_string(),
-                            &message.message_initiator_id,
-                            message.message_initiator_addr,
-                            state.get_clock(),
-                        )
-                        .await?;
-                    }
-
-                    let peer_count = state.get_nb_connected_neighbours();
-                    state
-                        .attended_neighbours_nb_for_transaction_wave
-                        .insert(message.message_initiator_id.clone(), peer_count as i64);
-                    state
-                        .parent_addr_for_transaction_wave
-                        .insert(message.message_initiator_id, "0.0.0.0:0".parse().unwrap());
-
-                    if should_reset && state.pending_commands.len() == 0 {
-                        // fin de la section critique on peut notifier les pairs
-                        state.release_mutex().await?;
-                    };
-                }
+                // If we are in sync mode, we can start the sync process
+                // And we have received all the responses from the first attended neighbours counter
+                // We can start the sync process by comparing our transactions with the neighbours
+                let ready_to_sync = state.get_sync()
+                    && state.get_nb_first_attended_neighbours()
+                        == state.get_nb_connected_neighbours();
+                // A site restarting from its database only asks for what it missed
+                let restart_clock = if ready_to_sync {
+                    state.take_restart_clock()
+                } else {
+                    None
+                };
+                (ready_to_sync, restart_clock)
+            };
+
+            // Récupérer le global_fifo envoyé dans l'acknowledgment
+            let global_fifo = match &message.info {
+                MessageInfo::Acknowledge(payload) => Some(payload.global_fifo.clone()),
+                _ => None,
+            };
+
+            if let Some(global_fifo) = global_fifo {
+                let mut state = node.state.lock().await;
+                state.set_global_mutex_fifo(global_fifo);
             }
 
-            NetworkMessageCode::Error => {
-                log::debug!("Error message received: {:?}", message);
-            }
-            NetworkMessageCode::Disconnect => {
-                {
-                    let mut state = LOCAL_APP_STATE.lock().await;
-                    state.remove_peer(message.message_initiator_addr).await;
+            if ready_to_sync {
+                log::info!("All neighbours have responded, starting synchronization");
+                match restart_clock {
+                    Some(clock) => crate::catch_up::start_catch_up(node, clock).await?,
+                    None => crate::merkle::start_anti_entropy(node).await?,
                 }
-                println!(
-                    "\x1b[1;31mSITE {} DISCONNECTED !\x1b[0m",
-                    message.message_initiator_id
-                );
             }
-            NetworkMessageCode::SnapshotRequest => {
-                // messages bleus
-                // wave diffusion
-                let mut diffuse = false;
-                let (local_site_id, local_site_addr) = {
-                    let mut state = LOCAL_APP_STATE.lock().await;
-                    let parent_id = state
-                        .parent_addr_for_transaction_wave
-                        .get(&message.message_initiator_id.clone())
-                        .unwrap_or(&"0.0.0.0:0".parse().unwrap())
-                        .to_string();
-                    if parent_id == "0.0.0.0:0" {
-                        state.set_parent_addr(
-                            message.message_initiator_id.clone(),
-                            message.sender_addr,
-                        );
-
-                        let nb_neighbours = state.get_nb_connected_neighbours();
-                        let current_value = state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .get(&message.message_initiator_id)
-                            .copied()
-                            .unwrap_or(nb_neighbours);
-
-                        state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .insert(message.message_initiator_id.clone(), current_value - 1);
-
-                        log::debug!("Nombre de voisin : {}", current_value - 1);
-
-                        diffuse = state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .get(&message.message_initiator_id.clone())
-                            .copied()
-                            .unwrap_or(0)
-                            > 0;
-                    }
-                    (state.get_site_id(), state.get_site_addr())
-                };
-
-                if diffuse {
-                    let mut snd_msg = message.clone();
-                    snd_msg.sender_id = local_site_id.to_string();
-                    snd_msg.sender_addr = local_site_addr;
-                    // Here we should diffuse the message because we are not on a leaf
-                    // We should also start a snapshot in network mode
-                    // When this snapshot is done, we should send the global snapshot to the parent
-                    log::debug!(
-                        "We are not on a leaf, we start our own global snapshot construction and diffuse the request to other nodes"
-                    );
-                    crate::snapshot::start_snapshot(crate::snapshot::SnapshotMode::NetworkMode)
-                        .await?;
-                    // When can then diffuse the request to other nodes
-                    diffuse_message(&snd_msg).await?;
-                } else {
-                    let parent_addr = {
-                        let state = LOCAL_APP_STATE.lock().await;
-                        state.get_parent_addr_for_wave(message.message_initiator_id.clone())
-                    };
-                    // Acquit message to parent
-                    log::debug!(
-                        "Réception d'une demande de snapshot, on est sur une feuille, on crée un snapshot local, on envoie à {}",
-                        message.sender_addr.to_string().as_str()
-                    );
-                    // Here we are on a leaf, we can crate a local snapshot and send it to the parent
-                    let txs = crate::db::get_local_transaction_log()?;
-                    let summaries: Vec<_> = txs.iter().map(|t| t.into()).collect();
-
-                    let (site_id, clock, local_addr) = {
-                        let st = LOCAL_APP_STATE.lock().await;
-                        (st.get_site_id(), st.get_clock(), st.get_site_addr())
-                    };
-
-                    send_message(
-                        message.sender_addr,
-                        MessageInfo::SnapshotResponse(crate::message::SnapshotResponse {
-                            site_id: site_id.clone(),
-                            clock: clock.clone(),
-                            tx_log: summaries,
-                        }),
-                        None,
-                        NetworkMessageCode::SnapshotResponse,
-                        local_addr,
-                        &site_id,
-                        &message.message_initiator_id,
-                        message.message_initiator_addr,
-                        clock.clone(),
-                    )
-                    .await?;
+        }
 
-                    if message.sender_addr == parent_addr {
-                        // réinitialisation s'il s'agit de la remontée après réception des rouges de tous les fils
-                        let mut state = LOCAL_APP_STATE.lock().await;
-                        let peer_count = state.get_nb_connected_neighbours();
-                        state
-                            .attended_neighbours_nb_for_transaction_wave
-                            .insert(message.message_initiator_id.clone(), peer_count as i64);
-                        state
-                            .parent_addr_for_transaction_wave
-                            .insert(message.message_initiator_id, "0.0.0.0:0".parse().unwrap());
-                    }
-                }
+        NetworkMessageCode::Heartbeat => {
+            // Heartbeats piggyback the membership view
+            if let MessageInfo::Membership(payload) = &message.info {
+                let mut state = node.state.lock().await;
+                state.membership.merge(&payload.members);
             }
-            NetworkMessageCode::SnapshotResponse => {
-                // Message rouge
-                let mut should_reset = false;
-                let mut state = LOCAL_APP_STATE.lock().await;
-
-                let nb_neighbours = state.get_nb_connected_neighbours();
-                let current_value = state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .get(&message.message_initiator_id)
-                    .copied()
-                    .unwrap_or(nb_neighbours);
+            // Liveness has already been recorded, and a heartbeat must not move the clock
+            return Ok(());
+        }
+        NetworkMessageCode::KeyRequest => {
+            // The key of the sender was pinned with the envelope, we answer with ours
+            let (site_addr, site_id, clock) = {
+                let state = node.state.lock().await;
+                (
+                    state.get_site_addr(),
+                    state.get_site_id(),
+                    state.get_clock(),
+                )
+            };
+            send_message(
+                node,
+                message.sender_addr,
+                MessageInfo::None,
+                NetworkMessageCode::KeyAnnouncement,
+                site_addr,
+                &site_id,
+                crate::message::Origin::local(&site_id, site_addr),
+                clock,
+            )
+            .await?;
+            return Ok(());
+        }
+        NetworkMessageCode::KeyAnnouncement => {
+            // The key was pinned with the envelope, and a key exchange is not an event
+            return Ok(());
+        }
+        NetworkMessageCode::Error => {
+            log::debug!("Error message received: {:?}", message);
+        }
+        NetworkMessageCode::Disconnect => {
+            {
+                let mut state = node.state.lock().await;
                 state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .insert(message.message_initiator_id.clone(), current_value - 1);
-
-                if state
-                    .attended_neighbours_nb_for_transaction_wave
-                    .get(&message.message_initiator_id)
-                    .copied()
-                    .unwrap_or(-1)
-                    == 0
-                {
-                    if state
-                        .parent_addr_for_transaction_wave
-                        .get(&message.message_initiator_id)
-                        .copied()
-                        .unwrap_or("99.99.99.99:0".parse().unwrap())
-                        == state.get_site_addr()
-                    {
-                        // on est chez le parent
-                        // diffusion terminée
-                        // Réinitialisation
-                        log::debug!(
-                            "L'initiateur à reçu toutes ses snapshots, il devrait créer sa snapshot globale"
-                        );
-                        if let MessageInfo::SnapshotResponse(resp) = message.info {
-                            let mut mgr = crate::snapshot::LOCAL_SNAPSHOT_MANAGER.lock().await;
-                            if mgr.mode == crate::snapshot::SnapshotMode::FileMode {
-                                log::debug!("La snapshot devrait être sauvegardée");
-                                if let Some(gs) = mgr.push(resp) {
-                                    log::info!(
-                                        "Global snapshot ready to save, hold per site : {:#?}",
-                                        gs.missing
-                                    );
-                                    mgr.path = crate::snapshot::persist(&gs, state.get_site_id())
-                                        .await
-                                        .unwrap()
-                                        .parse()
-                                        .ok();
-                                }
-                            } else if mgr.mode == crate::snapshot::SnapshotMode::SyncMode {
-                                log::debug!(
-                                    "La snapshot devrait être utilisée pour la synchronisation"
-                                );
-                                if let Some(gs) = mgr.push(resp) {
-                                    log::info!(
-                                        "Global snapshot ready to be synced, hold per site : {:#?}",
-                                        gs.missing
-                                    );
-                                    crate::db::update_db_with_snapshot(
-                                        &gs,
-                                        state.get_clock().get_vector_clock_map(),
-                                    );
-                                }
-                            }
-                        }
-
-                        println!("\x1b[1;31mDiffusion terminée et réussie !\x1b[0m");
-                        should_reset = true;
-                    } else {
-                        log::debug!(
-                            "On est dans le noeud {}. On a reçu un rouge de tous nos fils: on acquite au parent {}",
-                            state.get_site_addr().to_string().as_str(),
-                            state
-                                .get_parent_addr_for_wave(message.message_initiator_id.clone())
-                                .to_string()
-                                .as_str()
-                        );
-                        log::debug!(
-                            "On devrait pouvoir construire une snapshot globale avec tous nos voisins et l'envoyer à l'adresse de notre parent {}",
-                            state
-                                .get_parent_addr_for_wave(message.message_initiator_id.clone())
-                                .to_string()
-                                .as_str()
-                        );
-                        if let MessageInfo::SnapshotResponse(resp) = message.info {
-                            let mut mgr = crate::snapshot::LOCAL_SNAPSHOT_MANAGER.lock().await;
-                            if mgr.mode == crate::snapshot::SnapshotMode::NetworkMode {
-                                log::debug!("La snapshot devrait être envoyés au père");
-                                if let Some(gs) = mgr.push(resp) {
-                                    log::info!(
-                                        "Global snapshot ready to be send to parent, hold per site : {:#?}",
-                                        gs.missing
-                                    );
-                                    send_message(
-                                        state.get_parent_addr_for_wave(
-                                            message.message_initiator_id.clone(),
-                                        ),
-                                        MessageInfo::SnapshotResponse(
-                                            crate::message::SnapshotResponse {
-                                                site_id: state.get_site_id().to_string(),
-                                                clock: state.get_clock(),
-                                                tx_log: gs.all_transactions.into_iter().collect(),
-                                            },
-                                        ),
-                                        None,
-                                        NetworkMessageCode::SnapshotResponse,
-                                        state.get_site_addr(),
-                                        &state.get_site_id().to_string(),
-                                        &message.message_initiator_id,
-                                        message.message_initiator_addr,
-                                        state.get_clock(),
-                                    )
-                                    .await?;
-                                } else {
-                                    log::error!("Le site aurait du récupérer toutes ses snapshots");
-                                }
-                            }
-                        } else {
-                            log::error!("Message de type SnapshotResponse attendu, mais pas reçu");
-                        }
-                    }
-
-                    let peer_count = state.get_nb_connected_neighbours();
-                    state
-                        .attended_neighbours_nb_for_transaction_wave
-                        .insert(message.message_initiator_id.clone(), peer_count as i64);
-                    state
-                        .parent_addr_for_transaction_wave
-                        .insert(message.message_initiator_id, "0.0.0.0:0".parse().unwrap());
-                    if should_reset && state.pending_commands.len() == 0 {
-                        // fin de la section critique on peut notifier les pairs
-                        state.release_mutex().await?;
-                    };
-                } else {
-                    log::debug!(
-                        "On a reçu un message rouge d'un des fils mais la vague n'est pas encore terminée"
-                    );
-                    // We should add the Snapshot to our manager
-                    if let MessageInfo::SnapshotResponse(resp) = message.info {
-                        let mut mgr = crate::snapshot::LOCAL_SNAPSHOT_MANAGER.lock().await;
-                        log::debug!("La snapshot devrait être ajoutés à l'état du manager");
-                        if let Some(_) = mgr.push(resp) {
-                            log::error!(
-                                "On ne devrait pas encore pouvoir construire une snapshot globale vu que la vague n'est pas terminée"
-                            );
-                        }
-                    } else {
-                        log::error!("Message de type SnapshotResponse attendu, mais pas reçu");
-                    }
-                }
+                    .remove_peer(node, message.message_initiator_addr)
+                    .await;
+                state.membership.mark(
+                    message.message_initiator_addr,
+                    crate::membership::MemberStatus::Left,
+                );
             }
+            println!(
+                "\x1b[1;31mSITE {} DISCONNECTED !\x1b[0m",
+                message.message_initiator_id
+            );
         }
-
-        let mut state = LOCAL_APP_STATE.lock().await;
-        state.update_clock(Some(&message.clock.clone())).await;
     }
+
+    let mut state = node.state.lock().await;
+    state
+        .update_clock(&node.db, Some(&message.clock.clone()))
+        .await;
+    Ok(())
 }
 
 #[cfg(feature = "server")]
 /// Send a message to a specific peer
+///
+/// The transactions carry their command, and are sent with [`send_built_message`].
+#[allow(clippy::too_many_arguments)]
 pub async fn send_message(
+    node: &crate::node::Node,
     recipient_address: std::net::SocketAddr,
     info: crate::message::MessageInfo,
-    command: Option<crate::control::Command>,
     code: crate::message::NetworkMessageCode,
     local_addr: std::net::SocketAddr,
     local_site: &str,
-    initiator_id: &str,
-    initiator_addr: std::net::SocketAddr,
+    origin: crate::message::Origin,
     sender_clock: crate::clock::Clock,
 ) -> Result<(), Box<dyn std::error::Error>> {
     use crate::message::Message;
-    use rmp_serde::encode;
-
-    if code == crate::message::NetworkMessageCode::Transaction && command.is_none() {
-        log::error!("Command is None for Transaction message");
-        return Err("Command is None for Transaction message".into());
-    }
 
     let msg = Message {
         sender_id: local_site.to_string(),
         sender_addr: local_addr,
-        message_initiator_id: initiator_id.to_string(),
+        message_initiator_id: origin.wave.initiator_id,
         clock: sender_clock,
-        command,
+        command: None,
         info,
         code,
-        message_initiator_addr: initiator_addr,
+        message_initiator_addr: origin.initiator_addr,
+        wave_seq: origin.wave.seq,
+        deps: None,
+        initiator_signature: None,
     };
+    send_built_message(node, recipient_address, &msg).await
+}
+
+#[cfg(feature = "server")]
+/// Signs what a wave initiated by the local site diffuses
+///
+/// The signature is relayed unchanged, see [`crate::identity::KeyRing::verify_initiator`].
+pub async fn sign_as_initiator(
+    node: &crate::node::Node,
+    message: &crate::message::Message,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let payload = message.initiator_payload()?;
+    let manager = node.network.lock().await;
+    Ok(manager.identity.sign(payload, false).signature)
+}
+
+#[cfg(feature = "server")]
+/// Send a message already built to a specific peer
+pub async fn send_built_message(
+    node: &crate::node::Node,
+    recipient_address: std::net::SocketAddr,
+    msg: &crate::message::Message,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use rmp_serde::encode;
+
+    if msg.code == crate::message::NetworkMessageCode::Transaction && msg.command.is_none() {
+        log::error!("Command is None for Transaction message");
+        return Err("Command is None for Transaction message".into());
+    }
 
     if recipient_address.ip().is_unspecified() || recipient_address.port() == 0 {
         log::warn!("Skipping invalid peer address {}", recipient_address);
         return Ok(());
     }
 
-    let buf = encode::to_vec(&msg)?;
+    let buf = encode::to_vec(msg)?;
+
+    let mut manager = node.network.lock().await;
+
+    // The public key is announced with the messages that open a connection
+    let announce_key = matches!(
+        msg.code,
+        crate::message::NetworkMessageCode::Discovery
+            | crate::message::NetworkMessageCode::Acknowledgment
+            | crate::message::NetworkMessageCode::KeyRequest
+            | crate::message::NetworkMessageCode::KeyAnnouncement
+    );
+    let buf = encode::to_vec(&manager.identity.sign(buf, announce_key))?;
 
-    let mut manager = NETWORK_MANAGER.lock().await;
+    // Refuse the message here rather than letting the writer task drop it
+    crate::codec::FrameCodec::new(manager.max_frame_size).check_size(buf.len())?;
 
     let sender = match manager.get_sender(&recipient_address) {
         Some(s) => s,
         None => {
             if let Err(e) = manager.create_connection(recipient_address).await {
-                return Err(format!(
-                    "error with connection to {}: {}",
-                    recipient_address.to_string(),
-                    e
-                )
-                .into());
+                return Err(
+                    format!("error with connection to {}: {}", recipient_address, e).into(),
+                );
             }
             match manager.get_sender(&recipient_address) {
                 Some(s) => s,
@@ -1185,7 +773,7 @@ pub async fn send_message(
             return Err(err_msg.into());
         }
     };
-    log::debug!("Sent message {:?} to {}", &msg, recipient_address);
+    log::debug!("Sent message {:?} to {}", msg, recipient_address);
     Ok(())
 }
 
@@ -1194,25 +782,25 @@ pub async fn send_message(
 ///
 /// Lock the app state and diffuse a message
 pub async fn diffuse_message(
+    node: &crate::node::Node,
     message: &crate::message::Message,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    use crate::state::LOCAL_APP_STATE;
-
     log::debug!(
         "Début de la diffusion d'un message de type {:?}",
         message.code
     );
 
     let (local_addr, site_id, connected_nei_addr, parent_address) = {
-        let state = LOCAL_APP_STATE.lock().await;
+        let state = node.state.lock().await;
         (
             state.get_site_addr(),
             state.get_site_id(),
             state.get_connected_nei_addr(),
-            state.get_parent_addr_for_wave(message.message_initiator_id.clone()),
+            state.get_parent_addr_for_wave(&message.wave_id()),
         )
     };
     diffuse_message_without_lock(
+        node,
         message,
         local_addr,
         &site_id,
@@ -1228,6 +816,7 @@ pub async fn diffuse_message(
 ///
 /// Diffuse a message without locking the app state
 pub async fn diffuse_message_without_lock(
+    node: &crate::node::Node,
     message: &crate::message::Message,
     local_addr: std::net::SocketAddr,
     site_id: &str,
@@ -1239,19 +828,10 @@ pub async fn diffuse_message_without_lock(
         if connected_nei != parent_address {
             log::debug!("Sending message to: {}", peer_addr_str);
 
-            if let Err(e) = send_message(
-                connected_nei,
-                message.info.clone(),
-                message.command.clone(),
-                message.code.clone(),
-                local_addr,
-                &site_id,
-                &message.message_initiator_id,
-                message.message_initiator_addr,
-                message.clock.clone(),
-            )
-            .await
-            {
+            let mut msg = message.clone();
+            msg.sender_id = site_id.to_string();
+            msg.sender_addr = local_addr;
+            if let Err(e) = send_built_message(node, connected_nei, &msg).await {
                 log::error!("❌ Impossible d’envoyer à {} : {}", peer_addr_str, e);
             }
         }
@@ -1276,18 +856,26 @@ mod tests {
         let clock = Clock::new();
 
         let _listener = TcpListener::bind(address).await?;
+        let node = crate::node::Node::new(
+            crate::state::AppState::new(local_site.to_string(), Vec::new(), local_addr),
+            crate::db::Db::in_memory(),
+        );
+        {
+            let transport = crate::transport::TcpTransport::bind(local_addr, None).await?;
+            let mut manager = node.network.lock().await;
+            manager.init_transport(std::sync::Arc::new(transport));
+        }
 
         let code = NetworkMessageCode::Discovery;
 
         let send_result = send_message(
+            &node,
             address,
             MessageInfo::None,
-            None,
             code,
             local_addr,
             local_site,
-            local_site,
-            local_addr,
+            crate::message::Origin::local(local_site, local_addr),
             clock,
         )
         .await;
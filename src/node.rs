@@ -0,0 +1,184 @@
+//! Context of a site
+//!
+//! A [`Node`] holds everything a site works on: its state, its connections to
+//! the other sites, its database and its snapshots. The handlers receive the
+//! node they run for instead of reaching for process-wide singletons, so
+//! several sites can run in the same process, as in the tests below where
+//! they talk over a `MemoryTransport`. The site started by `main` is
+//! [`Node::global`], built on the singletons.
+
+#![cfg(feature = "server")]
+
+/// State, connections, database and snapshots of a site
+///
+/// Cloning a node gives another handle on the same site. The locks are taken
+/// in the order state, then network manager or snapshot manager.
+#[derive(Clone)]
+pub struct Node {
+    /// State of the site
+    pub state: std::sync::Arc<tokio::sync::Mutex<crate::state::AppState>>,
+    /// Connections to the other sites
+    pub network: std::sync::Arc<tokio::sync::Mutex<crate::network::NetworkManager>>,
+    /// Database of the site, with the Merkle tree of its transactions
+    pub db: std::sync::Arc<crate::db::Db>,
+    /// Snapshots started or collected by the site
+    pub snapshots: std::sync::Arc<tokio::sync::Mutex<crate::snapshot::SnapshotManager>>,
+}
+
+lazy_static::lazy_static! {
+    static ref LOCAL_NODE: Node = Node {
+        state: crate::state::LOCAL_APP_STATE.clone(),
+        network: crate::network::NETWORK_MANAGER.clone(),
+        db: crate::db::DB_CONN.clone(),
+        snapshots: crate::snapshot::LOCAL_SNAPSHOT_MANAGER.clone(),
+    };
+}
+
+impl Node {
+    /// Creates a site from its state and its database, not connected yet
+    #[cfg(test)]
+    pub fn new(state: crate::state::AppState, db: crate::db::Db) -> Self {
+        Self {
+            state: std::sync::Arc::new(tokio::sync::Mutex::new(state)),
+            network: std::sync::Arc::new(tokio::sync::Mutex::new(
+                crate::network::NetworkManager::new(),
+            )),
+            db: std::sync::Arc::new(db),
+            snapshots: std::sync::Arc::new(tokio::sync::Mutex::new(
+                crate::snapshot::SnapshotManager::new(0),
+            )),
+        }
+    }
+
+    /// Site started by `main`, the one the web interface works on
+    pub fn global() -> &'static Node {
+        &LOCAL_NODE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> std::net::SocketAddr {
+        format!("10.0.0.1:{}", port).parse().unwrap()
+    }
+
+    /// Starts a site bound on `network`, with the other sites as its peers
+    fn start_site(
+        network: &std::sync::Arc<crate::transport::MemoryNetwork>,
+        site_id: &str,
+        port: u16,
+        peers: &[u16],
+    ) -> Node {
+        use crate::transport::Transport;
+
+        let mut state = crate::state::AppState::new(
+            site_id.to_string(),
+            peers.iter().map(|port| addr(*port)).collect(),
+            addr(port),
+        );
+        state.init_membership(1);
+        state.init_wave_seq(1);
+        let node = Node::new(state, crate::db::Db::in_memory());
+
+        let transport: std::sync::Arc<dyn Transport> =
+            std::sync::Arc::new(network.bind(addr(port)).unwrap());
+        node.network
+            .try_lock()
+            .unwrap()
+            .init_transport(transport.clone());
+        let listener = node.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, from)) = transport.accept().await {
+                crate::network::start_listening(listener.clone(), stream, from).await;
+            }
+        });
+        crate::control::control_worker(node.clone());
+        node
+    }
+
+    /// Waits until `ready` holds, polling every few milliseconds
+    async fn wait_until<F, Fut>(mut ready: F)
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = bool>,
+    {
+        tokio::time::timeout(std::time::Duration::from_secs(10), async {
+            while !ready().await {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the sites did not converge in time");
+    }
+
+    #[tokio::test]
+    async fn test_three_sites_agree_on_the_balances() {
+        use crate::control::{CriticalCommands, run_critical};
+        use crate::money::Money;
+
+        let network = crate::transport::MemoryNetwork::new();
+        let sites = [
+            start_site(&network, "A", 1, &[2, 3]),
+            start_site(&network, "B", 2, &[1, 3]),
+            start_site(&network, "C", 3, &[1, 2]),
+        ];
+        for site in &sites {
+            crate::network::announce(site, None).await;
+        }
+        wait_until(|| async {
+            for site in &sites {
+                if site.state.lock().await.get_connected_nei_addr().len() != 2 {
+                    return false;
+                }
+            }
+            true
+        })
+        .await;
+
+        let name = |name: &str| name.to_string();
+        run_critical(
+            &sites[0],
+            CriticalCommands::CreateUser {
+                name: name("alice"),
+            },
+        )
+        .await
+        .unwrap();
+        run_critical(
+            &sites[0],
+            CriticalCommands::Deposit {
+                name: name("alice"),
+                amount: Money::from_cents(10000),
+            },
+        )
+        .await
+        .unwrap();
+        run_critical(
+            &sites[1],
+            CriticalCommands::CreateUser { name: name("bob") },
+        )
+        .await
+        .unwrap();
+        run_critical(
+            &sites[2],
+            CriticalCommands::Transfer {
+                from: name("alice"),
+                to: name("bob"),
+                amount: Money::from_cents(2500),
+            },
+        )
+        .await
+        .unwrap();
+
+        let expected =
+            std::collections::BTreeMap::from([(name("alice"), 7500), (name("bob"), 2500)]);
+        wait_until(|| async {
+            sites
+                .iter()
+                .all(|site| crate::db::get_balances(&site.db).unwrap() == expected)
+        })
+        .await;
+    }
+}
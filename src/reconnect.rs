@@ -0,0 +1,208 @@
+//! Automatic reconnection to the configured peers
+//!
+//! The peers given with `--cli-peers` are supervised for the whole life of the
+//! site. When one of them is not a connected neighbour anymore, the supervisor
+//! drops the stale connection and sends a new `Discovery`, retrying with an
+//! exponential backoff. Once the peer acknowledges, the site synchronizes with
+//! the network as it does at launch.
+
+#![cfg(feature = "server")]
+
+/// Delay before the first reconnection attempt, in milliseconds
+const BASE_DELAY_MS: u64 = 500;
+
+/// Maximum delay between two reconnection attempts, in milliseconds
+const MAX_DELAY_MS: u64 = 30_000;
+
+/// Exponential backoff with jitter
+///
+/// The n-th delay is drawn uniformly in `[d / 2, d]` where
+/// `d = min(max, base * 2^n)`, so that sites which lost the same peer do not
+/// all retry at the same instant.
+pub struct Backoff {
+    /// Delay of the first attempt
+    base: std::time::Duration,
+    /// Upper bound of the delay
+    max: std::time::Duration,
+    /// Number of attempts since the last reset
+    attempt: u32,
+    /// State of the pseudo-random generator used for the jitter
+    seed: u64,
+}
+
+impl Backoff {
+    /// Creates a new backoff with a seed for the jitter
+    pub fn new(base: std::time::Duration, max: std::time::Duration, seed: u64) -> Self {
+        Self {
+            base,
+            max,
+            attempt: 0,
+            seed,
+        }
+    }
+
+    /// Returns the delay to wait before the next attempt
+    pub fn next_delay(&mut self) -> std::time::Duration {
+        let factor = 2u32.saturating_pow(self.attempt);
+        let ceiling = self.base.saturating_mul(factor).min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+
+        // Same LCG as the one used for the random messages of the web app
+        self.seed = self.seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+        let half = ceiling / 2;
+        let jitter_range = (ceiling - half).as_millis() as u64;
+        let jitter = if jitter_range == 0 {
+            0
+        } else {
+            (self.seed >> 33) % (jitter_range + 1)
+        };
+        half + std::time::Duration::from_millis(jitter)
+    }
+
+    /// Restarts from the base delay, after a successful connection
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// Spawns one supervisor per configured peer
+pub fn reconnection_worker(node: crate::node::Node) {
+    tokio::spawn(async move {
+        let cli_peers = {
+            let st = node.state.lock().await;
+            st.get_cli_peers_addrs()
+        };
+
+        for peer in cli_peers {
+            tokio::spawn(supervise_peer(node.clone(), peer));
+        }
+    });
+}
+
+/// Keeps a configured peer connected, re-running the discovery when the link is lost
+async fn supervise_peer(node: crate::node::Node, peer: std::net::SocketAddr) {
+    use crate::message::{MessageInfo, NetworkMessageCode};
+
+    let seed = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+        ^ peer.port() as u64;
+    let mut backoff = Backoff::new(
+        std::time::Duration::from_millis(BASE_DELAY_MS),
+        std::time::Duration::from_millis(MAX_DELAY_MS),
+        seed,
+    );
+    let check_interval = std::time::Duration::from_millis(BASE_DELAY_MS * 2);
+    let mut delay = check_interval;
+
+    loop {
+        tokio::time::sleep(delay).await;
+
+        let (connected, local_addr, site_id, clock, membership) = {
+            let st = node.state.lock().await;
+            (
+                st.get_connected_nei_addr().contains(&peer),
+                st.get_site_addr(),
+                st.get_site_id(),
+                st.get_clock(),
+                st.get_membership_payload(),
+            )
+        };
+
+        if connected {
+            backoff.reset();
+            delay = check_interval;
+            continue;
+        }
+
+        // The previous connection, if any, points to a dead writer task
+        {
+            let mut manager = node.network.lock().await;
+            manager.remove_connection(&peer);
+        }
+
+        // Synchronize with the network once the peer has acknowledged
+        {
+            let mut st = node.state.lock().await;
+            let expected = st.get_nb_connected_neighbours() + 1;
+            st.init_sync(true);
+            st.init_nb_first_attended_neighbours(expected);
+        }
+
+        log::debug!("Trying to reconnect to {}", peer);
+        match crate::network::send_message(
+            &node,
+            peer,
+            MessageInfo::Membership(membership),
+            NetworkMessageCode::Discovery,
+            local_addr,
+            &site_id,
+            crate::message::Origin::local(&site_id, local_addr),
+            clock,
+        )
+        .await
+        {
+            Ok(()) => log::info!("Discovery sent to {}, waiting for its acknowledgment", peer),
+            Err(e) => log::debug!("Reconnection to {} failed: {}", peer, e),
+        }
+
+        // Whether the discovery was sent or not, we check again after the backoff:
+        // the peer is only connected once it has acknowledged
+        delay = backoff.next_delay();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_grow_exponentially_within_jitter_bounds() {
+        let mut backoff = Backoff::new(
+            std::time::Duration::from_millis(100),
+            std::time::Duration::from_millis(10_000),
+            42,
+        );
+        for n in 0..6 {
+            let ceiling = std::time::Duration::from_millis(100 * 2u64.pow(n));
+            let delay = backoff.next_delay();
+            assert!(delay >= ceiling / 2, "attempt {}: {:?}", n, delay);
+            assert!(delay <= ceiling, "attempt {}: {:?}", n, delay);
+        }
+    }
+
+    #[test]
+    fn delays_are_capped() {
+        let max = std::time::Duration::from_millis(1_000);
+        let mut backoff = Backoff::new(std::time::Duration::from_millis(100), max, 7);
+        for _ in 0..100 {
+            assert!(backoff.next_delay() <= max);
+        }
+    }
+
+    #[test]
+    fn reset_restarts_from_base_delay() {
+        let base = std::time::Duration::from_millis(100);
+        let mut backoff = Backoff::new(base, std::time::Duration::from_millis(10_000), 3);
+        for _ in 0..5 {
+            backoff.next_delay();
+        }
+        backoff.reset();
+        assert!(backoff.next_delay() <= base);
+    }
+
+    #[test]
+    fn jitter_depends_on_seed() {
+        let delays = |seed| {
+            let mut backoff = Backoff::new(
+                std::time::Duration::from_millis(1_000),
+                std::time::Duration::from_millis(60_000),
+                seed,
+            );
+            (0..5).map(|_| backoff.next_delay()).collect::<Vec<_>>()
+        };
+        assert_ne!(delays(1), delays(2));
+        assert_eq!(delays(1), delays(1));
+    }
+}
@@ -0,0 +1,1250 @@
+//! Deterministic simulation of Peillute sites
+//!
+//! The simulation runs real sites in one process: every site is a
+//! [`crate::node::Node`] with its own in-memory database, running the handlers
+//! of `network`, `wave`, `state` and `control` and the algorithms of `mutex`.
+//! The sites talk over [`FaultTransport`]s, memory transports whose links
+//! delay and drop the frames, and cut the sites in two during partitions.
+//!
+//! The faults are the ones a site sees over TCP: a stream delivers its frames
+//! once each and in order, so a lost frame is sent again after a while and
+//! holds back the frames behind it, and a partition holds a stream until it
+//! heals. Frames are reordered across streams, a frame held back long on one
+//! link being overtaken by the frames of the other links. A stream never
+//! duplicates nor reorders its own frames, the handlers rely on it.
+//!
+//! A run is driven by a current-thread runtime whose clock is paused: time
+//! only moves forward when every task waits, so the delays cost nothing and
+//! a run does not depend on the speed of the machine. The seed fixes the
+//! workload and the fate of every frame, the n-th frame sent on a link is
+//! dropped or delayed the same way in every run of the seed.
+//!
+//! Before every frame is delivered, each site must conserve money and have
+//! no overdrawn account. Every time a site enters the critical section, no
+//! other site may hold one of the accounts it locks. Once the workload is
+//! done and the network is quiet, the sites must agree on the transactions
+//! and on the balances.
+//!
+//! A failing run prints its seed and the last events before the broken
+//! invariant, replay it alone with
+//! `PEILLUTE_SIM_SEED=<seed> cargo test simulation`.
+
+#![cfg(all(test, feature = "server"))]
+
+/// Environment variable selecting the single seed to replay
+const SEED_ENV_VAR: &str = "PEILLUTE_SIM_SEED";
+
+/// Number of trace lines kept in a failure report
+const TRACE_TAIL: usize = 30;
+
+/// Users of the simulated bank
+const USERS: [&str; 3] = ["alice", "bob", "carol"];
+
+/// Port the sites listen on, each site having its own IP address
+const SITE_PORT: u16 = 8000;
+
+/// Capacity of the pipe between a site and its faulty link, in bytes
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// Interval between two heartbeats of a site, in milliseconds
+const HEARTBEAT_MS: u64 = 50;
+
+/// Interval at which the progress of the run is checked, in milliseconds
+const POLL_MS: u64 = 5;
+
+/// SplitMix64 generator, the only source of randomness of a run
+pub struct SimRng {
+    state: u64,
+}
+
+impl SimRng {
+    /// Creates a generator from a seed
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Generator of the link from `from` to `to`, independent of the other links
+    fn for_link(seed: u64, from: usize, to: usize) -> Self {
+        let mut rng = Self::new(seed);
+        rng.state ^= ((from as u64) << 32 | to as u64).wrapping_mul(0xD6E8FEB86659FD93);
+        rng
+    }
+
+    /// Returns the next pseudo-random number
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in `[low, high]`
+    pub fn range(&mut self, low: u64, high: u64) -> u64 {
+        low + self.next_u64() % (high - low + 1)
+    }
+
+    /// Returns true with probability `p`
+    pub fn chance(&mut self, p: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+/// Shape of the network, given to the sites as their peers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Topology {
+    /// Sites connected one after the other
+    Line,
+    /// A line whose ends are connected
+    Ring,
+    /// Every site connected to every other
+    Full,
+}
+
+impl Topology {
+    /// Returns the neighbours of a site
+    fn neighbours(self, nb_sites: usize, site: usize) -> Vec<usize> {
+        let mut neighbours = match self {
+            Topology::Full => (0..nb_sites).filter(|s| *s != site).collect(),
+            Topology::Line | Topology::Ring => {
+                let mut n = Vec::new();
+                if site > 0 {
+                    n.push(site - 1);
+                }
+                if site + 1 < nb_sites {
+                    n.push(site + 1);
+                }
+                if self == Topology::Ring && nb_sites > 2 {
+                    if site == 0 {
+                        n.push(nb_sites - 1);
+                    }
+                    if site == nb_sites - 1 {
+                        n.push(0);
+                    }
+                }
+                n
+            }
+        };
+        neighbours.sort();
+        neighbours.dedup();
+        neighbours
+    }
+}
+
+/// Links cut between two groups of sites during a time window
+#[derive(Debug, Clone)]
+pub struct Partition {
+    /// Start of the partition, in milliseconds since the start of the run
+    pub start_ms: u64,
+    /// End of the partition, in milliseconds since the start of the run
+    pub end_ms: u64,
+    /// Sites on one side, the others being on the other side
+    pub side: Vec<usize>,
+}
+
+impl Partition {
+    /// Returns true if the link between `a` and `b` is cut at `time_ms`
+    fn cuts(&self, time_ms: u64, a: usize, b: usize) -> bool {
+        time_ms >= self.start_ms
+            && time_ms < self.end_ms
+            && self.side.contains(&a) != self.side.contains(&b)
+    }
+}
+
+/// Faults injected by the links
+#[derive(Debug, Clone)]
+pub struct FaultConfig {
+    /// Minimum transmission delay, in milliseconds
+    pub min_delay_ms: u64,
+    /// Maximum transmission delay, in milliseconds
+    pub max_delay_ms: u64,
+    /// Probability that a frame is held back long enough for the frames of the
+    /// other links to overtake it
+    pub reorder_rate: f64,
+    /// Probability that a frame is lost
+    pub drop_rate: f64,
+    /// Delay before a lost frame is sent again, in milliseconds
+    pub retransmit_after_ms: u64,
+    /// Partitions of the network
+    pub partitions: Vec<Partition>,
+}
+
+impl FaultConfig {
+    /// Links delivering every frame after one millisecond
+    pub fn none() -> Self {
+        Self {
+            min_delay_ms: 1,
+            max_delay_ms: 1,
+            reorder_rate: 0.0,
+            drop_rate: 0.0,
+            retransmit_after_ms: 20,
+            partitions: Vec::new(),
+        }
+    }
+
+    /// Hostile links: jitter, reordering and losses
+    pub fn hostile() -> Self {
+        Self {
+            min_delay_ms: 1,
+            max_delay_ms: 8,
+            reorder_rate: 0.1,
+            drop_rate: 0.1,
+            retransmit_after_ms: 20,
+            partitions: Vec::new(),
+        }
+    }
+}
+
+/// Parameters of a run
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    /// Number of sites
+    pub nb_sites: usize,
+    /// Shape of the network
+    pub topology: Topology,
+    /// Mutual exclusion run by the sites
+    pub algorithm: crate::mutex::MutexAlgorithm,
+    /// Faults injected by the links
+    pub faults: FaultConfig,
+    /// Number of critical commands issued by the sites
+    pub nb_operations: usize,
+    /// Commands are issued before this time, in milliseconds
+    pub workload_ms: u64,
+    /// The run fails if the network is not quiet at this time, in milliseconds
+    pub max_time_ms: u64,
+    /// Run the mutual exclusion; only disabled to check that the invariants
+    /// catch a broken mutex
+    pub guarded: bool,
+}
+
+impl SimConfig {
+    /// Default run for a given network
+    pub fn new(
+        nb_sites: usize,
+        topology: Topology,
+        algorithm: crate::mutex::MutexAlgorithm,
+        faults: FaultConfig,
+    ) -> Self {
+        Self {
+            nb_sites,
+            topology,
+            algorithm,
+            faults,
+            nb_operations: 12,
+            workload_ms: 200,
+            max_time_ms: 5_000,
+            guarded: true,
+        }
+    }
+}
+
+/// Invariant broken during a run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// Several sites held the same accounts at the same time
+    MutualExclusion { sites: Vec<String> },
+    /// The balances of a site do not add up to its deposits minus its withdrawals
+    MoneyNotConserved {
+        site: String,
+        total: i64,
+        expected: i64,
+    },
+    /// An account went below zero
+    Overdraft {
+        site: String,
+        user: String,
+        balance: i64,
+    },
+    /// The network became quiet with sites disagreeing on the transactions
+    Diverged { site: String, other: String },
+    /// The network was still busy at the end of the run
+    NoProgress,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Violation::MutualExclusion { sites } => {
+                write!(f, "sites {:?} are in the critical section together", sites)
+            }
+            Violation::MoneyNotConserved {
+                site,
+                total,
+                expected,
+            } => write!(
+                f,
+                "site {} holds {} cents but deposits minus withdrawals are {}",
+                site, total, expected
+            ),
+            Violation::Overdraft {
+                site,
+                user,
+                balance,
+            } => write!(f, "site {} sees {} at {} cents", site, user, balance),
+            Violation::Diverged { site, other } => {
+                write!(
+                    f,
+                    "sites {} and {} disagree on the transactions",
+                    site, other
+                )
+            }
+            Violation::NoProgress => write!(f, "the network is still busy at the end of the run"),
+        }
+    }
+}
+
+/// Failed run, with what is needed to replay it
+#[derive(Debug, Clone)]
+pub struct SimFailure {
+    pub seed: u64,
+    /// Time of the violation, in milliseconds
+    pub time_ms: u64,
+    pub violation: Violation,
+    /// Last events before the violation
+    pub trace: Vec<String>,
+}
+
+impl std::fmt::Display for SimFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "seed {} failed at {} ms: {}",
+            self.seed, self.time_ms, self.violation
+        )?;
+        writeln!(f, "replay with {}={}", SEED_ENV_VAR, self.seed)?;
+        for line in &self.trace {
+            writeln!(f, "  {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Successful run
+#[derive(Debug, Clone)]
+pub struct SimReport {
+    /// Balances every site agrees on, in cents
+    pub balances: std::collections::BTreeMap<String, i64>,
+    /// Number of transactions stored everywhere
+    pub nb_transactions: usize,
+    /// Time at which the network became quiet, in milliseconds
+    pub end_time_ms: u64,
+    /// Frames lost by the links, partitions included
+    pub dropped: u64,
+    /// Frames overtaken by the frames of the other links
+    pub reordered: u64,
+    /// Hash of the whole trace, to compare two runs
+    pub trace_hash: u64,
+}
+
+/// What the links and the checks share during a run
+#[derive(Default)]
+struct Observations {
+    /// Generator of every link that carried a frame
+    links: std::collections::BTreeMap<(usize, usize), SimRng>,
+    /// Frames sent and not written to their destination yet
+    in_flight: usize,
+    dropped: u64,
+    reordered: u64,
+    /// Database of every site
+    dbs: Vec<(String, std::sync::Arc<crate::db::Db>)>,
+    /// Accounts locked by the sites in the critical section
+    holders: std::collections::BTreeMap<String, crate::state::LockScope>,
+    trace: std::collections::VecDeque<String>,
+    trace_hash: u64,
+    /// First broken invariant
+    failure: Option<SimFailure>,
+}
+
+/// Network the sites of a run are bound on
+pub struct SimNetwork {
+    memory: std::sync::Arc<crate::transport::MemoryNetwork>,
+    seed: u64,
+    faults: FaultConfig,
+    /// Address of every site
+    addrs: Vec<std::net::SocketAddr>,
+    start: tokio::time::Instant,
+    observations: std::sync::Mutex<Observations>,
+}
+
+impl SimNetwork {
+    fn new(seed: u64, config: &SimConfig) -> std::sync::Arc<Self> {
+        std::sync::Arc::new(Self {
+            memory: crate::transport::MemoryNetwork::new(),
+            seed,
+            faults: config.faults.clone(),
+            addrs: (0..config.nb_sites).map(site_addr).collect(),
+            start: tokio::time::Instant::now(),
+            observations: std::sync::Mutex::new(Observations {
+                trace_hash: 0xcbf29ce484222325,
+                ..Default::default()
+            }),
+        })
+    }
+
+    /// Milliseconds since the start of the run
+    fn now_ms(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn site_of(&self, addr: std::net::SocketAddr) -> Option<usize> {
+        self.addrs.iter().position(|a| *a == addr)
+    }
+
+    fn log(&self, observations: &mut Observations, line: String) {
+        // FNV-1a over the whole trace, to compare runs
+        for byte in line.bytes() {
+            observations.trace_hash ^= byte as u64;
+            observations.trace_hash = observations.trace_hash.wrapping_mul(0x100000001b3);
+        }
+        if observations.trace.len() == TRACE_TAIL {
+            observations.trace.pop_front();
+        }
+        observations
+            .trace
+            .push_back(format!("[{}] {}", self.now_ms(), line));
+    }
+
+    fn fail(&self, observations: &mut Observations, violation: Violation) {
+        if observations.failure.is_none() {
+            observations.failure = Some(SimFailure {
+                seed: self.seed,
+                time_ms: self.now_ms(),
+                violation,
+                trace: observations.trace.iter().cloned().collect(),
+            });
+        }
+    }
+
+    fn failure(&self) -> Option<SimFailure> {
+        self.observations.lock().unwrap().failure.clone()
+    }
+
+    /// Records that a site entered the critical section, locking `scope`
+    fn enter(&self, site: &str, scope: &crate::state::LockScope) {
+        let mut observations = self.observations.lock().unwrap();
+        self.log(
+            &mut observations,
+            format!("{} enters the critical section on {:?}", site, scope),
+        );
+        let mut sites: Vec<String> = observations
+            .holders
+            .iter()
+            .filter(|(_, held)| held.conflicts_with(scope))
+            .map(|(holder, _)| holder.clone())
+            .collect();
+        observations.holders.insert(site.to_string(), scope.clone());
+        if !sites.is_empty() {
+            sites.push(site.to_string());
+            self.fail(&mut observations, Violation::MutualExclusion { sites });
+        }
+    }
+
+    /// Records that a site left the critical section
+    fn leave(&self, site: &str) {
+        let mut observations = self.observations.lock().unwrap();
+        observations.holders.remove(site);
+        self.log(
+            &mut observations,
+            format!("{} leaves the critical section", site),
+        );
+    }
+
+    /// Checks that every site conserves money and has no overdrawn account
+    fn check_sites(&self, observations: &mut Observations) {
+        for (site, db) in observations.dbs.clone() {
+            let (total, expected, overdrawn) = ledger_of(&db);
+            if total != expected {
+                let violation = Violation::MoneyNotConserved {
+                    site,
+                    total,
+                    expected,
+                };
+                return self.fail(observations, violation);
+            }
+            if let Some((user, balance)) = overdrawn {
+                let violation = Violation::Overdraft {
+                    site,
+                    user,
+                    balance,
+                };
+                return self.fail(observations, violation);
+            }
+        }
+    }
+
+    /// Carries the frames written by `from` on its stream to `to`
+    ///
+    /// A stream delivers its frames in order, once each, as a TCP connection
+    /// does: a frame held back by a delay or a loss holds back the frames
+    /// written after it on the same stream.
+    async fn carry(
+        self: std::sync::Arc<Self>,
+        from: usize,
+        to: usize,
+        mut reader: tokio::io::DuplexStream,
+        mut stream: crate::transport::PeerWriter,
+    ) {
+        use crate::codec::{DEFAULT_MAX_FRAME_SIZE, FrameCodec};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (tx, mut rx) =
+            tokio::sync::mpsc::unbounded_channel::<(tokio::time::Instant, Vec<u8>)>();
+        let network = self.clone();
+        tokio::spawn(async move {
+            let codec = FrameCodec::new(DEFAULT_MAX_FRAME_SIZE);
+            while let Some((deadline, frame)) = rx.recv().await {
+                tokio::time::sleep_until(deadline).await;
+                let code = code_of(&frame);
+                {
+                    let mut observations = network.observations.lock().unwrap();
+                    network.check_sites(&mut observations);
+                    if !is_background(&code) {
+                        let line = format!("S{} -> S{}: {}", from, to, describe(&code));
+                        network.log(&mut observations, line);
+                    }
+                }
+                let written = match codec.encode(&frame) {
+                    Ok(bytes) => stream.write_all(&bytes).await.is_ok(),
+                    Err(_) => false,
+                };
+                if !is_background(&code) {
+                    network.observations.lock().unwrap().in_flight -= 1;
+                }
+                if !written {
+                    break;
+                }
+            }
+        });
+
+        let mut codec = FrameCodec::new(DEFAULT_MAX_FRAME_SIZE);
+        let mut buf = vec![0u8; 4096];
+        let mut last_delivery = tokio::time::Instant::now();
+        loop {
+            let n = match reader.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => n,
+            };
+            codec.extend(&buf[..n]);
+            while let Ok(Some(frame)) = codec.decode() {
+                let delivery = self.delivery_time(from, to).max(last_delivery);
+                last_delivery = delivery;
+                if !is_background(&code_of(&frame)) {
+                    self.observations.lock().unwrap().in_flight += 1;
+                }
+                if tx.send((delivery, frame)).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Draws the time at which a frame sent now on the link reaches `to`
+    ///
+    /// Every lost transmission, and every transmission across a partition,
+    /// costs a retransmission delay before the frame is sent again.
+    fn delivery_time(&self, from: usize, to: usize) -> tokio::time::Instant {
+        let faults = &self.faults;
+        let mut observations = self.observations.lock().unwrap();
+        let mut sent_ms = self.now_ms();
+        loop {
+            let rng = observations
+                .links
+                .entry((from, to))
+                .or_insert_with(|| SimRng::for_link(self.seed, from, to));
+            let lost = rng.chance(faults.drop_rate);
+            if !lost && !faults.partitions.iter().any(|p| p.cuts(sent_ms, from, to)) {
+                break;
+            }
+            observations.dropped += 1;
+            let line = format!("S{} -> S{}: lost at {} ms", from, to, sent_ms);
+            self.log(&mut observations, line);
+            sent_ms += faults.retransmit_after_ms;
+        }
+
+        let rng = observations.links.get_mut(&(from, to)).unwrap();
+        let mut delay_ms = rng.range(faults.min_delay_ms, faults.max_delay_ms);
+        if rng.chance(faults.reorder_rate) {
+            delay_ms += faults.max_delay_ms * 4;
+            observations.reordered += 1;
+        }
+        self.start + std::time::Duration::from_millis(sent_ms + delay_ms)
+    }
+}
+
+/// Memory transport whose outgoing links inject the faults of a [`SimNetwork`]
+pub struct FaultTransport {
+    inner: crate::transport::MemoryTransport,
+    site: usize,
+    network: std::sync::Arc<SimNetwork>,
+}
+
+impl crate::transport::Transport for FaultTransport {
+    fn local_addr(&self) -> std::net::SocketAddr {
+        self.inner.local_addr()
+    }
+
+    fn connect(
+        &self,
+        addr: std::net::SocketAddr,
+    ) -> crate::transport::TransportFuture<'_, crate::transport::PeerWriter> {
+        Box::pin(async move {
+            let to = self
+                .network
+                .site_of(addr)
+                .ok_or(std::io::ErrorKind::ConnectionRefused)?;
+            let stream = self.inner.connect(addr).await?;
+            let (writer, reader) = tokio::io::duplex(PIPE_CAPACITY);
+            tokio::spawn(self.network.clone().carry(self.site, to, reader, stream));
+            let writer: crate::transport::PeerWriter = Box::new(writer);
+            Ok(writer)
+        })
+    }
+
+    fn accept(
+        &self,
+    ) -> crate::transport::TransportFuture<'_, (crate::transport::PeerReader, std::net::SocketAddr)>
+    {
+        self.inner.accept()
+    }
+}
+
+/// Mutual exclusion of a site, reporting when it enters and leaves the critical section
+struct ObservedMutex {
+    inner: Box<dyn crate::mutex::MutualExclusion>,
+    site: String,
+    network: std::sync::Arc<SimNetwork>,
+    held: bool,
+}
+
+impl ObservedMutex {
+    fn observe(
+        &mut self,
+        effects: Vec<crate::mutex::MutexEffect>,
+    ) -> Vec<crate::mutex::MutexEffect> {
+        let held = self.inner.status() == crate::mutex::MutexStatus::Held;
+        if held && !self.held {
+            let scope = self
+                .inner
+                .held_scope()
+                .cloned()
+                .unwrap_or(crate::state::LockScope::All);
+            self.network.enter(&self.site, &scope);
+        } else if !held && self.held {
+            self.network.leave(&self.site);
+        }
+        self.held = held;
+        effects
+    }
+}
+
+impl crate::mutex::MutualExclusion for ObservedMutex {
+    fn algorithm(&self) -> crate::mutex::MutexAlgorithm {
+        self.inner.algorithm()
+    }
+
+    fn status(&self) -> crate::mutex::MutexStatus {
+        self.inner.status()
+    }
+
+    fn held_scope(&self) -> Option<&crate::state::LockScope> {
+        self.inner.held_scope()
+    }
+
+    fn request(
+        &mut self,
+        ctx: &crate::mutex::MutexContext,
+        scope: crate::state::LockScope,
+    ) -> Vec<crate::mutex::MutexEffect> {
+        let effects = self.inner.request(ctx, scope);
+        self.observe(effects)
+    }
+
+    fn release(&mut self, ctx: &crate::mutex::MutexContext) -> Vec<crate::mutex::MutexEffect> {
+        let effects = self.inner.release(ctx);
+        self.observe(effects)
+    }
+
+    fn receive(
+        &mut self,
+        ctx: &crate::mutex::MutexContext,
+        from: &str,
+        payload: crate::mutex::MutexPayload,
+    ) -> Vec<crate::mutex::MutexEffect> {
+        let effects = self.inner.receive(ctx, from, payload);
+        self.observe(effects)
+    }
+
+    fn forget_site(&mut self, ctx: &crate::mutex::MutexContext, site_id: &str) -> bool {
+        let forgotten = self.inner.forget_site(ctx, site_id);
+        self.observe(Vec::new());
+        forgotten
+    }
+
+    fn resume(&mut self, ctx: &crate::mutex::MutexContext) -> Vec<crate::mutex::MutexEffect> {
+        let effects = self.inner.resume(ctx);
+        self.observe(effects)
+    }
+
+    fn queue(&self) -> std::collections::HashMap<String, crate::state::MutexStamp> {
+        self.inner.queue()
+    }
+
+    fn adopt_queue(&mut self, queue: std::collections::HashMap<String, crate::state::MutexStamp>) {
+        self.inner.adopt_queue(queue);
+        self.observe(Vec::new());
+    }
+}
+
+/// Mutex granting every request at once, to check that the invariants catch it
+#[derive(Default)]
+struct Unguarded {
+    held: Option<crate::state::LockScope>,
+}
+
+impl crate::mutex::MutualExclusion for Unguarded {
+    fn algorithm(&self) -> crate::mutex::MutexAlgorithm {
+        crate::mutex::MutexAlgorithm::Wave
+    }
+
+    fn status(&self) -> crate::mutex::MutexStatus {
+        match self.held {
+            Some(_) => crate::mutex::MutexStatus::Held,
+            None => crate::mutex::MutexStatus::Idle,
+        }
+    }
+
+    fn held_scope(&self) -> Option<&crate::state::LockScope> {
+        self.held.as_ref()
+    }
+
+    fn request(
+        &mut self,
+        _ctx: &crate::mutex::MutexContext,
+        scope: crate::state::LockScope,
+    ) -> Vec<crate::mutex::MutexEffect> {
+        self.held = Some(scope);
+        Vec::new()
+    }
+
+    fn release(&mut self, _ctx: &crate::mutex::MutexContext) -> Vec<crate::mutex::MutexEffect> {
+        self.held = None;
+        Vec::new()
+    }
+
+    fn receive(
+        &mut self,
+        _ctx: &crate::mutex::MutexContext,
+        _from: &str,
+        _payload: crate::mutex::MutexPayload,
+    ) -> Vec<crate::mutex::MutexEffect> {
+        Vec::new()
+    }
+
+    fn forget_site(&mut self, _ctx: &crate::mutex::MutexContext, _site_id: &str) -> bool {
+        false
+    }
+}
+
+fn site_id(site: usize) -> String {
+    format!("S{}", site)
+}
+
+fn site_addr(site: usize) -> std::net::SocketAddr {
+    format!("10.0.0.{}:{}", site + 1, SITE_PORT)
+        .parse()
+        .unwrap()
+}
+
+/// Code of the message carried by a frame, for the trace
+fn code_of(frame: &[u8]) -> Option<crate::message::NetworkMessageCode> {
+    let signed = rmp_serde::from_slice::<crate::identity::SignedMessage>(frame).ok()?;
+    let message = rmp_serde::from_slice::<crate::message::Message>(&signed.message).ok()?;
+    Some(message.code)
+}
+
+/// Name of a message code in the trace
+fn describe(code: &Option<crate::message::NetworkMessageCode>) -> String {
+    match code {
+        Some(code) => format!("{:?}", code),
+        None => "undecodable frame".to_string(),
+    }
+}
+
+/// Heartbeats never stop, they are left out of the trace and of the frames
+/// the end of a run waits for
+fn is_background(code: &Option<crate::message::NetworkMessageCode>) -> bool {
+    *code == Some(crate::message::NetworkMessageCode::Heartbeat)
+}
+
+/// Total of the balances of a site, what its transactions say it must be,
+/// and an overdrawn account if there is one
+fn ledger_of(db: &crate::db::Db) -> (i64, i64, Option<(String, i64)>) {
+    let balances = crate::db::get_balances(db).unwrap();
+    let total = balances.values().sum();
+    let overdrawn = balances.into_iter().find(|(_, balance)| *balance < 0);
+
+    let conn = db.lock().unwrap();
+    let mut stmt = conn
+        .prepare("SELECT from_user, to_user, amount FROM Transactions")
+        .unwrap();
+    let expected = stmt
+        .query_map([], |row| {
+            let (from, to): (String, String) = (row.get(0)?, row.get(1)?);
+            let amount: crate::money::Money = row.get(2)?;
+            let into_bank = (from == crate::db::NULL) as i64 - (to == crate::db::NULL) as i64;
+            Ok(into_bank * amount.cents())
+        })
+        .unwrap()
+        .map(Result::unwrap)
+        .sum();
+    (total, expected, overdrawn)
+}
+
+/// Keys of the transactions stored by a site
+fn transactions_of(db: &crate::db::Db) -> std::collections::BTreeSet<(String, i64)> {
+    let conn = db.lock().unwrap();
+    let mut stmt = conn
+        .prepare("SELECT source_node, lamport_time FROM Transactions")
+        .unwrap();
+    stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect()
+}
+
+/// Starts a site of the run, bound on the network and listening to its peers
+fn start_site(
+    network: &std::sync::Arc<SimNetwork>,
+    config: &SimConfig,
+    site: usize,
+) -> crate::node::Node {
+    let peers = config
+        .topology
+        .neighbours(config.nb_sites, site)
+        .into_iter()
+        .map(site_addr)
+        .collect();
+    let mut state = crate::state::AppState::new(site_id(site), peers, site_addr(site));
+    state.init_membership(1);
+    state.init_wave_seq(1);
+    // le détecteur mesure le temps avec l'horloge système, que le runtime en
+    // pause ne fait pas avancer : les sites ne se soupçonnent jamais
+    state.init_failure_detector(HEARTBEAT_MS, f64::INFINITY);
+    let inner = if config.guarded {
+        config.algorithm.build()
+    } else {
+        Box::new(Unguarded::default())
+    };
+    state.mutex = Box::new(ObservedMutex {
+        inner,
+        site: site_id(site),
+        network: network.clone(),
+        held: false,
+    });
+
+    let node = crate::node::Node::new(state, crate::db::Db::in_memory());
+    network
+        .observations
+        .lock()
+        .unwrap()
+        .dbs
+        .push((site_id(site), node.db.clone()));
+
+    let transport: std::sync::Arc<dyn crate::transport::Transport> =
+        std::sync::Arc::new(FaultTransport {
+            inner: network.memory.bind(site_addr(site)).unwrap(),
+            site,
+            network: network.clone(),
+        });
+    node.network
+        .try_lock()
+        .unwrap()
+        .init_transport(transport.clone());
+    let listener = node.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, addr)) = transport.accept().await {
+            crate::network::start_listening(listener.clone(), stream, addr).await;
+        }
+    });
+    crate::control::control_worker(node.clone());
+    crate::control::causal_delivery_worker(node.clone());
+    crate::heartbeat::heartbeat_worker(node.clone());
+    node
+}
+
+/// Critical commands of the run: when, on which site, and what
+fn workload(
+    rng: &mut SimRng,
+    config: &SimConfig,
+) -> Vec<(u64, usize, crate::control::CriticalCommands)> {
+    use crate::control::CriticalCommands;
+
+    let user = |rng: &mut SimRng| USERS[rng.range(0, USERS.len() as u64 - 1) as usize];
+    let mut commands: Vec<_> = (0..config.nb_operations)
+        .map(|_| {
+            let time_ms = rng.range(0, config.workload_ms - 1);
+            let site = rng.range(0, config.nb_sites as u64 - 1) as usize;
+            let name = user(rng).to_string();
+            let amount = crate::money::Money::from_cents(rng.range(1, 100) as i64 * 100);
+            let command = match rng.range(0, 2) {
+                0 => CriticalCommands::Deposit { name, amount },
+                1 => CriticalCommands::Withdraw { name, amount },
+                _ => CriticalCommands::Transfer {
+                    from: name,
+                    to: user(rng).to_string(),
+                    amount,
+                },
+            };
+            (time_ms, site, command)
+        })
+        .collect();
+    commands.sort_by_key(|(time_ms, _, _)| *time_ms);
+    commands
+}
+
+/// Returns true once every site has nothing left to do
+async fn is_idle(sites: &[crate::node::Node]) -> bool {
+    for site in sites {
+        let state = site.state.lock().await;
+        if state.mutex_status() != crate::mutex::MutexStatus::Idle
+            || !state.pending_commands.is_empty()
+        {
+            return false;
+        }
+    }
+    true
+}
+
+/// A run of the simulation
+struct SimRun {
+    seed: u64,
+    config: SimConfig,
+    network: std::sync::Arc<SimNetwork>,
+    sites: Vec<crate::node::Node>,
+}
+
+impl SimRun {
+    /// Polls `done` until it holds, or an invariant is broken
+    async fn wait_until<F, Fut>(&self, mut done: F) -> Result<(), SimFailure>
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = bool>,
+    {
+        loop {
+            if let Some(failure) = self.network.failure() {
+                return Err(failure);
+            }
+            if done().await {
+                return Ok(());
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(POLL_MS)).await;
+        }
+    }
+
+    /// Connects the sites and creates the users of the workload
+    async fn setup(&self) -> Result<(), SimFailure> {
+        use crate::control::{CriticalCommands, run_critical};
+
+        for site in &self.sites {
+            crate::network::announce(site, None).await;
+        }
+        // les clés et la vue des membres se propagent avec les heartbeats
+        self.wait_until(|| async {
+            for (n, site) in self.sites.iter().enumerate() {
+                let expected = self.config.topology.neighbours(self.config.nb_sites, n);
+                let state = site.state.lock().await;
+                if state.get_connected_nei_addr().len() != expected.len()
+                    || state.membership.alive_sites().len() != self.config.nb_sites
+                    || !state.unpinned_members().is_empty()
+                {
+                    return false;
+                }
+            }
+            true
+        })
+        .await?;
+
+        for user in USERS {
+            let command = CriticalCommands::CreateUser {
+                name: user.to_string(),
+            };
+            run_critical(&self.sites[0], command)
+                .await
+                .expect("the users are created before the workload");
+        }
+        self.wait_until(|| async {
+            self.sites
+                .iter()
+                .all(|site| crate::db::get_users(&site.db).unwrap().len() == USERS.len())
+        })
+        .await
+    }
+
+    /// Issues the critical commands at their time and waits for all of them
+    async fn play(&self) -> Result<(), SimFailure> {
+        let mut rng = SimRng::new(self.seed);
+        let start = tokio::time::Instant::now();
+        let done = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        for (time_ms, site, command) in workload(&mut rng, &self.config) {
+            let node = self.sites[site].clone();
+            let network = self.network.clone();
+            let done = done.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep_until(start + std::time::Duration::from_millis(time_ms)).await;
+                let line = format!("{} issues {:?}", site_id(site), command);
+                {
+                    let mut observations = network.observations.lock().unwrap();
+                    network.log(&mut observations, line);
+                }
+                // Commands refused by the site, for lack of funds, are part of the run
+                let _ = crate::control::run_critical(&node, command).await;
+                done.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            });
+        }
+        self.wait_until(|| async {
+            done.load(std::sync::atomic::Ordering::SeqCst) == self.config.nb_operations
+        })
+        .await
+    }
+
+    /// Waits for the network to be quiet, twice in a row to let the last frames be handled
+    async fn settle(&self) -> Result<(), SimFailure> {
+        let partitions_end = self
+            .config
+            .faults
+            .partitions
+            .iter()
+            .map(|p| p.end_ms)
+            .max()
+            .unwrap_or(0);
+        let quiet_polls = std::cell::Cell::new(0);
+        self.wait_until(|| async {
+            let quiet = self.network.now_ms() >= partitions_end
+                && self.network.observations.lock().unwrap().in_flight == 0
+                && is_idle(&self.sites).await;
+            quiet_polls.set(if quiet { quiet_polls.get() + 1 } else { 0 });
+            quiet_polls.get() >= 2
+        })
+        .await
+    }
+
+    /// Checks that the sites agree, once the network is quiet
+    fn converged(&self) -> Result<SimReport, SimFailure> {
+        let reference = &self.sites[0];
+        let transactions = transactions_of(&reference.db);
+        let balances = crate::db::get_balances(&reference.db).unwrap();
+        for (n, site) in self.sites.iter().enumerate().skip(1) {
+            if transactions_of(&site.db) != transactions
+                || crate::db::get_balances(&site.db).unwrap() != balances
+            {
+                let violation = Violation::Diverged {
+                    site: site_id(0),
+                    other: site_id(n),
+                };
+                let mut observations = self.network.observations.lock().unwrap();
+                self.network.fail(&mut observations, violation);
+                return Err(observations.failure.clone().unwrap());
+            }
+        }
+
+        let observations = self.network.observations.lock().unwrap();
+        Ok(SimReport {
+            balances,
+            nb_transactions: transactions.len(),
+            end_time_ms: self.network.now_ms(),
+            dropped: observations.dropped,
+            reordered: observations.reordered,
+            trace_hash: observations.trace_hash,
+        })
+    }
+
+    async fn run(self) -> Result<SimReport, SimFailure> {
+        let max_time = std::time::Duration::from_millis(self.config.max_time_ms);
+        let outcome = tokio::time::timeout(max_time, async {
+            self.setup().await?;
+            self.play().await?;
+            self.settle().await
+        })
+        .await;
+
+        if let Some(failure) = self.network.failure() {
+            return Err(failure);
+        }
+        match outcome {
+            Ok(Ok(())) => self.converged(),
+            Ok(Err(failure)) => Err(failure),
+            Err(_) => {
+                let mut observations = self.network.observations.lock().unwrap();
+                self.network.fail(&mut observations, Violation::NoProgress);
+                Err(observations.failure.clone().unwrap())
+            }
+        }
+    }
+}
+
+/// Runs the simulation once, on a runtime of its own
+pub fn run(seed: u64, config: SimConfig) -> Result<SimReport, SimFailure> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()
+        .unwrap();
+    runtime.block_on(async move {
+        let network = SimNetwork::new(seed, &config);
+        let sites = (0..config.nb_sites)
+            .map(|site| start_site(&network, &config, site))
+            .collect();
+        SimRun {
+            seed,
+            config,
+            network,
+            sites,
+        }
+        .run()
+        .await
+    })
+}
+
+/// Seeds to run: the one given in the environment to replay it, or `0..count`
+pub fn seeds(count: u64) -> Vec<u64> {
+    match std::env::var(SEED_ENV_VAR) {
+        Ok(seed) => vec![seed.parse().expect("the replayed seed must be a number")],
+        Err(_) => (0..count).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mutex::MutexAlgorithm;
+
+    fn check_all_seeds(config: SimConfig) {
+        for seed in seeds(8) {
+            if let Err(failure) = run(seed, config.clone()) {
+                panic!(
+                    "{:?} network, {:?} mutex: {}",
+                    config.topology, config.algorithm, failure
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn invariants_hold_without_faults() {
+        check_all_seeds(SimConfig::new(
+            4,
+            Topology::Full,
+            MutexAlgorithm::Wave,
+            FaultConfig::none(),
+        ));
+    }
+
+    #[test]
+    fn invariants_hold_on_hostile_networks() {
+        for topology in [Topology::Line, Topology::Ring, Topology::Full] {
+            check_all_seeds(SimConfig::new(
+                4,
+                topology,
+                MutexAlgorithm::Wave,
+                FaultConfig::hostile(),
+            ));
+        }
+    }
+
+    #[test]
+    fn every_mutex_keeps_the_invariants() {
+        for algorithm in [MutexAlgorithm::RicartAgrawala, MutexAlgorithm::SuzukiKasami] {
+            for topology in [Topology::Ring, Topology::Full] {
+                check_all_seeds(SimConfig::new(
+                    4,
+                    topology,
+                    algorithm,
+                    FaultConfig::hostile(),
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn invariants_hold_across_partitions() {
+        let mut faults = FaultConfig::hostile();
+        faults.partitions = vec![
+            Partition {
+                start_ms: 20,
+                end_ms: 150,
+                side: vec![0, 1],
+            },
+            Partition {
+                start_ms: 180,
+                end_ms: 300,
+                side: vec![2],
+            },
+        ];
+        check_all_seeds(SimConfig::new(
+            4,
+            Topology::Ring,
+            MutexAlgorithm::Wave,
+            faults,
+        ));
+    }
+
+    #[test]
+    fn faults_are_actually_injected() {
+        let report = run(
+            7,
+            SimConfig::new(
+                4,
+                Topology::Full,
+                MutexAlgorithm::Wave,
+                FaultConfig::hostile(),
+            ),
+        )
+        .unwrap();
+        assert!(report.dropped > 0);
+        assert!(report.reordered > 0);
+        assert!(report.nb_transactions > 0);
+    }
+
+    #[test]
+    fn same_seed_replays_the_same_run() {
+        let config = SimConfig::new(
+            4,
+            Topology::Ring,
+            MutexAlgorithm::Wave,
+            FaultConfig::hostile(),
+        );
+        let first = run(42, config.clone()).unwrap();
+        let replay = run(42, config.clone()).unwrap();
+        assert_eq!(first.trace_hash, replay.trace_hash);
+        assert_eq!(first.end_time_ms, replay.end_time_ms);
+        assert_eq!(first.balances, replay.balances);
+
+        let other = run(43, config).unwrap();
+        assert_ne!(first.trace_hash, other.trace_hash);
+    }
+
+    #[test]
+    fn broken_mutex_is_caught_and_replayable() {
+        let mut config = SimConfig::new(
+            4,
+            Topology::Full,
+            MutexAlgorithm::Wave,
+            FaultConfig::hostile(),
+        );
+        config.guarded = false;
+
+        let failure = (0..8)
+            .find_map(|seed| run(seed, config.clone()).err())
+            .expect("a mutex granting every request must break an invariant");
+        assert!(matches!(
+            failure.violation,
+            Violation::MutualExclusion { .. } | Violation::Overdraft { .. }
+        ));
+        assert!(failure.to_string().contains(SEED_ENV_VAR));
+
+        let replay = run(failure.seed, config).unwrap_err();
+        assert_eq!(replay.time_ms, failure.time_ms);
+        assert_eq!(replay.violation, failure.violation);
+        assert_eq!(replay.trace, failure.trace);
+    }
+}
@@ -18,6 +18,15 @@ pub struct TxSummary {
     pub to_user: String,
     /// Transaction amount
     pub amount_in_cent: i64,
+    /// Hybrid logical clock of the node that created the transaction
+    #[serde(default)]
+    pub hlc: crate::clock::Hlc,
+    /// Transaction refunded by this one, if it is a refund
+    #[serde(default)]
+    pub refund_of: Option<crate::merkle::TxKey>,
+    /// Vector clock of the transaction, as stored by the node that created it
+    #[serde(default)]
+    pub vector_clock: std::collections::BTreeMap<String, i64>,
 }
 
 #[cfg(feature = "server")]
@@ -28,8 +37,12 @@ pub enum SnapshotMode {
     FileMode,
     /// When all snapshot are received, we can create a global snapshot and send it to the network
     NetworkMode,
-    /// When all snapshot are received, we can create a global snapshot and apply it to the local state
-    SyncMode,
+    /// Markers sent on every channel record the local states and the channel
+    /// states of a consistent cut, the global snapshot is saved as a JSON
+    ChandyLamport,
+    /// Like the Chandy–Lamport mode, the ledger invariants are also checked on
+    /// the global snapshot, where every site reports its own balances
+    CheckMode,
 }
 
 #[cfg(feature = "server")]
@@ -40,7 +53,10 @@ impl From<&crate::db::Transaction> for TxSummary {
             source_node: tx.source_node.clone(),
             from_user: tx.from_user.clone(),
             to_user: tx.to_user.clone(),
-            amount_in_cent: (tx.amount * 100.0) as i64,
+            amount_in_cent: tx.amount.cents(),
+            hlc: tx.hlc,
+            refund_of: tx.optional_msg.as_deref().and_then(crate::db::refunded_by),
+            vector_clock: tx.vector_clock.clone().into_iter().collect(),
         }
     }
 }
@@ -55,16 +71,50 @@ pub struct LocalSnapshot {
     pub vector_clock: std::collections::HashMap<String, i64>,
     /// Set of transactions known to this node
     pub tx_log: std::collections::HashSet<TxSummary>,
+    /// Stored balance of every user in cents, if they match the log
+    pub balances: Option<std::collections::BTreeMap<String, i64>>,
+}
+
+#[cfg(feature = "server")]
+/// Message received on a channel after the local state was recorded, before the marker
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct RecordedMessage {
+    /// ID of the node that issued the message
+    pub initiator_id: String,
+    /// Lamport timestamp of the message
+    pub lamport_time: i64,
+    /// Payload of the message
+    pub info: crate::message::MessageInfo,
+}
+
+#[cfg(feature = "server")]
+/// State of the channel between two nodes in a Chandy–Lamport snapshot
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ChannelState {
+    /// ID of the sending node
+    pub from: String,
+    /// ID of the receiving node
+    pub to: String,
+    /// Messages in flight on the channel at the time of the cut
+    pub messages: Vec<RecordedMessage>,
 }
 
 #[cfg(feature = "server")]
 /// Global snapshot combining all local snapshots
-#[derive(Clone, Debug, serde::Serialize)]
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct GlobalSnapshot {
     /// Union of all transactions across nodes
     pub all_transactions: std::collections::HashSet<TxSummary>,
     /// Map of missing transactions per node
     pub missing: std::collections::HashMap<String, std::collections::HashSet<TxSummary>>,
+    /// State of every channel, only recorded by the Chandy–Lamport mode
+    #[serde(default)]
+    pub channels: Vec<ChannelState>,
+    /// Stored balances in cents of the sites whose own log is in the cut
+    ///
+    /// Site_id -> user -> balance
+    #[serde(default)]
+    pub balances: std::collections::HashMap<String, std::collections::BTreeMap<String, i64>>,
 }
 
 #[cfg(feature = "server")]
@@ -73,21 +123,28 @@ impl GlobalSnapshot {
     ///
     /// A set of snapshots is consistent if for any pair of nodes i and j,
     /// the vector clock value of j in i's snapshot is not greater than
-    /// the vector clock value of j in j's own snapshot.
+    /// the vector clock value of j in j's own snapshot: the clock made of the
+    /// own entries of the nodes dominates the join of the snapshot clocks.
     pub fn is_consistent(snaps: &[LocalSnapshot]) -> bool {
-        for si in snaps {
-            for sj in snaps {
-                if let (Some(&cij), Some(&cjj)) = (
-                    si.vector_clock.get(&sj.site_id),
-                    sj.vector_clock.get(&sj.site_id),
-                ) {
-                    if cij > cjj {
-                        return false;
-                    }
-                }
-            }
-        }
-        true
+        use crate::clock::Clock;
+
+        let frontier: std::collections::HashMap<String, i64> = snaps
+            .iter()
+            .filter_map(|s| {
+                let own = s.vector_clock.get(&s.site_id)?;
+                Some((s.site_id.clone(), *own))
+            })
+            .collect();
+        let seen = snaps.iter().fold(Clock::new(), |seen, s| {
+            let known = s
+                .vector_clock
+                .iter()
+                .filter(|(site_id, _)| frontier.contains_key(*site_id))
+                .map(|(site_id, value)| (site_id.clone(), *value))
+                .collect();
+            seen.join(&Clock::from_parts(0, known))
+        });
+        Clock::from_parts(0, frontier).dominates(&seen)
     }
 }
 
@@ -98,10 +155,16 @@ pub struct SnapshotManager {
     pub expected: usize,
     /// Vector of received local snapshots
     pub received: Vec<LocalSnapshot>,
-    /// Path to the last snapshot saved
-    pub path: Option<std::path::PathBuf>,
+    /// Snapshots saved by the site
+    pub index: crate::snapshot_index::SnapshotIndex,
     /// Snapshot mode
     pub mode: SnapshotMode,
+    /// Channel states received, in Chandy–Lamport mode
+    pub channels: Vec<ChannelState>,
+    /// Recording of our channels for the Chandy–Lamport snapshot in progress
+    pub recorder: Option<MarkerRecorder>,
+    /// Last Chandy–Lamport snapshot recorded, to ignore its late markers
+    pub last_recorded: Option<crate::message::WaveId>,
 }
 
 #[cfg(feature = "server")]
@@ -111,8 +174,11 @@ impl SnapshotManager {
         Self {
             expected,
             received: Vec::new(),
-            path: None,
+            index: crate::snapshot_index::SnapshotIndex::new(),
             mode: SnapshotMode::FileMode,
+            channels: Vec::new(),
+            recorder: None,
+            last_recorded: None,
         }
     }
 
@@ -129,8 +195,29 @@ impl SnapshotManager {
             site_id: resp.site_id.clone(),
             vector_clock: resp.clock.get_vector_clock_map().clone(),
             tx_log: resp.tx_log.into_iter().collect(),
+            balances: resp.balances,
         });
 
+        // Markers cross every channel: the cut is consistent by construction,
+        // and complete once every site at the source of a channel answered
+        if matches!(
+            self.mode,
+            SnapshotMode::ChandyLamport | SnapshotMode::CheckMode
+        ) {
+            self.channels.extend(resp.channels);
+            let answered: std::collections::HashSet<&str> =
+                self.received.iter().map(|s| s.site_id.as_str()).collect();
+            if let Some(channel) = self
+                .channels
+                .iter()
+                .find(|c| !answered.contains(c.from.as_str()))
+            {
+                log::debug!("Waiting for the local snapshot of {}", channel.from);
+                return None;
+            }
+            return Some(self.build_snapshot(&self.received));
+        }
+
         if self.received.len() < self.expected {
             log::debug!("{}/{} sites received.", self.received.len(), self.expected);
             return None;
@@ -138,15 +225,11 @@ impl SnapshotManager {
 
         log::debug!("All local snapshots received, processing snapshot.");
 
-        // In Sync and Network modes we simply aggregate all received
-        // transactions without enforcing snapshot consistency. This prevents
-        // dropping valid transactions when a node joins and requests a global
-        // snapshot or when an intermediate node aggregates snapshots from its
+        // In Network mode we simply aggregate all received transactions
+        // without enforcing snapshot consistency. This prevents dropping valid
+        // transactions when an intermediate node aggregates snapshots from its
         // children
-        if matches!(
-            self.mode,
-            SnapshotMode::SyncMode | SnapshotMode::NetworkMode
-        ) {
+        if self.mode == SnapshotMode::NetworkMode {
             return Some(self.build_snapshot(&self.received));
         }
 
@@ -178,11 +261,16 @@ impl SnapshotManager {
 
             // Filter the transaction log to only include transactions that are consistent
             // with the minimum vector clock for their source node.
+            let logged = s.tx_log.len();
             let tx_keep: std::collections::HashSet<_> = s
                 .tx_log
                 .into_iter()
                 .filter(|t| t.lamport_time <= *vmin.get(&t.source_node).unwrap_or(&0))
                 .collect();
+            // les soldes ne correspondent plus au journal tronqué
+            if tx_keep.len() < logged {
+                s.balances = None;
+            }
             s.tx_log = tx_keep;
 
             trimmed.push(s);
@@ -191,6 +279,12 @@ impl SnapshotManager {
         Some(self.build_snapshot(&trimmed))
     }
 
+    /// Writes a global snapshot to the snapshot directory and indexes it
+    pub async fn save(&mut self, snapshot: &GlobalSnapshot, site_id: &str) -> std::io::Result<()> {
+        let path = persist(snapshot, site_id, self.index.dir()).await?;
+        self.index.add(path)
+    }
+
     /// Builds a global snapshot from a set of local snapshots
     ///
     /// Computes the union of all transactions and identifies missing
@@ -214,9 +308,15 @@ impl SnapshotManager {
                 miss.insert(s.site_id.clone(), diff);
             }
         }
+        let balances = snaps
+            .iter()
+            .filter_map(|s| Some((s.site_id.clone(), s.balances.clone()?)))
+            .collect();
         GlobalSnapshot {
             all_transactions: union,
             missing: miss,
+            channels: self.channels.clone(),
+            balances,
         }
     }
 }
@@ -225,12 +325,15 @@ impl SnapshotManager {
 /// Initiates a new snapshot process
 ///
 /// Collects the local transaction log and sends snapshot requests to all peers.
-pub async fn start_snapshot(mode: SnapshotMode) -> Result<(), Box<dyn std::error::Error>> {
-    let local_txs = crate::db::get_local_transaction_log()?;
+pub async fn start_snapshot(
+    node: &crate::node::Node,
+    mode: SnapshotMode,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let local_txs = crate::db::get_local_transaction_log(&node.db)?;
     let summaries: Vec<TxSummary> = local_txs.iter().map(|t| t.into()).collect();
 
     let (site_id, clock, expected) = {
-        let st = crate::state::LOCAL_APP_STATE.lock().await;
+        let st = node.state.lock().await;
         // We expect a snapshot from all connected peers
         // + 1 for self
         let expected_peers = match mode {
@@ -247,27 +350,26 @@ pub async fn start_snapshot(mode: SnapshotMode) -> Result<(), Box<dyn std::error
     };
 
     {
-        let mut mgr = LOCAL_SNAPSHOT_MANAGER.lock().await;
+        let mut mgr = node.snapshots.lock().await;
         mgr.expected = expected;
         mgr.received.clear();
+        mgr.channels.clear();
         mgr.mode = mode.clone();
         if let Some(gs) = mgr.push(crate::message::SnapshotResponse {
             site_id: site_id.clone(),
             clock: clock.clone(),
             tx_log: summaries.clone(),
+            channels: Vec::new(),
+            balances: Some(crate::db::get_balances(&node.db)?),
         }) {
-            if mode.clone() == SnapshotMode::FileMode {
+            if mode == SnapshotMode::FileMode {
                 log::info!(
                     "Global snapshot ready to be saved at start, hold per site : {:#?}",
                     gs.missing
                 );
-                mgr.path = crate::snapshot::persist(&gs, site_id.clone())
-                    .await
-                    .unwrap()
-                    .parse()
-                    .ok();
-            } else if mode.clone() == SnapshotMode::SyncMode {
-                log::info!("No other site, synchronization done");
+                if let Err(e) = mgr.save(&gs, &site_id).await {
+                    log::error!("Cannot save the snapshot: {}", e);
+                }
             } else {
                 log::error!(
                     "Start snapshot is not supposed to be called when there is no neighbours with network mode"
@@ -279,28 +381,569 @@ pub async fn start_snapshot(mode: SnapshotMode) -> Result<(), Box<dyn std::error
     Ok(())
 }
 
+#[cfg(feature = "server")]
+/// Wave collecting the transaction logs of every site
+///
+/// Every site that forwards the request starts its own collection in
+/// network mode and sends up the union of its subtree, the initiator builds
+/// the global snapshot from the logs of its neighbours and its own.
+pub struct SnapshotWave;
+
+#[cfg(feature = "server")]
+impl crate::wave::WaveHandler for SnapshotWave {
+    fn request_code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::SnapshotRequest
+    }
+
+    fn answer_code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::SnapshotResponse
+    }
+
+    fn visit<'a>(
+        &'a self,
+        node: &'a crate::node::Node,
+        _message: &'a crate::message::Message,
+        forwarded: bool,
+    ) -> crate::wave::WaveFuture<'a, ()> {
+        Box::pin(async move {
+            if forwarded {
+                // We are not on a leaf: we build the snapshot of our subtree
+                // When it is done, we send it to the parent
+                log::debug!(
+                    "We are not on a leaf, we start our own global snapshot construction and diffuse the request to other nodes"
+                );
+                start_snapshot(node, SnapshotMode::NetworkMode).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn leaf_answer<'a>(
+        &'a self,
+        node: &'a crate::node::Node,
+        _message: &'a crate::message::Message,
+    ) -> crate::wave::WaveFuture<'a, crate::message::MessageInfo> {
+        Box::pin(async move {
+            // Here we are on a leaf, we can create a local snapshot and send it to the parent
+            let txs = crate::db::get_local_transaction_log(&node.db)?;
+            let summaries: Vec<_> = txs.iter().map(|t| t.into()).collect();
+            let (site_id, clock) = {
+                let st = node.state.lock().await;
+                (st.get_site_id(), st.get_clock())
+            };
+            Ok(crate::message::MessageInfo::SnapshotResponse(
+                crate::message::SnapshotResponse {
+                    site_id,
+                    clock,
+                    tx_log: summaries,
+                    channels: Vec::new(),
+                    balances: Some(crate::db::get_balances(&node.db)?),
+                },
+            ))
+        })
+    }
+
+    fn aggregate<'a>(
+        &'a self,
+        node: &'a crate::node::Node,
+        state: &'a crate::state::AppState,
+        answer: &'a crate::message::Message,
+        last: bool,
+    ) -> crate::wave::WaveFuture<'a, crate::message::MessageInfo> {
+        Box::pin(async move {
+            let mut mgr = node.snapshots.lock().await;
+            let global = match &answer.info {
+                crate::message::MessageInfo::SnapshotResponse(resp) => mgr.push(resp.clone()),
+                _ => {
+                    log::error!("Message de type SnapshotResponse attendu, mais pas reçu");
+                    None
+                }
+            };
+            if !last {
+                if global.is_some() {
+                    log::error!(
+                        "On ne devrait pas encore pouvoir construire une snapshot globale vu que la vague n'est pas terminée"
+                    );
+                }
+                return Ok(crate::message::MessageInfo::None);
+            }
+            let Some(gs) = global else {
+                // The wave still terminates, the initiator has to leave its critical section
+                log::error!("Le site aurait du récupérer toutes ses snapshots");
+                return Ok(crate::message::MessageInfo::None);
+            };
+
+            match mgr.mode {
+                SnapshotMode::FileMode => {
+                    log::info!(
+                        "Global snapshot ready to save, hold per site : {:#?}",
+                        gs.missing
+                    );
+                    mgr.save(&gs, &state.get_site_id()).await?;
+                }
+                SnapshotMode::NetworkMode => {
+                    log::info!(
+                        "Global snapshot ready to be send to parent, hold per site : {:#?}",
+                        gs.missing
+                    );
+                }
+                SnapshotMode::ChandyLamport | SnapshotMode::CheckMode => {
+                    log::error!("A Chandy–Lamport snapshot does not collect through a wave");
+                }
+            }
+            Ok(crate::message::MessageInfo::SnapshotResponse(
+                crate::message::SnapshotResponse {
+                    site_id: state.get_site_id(),
+                    clock: state.get_clock(),
+                    tx_log: gs.all_transactions.into_iter().collect(),
+                    channels: Vec::new(),
+                    balances: None,
+                },
+            ))
+        })
+    }
+
+    fn complete<'a>(
+        &'a self,
+        node: &'a crate::node::Node,
+        state: &'a mut crate::state::AppState,
+        _aggregate: crate::message::MessageInfo,
+    ) -> crate::wave::WaveFuture<'a, ()> {
+        Box::pin(async move { state.release_mutex_if_done(node).await })
+    }
+}
+
+#[cfg(feature = "server")]
+/// Recording of the incoming channels of a site during a Chandy–Lamport snapshot
+///
+/// A channel is recorded from the moment the local state is taken until its
+/// marker arrives. The channel the first marker came from is empty.
+pub struct MarkerRecorder {
+    /// Identifier of the snapshot
+    pub id: crate::message::WaveId,
+    /// Address of the site that started the snapshot
+    pub initiator_addr: std::net::SocketAddr,
+    /// Local state of the site, taken when the snapshot reached it
+    local: crate::message::SnapshotResponse,
+    /// Channels whose marker has not arrived yet, by address of the sending neighbour
+    open: std::collections::HashMap<std::net::SocketAddr, Vec<RecordedMessage>>,
+    /// Channels whose marker arrived, with the messages recorded on them
+    closed: Vec<(std::net::SocketAddr, Vec<RecordedMessage>)>,
+}
+
+#[cfg(feature = "server")]
+impl MarkerRecorder {
+    /// Starts recording the channels from the given neighbours
+    pub fn new(
+        id: crate::message::WaveId,
+        initiator_addr: std::net::SocketAddr,
+        local: crate::message::SnapshotResponse,
+        neighbours: &[std::net::SocketAddr],
+        marker_from: Option<std::net::SocketAddr>,
+    ) -> Self {
+        let mut recorder = Self {
+            id,
+            initiator_addr,
+            local,
+            open: neighbours.iter().map(|addr| (*addr, Vec::new())).collect(),
+            closed: Vec::new(),
+        };
+        if let Some(from) = marker_from {
+            recorder.close(from);
+        }
+        recorder
+    }
+
+    /// Records a message received from a neighbour, if its channel is still open
+    ///
+    /// A transaction already in the local state, or already recorded on
+    /// another channel, is a duplicate visit of its wave and is not recorded.
+    pub fn record(&mut self, from: std::net::SocketAddr, message: RecordedMessage) {
+        let same = |lamport_time: i64, site: &str| {
+            lamport_time == message.lamport_time && site == message.initiator_id
+        };
+        let applied = self
+            .local
+            .tx_log
+            .iter()
+            .any(|tx| same(tx.lamport_time, &tx.source_node));
+        let recorded = self
+            .open
+            .values()
+            .chain(self.closed.iter().map(|(_, messages)| messages))
+            .flatten()
+            .any(|m| same(m.lamport_time, &m.initiator_id));
+        if applied || recorded {
+            return;
+        }
+        if let Some(messages) = self.open.get_mut(&from) {
+            messages.push(message);
+        }
+    }
+
+    /// Stops recording the channel a marker came from
+    pub fn close(&mut self, from: std::net::SocketAddr) {
+        let messages = self.open.remove(&from).unwrap_or_default();
+        self.closed.push((from, messages));
+    }
+
+    /// Whether the marker of every channel arrived
+    pub fn is_complete(&self) -> bool {
+        self.open.is_empty()
+    }
+
+    /// Local snapshot of the site with the state of its incoming channels
+    pub fn finish(
+        self,
+        site_ids: &std::collections::HashMap<std::net::SocketAddr, String>,
+    ) -> crate::message::SnapshotResponse {
+        let to = self.local.site_id.clone();
+        let channels = self
+            .closed
+            .into_iter()
+            .map(|(addr, messages)| ChannelState {
+                from: site_ids.get(&addr).cloned().unwrap_or_else(|| {
+                    log::warn!("Unknown site at {}, channel named after its address", addr);
+                    addr.to_string()
+                }),
+                to: to.clone(),
+                messages,
+            })
+            .collect();
+        crate::message::SnapshotResponse {
+            channels,
+            ..self.local
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+/// Local state of the site for a snapshot
+fn local_response(
+    db: &crate::db::Db,
+    state: &crate::state::AppState,
+) -> Result<crate::message::SnapshotResponse, Box<dyn std::error::Error>> {
+    let txs = crate::db::get_local_transaction_log(db)?;
+    Ok(crate::message::SnapshotResponse {
+        site_id: state.get_site_id(),
+        clock: state.get_clock(),
+        tx_log: txs.iter().map(|t| t.into()).collect(),
+        channels: Vec::new(),
+        balances: Some(crate::db::get_balances(db)?),
+    })
+}
+
+#[cfg(feature = "server")]
+/// Sends the marker of a snapshot on every outgoing channel
+async fn send_markers(
+    node: &crate::node::Node,
+    state: &crate::state::AppState,
+    id: &crate::message::WaveId,
+    initiator_addr: std::net::SocketAddr,
+) {
+    for addr in state.get_connected_nei_addr() {
+        let result = crate::network::send_message(
+            node,
+            addr,
+            crate::message::MessageInfo::None,
+            crate::message::NetworkMessageCode::SnapshotMarker,
+            state.get_site_addr(),
+            &state.get_site_id(),
+            crate::message::Origin {
+                wave: id.clone(),
+                initiator_addr,
+            },
+            state.get_clock(),
+        )
+        .await;
+        if let Err(e) = result {
+            log::error!("Cannot send the snapshot marker to {}: {}", addr, e);
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+/// Starts a Chandy–Lamport snapshot, in the Chandy–Lamport or check mode
+///
+/// The state of the site is taken and the markers sent without releasing
+/// the state, so no message leaves the site in between.
+pub async fn start_marker_snapshot(
+    node: &crate::node::Node,
+    mode: SnapshotMode,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let finished = {
+        let mut state = node.state.lock().await;
+        let id = state.next_wave_id();
+        let local = local_response(&node.db, &state)?;
+        let site_addr = state.get_site_addr();
+        let mut mgr = node.snapshots.lock().await;
+        mgr.mode = mode;
+        mgr.received.clear();
+        mgr.channels.clear();
+        mgr.last_recorded = Some(id.clone());
+        mgr.recorder = Some(MarkerRecorder::new(
+            id.clone(),
+            site_addr,
+            local,
+            &state.get_connected_nei_addr(),
+            None,
+        ));
+        send_markers(node, &state, &id, site_addr).await;
+        take_finished(&mut mgr, &state)
+    };
+    if let Some((resp, id, initiator_addr)) = finished {
+        deliver_recorded(node, resp, id, initiator_addr).await?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+/// Handles the marker of a Chandy–Lamport snapshot received from a neighbour
+pub async fn receive_marker(
+    node: &crate::node::Node,
+    message: &crate::message::Message,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let id = message.wave_id();
+    let finished = {
+        let state = node.state.lock().await;
+        let mut guard = node.snapshots.lock().await;
+        let mgr: &mut SnapshotManager = &mut guard;
+        match &mut mgr.recorder {
+            Some(recorder) if recorder.id == id => recorder.close(message.sender_addr),
+            _ if mgr.last_recorded.as_ref() == Some(&id) => {
+                log::debug!("Late marker of the snapshot {}", id);
+                return Ok(());
+            }
+            _ => {
+                // premier marqueur : on enregistre notre état puis on relaie
+                // avant de laisser partir tout autre message
+                log::info!("Recording our state for the snapshot {}", id);
+                let local = local_response(&node.db, &state)?;
+                mgr.last_recorded = Some(id.clone());
+                mgr.recorder = Some(MarkerRecorder::new(
+                    id.clone(),
+                    message.message_initiator_addr,
+                    local,
+                    &state.get_connected_nei_addr(),
+                    Some(message.sender_addr),
+                ));
+                send_markers(node, &state, &id, message.message_initiator_addr).await;
+            }
+        }
+        take_finished(mgr, &state)
+    };
+    if let Some((resp, id, initiator_addr)) = finished {
+        deliver_recorded(node, resp, id, initiator_addr).await?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+/// Takes the local snapshot out of the recorder once every marker arrived
+fn take_finished(
+    mgr: &mut SnapshotManager,
+    state: &crate::state::AppState,
+) -> Option<(
+    crate::message::SnapshotResponse,
+    crate::message::WaveId,
+    std::net::SocketAddr,
+)> {
+    if !mgr.recorder.as_ref().is_some_and(|r| r.is_complete()) {
+        return None;
+    }
+    let recorder = mgr.recorder.take()?;
+    let (id, initiator_addr) = (recorder.id.clone(), recorder.initiator_addr);
+    Some((recorder.finish(&state.site_ids_to_adr), id, initiator_addr))
+}
+
+#[cfg(feature = "server")]
+/// Records a transaction received while its channel is recorded
+///
+/// Only the first visit of a transaction wave is recorded: the copies coming
+/// from the other neighbours are not in flight towards the site.
+pub async fn record_in_flight(node: &crate::node::Node, message: &crate::message::Message) {
+    let first_visit = !node.state.lock().await.knows_wave(&message.wave_id());
+    if !first_visit {
+        return;
+    }
+    let mut mgr = node.snapshots.lock().await;
+    if let Some(recorder) = &mut mgr.recorder {
+        recorder.record(
+            message.sender_addr,
+            RecordedMessage {
+                initiator_id: message.message_initiator_id.clone(),
+                lamport_time: *message.clock.get_lamport(),
+                info: message.info.clone(),
+            },
+        );
+    }
+}
+
+#[cfg(feature = "server")]
+/// Sends our local snapshot to the initiator, or collects it if we are the initiator
+async fn deliver_recorded(
+    node: &crate::node::Node,
+    resp: crate::message::SnapshotResponse,
+    id: crate::message::WaveId,
+    initiator_addr: std::net::SocketAddr,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (site_addr, site_id, clock) = {
+        let state = node.state.lock().await;
+        (
+            state.get_site_addr(),
+            state.get_site_id(),
+            state.get_clock(),
+        )
+    };
+    if initiator_addr == site_addr {
+        return collect_recorded(node, resp, &site_id).await;
+    }
+    crate::network::send_message(
+        node,
+        initiator_addr,
+        crate::message::MessageInfo::SnapshotResponse(resp),
+        crate::message::NetworkMessageCode::SnapshotRecorded,
+        site_addr,
+        &site_id,
+        crate::message::Origin {
+            wave: id.clone(),
+            initiator_addr,
+        },
+        clock,
+    )
+    .await
+}
+
+#[cfg(feature = "server")]
+/// Collects a local snapshot of a Chandy–Lamport snapshot we initiated
+pub async fn collect_recorded(
+    node: &crate::node::Node,
+    resp: crate::message::SnapshotResponse,
+    site_id: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut mgr = node.snapshots.lock().await;
+    if !matches!(
+        mgr.mode,
+        SnapshotMode::ChandyLamport | SnapshotMode::CheckMode
+    ) {
+        log::warn!(
+            "Local snapshot of {} received after the end of the snapshot",
+            resp.site_id
+        );
+        return Ok(());
+    }
+    if let Some(gs) = mgr.push(resp) {
+        log::info!(
+            "Chandy–Lamport snapshot complete, {} channels recorded",
+            gs.channels.len()
+        );
+        mgr.save(&gs, site_id).await?;
+        if mgr.mode == SnapshotMode::CheckMode {
+            report_ledger(&gs);
+        }
+        // les réponses en retard ne doivent pas relancer la construction
+        mgr.mode = SnapshotMode::FileMode;
+    }
+    Ok(())
+}
+
 #[cfg(feature = "server")]
 /// Persists a global snapshot to disk
 ///
-/// Saves the snapshot as a JSON file with a timestamp in the filename.
-pub async fn persist(snapshot: &GlobalSnapshot, site_id: String) -> std::io::Result<String> {
+/// Saves the snapshot in `dir` as a JSON file with a timestamp in the filename.
+pub async fn persist(
+    snapshot: &GlobalSnapshot,
+    site_id: &str,
+    dir: &std::path::Path,
+) -> std::io::Result<std::path::PathBuf> {
     use std::io::Write;
 
     let ts = chrono::Local::now().format("%Y%m%d_%H%M%S");
-    let filename = format!("snapshot_{}_{}.json", site_id, ts);
+    std::fs::create_dir_all(dir)?;
+    let filename = dir.join(format!("snapshot_{}_{}.json", site_id, ts));
 
     let mut file = std::fs::File::create(&filename)?;
     let json = serde_json::to_string_pretty(snapshot).unwrap();
     file.write_all(json.as_bytes())?;
-    println!("📸 Snapshot completed successfully at {}", filename);
+    println!(
+        "📸 Snapshot completed successfully at {}",
+        filename.display()
+    );
 
     Ok(filename)
 }
 
+#[cfg(feature = "server")]
+/// Checks the ledger invariants on a global snapshot and prints the violations
+fn report_ledger(snapshot: &GlobalSnapshot) {
+    let report = crate::ledger::check(snapshot);
+    if report.violations.is_empty() {
+        log::info!("Ledger checked, no violation found");
+    } else {
+        log::error!("{} ledger violations found", report.violations.len());
+    }
+    println!("🔎 Ledger check:\n{}", report);
+}
+
+#[cfg(feature = "server")]
+/// Reads a global snapshot saved by [`persist`]
+///
+/// Fails if the file is not a global snapshot, or if it holds two different
+/// transactions with the same key or a negative amount.
+pub fn load(path: &str) -> Result<GlobalSnapshot, Box<dyn std::error::Error>> {
+    let json = std::fs::read_to_string(path)?;
+    let snapshot: GlobalSnapshot =
+        serde_json::from_str(&json).map_err(|e| format!("{} is not a snapshot: {}", path, e))?;
+
+    let mut keys = std::collections::HashSet::new();
+    for tx in &snapshot.all_transactions {
+        if !keys.insert((&tx.source_node, tx.lamport_time)) {
+            return Err(format!(
+                "Transaction {}-{} appears twice in {}",
+                tx.source_node, tx.lamport_time, path
+            )
+            .into());
+        }
+        if tx.amount_in_cent < 0 {
+            return Err(format!(
+                "Transaction {}-{} has a negative amount in {}",
+                tx.source_node, tx.lamport_time, path
+            )
+            .into());
+        }
+    }
+    Ok(snapshot)
+}
+
+#[cfg(feature = "server")]
+/// Rebuilds the users and transactions of the site from a snapshot file
+///
+/// The database is emptied first, and the clock of the site is reset ahead of
+/// the restored transactions. Returns the number of transactions restored.
+pub async fn restore(
+    node: &crate::node::Node,
+    path: &str,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let snapshot = load(path)?;
+    let txs: Vec<TxSummary> = snapshot.all_transactions.into_iter().collect();
+    crate::db::restore_transactions(&node.db, &txs)?;
+    crate::merkle::reload(&node.db);
+
+    let lamport = txs.iter().map(|tx| tx.lamport_time).max().unwrap_or(0);
+    let hlc = txs.iter().map(|tx| tx.hlc).max().unwrap_or_default();
+    let mut state = node.state.lock().await;
+    let site_id = state.get_site_id();
+    let clock = state.get_clock().restored(&site_id, lamport, hlc);
+    crate::db::update_local_state(&node.db, &site_id, clock.clone())?;
+    state.init_clock(clock);
+    log::info!("{} transactions restored from {}", txs.len(), path);
+    Ok(txs.len())
+}
+
 #[cfg(feature = "server")]
 lazy_static::lazy_static! {
-    pub static ref LOCAL_SNAPSHOT_MANAGER: tokio::sync::Mutex<SnapshotManager> =
-        tokio::sync::Mutex::new(SnapshotManager::new(0));
+    pub static ref LOCAL_SNAPSHOT_MANAGER: std::sync::Arc<tokio::sync::Mutex<SnapshotManager>> =
+        std::sync::Arc::new(tokio::sync::Mutex::new(SnapshotManager::new(0)));
 }
 
 #[cfg(test)]
@@ -322,6 +965,8 @@ mod tests {
             site_id: site.to_string(),
             clock: mk_clock(vc),
             tx_log: txs.to_vec(),
+            channels: Vec::new(),
+            balances: None,
         }
     }
 
@@ -331,11 +976,13 @@ mod tests {
             site_id: "A".into(),
             vector_clock: std::collections::HashMap::from_iter([("A".into(), 1), ("B".into(), 0)]),
             tx_log: std::collections::HashSet::new(),
+            balances: None,
         };
         let s2 = LocalSnapshot {
             site_id: "B".into(),
             vector_clock: std::collections::HashMap::from_iter([("A".into(), 1), ("B".into(), 1)]),
             tx_log: std::collections::HashSet::new(),
+            balances: None,
         };
         assert!(GlobalSnapshot::is_consistent(&[s1, s2]));
     }
@@ -346,11 +993,13 @@ mod tests {
             site_id: "A".into(),
             vector_clock: std::collections::HashMap::from_iter([("A".into(), 2), ("B".into(), 2)]),
             tx_log: std::collections::HashSet::new(),
+            balances: None,
         };
         let s2 = LocalSnapshot {
             site_id: "B".into(),
             vector_clock: std::collections::HashMap::from_iter([("A".into(), 1), ("B".into(), 1)]),
             tx_log: std::collections::HashSet::new(),
+            balances: None,
         };
         assert!(!GlobalSnapshot::is_consistent(&[s1, s2]));
     }
@@ -364,6 +1013,9 @@ mod tests {
             from_user: "user1".into(),
             to_user: "user2".into(),
             amount_in_cent: 100,
+            hlc: crate::clock::Hlc::default(),
+            refund_of: None,
+            vector_clock: Default::default(),
         };
         let r1 = resp("A", &[("A", 1)], &[tx.clone()]);
         assert!(mgr.push(r1).is_none());
@@ -380,11 +1032,42 @@ mod tests {
         assert!(GlobalSnapshot::is_consistent(&[LocalSnapshot {
             site_id: "dummy".into(),
             vector_clock: std::collections::HashMap::new(),
-            tx_log: snap.all_transactions.clone()
+            tx_log: snap.all_transactions.clone(),
+            balances: None,
         }]));
         assert!(snap.missing.is_empty() || !snap.missing.contains_key("A"));
     }
 
+    #[test]
+    fn trimmed_logs_drop_their_balances() {
+        let tx = |lamport| TxSummary {
+            lamport_time: lamport,
+            source_node: "A".into(),
+            from_user: "NULL".into(),
+            to_user: "alice".into(),
+            amount_in_cent: 100,
+            hlc: crate::clock::Hlc::default(),
+            refund_of: None,
+            vector_clock: Default::default(),
+        };
+        let balances = |cents| Some(std::collections::BTreeMap::from([("alice".into(), cents)]));
+
+        let mut mgr = SnapshotManager::new(2);
+        let mut a = resp("A", &[("A", 1)], &[tx(1)]);
+        a.balances = balances(100);
+        // B already saw the second deposit of A, which A has not recorded
+        let mut b = resp("B", &[("A", 2), ("B", 1)], &[tx(1), tx(2)]);
+        b.balances = balances(200);
+        assert!(mgr.push(a).is_none());
+        let gs = mgr.push(b).expect("back-tracked snapshot");
+
+        assert_eq!(gs.all_transactions.len(), 1);
+        assert_eq!(
+            gs.balances.keys().collect::<Vec<_>>(),
+            vec![&"A".to_string()]
+        );
+    }
+
     #[test]
     fn push_computes_missing_and_dedup() {
         let mut mgr = SnapshotManager::new(2);
@@ -394,6 +1077,9 @@ mod tests {
             from_user: "user1".into(),
             to_user: "user2".into(),
             amount_in_cent: 100,
+            hlc: crate::clock::Hlc::default(),
+            refund_of: None,
+            vector_clock: Default::default(),
         };
         let t2 = TxSummary {
             lamport_time: 11,
@@ -401,6 +1087,9 @@ mod tests {
             from_user: "user3".into(),
             to_user: "user4".into(),
             amount_in_cent: 200,
+            hlc: crate::clock::Hlc::default(),
+            refund_of: None,
+            vector_clock: Default::default(),
         };
 
         let r1 = resp("A", &[("A", 1)], &[t1.clone()]);
@@ -423,11 +1112,13 @@ mod tests {
             site_id: "A".into(),
             vector_clock: std::collections::HashMap::from_iter([("A".into(), 3)]),
             tx_log: std::collections::HashSet::new(),
+            balances: None,
         };
         let b = LocalSnapshot {
             site_id: "B".into(),
             vector_clock: std::collections::HashMap::from_iter([("B".into(), 1)]),
             tx_log: std::collections::HashSet::new(),
+            balances: None,
         };
         assert!(GlobalSnapshot::is_consistent(&[a, b]));
     }
@@ -442,6 +1133,9 @@ mod tests {
             from_user: "user1".into(),
             to_user: "user2".into(),
             amount_in_cent: 100,
+            hlc: crate::clock::Hlc::default(),
+            refund_of: None,
+            vector_clock: Default::default(),
         };
         let t3 = TxSummary {
             lamport_time: 3,
@@ -449,6 +1143,9 @@ mod tests {
             from_user: "user1".into(),
             to_user: "user2".into(),
             amount_in_cent: 300,
+            hlc: crate::clock::Hlc::default(),
+            refund_of: None,
+            vector_clock: Default::default(),
         };
         let t5 = TxSummary {
             lamport_time: 5,
@@ -456,6 +1153,9 @@ mod tests {
             from_user: "user1".into(),
             to_user: "user2".into(),
             amount_in_cent: 500,
+            hlc: crate::clock::Hlc::default(),
+            refund_of: None,
+            vector_clock: Default::default(),
         };
 
         let r_a = resp(
@@ -473,6 +1173,122 @@ mod tests {
         assert!(!snap.all_transactions.contains(&t5));
     }
 
+    fn recorded(lamport_time: i64) -> RecordedMessage {
+        RecordedMessage {
+            initiator_id: "B".into(),
+            lamport_time,
+            info: crate::message::MessageInfo::None,
+        }
+    }
+
+    #[test]
+    fn recorder_keeps_messages_until_the_marker() {
+        let a: std::net::SocketAddr = "127.0.0.1:10000".parse().unwrap();
+        let b: std::net::SocketAddr = "127.0.0.1:10001".parse().unwrap();
+        let c: std::net::SocketAddr = "127.0.0.1:10002".parse().unwrap();
+        let id = crate::message::WaveId {
+            initiator_id: "A".into(),
+            seq: 1,
+        };
+        let mut recorder = MarkerRecorder::new(id, a, resp("D", &[], &[]), &[a, b, c], Some(a));
+
+        // The channel of the first marker is empty
+        recorder.record(a, recorded(1));
+        recorder.record(b, recorded(2));
+        recorder.close(b);
+        recorder.record(b, recorded(3));
+        assert!(!recorder.is_complete());
+        recorder.close(c);
+        assert!(recorder.is_complete());
+
+        let site_ids = std::collections::HashMap::from([
+            (a, "A".to_string()),
+            (b, "B".to_string()),
+            (c, "C".to_string()),
+        ]);
+        let local = recorder.finish(&site_ids);
+        let mut channels: Vec<_> = local
+            .channels
+            .iter()
+            .map(|c| {
+                let times: Vec<_> = c.messages.iter().map(|m| m.lamport_time).collect();
+                (c.from.as_str(), c.to.as_str(), times)
+            })
+            .collect();
+        channels.sort();
+        assert_eq!(
+            channels,
+            vec![("A", "D", vec![]), ("B", "D", vec![2]), ("C", "D", vec![])]
+        );
+    }
+
+    #[test]
+    fn recorder_ignores_duplicate_visits() {
+        let a: std::net::SocketAddr = "127.0.0.1:10000".parse().unwrap();
+        let b: std::net::SocketAddr = "127.0.0.1:10001".parse().unwrap();
+        let c: std::net::SocketAddr = "127.0.0.1:10002".parse().unwrap();
+        let id = crate::message::WaveId {
+            initiator_id: "A".into(),
+            seq: 1,
+        };
+        let applied = TxSummary {
+            lamport_time: 1,
+            source_node: "B".into(),
+            from_user: "NULL".into(),
+            to_user: "alice".into(),
+            amount_in_cent: 100,
+            hlc: crate::clock::Hlc::default(),
+            refund_of: None,
+            vector_clock: Default::default(),
+        };
+        let mut recorder = MarkerRecorder::new(id, a, resp("D", &[], &[applied]), &[a, b, c], None);
+
+        // The wave of transaction 1 was applied before the snapshot, the one
+        // of transaction 2 reaches the site through both b and c
+        recorder.record(a, recorded(1));
+        recorder.record(b, recorded(2));
+        recorder.close(b);
+        recorder.record(c, recorded(2));
+        recorder.close(a);
+        recorder.close(c);
+
+        let local = recorder.finish(&std::collections::HashMap::new());
+        let times: Vec<_> = local
+            .channels
+            .iter()
+            .flat_map(|c| c.messages.iter().map(|m| m.lamport_time))
+            .collect();
+        assert_eq!(times, vec![2]);
+    }
+
+    #[test]
+    fn chandy_lamport_waits_for_every_channel_source() {
+        let mut mgr = SnapshotManager::new(0);
+        mgr.mode = SnapshotMode::ChandyLamport;
+        let channel = |from: &str, to: &str, messages| ChannelState {
+            from: from.into(),
+            to: to.into(),
+            messages,
+        };
+
+        let mut a = resp("A", &[("A", 2)], &[]);
+        a.channels = vec![channel("B", "A", vec![recorded(4)])];
+        assert!(mgr.push(a).is_none());
+
+        let mut b = resp("B", &[("B", 4)], &[]);
+        b.channels = vec![channel("A", "B", vec![]), channel("C", "B", vec![])];
+        assert!(mgr.push(b).is_none());
+
+        let mut c = resp("C", &[("C", 1)], &[]);
+        c.channels = vec![channel("B", "C", vec![])];
+        let gs = mgr.push(c).expect("every site answered");
+        assert_eq!(gs.channels.len(), 4);
+        assert_eq!(
+            gs.channels.iter().map(|c| c.messages.len()).sum::<usize>(),
+            1
+        );
+    }
+
     #[test]
     fn union_is_deduplicated() {
         let mut mgr = SnapshotManager::new(2);
@@ -482,6 +1298,9 @@ mod tests {
             from_user: "user1".into(),
             to_user: "user2".into(),
             amount_in_cent: 700,
+            hlc: crate::clock::Hlc::default(),
+            refund_of: None,
+            vector_clock: Default::default(),
         };
 
         let r1 = resp("A", &[("A", 1)], &[tx.clone()]);
@@ -491,4 +1310,39 @@ mod tests {
         let gs = mgr.push(r2).expect("snapshot ready");
         assert_eq!(gs.all_transactions.len(), 1);
     }
+
+    #[test]
+    fn load_validates_the_file() {
+        let dir = std::env::temp_dir();
+        let tx = |lamport: i64, amount_in_cent: i64| {
+            format!(
+                r#"{{"lamport_time":{lamport},"source_node":"A","from_user":"NULL","to_user":"alice","amount_in_cent":{amount_in_cent}}}"#
+            )
+        };
+        let write = |name: &str, txs: &[String]| {
+            let path = dir.join(format!("peillute_{}_{}.json", name, std::process::id()));
+            let json = format!(
+                r#"{{"all_transactions":[{}],"missing":{{}}}}"#,
+                txs.join(",")
+            );
+            std::fs::write(&path, json).unwrap();
+            path.to_string_lossy().to_string()
+        };
+
+        let ok = write("ok", &[tx(1, 500), tx(2, 300)]);
+        let snapshot = load(&ok).expect("valid snapshot");
+        assert_eq!(snapshot.all_transactions.len(), 2);
+        assert!(snapshot.channels.is_empty());
+
+        let twice = write("twice", &[tx(1, 500), tx(1, 300)]);
+        assert!(load(&twice).is_err());
+        let negative = write("negative", &[tx(1, -500)]);
+        assert!(load(&negative).is_err());
+        let garbage = write("garbage", &["42".to_string()]);
+        assert!(load(&garbage).is_err());
+
+        for path in [ok, twice, negative, garbage] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
 }
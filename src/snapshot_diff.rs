@@ -0,0 +1,400 @@
+//! Comparison of two global snapshots
+//!
+//! When sites disagree, the snapshots they saved tell where: the transactions
+//! that only one of them holds, the ones both hold with different contents,
+//! the balances these transactions change, and the transactions each site was
+//! missing at the time of each snapshot. Transactions are matched by their key,
+//! the site that made them and their Lamport time, so that a transaction whose
+//! clock or refund was not recorded by an older version is reported as changed
+//! rather than as two unrelated transactions. The balances are recomputed from
+//! the transactions, the way the database does.
+
+#![cfg(feature = "server")]
+
+/// Balance of a user in both snapshots, in cents
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct BalanceDiff {
+    /// The user
+    pub user: String,
+    /// Balance in the first snapshot
+    pub left: i64,
+    /// Balance in the second snapshot
+    pub right: i64,
+}
+
+/// Transaction held by both snapshots with different contents
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct TxChange {
+    /// The transaction in the first snapshot
+    pub left: crate::snapshot::TxSummary,
+    /// The transaction in the second snapshot
+    pub right: crate::snapshot::TxSummary,
+    /// Fields that differ
+    pub fields: Vec<&'static str>,
+}
+
+/// Change of the transactions a site was missing between both snapshots
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct MissingDiff {
+    /// The site
+    pub site_id: String,
+    /// Missing in the second snapshot only
+    pub added: Vec<crate::snapshot::TxSummary>,
+    /// Missing in the first snapshot only
+    pub removed: Vec<crate::snapshot::TxSummary>,
+}
+
+/// Differences between two global snapshots
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct SnapshotDiff {
+    /// Transactions of the first snapshot only, in Lamport order
+    pub only_in_left: Vec<crate::snapshot::TxSummary>,
+    /// Transactions of the second snapshot only, in Lamport order
+    pub only_in_right: Vec<crate::snapshot::TxSummary>,
+    /// Transactions of both snapshots whose contents differ, in Lamport order
+    pub changed: Vec<TxChange>,
+    /// Users whose balance differs
+    pub balances: Vec<BalanceDiff>,
+    /// Sites whose missing transactions differ
+    pub missing: Vec<MissingDiff>,
+}
+
+impl SnapshotDiff {
+    /// Whether both snapshots hold the same state
+    pub fn is_empty(&self) -> bool {
+        self.only_in_left.is_empty()
+            && self.only_in_right.is_empty()
+            && self.changed.is_empty()
+            && self.balances.is_empty()
+            && self.missing.is_empty()
+    }
+}
+
+/// Compares two global snapshots
+pub fn diff(
+    left: &crate::snapshot::GlobalSnapshot,
+    right: &crate::snapshot::GlobalSnapshot,
+) -> SnapshotDiff {
+    let left_balances = balances(left.all_transactions.iter());
+    let right_balances = balances(right.all_transactions.iter());
+    let users: std::collections::BTreeSet<&String> =
+        left_balances.keys().chain(right_balances.keys()).collect();
+    let balances = users
+        .into_iter()
+        .filter_map(|user| {
+            let left = left_balances.get(user).copied().unwrap_or(0);
+            let right = right_balances.get(user).copied().unwrap_or(0);
+            (left != right).then(|| BalanceDiff {
+                user: user.clone(),
+                left,
+                right,
+            })
+        })
+        .collect();
+
+    let empty = std::collections::HashSet::new();
+    let sites: std::collections::BTreeSet<&String> =
+        left.missing.keys().chain(right.missing.keys()).collect();
+    let missing = sites
+        .into_iter()
+        .filter_map(|site_id| {
+            let before = by_key(left.missing.get(site_id).unwrap_or(&empty));
+            let after = by_key(right.missing.get(site_id).unwrap_or(&empty));
+            let added = only_in(&after, &before);
+            let removed = only_in(&before, &after);
+            (!added.is_empty() || !removed.is_empty()).then(|| MissingDiff {
+                site_id: site_id.clone(),
+                added,
+                removed,
+            })
+        })
+        .collect();
+
+    let left_txs = by_key(&left.all_transactions);
+    let right_txs = by_key(&right.all_transactions);
+    let mut changed: Vec<TxChange> = left_txs
+        .iter()
+        .filter_map(|(key, l)| {
+            let r = right_txs.get(key)?;
+            let fields = changed_fields(l, r);
+            (!fields.is_empty()).then(|| TxChange {
+                left: (*l).clone(),
+                right: (*r).clone(),
+                fields,
+            })
+        })
+        .collect();
+    changed.sort_by(|a, b| {
+        (a.left.lamport_time, &a.left.source_node).cmp(&(b.left.lamport_time, &b.left.source_node))
+    });
+
+    SnapshotDiff {
+        only_in_left: only_in(&left_txs, &right_txs),
+        only_in_right: only_in(&right_txs, &left_txs),
+        changed,
+        balances,
+        missing,
+    }
+}
+
+/// Fields of a transaction that differ between both snapshots
+fn changed_fields(
+    left: &crate::snapshot::TxSummary,
+    right: &crate::snapshot::TxSummary,
+) -> Vec<&'static str> {
+    [
+        ("from_user", left.from_user != right.from_user),
+        ("to_user", left.to_user != right.to_user),
+        (
+            "amount_in_cent",
+            left.amount_in_cent != right.amount_in_cent,
+        ),
+        ("hlc", left.hlc != right.hlc),
+        ("refund_of", left.refund_of != right.refund_of),
+        ("vector_clock", left.vector_clock != right.vector_clock),
+    ]
+    .into_iter()
+    .filter_map(|(field, differs)| differs.then_some(field))
+    .collect()
+}
+
+fn by_key(
+    txs: &std::collections::HashSet<crate::snapshot::TxSummary>,
+) -> std::collections::HashMap<crate::merkle::TxKey, &crate::snapshot::TxSummary> {
+    txs.iter()
+        .map(|tx| ((tx.source_node.clone(), tx.lamport_time), tx))
+        .collect()
+}
+
+/// Transactions of `txs` whose key is not in `other`, in Lamport order
+fn only_in(
+    txs: &std::collections::HashMap<crate::merkle::TxKey, &crate::snapshot::TxSummary>,
+    other: &std::collections::HashMap<crate::merkle::TxKey, &crate::snapshot::TxSummary>,
+) -> Vec<crate::snapshot::TxSummary> {
+    sorted(
+        txs.iter()
+            .filter(|(key, _)| !other.contains_key(*key))
+            .map(|(_, tx)| *tx),
+    )
+}
+
+/// Balance of every user in cents, the `NULL` user excluded
+pub fn balances<'a>(
+    txs: impl Iterator<Item = &'a crate::snapshot::TxSummary>,
+) -> std::collections::BTreeMap<String, i64> {
+    let mut balances = std::collections::BTreeMap::new();
+    for tx in txs {
+        if tx.from_user != crate::db::NULL {
+            *balances.entry(tx.from_user.clone()).or_insert(0) -= tx.amount_in_cent;
+        }
+        if tx.to_user != crate::db::NULL {
+            *balances.entry(tx.to_user.clone()).or_insert(0) += tx.amount_in_cent;
+        }
+    }
+    balances
+}
+
+fn sorted<'a>(
+    txs: impl Iterator<Item = &'a crate::snapshot::TxSummary>,
+) -> Vec<crate::snapshot::TxSummary> {
+    let mut txs: Vec<_> = txs.cloned().collect();
+    txs.sort_by(|a, b| (a.lamport_time, &a.source_node).cmp(&(b.lamport_time, &b.source_node)));
+    txs
+}
+
+fn write_tx(f: &mut std::fmt::Formatter<'_>, tx: &crate::snapshot::TxSummary) -> std::fmt::Result {
+    writeln!(
+        f,
+        "    {}-{} : {} -> {}, {}",
+        tx.source_node,
+        tx.lamport_time,
+        tx.from_user,
+        tx.to_user,
+        crate::money::Money::from_cents(tx.amount_in_cent)
+    )
+}
+
+impl std::fmt::Display for SnapshotDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "The snapshots are identical.");
+        }
+
+        for (label, txs) in [
+            ("first", &self.only_in_left),
+            ("second", &self.only_in_right),
+        ] {
+            if !txs.is_empty() {
+                writeln!(f, "Transactions only in the {} snapshot:", label)?;
+                for tx in txs {
+                    write_tx(f, tx)?;
+                }
+            }
+        }
+
+        if !self.changed.is_empty() {
+            writeln!(f, "Transactions that differ:")?;
+            for c in &self.changed {
+                writeln!(
+                    f,
+                    "  {}-{} ({}):",
+                    c.left.source_node,
+                    c.left.lamport_time,
+                    c.fields.join(", ")
+                )?;
+                write!(f, "  <")?;
+                write_tx(f, &c.left)?;
+                write!(f, "  >")?;
+                write_tx(f, &c.right)?;
+            }
+        }
+
+        if !self.balances.is_empty() {
+            writeln!(f, "Balances:")?;
+            for b in &self.balances {
+                writeln!(
+                    f,
+                    "    {} : {} -> {}",
+                    b.user,
+                    crate::money::Money::from_cents(b.left),
+                    crate::money::Money::from_cents(b.right)
+                )?;
+            }
+        }
+
+        for m in &self.missing {
+            writeln!(f, "Missing transactions of {}:", m.site_id)?;
+            for tx in &m.added {
+                write!(f, "  +")?;
+                write_tx(f, tx)?;
+            }
+            for tx in &m.removed {
+                write!(f, "  -")?;
+                write_tx(f, tx)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tx(
+        node: &str,
+        lamport: i64,
+        from: &str,
+        to: &str,
+        amount_in_cent: i64,
+    ) -> crate::snapshot::TxSummary {
+        crate::snapshot::TxSummary {
+            lamport_time: lamport,
+            source_node: node.to_string(),
+            from_user: from.to_string(),
+            to_user: to.to_string(),
+            amount_in_cent,
+            hlc: crate::clock::Hlc::default(),
+            refund_of: None,
+            vector_clock: Default::default(),
+        }
+    }
+
+    fn snapshot(
+        txs: Vec<crate::snapshot::TxSummary>,
+        missing: Vec<(&str, Vec<crate::snapshot::TxSummary>)>,
+    ) -> crate::snapshot::GlobalSnapshot {
+        crate::snapshot::GlobalSnapshot {
+            all_transactions: txs.into_iter().collect(),
+            missing: missing
+                .into_iter()
+                .map(|(site, txs)| (site.to_string(), txs.into_iter().collect()))
+                .collect(),
+            channels: Vec::new(),
+            balances: std::collections::HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_identical_snapshots() {
+        let deposit = tx("A", 1, "NULL", "alice", 1000);
+        let s = snapshot(vec![deposit.clone()], vec![("B", vec![deposit])]);
+        let d = diff(&s, &s);
+        assert!(d.is_empty());
+        assert_eq!(d.to_string(), "The snapshots are identical.\n");
+    }
+
+    #[test]
+    fn test_differences_are_reported() {
+        let deposit = tx("A", 1, "NULL", "alice", 1000);
+        let transfer = tx("A", 2, "alice", "bob", 300);
+        let pay = tx("B", 3, "bob", "NULL", 100);
+
+        let left = snapshot(
+            vec![deposit.clone(), transfer.clone()],
+            vec![("B", vec![transfer.clone()])],
+        );
+        let right = snapshot(
+            vec![deposit, transfer.clone(), pay.clone()],
+            vec![("A", vec![pay.clone()])],
+        );
+        let d = diff(&left, &right);
+
+        assert!(d.only_in_left.is_empty());
+        assert_eq!(d.only_in_right, vec![pay.clone()]);
+        assert_eq!(
+            d.balances,
+            vec![BalanceDiff {
+                user: "bob".to_string(),
+                left: 300,
+                right: 200,
+            }]
+        );
+        assert_eq!(
+            d.missing,
+            vec![
+                MissingDiff {
+                    site_id: "A".to_string(),
+                    added: vec![pay],
+                    removed: Vec::new(),
+                },
+                MissingDiff {
+                    site_id: "B".to_string(),
+                    added: Vec::new(),
+                    removed: vec![transfer],
+                },
+            ]
+        );
+
+        assert!(d.changed.is_empty());
+
+        let json: serde_json::Value = serde_json::to_value(&d).unwrap();
+        assert_eq!(json["balances"][0]["right"], 200);
+        assert!(d.to_string().contains("bob : 3.00 -> 2.00"));
+    }
+
+    #[test]
+    fn test_transactions_are_matched_by_key() {
+        // fichier écrit avant les horloges hybrides et les remboursements
+        let deposit = tx("A", 1, "NULL", "alice", 1000);
+        let refund = tx("B", 2, "alice", "NULL", 1000);
+        let mut stamped = refund.clone();
+        stamped.hlc.physical_ms = 42;
+        stamped.refund_of = Some(("A".to_string(), 1));
+
+        let left = snapshot(
+            vec![deposit.clone(), refund.clone()],
+            vec![("C", vec![refund])],
+        );
+        let right = snapshot(vec![deposit, stamped.clone()], vec![("C", vec![stamped])]);
+        let d = diff(&left, &right);
+
+        assert!(d.only_in_left.is_empty());
+        assert!(d.only_in_right.is_empty());
+        assert!(d.balances.is_empty());
+        assert!(d.missing.is_empty());
+        assert_eq!(d.changed.len(), 1);
+        assert_eq!(d.changed[0].fields, vec!["hlc", "refund_of"]);
+        assert!(d.to_string().contains("B-2 (hlc, refund_of)"));
+    }
+}
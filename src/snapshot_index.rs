@@ -0,0 +1,249 @@
+//! Index of the snapshot files saved by the site
+//!
+//! The global snapshots are written as `snapshot_<site>_<timestamp>.json`
+//! files in a snapshot directory. The index lists the ones it knows, with
+//! their size and the time they were taken, so that they can be browsed from
+//! the Info page, and deletes the old ones according to a retention policy:
+//! the latest snapshots, and the latest snapshot of each of the last days. A
+//! snapshot kept by either rule is kept, and without any rule every snapshot
+//! is kept.
+
+#![cfg(feature = "server")]
+
+/// Directory the snapshots are written to by default
+pub const DEFAULT_SNAPSHOT_DIR: &str = "snapshots";
+
+/// Which snapshots are kept once a new one is saved
+///
+/// A count of 0 disables the rule.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RetentionPolicy {
+    /// Number of latest snapshots kept
+    pub keep_last: usize,
+    /// Number of days whose latest snapshot is kept
+    pub keep_daily: usize,
+}
+
+/// Snapshot file known by the index
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotEntry {
+    /// Path of the file
+    pub path: std::path::PathBuf,
+    /// Size of the file in bytes
+    pub size: u64,
+    /// When the snapshot was saved
+    pub taken_at: chrono::DateTime<chrono::Local>,
+}
+
+/// Snapshot files of the snapshot directory, oldest first
+pub struct SnapshotIndex {
+    /// Directory the snapshots are written to
+    dir: std::path::PathBuf,
+    /// Which snapshots are kept
+    retention: RetentionPolicy,
+    /// Known snapshots, oldest first
+    entries: Vec<SnapshotEntry>,
+}
+
+impl SnapshotIndex {
+    /// Creates an empty index of the default directory, keeping every snapshot
+    pub fn new() -> Self {
+        Self {
+            dir: std::path::PathBuf::from(DEFAULT_SNAPSHOT_DIR),
+            retention: RetentionPolicy::default(),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Set the snapshot directory, and index the snapshots it already holds
+    pub fn init_dir(&mut self, dir: std::path::PathBuf) -> std::io::Result<()> {
+        self.entries = scan(&dir)?;
+        log::debug!(
+            "{} snapshots found in {}",
+            self.entries.len(),
+            dir.display()
+        );
+        self.dir = dir;
+        Ok(())
+    }
+
+    /// Set which snapshots are kept
+    pub fn init_retention(&mut self, retention: RetentionPolicy) {
+        self.retention = retention;
+    }
+
+    /// Directory the snapshots are written to
+    pub fn dir(&self) -> &std::path::Path {
+        &self.dir
+    }
+
+    /// Known snapshots, oldest first
+    pub fn entries(&self) -> &[SnapshotEntry] {
+        &self.entries
+    }
+
+    /// Latest snapshot saved, if any
+    pub fn latest(&self) -> Option<&SnapshotEntry> {
+        self.entries.last()
+    }
+
+    /// Adds a snapshot that was just written, and deletes the ones the
+    /// retention policy no longer keeps
+    pub fn add(&mut self, path: std::path::PathBuf) -> std::io::Result<()> {
+        let size = std::fs::metadata(&path)?.len();
+        self.entries.push(SnapshotEntry {
+            path,
+            size,
+            taken_at: chrono::Local::now(),
+        });
+
+        let expired = expired(&self.entries, self.retention);
+        let mut position = 0;
+        self.entries.retain(|entry| {
+            let keep = !expired.contains(&position);
+            position += 1;
+            if !keep {
+                log::info!("Deleting the old snapshot {}", entry.path.display());
+                if let Err(e) = std::fs::remove_file(&entry.path) {
+                    log::error!("Cannot delete {}: {}", entry.path.display(), e);
+                }
+            }
+            keep
+        });
+        Ok(())
+    }
+}
+
+/// Positions of the snapshots the retention policy does not keep
+///
+/// The entries are sorted oldest first.
+pub fn expired(entries: &[SnapshotEntry], retention: RetentionPolicy) -> Vec<usize> {
+    if retention == RetentionPolicy::default() {
+        return Vec::new();
+    }
+
+    let mut kept = std::collections::HashSet::new();
+    kept.extend((0..entries.len()).rev().take(retention.keep_last));
+
+    // la dernière sauvegarde de chaque jour, en partant du plus récent
+    let mut days = Vec::new();
+    for (position, entry) in entries.iter().enumerate().rev() {
+        let day = entry.taken_at.date_naive();
+        if days.len() == retention.keep_daily {
+            break;
+        }
+        if !days.contains(&day) {
+            days.push(day);
+            kept.insert(position);
+        }
+    }
+
+    (0..entries.len())
+        .filter(|position| !kept.contains(position))
+        .collect()
+}
+
+/// Lists the snapshot files of a directory, oldest first
+fn scan(dir: &std::path::Path) -> std::io::Result<Vec<SnapshotEntry>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut entries = Vec::new();
+    for file in std::fs::read_dir(dir)? {
+        let file = file?;
+        let name = file.file_name().to_string_lossy().to_string();
+        if !name.starts_with("snapshot_") || !name.ends_with(".json") {
+            continue;
+        }
+        let metadata = file.metadata()?;
+        entries.push(SnapshotEntry {
+            path: file.path(),
+            size: metadata.len(),
+            taken_at: metadata.modified()?.into(),
+        });
+    }
+    entries.sort_by_key(|entry| entry.taken_at);
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(day: u32, hour: u32) -> SnapshotEntry {
+        use chrono::TimeZone;
+        SnapshotEntry {
+            path: format!("snapshot_A_{}_{}.json", day, hour).into(),
+            size: 0,
+            taken_at: chrono::Local
+                .with_ymd_and_hms(2025, 6, day, hour, 0, 0)
+                .unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_every_snapshot_is_kept_without_policy() {
+        let entries = vec![entry(1, 8), entry(1, 9), entry(2, 8)];
+        assert!(expired(&entries, RetentionPolicy::default()).is_empty());
+    }
+
+    #[test]
+    fn test_retention_rules_are_combined() {
+        let entries = vec![
+            entry(1, 8),
+            entry(1, 9),
+            entry(2, 8),
+            entry(3, 8),
+            entry(3, 9),
+            entry(3, 10),
+        ];
+        let keep_last = RetentionPolicy {
+            keep_last: 2,
+            keep_daily: 0,
+        };
+        assert_eq!(expired(&entries, keep_last), vec![0, 1, 2, 3]);
+
+        let keep_daily = RetentionPolicy {
+            keep_last: 0,
+            keep_daily: 2,
+        };
+        assert_eq!(expired(&entries, keep_daily), vec![0, 1, 3, 4]);
+
+        let both = RetentionPolicy {
+            keep_last: 2,
+            keep_daily: 3,
+        };
+        assert_eq!(expired(&entries, both), vec![0, 3]);
+    }
+
+    #[test]
+    fn test_old_snapshots_are_deleted() {
+        let dir = std::env::temp_dir().join(format!("peillute_snapshots_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a snapshot").unwrap();
+
+        let mut index = SnapshotIndex::new();
+        index.init_dir(dir.clone()).unwrap();
+        assert!(index.entries().is_empty());
+        index.init_retention(RetentionPolicy {
+            keep_last: 2,
+            keep_daily: 0,
+        });
+
+        for n in 0..3 {
+            let path = dir.join(format!("snapshot_A_{}.json", n));
+            std::fs::write(&path, "{}").unwrap();
+            index.add(path).unwrap();
+        }
+        assert_eq!(index.entries().len(), 2);
+        assert_eq!(index.latest().unwrap().size, 2);
+        assert!(!dir.join("snapshot_A_0.json").exists());
+
+        let mut reloaded = SnapshotIndex::new();
+        reloaded.init_dir(dir.clone()).unwrap();
+        assert_eq!(reloaded.entries().len(), 2);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -13,12 +13,79 @@ pub enum MutexTag {
 }
 
 #[cfg(feature = "server")]
-#[derive(serde::Serialize, serde::Deserialize, Copy, Clone, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct MutexStamp {
     pub tag: MutexTag,
     pub date: i64,
+    /// Accounts locked by the request
+    pub scope: LockScope,
 }
 
+#[cfg(feature = "server")]
+/// Accounts a critical section works on
+///
+/// Two requests only exclude each other when their scopes overlap, so
+/// operations on different accounts run at the same time on different sites.
+/// A request locks all its accounts at once, and conflicting requests are
+/// served in the total order of their (lamport, site) stamps: the oldest one
+/// always goes first, so a transfer locking two accounts cannot deadlock with
+/// another one locking them the other way round.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum LockScope {
+    /// Every account, for the operations working on the whole database
+    All,
+    /// Only the given accounts
+    Accounts(std::collections::BTreeSet<String>),
+}
+
+#[cfg(feature = "server")]
+impl LockScope {
+    /// Scope of the given accounts
+    pub fn accounts<I, S>(names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        LockScope::Accounts(names.into_iter().map(Into::into).collect())
+    }
+
+    /// Returns true if the two scopes share an account
+    pub fn conflicts_with(&self, other: &LockScope) -> bool {
+        match (self, other) {
+            (LockScope::Accounts(a), LockScope::Accounts(b)) => !a.is_disjoint(b),
+            _ => true,
+        }
+    }
+
+    /// Returns true if every account of `other` is in this scope
+    pub fn covers(&self, other: &LockScope) -> bool {
+        match (self, other) {
+            (LockScope::All, _) => true,
+            (LockScope::Accounts(_), LockScope::All) => false,
+            (LockScope::Accounts(a), LockScope::Accounts(b)) => b.is_subset(a),
+        }
+    }
+
+    /// Returns the scope covering both scopes
+    pub fn union(self, other: LockScope) -> LockScope {
+        match (self, other) {
+            (LockScope::Accounts(mut a), LockScope::Accounts(b)) => {
+                a.extend(b);
+                LockScope::Accounts(a)
+            }
+            _ => LockScope::All,
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+/// Default time after which a request for the global mutex is given up, in milliseconds
+pub const DEFAULT_MUTEX_TIMEOUT_MS: u64 = 30_000;
+
+#[cfg(feature = "server")]
+/// Default drift allowed to the hybrid logical clocks of the messages, in milliseconds
+pub const DEFAULT_MAX_CLOCK_DRIFT_MS: i64 = 60_000;
+
 #[cfg(feature = "server")]
 /// Represents the global state of a Peillute node
 pub struct AppState {
@@ -35,27 +102,60 @@ pub struct AppState {
     neighbours_socket: std::collections::HashMap<std::net::SocketAddr, std::net::SocketAddr>,
     /// Synchronization boolean
     sync_needed: bool,
+    /// Vector clock reloaded from the database, until the site caught up from it
+    restart_clock: Option<std::collections::HashMap<String, i64>>,
     /// Number of attended neighbours at launch, for the discovery phase
     nb_first_attended_neighbours: i64,
 
     pub site_ids_to_adr: std::collections::HashMap<std::net::SocketAddr, String>,
+    /// Gossiped view of every site of the network, neighbours or not
+    pub membership: crate::membership::MembershipView,
 
     // --- Message Diffusion Info for Transaction ---
-    /// Adress of the parent (deg(1) neighbour for this site) for a specific wave from initiator id
-    pub parent_addr_for_transaction_wave: std::collections::HashMap<String, std::net::SocketAddr>,
-    /// Number of response expected from our direct neighbours (deg(1) neighbours for this site) = nb of connected neighbours - 1 (parent) for a specific wave initiator id
-    pub attended_neighbours_nb_for_transaction_wave: std::collections::HashMap<String, i64>,
+    /// Adress of the parent (deg(1) neighbour for this site) for each wave in progress
+    pub parent_addr_for_transaction_wave:
+        std::collections::HashMap<crate::message::WaveId, std::net::SocketAddr>,
+    /// Number of response expected from our direct neighbours (deg(1) neighbours for this site) = nb of connected neighbours - 1 (parent) for each wave in progress
+    pub attended_neighbours_nb_for_transaction_wave:
+        std::collections::HashMap<crate::message::WaveId, i64>,
+    /// Sequence number of the next wave initiated by this site
+    next_wave_seq: u64,
 
     // --- Logical Clocks ---
     /// Logical clock implementation for distributed synchronization
     clocks: crate::clock::Clock,
+    /// Milliseconds a received hybrid logical clock may be ahead of our wall clock
+    max_clock_drift_ms: i64,
+    /// Number of messages whose hybrid logical clock drifted beyond the bound
+    pub drifted_messages: u64,
 
     // GLobal mutex
-    pub global_mutex_fifo: std::collections::HashMap<String, MutexStamp>,
-    pub waiting_sc: bool,
-    pub in_sc: bool,
+    /// Mutual exclusion algorithm run by the site
+    pub mutex: Box<dyn crate::mutex::MutualExclusion>,
+    /// Message counts and waiting times of the algorithm
+    pub mutex_stats: crate::mutex::MutexStats,
     pub notify_sc: std::sync::Arc<tokio::sync::Notify>,
-    pub pending_commands: std::collections::VecDeque<crate::control::CriticalCommands>,
+    pub pending_commands: std::collections::VecDeque<crate::control::PendingCommand>,
+    /// Time after which a request for the global mutex is given up
+    mutex_timeout: std::time::Duration,
+    /// When the pending request for the global mutex was made
+    mutex_requested_at: Option<std::time::Instant>,
+
+    // --- Causal delivery ---
+    /// Transactions held until the ones they depend on are delivered
+    pub causal: crate::causal::CausalBuffer,
+
+    // --- Failure detection ---
+    /// Suspicion level of each connected neighbour, fed by heartbeats
+    pub failure_detector: crate::heartbeat::FailureDetector,
+
+    // --- Authentication ---
+    /// Public keys pinned for the other sites
+    pub key_ring: crate::identity::KeyRing,
+
+    // --- Anti-entropy ---
+    /// Transactions asked for again after they could not be applied
+    pub refetched: std::collections::HashSet<crate::merkle::TxKey>,
 }
 
 #[cfg(feature = "server")]
@@ -71,9 +171,6 @@ impl AppState {
         let nb_of_attended_neighbors = std::collections::HashMap::new();
         let in_use_neighbors = Vec::new();
         let sockets_for_connected_peers = std::collections::HashMap::new();
-        let gm = std::collections::HashMap::new();
-        let waiting_sc = false;
-        let in_sc = false;
 
         Self {
             site_id,
@@ -82,31 +179,45 @@ impl AppState {
             site_addr: local_addr,
             parent_addr_for_transaction_wave: parent_addr,
             attended_neighbours_nb_for_transaction_wave: nb_of_attended_neighbors,
+            next_wave_seq: 1,
             connected_neighbours_addrs: in_use_neighbors,
             clocks,
+            max_clock_drift_ms: DEFAULT_MAX_CLOCK_DRIFT_MS,
+            drifted_messages: 0,
             sync_needed: false,
+            restart_clock: None,
             nb_first_attended_neighbours: 0,
-            global_mutex_fifo: gm,
-            waiting_sc,
-            in_sc,
+            mutex: crate::mutex::MutexAlgorithm::Wave.build(),
+            mutex_stats: crate::mutex::MutexStats::default(),
             notify_sc: std::sync::Arc::new(tokio::sync::Notify::new()),
             pending_commands: std::collections::VecDeque::new(),
+            mutex_timeout: std::time::Duration::from_millis(DEFAULT_MUTEX_TIMEOUT_MS),
+            mutex_requested_at: None,
+            causal: crate::causal::CausalBuffer::new(),
             site_ids_to_adr: std::collections::HashMap::new(),
+            membership: crate::membership::MembershipView::new(
+                "0.0.0.0:0".parse().unwrap(),
+                String::new(),
+                0,
+            ),
+            failure_detector: crate::heartbeat::FailureDetector::new(
+                std::time::Duration::from_millis(crate::heartbeat::DEFAULT_HEARTBEAT_INTERVAL_MS),
+                crate::heartbeat::DEFAULT_PHI_THRESHOLD,
+            ),
+            key_ring: crate::identity::KeyRing::new(),
+            refetched: std::collections::HashSet::new(),
         }
     }
 
-    pub fn get_global_mutex_fifo(&self) -> &std::collections::HashMap<String, MutexStamp> {
-        &self.global_mutex_fifo
+    pub fn get_global_mutex_fifo(&self) -> std::collections::HashMap<String, MutexStamp> {
+        self.mutex.queue()
     }
 
     pub fn set_global_mutex_fifo(
         &mut self,
         global_mutex_fifo: std::collections::HashMap<String, MutexStamp>,
     ) {
-        if self.global_mutex_fifo.len() >= global_mutex_fifo.len() {
-            return; // Do not overwrite if the new FIFO is smaller or equal
-        }
-        self.global_mutex_fifo = global_mutex_fifo;
+        self.mutex.adopt_queue(global_mutex_fifo);
     }
 
     pub fn add_site_id(&mut self, site_id: String, addr: std::net::SocketAddr) {
@@ -115,6 +226,11 @@ impl AppState {
         }
     }
 
+    /// Set the mutual exclusion algorithm at initialization
+    pub fn init_mutex(&mut self, algorithm: crate::mutex::MutexAlgorithm) {
+        self.mutex = algorithm.build();
+    }
+
     /// Sets the site ID at initialization
     pub fn init_site_id(&mut self, site_id: String) {
         self.site_id = site_id;
@@ -125,11 +241,93 @@ impl AppState {
         self.site_addr = site_addr;
     }
 
+    /// Creates the membership view once the site id and address are known
+    pub fn init_membership(&mut self, incarnation: u64) {
+        self.membership = crate::membership::MembershipView::new(
+            self.site_addr,
+            self.site_id.clone(),
+            incarnation,
+        );
+    }
+
+    /// Pins the keys persisted by a previous run at initialization
+    pub fn init_key_ring(&mut self, keys: Vec<(String, Vec<u8>)>) {
+        for (site_id, public_key) in keys {
+            if let Err(e) = self.key_ring.pin(&site_id, &public_key) {
+                log::error!("Persisted key of site {} ignored: {}", site_id, e);
+            }
+        }
+    }
+
+    /// Pins the key of a site, and persists it the first time it is seen
+    pub fn pin_key(
+        &mut self,
+        db: &crate::db::Db,
+        site_id: &str,
+        public_key: &[u8],
+    ) -> Result<(), crate::identity::SignatureError> {
+        if self.key_ring.pin(site_id, public_key)?
+            && let Err(e) = crate::db::pin_key(db, site_id, public_key)
+        {
+            log::error!("Failed to persist the key of site {}: {}", site_id, e);
+        }
+        Ok(())
+    }
+
+    /// Members believed alive whose public key is not pinned yet
+    pub fn unpinned_members(&self) -> Vec<std::net::SocketAddr> {
+        self.membership
+            .digest()
+            .into_iter()
+            .filter(|m| {
+                m.status == crate::membership::MemberStatus::Alive
+                    && m.site_id != self.site_id
+                    && !self.key_ring.is_pinned(&m.site_id)
+            })
+            .map(|m| m.addr)
+            .collect()
+    }
+
+    /// Returns the membership view payload sent to the other sites
+    pub fn get_membership_payload(&self) -> crate::message::MembershipPayload {
+        crate::message::MembershipPayload {
+            members: self.membership.digest(),
+        }
+    }
+
     /// Sets the list of CLI peer addresses at initialization
     pub fn init_cli_peer_addrs(&mut self, cli_peer_addrs: Vec<std::net::SocketAddr>) {
         self.cli_peer_addrs = cli_peer_addrs;
     }
 
+    /// Set the failure detector parameters at initialization
+    pub fn init_failure_detector(&mut self, heartbeat_interval_ms: u64, phi_threshold: f64) {
+        self.failure_detector = crate::heartbeat::FailureDetector::new(
+            std::time::Duration::from_millis(heartbeat_interval_ms),
+            phi_threshold,
+        );
+    }
+
+    /// Set the time after which a request for the global mutex is given up
+    pub fn init_mutex_timeout(&mut self, mutex_timeout_ms: u64) {
+        self.mutex_timeout = std::time::Duration::from_millis(mutex_timeout_ms);
+    }
+
+    /// Set the time a transaction waits for the transactions it depends on
+    pub fn init_causal_timeout(&mut self, causal_timeout_ms: u64) {
+        self.causal.init_timeout(causal_timeout_ms);
+    }
+
+    /// Set the transactions delivered in causal order before a restart
+    pub fn init_causal_delivered(&mut self, delivered: crate::causal::Dependencies) {
+        self.causal.init_delivered(delivered);
+    }
+
+    /// Set the drift allowed to the hybrid logical clocks of the messages
+    pub fn init_max_clock_drift(&mut self, max_clock_drift_ms: i64) {
+        self.max_clock_drift_ms = max_clock_drift_ms;
+    }
+
     /// Set the clock at initialization
     pub fn init_clock(&mut self, clock: crate::clock::Clock) {
         self.clocks = clock;
@@ -148,6 +346,16 @@ impl AppState {
         self.sync_needed
     }
 
+    /// Set the vector clock the site restarted with, to catch up from it
+    pub fn init_restart_clock(&mut self, clock: std::collections::HashMap<String, i64>) {
+        self.restart_clock = Some(clock);
+    }
+
+    /// Takes the vector clock the site restarted with, if it has not caught up yet
+    pub fn take_restart_clock(&mut self) -> Option<std::collections::HashMap<String, i64>> {
+        self.restart_clock.take()
+    }
+
     /// Set the number of attended neighbours at initialization
     pub fn init_nb_first_attended_neighbours(&mut self, nb: i64) {
         log::debug!("We will wait for {} attended neighbours", nb);
@@ -159,10 +367,12 @@ impl AppState {
         self.nb_first_attended_neighbours
     }
 
-    /// Initialize the parent of the current site as self for the wave protocol
-    pub fn init_parent_addr_for_transaction_wave(&mut self) {
-        self.parent_addr_for_transaction_wave
-            .insert(self.site_id.clone(), self.site_addr.clone());
+    /// Set the first wave sequence number at initialization
+    ///
+    /// Starting at the launch time keeps the waves of a restarted site apart
+    /// from the unfinished ones of its previous run.
+    pub fn init_wave_seq(&mut self, first_seq: u64) {
+        self.next_wave_seq = first_seq;
     }
 
     /// Adds a new peer to the network and updates the logical clock
@@ -191,24 +401,31 @@ impl AppState {
     /// If a site disappear from the network, every neighbours will detected the closing of the tcp connection and will launch a wave diffusion to announce the disappearance of this site
     ///
     /// If a site is closed properly, it will send a disconnect message to all its neighbours
-    pub async fn remove_peer(&mut self, addr_to_remove: std::net::SocketAddr) {
+    pub async fn remove_peer(
+        &mut self,
+        node: &crate::node::Node,
+        addr_to_remove: std::net::SocketAddr,
+    ) {
         {
-            let mut net_manager = crate::network::NETWORK_MANAGER.lock().await;
+            let mut net_manager = node.network.lock().await;
             net_manager.remove_connection(&addr_to_remove);
         }
 
+        self.failure_detector.remove(&addr_to_remove);
+
         if let Some(pos) = self
             .connected_neighbours_addrs
             .iter()
             .position(|x| *x == addr_to_remove)
         {
             self.connected_neighbours_addrs.remove(pos);
-            let site_id = self.site_ids_to_adr.get(&addr_to_remove);
+            let site_id = self.site_ids_to_adr.get(&addr_to_remove).cloned();
             if let Some(site_id) = site_id {
-                self.global_mutex_fifo.remove(site_id);
+                self.forget_mutex_site(&site_id);
                 self.attended_neighbours_nb_for_transaction_wave
-                    .remove(site_id);
-                self.parent_addr_for_transaction_wave.remove(site_id);
+                    .retain(|wave, _| wave.initiator_id != site_id);
+                self.parent_addr_for_transaction_wave
+                    .retain(|wave, _| wave.initiator_id != site_id);
                 self.site_ids_to_adr.remove(&addr_to_remove);
             }
 
@@ -226,23 +443,27 @@ impl AppState {
     /// If a site is closed properly, it will send a disconnect message to all its neighbours
     pub async fn remove_peer_from_socket_closed(&mut self, socket_to_remove: std::net::SocketAddr) {
         // Find the site adress based on the socket
-        let Some(addr_to_remove) = self.neighbours_socket.get(&socket_to_remove) else {
+        let Some(addr_to_remove) = self.neighbours_socket.get(&socket_to_remove).copied() else {
             log::debug!("Site not found in the neighbours socket");
             return;
         };
+        self.failure_detector.remove(&addr_to_remove);
+        self.membership
+            .mark(addr_to_remove, crate::membership::MemberStatus::Suspect);
 
         if let Some(pos) = self
             .connected_neighbours_addrs
             .iter()
-            .position(|x| *x == *addr_to_remove)
+            .position(|x| *x == addr_to_remove)
         {
             self.connected_neighbours_addrs.remove(pos);
-            let site_id = self.site_ids_to_adr.get(&addr_to_remove);
+            let site_id = self.site_ids_to_adr.get(&addr_to_remove).cloned();
             if let Some(site_id) = site_id {
-                self.global_mutex_fifo.remove(site_id);
+                self.forget_mutex_site(&site_id);
                 self.attended_neighbours_nb_for_transaction_wave
-                    .remove(site_id);
-                self.parent_addr_for_transaction_wave.remove(site_id);
+                    .retain(|wave, _| wave.initiator_id != site_id);
+                self.parent_addr_for_transaction_wave
+                    .retain(|wave, _| wave.initiator_id != site_id);
                 self.site_ids_to_adr.remove(&addr_to_remove);
             }
 
@@ -256,149 +477,273 @@ impl AppState {
         self.site_addr.clone()
     }
 
-    pub async fn acquire_mutex(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        use crate::message::{Message, MessageInfo, NetworkMessageCode};
-        use crate::network::diffuse_message_without_lock;
+    /// Returns what the mutual exclusion algorithm needs to know of the site
+    pub fn mutex_context(&self) -> crate::mutex::MutexContext {
+        crate::mutex::MutexContext {
+            site_id: self.site_id.clone(),
+            lamport: *self.clocks.get_lamport(),
+            peers: self
+                .membership
+                .alive_peers()
+                .into_iter()
+                .filter(|site| *site != self.site_id)
+                .collect(),
+            members: self.membership.alive_sites(),
+        }
+    }
 
-        self.update_clock(None).await;
+    /// Returns where the site stands regarding the critical section
+    pub fn mutex_status(&self) -> crate::mutex::MutexStatus {
+        self.mutex.status()
+    }
 
-        self.global_mutex_fifo.insert(
-            self.site_id.clone(),
-            MutexStamp {
-                tag: MutexTag::Request,
-                date: self.clocks.get_lamport().clone(),
-            },
-        );
+    /// Requests the global mutex for the accounts of the queued commands
+    pub async fn acquire_mutex(
+        &mut self,
+        node: &crate::node::Node,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.update_clock(&node.db, None).await;
 
-        let msg = Message {
-            sender_id: self.site_id.clone(),
-            sender_addr: self.site_addr,
-            message_initiator_id: self.site_id.clone(),
-            message_initiator_addr: self.site_addr,
-            clock: self.clocks.clone(),
-            command: None,
-            info: MessageInfo::AcquireMutex(crate::message::AcquireMutexPayload),
-            code: NetworkMessageCode::AcquireMutex,
-        };
+        let scope = self
+            .pending_commands
+            .iter()
+            .map(|pending| pending.scope.clone())
+            .reduce(LockScope::union)
+            .unwrap_or(LockScope::All);
+
+        self.mutex_requested_at = Some(std::time::Instant::now());
+        let ctx = self.mutex_context();
+        let effects = self.mutex.request(&ctx, scope);
+        self.apply_mutex_effects(node, effects).await
+    }
 
-        let should_diffuse = {
-            // initialisation des paramètres avant la diffusion d'un message
-            self.set_parent_addr(self.site_id.to_string(), self.site_addr);
-            self.set_nb_nei_for_wave(self.site_id.to_string(), self.get_nb_connected_neighbours());
-            self.get_nb_connected_neighbours() > 0
-        };
+    /// Leaves the critical section, or withdraws the pending request
+    pub async fn release_mutex(
+        &mut self,
+        node: &crate::node::Node,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.update_clock(&node.db, None).await;
+
+        self.mutex_requested_at = None;
+        let ctx = self.mutex_context();
+        let effects = self.mutex.release(&ctx);
+        self.apply_mutex_effects(node, effects).await
+    }
 
-        if should_diffuse {
-            self.notify_sc.notify_waiters();
-            self.in_sc = false;
-            self.waiting_sc = true;
-            log::info!("Début de la diffusion d'une acquisition de mutex");
-            diffuse_message_without_lock(
-                &msg,
-                self.get_site_addr(),
-                self.get_site_id().as_str(),
-                self.get_connected_nei_addr(),
-                self.get_parent_addr_for_wave(msg.message_initiator_id.clone()),
-            )
-            .await?;
-        } else {
-            log::info!("Il n'y a pas de voisins, on prends la section critique");
-            self.in_sc = true;
-            self.waiting_sc = false;
-            self.notify_sc.notify_waiters();
+    /// Handles a message of the mutual exclusion algorithm sent by another site
+    pub async fn receive_mutex(
+        &mut self,
+        node: &crate::node::Node,
+        from: &str,
+        payload: crate::mutex::MutexPayload,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let ctx = self.mutex_context();
+        let effects = self.mutex.receive(&ctx, from, payload);
+        self.apply_mutex_effects(node, effects).await
+    }
+
+    /// Sends the messages asked for by the mutual exclusion algorithm
+    async fn apply_mutex_effects(
+        &mut self,
+        node: &crate::node::Node,
+        effects: Vec<crate::mutex::MutexEffect>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        use crate::message::{MessageInfo, NetworkMessageCode};
+        use crate::mutex::{MutexEffect, MutexPayload};
+
+        let mut effects: std::collections::VecDeque<_> = effects.into();
+        while let Some(effect) = effects.pop_front() {
+            let clock = self.clocks.clone();
+            match effect {
+                MutexEffect::DiffuseRequest(scope) => {
+                    let wave = crate::wave::start_wave(
+                        node,
+                        self,
+                        &MutexRequestWave,
+                        MessageInfo::AcquireMutex(crate::message::AcquireMutexPayload { scope }),
+                        None,
+                        clock,
+                    )
+                    .await?;
+                    if wave.is_none() {
+                        log::info!("Il n'y a pas de voisins, notre demande est connue de tous");
+                        let ctx = self.mutex_context();
+                        let site_id = self.site_id.clone();
+                        effects.extend(self.mutex.receive(&ctx, &site_id, MutexPayload::Announced));
+                    }
+                }
+                MutexEffect::DiffuseRelease => {
+                    crate::wave::start_wave(
+                        node,
+                        self,
+                        &MutexReleaseWave,
+                        MessageInfo::ReleaseMutex(crate::message::ReleaseMutexPayload),
+                        None,
+                        clock,
+                    )
+                    .await?;
+                }
+                MutexEffect::Send { to, payload } => {
+                    // the sites that are not neighbours are reached at their address in the view
+                    let Some(addr) = self.membership.alive_addr(&to).or_else(|| {
+                        self.site_ids_to_adr
+                            .iter()
+                            .find(|(_, id)| **id == to)
+                            .map(|(addr, _)| *addr)
+                    }) else {
+                        log::error!("No address known for the site {}, mutex message lost", to);
+                        continue;
+                    };
+                    crate::network::send_message(
+                        node,
+                        addr,
+                        MessageInfo::Mutex(payload),
+                        NetworkMessageCode::Mutex,
+                        self.site_addr,
+                        &self.site_id,
+                        crate::message::Origin::local(&self.site_id, self.site_addr),
+                        clock,
+                    )
+                    .await?;
+                }
+            }
         }
 
+        self.check_mutex_granted();
         Ok(())
     }
 
-    pub async fn release_mutex(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        use crate::message::{Message, MessageInfo, NetworkMessageCode};
-        use crate::network::diffuse_message_without_lock;
-
-        self.update_clock(None).await;
+    /// Wakes the control worker up once the critical section is granted
+    fn check_mutex_granted(&mut self) {
+        if self.mutex.status() != crate::mutex::MutexStatus::Held {
+            return;
+        }
+        if let Some(requested_at) = self.mutex_requested_at.take() {
+            self.mutex_stats.acquisitions += 1;
+            self.mutex_stats.total_wait += requested_at.elapsed();
+            // notifies worker to execute pending commands
+            self.notify_sc.notify_waiters();
+        }
+    }
 
-        let msg = Message {
-            sender_id: self.site_id.clone(),
-            sender_addr: self.site_addr,
-            message_initiator_id: self.site_id.clone(),
-            message_initiator_addr: self.site_addr,
-            clock: self.clocks.clone(),
-            command: None,
-            info: MessageInfo::ReleaseMutex(crate::message::ReleaseMutexPayload),
-            code: NetworkMessageCode::ReleaseGlobalMutex,
-        };
+    /// Returns the accounts locked by the critical section we are in
+    pub fn get_held_scope(&self) -> Option<&LockScope> {
+        self.mutex.held_scope()
+    }
 
-        self.global_mutex_fifo.remove(&self.site_id);
-        self.in_sc = false;
-        self.waiting_sc = false;
+    /// Pops the next queued command if the critical section we are in covers its accounts
+    ///
+    /// Commands are run in their order of arrival: one touching other accounts
+    /// waits for the next request, and so do the ones queued behind it.
+    pub fn pop_runnable_command(&mut self) -> Option<crate::control::PendingCommand> {
+        let held = self.get_held_scope()?;
+        if held.covers(&self.pending_commands.front()?.scope) {
+            self.pending_commands.pop_front()
+        } else {
+            None
+        }
+    }
 
-        let should_diffuse = {
-            // initialisation des paramètres avant la diffusion d'un message
-            self.set_parent_addr(self.site_id.to_string(), self.site_addr);
-            self.set_nb_nei_for_wave(self.site_id.to_string(), self.get_nb_connected_neighbours());
-            self.get_nb_connected_neighbours() > 0
+    /// Releases the global mutex once no queued command can run under it
+    ///
+    /// The commands left in the queue touch other accounts, a new request is
+    /// made for them right away. Nothing is done if the mutex is not held: the
+    /// wave of an earlier command of the same critical section may end after
+    /// it was released, and releasing again would withdraw the new request.
+    pub async fn release_mutex_if_done(
+        &mut self,
+        node: &crate::node::Node,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(held) = self.get_held_scope() else {
+            return Ok(());
         };
-
-        if should_diffuse {
-            log::info!("Début de la diffusion d'un relachement de mutex");
-            diffuse_message_without_lock(
-                &msg,
-                self.get_site_addr(),
-                self.get_site_id().as_str(),
-                self.get_connected_nei_addr(),
-                self.get_parent_addr_for_wave(msg.message_initiator_id.clone()),
-            )
-            .await?;
+        if let Some(next) = self.pending_commands.front()
+            && held.covers(&next.scope)
+        {
+            return Ok(());
+        }
+        // fin de la section critique on peut notifier les pairs
+        self.release_mutex(node).await?;
+        if !self.pending_commands.is_empty() {
+            self.acquire_mutex(node).await?;
         }
         Ok(())
     }
 
-    pub fn try_enter_sc(&mut self) {
-        // MUST BE CALLED ONLY AFTER A SUCCESSFUL WAVE AFTER ACQUIRE MUTEX
-        // This function checks if the site can enter the critical section
-        // It checks if the site is waiting for the critical section and if it can enter
-        // based on the FIFO order of requests in the global mutex FIFO.
+    /// Returns true if the pending request for the global mutex has waited too long
+    pub fn mutex_request_expired(&self, now: std::time::Instant) -> bool {
+        self.mutex.status() == crate::mutex::MutexStatus::Waiting
+            && self
+                .mutex_requested_at
+                .is_some_and(|at| now.saturating_duration_since(at) >= self.mutex_timeout)
+    }
 
-        // Pour respecter l'algo du poly il faut que la vague soit complete
-        // c'est à dire que tout le monde ait répondu ACK pour appeller cette fonction
-        // sinon on va entrer en section critique à un moment sans qu'un des peers ait noté notre demande
-        let my_stamp = match self.global_mutex_fifo.get(&self.site_id) {
-            Some(s) => *s,
-            None => return, // No local request found
-        };
-        let me = (my_stamp.date, self.site_id.clone());
-
-        // ici on compara les stamps des autres demandes, est-ce qu'on est le suivant dans la FIFO ?
-        // si oui on peut entrer en section critique
-        let ok = self.global_mutex_fifo.iter().all(|(id, stamp)| {
-            if id == &self.site_id {
-                true
-            } else {
-                match stamp.tag {
-                    MutexTag::Request => me <= (stamp.date, id.clone()),
-                    _ => true,
-                }
+    /// Gives up the pending request for the global mutex
+    ///
+    /// The waiting commands fail with a timeout error reported to their
+    /// callers, and the request is withdrawn from the other sites with a release.
+    pub async fn abort_mutex_request(
+        &mut self,
+        node: &crate::node::Node,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let error = format!(
+            "Timed out after {:?} waiting for the global mutex",
+            self.mutex_timeout
+        );
+        log::warn!(
+            "{}, {} commands dropped",
+            error,
+            self.pending_commands.len()
+        );
+        for pending in self.pending_commands.drain(..) {
+            if let Some(done) = pending.done {
+                let _ = done.send(Err(error.clone()));
             }
-        });
-
-        if ok {
-            self.waiting_sc = false;
-            self.in_sc = true;
-            // All other sites are notified that we are in critical section
-            self.notify_sc.notify_waiters(); // notifies worker to execute pending commands
-            // We remove obsolete Releases
-            self.global_mutex_fifo
-                .retain(|_, s| s.tag != MutexTag::Release);
-        } else {
-            println!("\x1b[1;31mSECTION CRITIQUE REFUSEE !\x1b[0m");
-            // print fifo order
-            println!("FIFO order:");
-            for (id, stamp) in &self.global_mutex_fifo {
-                println!("{}: {:?} - {:?}", id, stamp.tag, stamp.date);
+        }
+        self.release_mutex(node).await
+    }
+
+    /// Forgets a site in the mutual exclusion algorithm
+    ///
+    /// Returns true if the site was taking part in it.
+    fn forget_mutex_site(&mut self, site_id: &str) -> bool {
+        let ctx = self.mutex_context();
+        let forgotten = self.mutex.forget_site(&ctx, site_id);
+        self.check_mutex_granted();
+        forgotten
+    }
+
+    /// Drops the mutex requests of the sites that failed or left the network
+    ///
+    /// The membership view tells which sites are gone, neighbours or not, so a
+    /// site that died while holding or waiting for the critical section does
+    /// not block the other ones forever. Returns the ids of the dropped requests.
+    pub fn forget_departed_requests(&mut self) -> Vec<String> {
+        let mut dropped = Vec::new();
+        for site_id in self.membership.departed_sites() {
+            if self.forget_mutex_site(&site_id) {
+                log::warn!(
+                    "Dropping the mutex request of the departed site {}",
+                    site_id
+                );
+                self.attended_neighbours_nb_for_transaction_wave
+                    .retain(|wave, _| wave.initiator_id != site_id);
+                self.parent_addr_for_transaction_wave
+                    .retain(|wave, _| wave.initiator_id != site_id);
+                dropped.push(site_id);
             }
-            // print my stamp
-            println!("{}: {:?} - {:?}", self.site_id, my_stamp.tag, my_stamp.date);
         }
+        dropped
+    }
+
+    /// Resumes the mutual exclusion once the departed sites were forgotten
+    pub async fn resume_mutex(
+        &mut self,
+        node: &crate::node::Node,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let ctx = self.mutex_context();
+        let effects = self.mutex.resume(&ctx);
+        self.apply_mutex_effects(node, effects).await
     }
 
     /// Returns the local address as a string
@@ -426,6 +771,12 @@ impl AppState {
         self.connected_neighbours_addrs.clone()
     }
 
+    /// Returns the suspicion level of each connected neighbour
+    pub fn get_suspicion_levels(&self) -> Vec<(std::net::SocketAddr, f64)> {
+        self.failure_detector
+            .suspicion_levels(std::time::Instant::now())
+    }
+
     /// Returns a list of conncted neibhours as strings
     pub fn get_connected_nei_addr_string(&self) -> Vec<String> {
         self.connected_neighbours_addrs
@@ -444,55 +795,157 @@ impl AppState {
         self.clocks.clone()
     }
 
-    /// Set the number of attended neighbors for the wave from initiator_id
-    pub fn set_nb_nei_for_wave(&mut self, initiator_id: String, n: i64) {
+    /// Returns a fresh identifier for a wave initiated by this site
+    pub fn next_wave_id(&mut self) -> crate::message::WaveId {
+        let seq = self.next_wave_seq;
+        self.next_wave_seq += 1;
+        crate::message::WaveId {
+            initiator_id: self.site_id.clone(),
+            seq,
+        }
+    }
+
+    /// Prepares the diffusion of a wave initiated by this site
+    ///
+    /// Returns false if there is no neighbour to diffuse to, nothing is recorded then.
+    pub fn start_wave(&mut self, wave: &crate::message::WaveId) -> bool {
+        let nb_neighbours = self.get_nb_connected_neighbours();
+        if nb_neighbours == 0 {
+            return false;
+        }
+        self.parent_addr_for_transaction_wave
+            .insert(wave.clone(), self.site_addr);
+        self.attended_neighbours_nb_for_transaction_wave
+            .insert(wave.clone(), nb_neighbours);
+        true
+    }
+
+    /// Returns true if the wave already went through this site
+    pub fn knows_wave(&self, wave: &crate::message::WaveId) -> bool {
+        self.parent_addr_for_transaction_wave.contains_key(wave)
+    }
+
+    /// Records a blue message of a wave received from a neighbour
+    ///
+    /// Returns true if the message must be diffused to the other neighbours, false if
+    /// it must be answered right away: the wave already went through this site, or
+    /// the sender is our only neighbour.
+    pub fn receive_wave(
+        &mut self,
+        wave: &crate::message::WaveId,
+        sender_addr: std::net::SocketAddr,
+    ) -> bool {
+        if self.knows_wave(wave) {
+            return false;
+        }
+        self.parent_addr_for_transaction_wave
+            .insert(wave.clone(), sender_addr);
+
+        let nb_neighbours = self.get_nb_connected_neighbours();
+        let attended = self
+            .attended_neighbours_nb_for_transaction_wave
+            .get(wave)
+            .copied()
+            .unwrap_or(nb_neighbours)
+            - 1;
+        log::debug!("Nombre de voisin pour la vague {} : {}", wave, attended);
+
+        if attended > 0 {
+            self.attended_neighbours_nb_for_transaction_wave
+                .insert(wave.clone(), attended);
+            true
+        } else {
+            // Feuille : la vague se termine ici dès qu'on acquitte le parent
+            self.finish_wave(wave);
+            false
+        }
+    }
+
+    /// Records a red message of a wave received from a neighbour
+    ///
+    /// Once every neighbour has answered, the wave is over on this site and the
+    /// parent to acknowledge is returned, which is the local address on the initiator.
+    pub fn receive_wave_answer(
+        &mut self,
+        wave: &crate::message::WaveId,
+    ) -> Option<std::net::SocketAddr> {
+        let nb_neighbours = self.get_nb_connected_neighbours();
+        let attended = self
+            .attended_neighbours_nb_for_transaction_wave
+            .get(wave)
+            .copied()
+            .unwrap_or(nb_neighbours)
+            - 1;
+        if attended > 0 {
+            self.attended_neighbours_nb_for_transaction_wave
+                .insert(wave.clone(), attended);
+            return None;
+        }
+        let parent = self.get_parent_addr_for_wave(wave);
+        self.finish_wave(wave);
+        Some(parent)
+    }
+
+    /// Forgets a wave that is over on this site
+    fn finish_wave(&mut self, wave: &crate::message::WaveId) {
+        self.parent_addr_for_transaction_wave.remove(wave);
         self.attended_neighbours_nb_for_transaction_wave
-            .insert(initiator_id, n);
+            .remove(wave);
     }
 
-    /// Get the list of attended neighbors for the wave from initiator_id
+    /// Get the parent of every wave in progress
     pub fn get_parent_for_wave_map(
         &self,
-    ) -> std::collections::HashMap<String, std::net::SocketAddr> {
+    ) -> std::collections::HashMap<crate::message::WaveId, std::net::SocketAddr> {
         self.parent_addr_for_transaction_wave.clone()
     }
 
-    /// Get the list of attended neighbors for the wave from initiator_id
-    pub fn get_nb_nei_for_wave(&self) -> std::collections::HashMap<String, i64> {
+    /// Get the number of attended neighbors of every wave in progress
+    pub fn get_nb_nei_for_wave(&self) -> std::collections::HashMap<crate::message::WaveId, i64> {
         self.attended_neighbours_nb_for_transaction_wave.clone()
     }
 
-    /// Get the parent (neighbour deg(1)) address for the wave from initiator_id
-    pub fn get_parent_addr_for_wave(&self, initiator_id: String) -> std::net::SocketAddr {
+    /// Get the parent (neighbour deg(1)) address for a wave
+    pub fn get_parent_addr_for_wave(&self, wave: &crate::message::WaveId) -> std::net::SocketAddr {
         self.parent_addr_for_transaction_wave
-            .get(&initiator_id)
+            .get(wave)
             .copied()
             .unwrap_or("0.0.0.0:0".parse().unwrap())
     }
 
-    /// Set the parent (neighbour deg(1)) address for the wave from initiator_id
-    pub fn set_parent_addr(&mut self, initiator_id: String, peer_adr: std::net::SocketAddr) {
-        self.parent_addr_for_transaction_wave
-            .insert(initiator_id, peer_adr);
-    }
-
     /// Returns the number of deg(1) neighbors connected
     pub fn get_nb_connected_neighbours(&self) -> i64 {
         self.connected_neighbours_addrs.len() as i64
     }
 
     /// Update the clock of the site
-    pub async fn update_clock(&mut self, received_vc: Option<&crate::clock::Clock>) {
+    pub async fn update_clock(
+        &mut self,
+        db: &crate::db::Db,
+        received_vc: Option<&crate::clock::Clock>,
+    ) {
         // this wrapper is needed to ensure that the clock is saved
         // each time it is updated
         // please DO NOT call the `update_clock` method directly from the clock
-        self.clocks.update_clock(&self.site_id, received_vc);
-        self.save_local_state().await;
+        let now_ms = crate::clock::Hlc::wall_clock_ms();
+        if !self
+            .clocks
+            .update_clock_at(&self.site_id, received_vc, now_ms, self.max_clock_drift_ms)
+        {
+            self.drifted_messages += 1;
+            log::warn!(
+                "Received a clock {} ms ahead of ours, beyond the allowed drift of {} ms",
+                received_vc.map_or(0, |rc| rc.get_hlc().drift_ms(now_ms)),
+                self.max_clock_drift_ms
+            );
+        }
+        self.save_local_state(db).await;
     }
 
-    pub async fn save_local_state(&self) {
+    pub async fn save_local_state(&self, db: &crate::db::Db) {
         // this is likely to be called whenever the clocks are updated
-        let _ = crate::db::update_local_state(&self.site_id, self.clocks.clone());
+        let _ = crate::db::update_local_state(db, &self.site_id, self.clocks.clone());
+        let _ = crate::db::update_causal_delivered(db, &self.causal.delivered());
     }
 
     /// For tokyo test, set manually the number of connected neighbours
@@ -507,6 +960,117 @@ impl AppState {
     }
 }
 
+#[cfg(feature = "server")]
+/// Wave announcing a request of the global mutex
+///
+/// Every site records the request in its FIFO, the requesting site tries to
+/// enter the critical section once all of them have.
+pub struct MutexRequestWave;
+
+#[cfg(feature = "server")]
+impl crate::wave::WaveHandler for MutexRequestWave {
+    fn request_code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::AcquireMutex
+    }
+
+    fn answer_code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::AckGlobalMutex
+    }
+
+    fn visit<'a>(
+        &'a self,
+        node: &'a crate::node::Node,
+        message: &'a crate::message::Message,
+        _forwarded: bool,
+    ) -> crate::wave::WaveFuture<'a, ()> {
+        Box::pin(async move {
+            // We store the request
+            let scope = match &message.info {
+                crate::message::MessageInfo::AcquireMutex(payload) => payload.scope.clone(),
+                _ => LockScope::All,
+            };
+            let request = crate::mutex::MutexPayload::Request {
+                date: *message.clock.get_lamport(),
+                scope,
+            };
+            let mut st = node.state.lock().await;
+            st.receive_mutex(node, &message.message_initiator_id, request)
+                .await
+        })
+    }
+
+    fn leaf_answer<'a>(
+        &'a self,
+        _node: &'a crate::node::Node,
+        message: &'a crate::message::Message,
+    ) -> crate::wave::WaveFuture<'a, crate::message::MessageInfo> {
+        Box::pin(async move {
+            Ok(crate::message::MessageInfo::AckMutex(
+                crate::message::AckMutexPayload {
+                    clock: *message.clock.get_lamport(),
+                },
+            ))
+        })
+    }
+
+    fn complete<'a>(
+        &'a self,
+        node: &'a crate::node::Node,
+        state: &'a mut AppState,
+        _aggregate: crate::message::MessageInfo,
+    ) -> crate::wave::WaveFuture<'a, ()> {
+        Box::pin(async move {
+            let site_id = state.get_site_id();
+            state
+                .receive_mutex(node, &site_id, crate::mutex::MutexPayload::Announced)
+                .await
+        })
+    }
+}
+
+#[cfg(feature = "server")]
+/// Wave announcing the release of the global mutex
+pub struct MutexReleaseWave;
+
+#[cfg(feature = "server")]
+impl crate::wave::WaveHandler for MutexReleaseWave {
+    fn request_code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::ReleaseGlobalMutex
+    }
+
+    fn answer_code(&self) -> crate::message::NetworkMessageCode {
+        crate::message::NetworkMessageCode::AckReleaseGlobalMutex
+    }
+
+    fn visit<'a>(
+        &'a self,
+        node: &'a crate::node::Node,
+        message: &'a crate::message::Message,
+        _forwarded: bool,
+    ) -> crate::wave::WaveFuture<'a, ()> {
+        Box::pin(async move {
+            // A node is releasing the critical section
+            let mut st = node.state.lock().await;
+            st.receive_mutex(
+                node,
+                &message.message_initiator_id,
+                crate::mutex::MutexPayload::Release,
+            )
+            .await
+        })
+    }
+
+    fn complete<'a>(
+        &'a self,
+        _node: &'a crate::node::Node,
+        _state: &'a mut AppState,
+        _aggregate: crate::message::MessageInfo,
+    ) -> crate::wave::WaveFuture<'a, ()> {
+        // Tous les sites savent qu'on a quitté la section critique
+        Box::pin(async { Ok(()) })
+    }
+}
+
 // Singleton
 #[cfg(feature = "server")]
 lazy_static::lazy_static! {
@@ -539,4 +1103,322 @@ mod tests {
         assert_eq!(shared_state.cli_peer_addrs, peer_addrs);
         assert_eq!(shared_state.clocks.get_vector_clock_map().len(), 0); // Initially empty
     }
+
+    /// Blue or red message of a wave in flight between two sites
+    #[derive(Clone)]
+    struct WaveMessage {
+        wave: crate::message::WaveId,
+        from: usize,
+        to: usize,
+        blue: bool,
+    }
+
+    fn addr(site: usize) -> std::net::SocketAddr {
+        format!("127.0.0.1:{}", 9000 + site).parse().unwrap()
+    }
+
+    /// Builds the states of sites connected by the given links
+    fn network(nb_sites: usize, links: &[(usize, usize)]) -> Vec<AppState> {
+        let mut sites: Vec<AppState> = (0..nb_sites)
+            .map(|i| AppState::new(format!("S{}", i), Vec::new(), addr(i)))
+            .collect();
+        for (a, b) in links {
+            sites[*a].add_connected_neighbour(addr(*b));
+            sites[*b].add_connected_neighbour(addr(*a));
+        }
+        sites
+    }
+
+    fn site_of(addr_to_find: std::net::SocketAddr) -> usize {
+        addr_to_find.port() as usize - 9000
+    }
+
+    /// Sends the blue messages of a wave to every neighbour but the parent
+    fn diffuse(sites: &[AppState], site: usize, wave: &crate::message::WaveId) -> Vec<WaveMessage> {
+        let parent = sites[site].get_parent_addr_for_wave(wave);
+        sites[site]
+            .get_connected_nei_addr()
+            .into_iter()
+            .filter(|n| *n != parent)
+            .map(|n| WaveMessage {
+                wave: wave.clone(),
+                from: site,
+                to: site_of(n),
+                blue: true,
+            })
+            .collect()
+    }
+
+    /// Runs waves started by the given sites, delivering the messages of the
+    /// different links in turn while keeping each link FIFO like TCP
+    ///
+    /// Returns the waves in the order they completed on their initiator.
+    fn run_waves(sites: &mut [AppState], initiators: &[usize]) -> Vec<crate::message::WaveId> {
+        let mut links: std::collections::BTreeMap<
+            (usize, usize),
+            std::collections::VecDeque<WaveMessage>,
+        > = std::collections::BTreeMap::new();
+        let push = |links: &mut std::collections::BTreeMap<_, _>, msgs: Vec<WaveMessage>| {
+            for m in msgs {
+                links
+                    .entry((m.from, m.to))
+                    .or_insert_with(std::collections::VecDeque::new)
+                    .push_back(m);
+            }
+        };
+
+        for initiator in initiators {
+            let wave = sites[*initiator].next_wave_id();
+            assert!(sites[*initiator].start_wave(&wave));
+            let msgs = diffuse(sites, *initiator, &wave);
+            push(&mut links, msgs);
+        }
+
+        let mut completed = Vec::new();
+        let mut step = 0;
+        loop {
+            let busy: Vec<(usize, usize)> = links
+                .iter()
+                .filter(|(_, q)| !q.is_empty())
+                .map(|(k, _)| *k)
+                .collect();
+            if busy.is_empty() {
+                break;
+            }
+            // Rotate over the links so the waves interleave everywhere
+            let link = busy[step % busy.len()];
+            step += 1;
+            let m = links.get_mut(&link).unwrap().pop_front().unwrap();
+
+            let answer = |wave: &crate::message::WaveId, to: usize| WaveMessage {
+                wave: wave.clone(),
+                from: m.to,
+                to,
+                blue: false,
+            };
+            if m.blue {
+                if sites[m.to].receive_wave(&m.wave, addr(m.from)) {
+                    let msgs = diffuse(sites, m.to, &m.wave);
+                    push(&mut links, msgs);
+                } else {
+                    push(&mut links, vec![answer(&m.wave, m.from)]);
+                }
+            } else if let Some(parent) = sites[m.to].receive_wave_answer(&m.wave) {
+                if parent == addr(m.to) {
+                    completed.push(m.wave.clone());
+                } else {
+                    push(&mut links, vec![answer(&m.wave, site_of(parent))]);
+                }
+            }
+        }
+        completed
+    }
+
+    fn assert_waves_forgotten(sites: &[AppState]) {
+        for site in sites {
+            assert!(site.parent_addr_for_transaction_wave.is_empty());
+            assert!(site.attended_neighbours_nb_for_transaction_wave.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_wave_ids_are_unique_per_initiator() {
+        let mut state = AppState::new("A".to_string(), Vec::new(), addr(0));
+        state.init_wave_seq(41);
+        let first = state.next_wave_id();
+        let second = state.next_wave_id();
+        assert_eq!(first.initiator_id, "A");
+        assert_eq!((first.seq, second.seq), (41, 42));
+    }
+
+    #[test]
+    fn test_lonely_site_does_not_record_its_wave() {
+        let mut state = AppState::new("A".to_string(), Vec::new(), addr(0));
+        let wave = state.next_wave_id();
+        assert!(!state.start_wave(&wave));
+        assert_waves_forgotten(&[state]);
+    }
+
+    #[test]
+    fn test_interleaved_waves_of_one_initiator_on_a_line() {
+        // 0 - 1 - 2 - 3 - 4, the middle site runs two waves at once
+        let mut sites = network(5, &[(0, 1), (1, 2), (2, 3), (3, 4)]);
+        let completed = run_waves(&mut sites, &[2, 2]);
+
+        assert_eq!(completed.len(), 2);
+        assert_ne!(completed[0], completed[1]);
+        assert!(completed.iter().all(|w| w.initiator_id == "S2"));
+        assert_waves_forgotten(&sites);
+    }
+
+    #[test]
+    fn test_interleaved_waves_on_a_ring() {
+        // 0 - 1 - 2 - 3 - 4 - 5 - 0, two waves from 0 overlap one from 3
+        let links = [(0, 1), (1, 2), (2, 3), (3, 4), (4, 5), (5, 0)];
+        let mut sites = network(6, &links);
+        let completed = run_waves(&mut sites, &[0, 3, 0]);
+
+        assert_eq!(completed.len(), 3);
+        let from_0 = completed.iter().filter(|w| w.initiator_id == "S0").count();
+        assert_eq!(from_0, 2);
+        assert_waves_forgotten(&sites);
+
+        // The sites are ready for the next waves
+        let completed = run_waves(&mut sites, &[0, 0]);
+        assert_eq!(completed.len(), 2);
+        assert_waves_forgotten(&sites);
+    }
+
+    fn wave_queue(requests: &[(&str, i64, LockScope)]) -> crate::mutex::WaveQueue {
+        let mut queue = crate::mutex::WaveQueue::new();
+        for (site_id, date, scope) in requests {
+            queue.fifo.insert(
+                site_id.to_string(),
+                MutexStamp {
+                    tag: MutexTag::Request,
+                    date: *date,
+                    scope: scope.clone(),
+                },
+            );
+        }
+        queue
+    }
+
+    #[test]
+    fn test_lock_scopes() {
+        let alice = LockScope::accounts(["alice"]);
+        let bob = LockScope::accounts(["bob"]);
+        let both = alice.clone().union(bob.clone());
+
+        assert!(!alice.conflicts_with(&bob));
+        assert!(both.conflicts_with(&bob));
+        assert!(LockScope::All.conflicts_with(&alice));
+        assert!(both.covers(&alice) && !alice.covers(&both));
+        assert!(LockScope::All.covers(&both) && !both.covers(&LockScope::All));
+        assert_eq!(alice.union(LockScope::All), LockScope::All);
+    }
+
+    #[test]
+    fn test_only_covered_commands_run() {
+        use crate::control::{CriticalCommands, PendingCommand};
+
+        let db = crate::db::Db::in_memory();
+        let mut state = AppState::new("A".to_string(), Vec::new(), addr(0));
+        for name in ["alice", "bob", "alice"] {
+            state.pending_commands.push_back(PendingCommand::new(
+                &db,
+                CriticalCommands::Deposit {
+                    name: name.to_string(),
+                    amount: crate::money::Money::from_cents(100),
+                },
+                None,
+            ));
+        }
+        let mut queue = wave_queue(&[("A", 1, LockScope::accounts(["alice"]))]);
+        queue.scope = Some(LockScope::accounts(["alice"]));
+        queue.waiting = true;
+        state.mutex = Box::new(queue);
+        assert!(state.pop_runnable_command().is_none());
+
+        let mut queue = wave_queue(&[("A", 1, LockScope::accounts(["alice"]))]);
+        queue.scope = Some(LockScope::accounts(["alice"]));
+        queue.in_sc = true;
+        state.mutex = Box::new(queue);
+        assert!(state.pop_runnable_command().is_some());
+        // The deposit to bob is not covered, and the next one waits behind it
+        assert!(state.pop_runnable_command().is_none());
+        assert_eq!(state.pending_commands.len(), 2);
+    }
+
+    #[test]
+    fn test_mutex_peers_are_the_members_not_only_the_neighbours() {
+        let mut state = AppState::new("A".to_string(), Vec::new(), addr(0));
+        state.init_membership(1);
+        for (port, site) in [(1, "B"), (2, "C")] {
+            let member = crate::membership::MembershipView::new(addr(port), site.to_string(), 1);
+            state.membership.merge(&member.digest());
+        }
+        // Neither B nor C is a neighbour
+        assert!(state.get_connected_nei_addr().is_empty());
+        assert_eq!(state.mutex_context().peers, vec!["B", "C"]);
+
+        state
+            .membership
+            .mark(addr(2), crate::membership::MemberStatus::Suspect);
+        assert_eq!(state.mutex_context().peers, vec!["B"]);
+    }
+
+    #[test]
+    fn test_only_members_without_a_pinned_key_are_asked_for_it() {
+        let mut state = AppState::new("A".to_string(), Vec::new(), addr(0));
+        state.init_membership(1);
+        for (port, site) in [(1, "B"), (2, "C")] {
+            let member = crate::membership::MembershipView::new(addr(port), site.to_string(), 1);
+            state.membership.merge(&member.digest());
+        }
+        assert_eq!(state.unpinned_members(), vec![addr(1), addr(2)]);
+
+        let key = crate::identity::SiteIdentity::generate()
+            .unwrap()
+            .public_key();
+        state.key_ring.pin("B", &key).unwrap();
+        assert_eq!(state.unpinned_members(), vec![addr(2)]);
+    }
+
+    #[test]
+    fn test_request_of_departed_site_is_dropped() {
+        let mut state = AppState::new("A".to_string(), Vec::new(), addr(0));
+        state.init_membership(1);
+        let mut holder = crate::membership::MembershipView::new(addr(1), "B".to_string(), 1);
+        state.membership.merge(&holder.digest());
+
+        // B holds the critical section, our request has reached every site
+        let mut queue = wave_queue(&[("A", 2, LockScope::All), ("B", 1, LockScope::All)]);
+        queue.waiting = true;
+        queue.announced = true;
+        queue.try_enter_sc("A");
+        state.mutex = Box::new(queue);
+        assert_eq!(state.mutex_status(), crate::mutex::MutexStatus::Waiting);
+        assert!(state.forget_departed_requests().is_empty());
+
+        // B dies, the rumour reaches us through the gossip
+        holder.leave();
+        state.membership.merge(&holder.digest());
+        assert_eq!(state.forget_departed_requests(), vec!["B".to_string()]);
+        assert_eq!(state.mutex_status(), crate::mutex::MutexStatus::Held);
+        assert!(!state.get_global_mutex_fifo().contains_key("B"));
+    }
+
+    #[tokio::test]
+    async fn test_expired_request_fails_the_waiting_commands() {
+        let node = crate::node::Node::new(
+            AppState::new("A".to_string(), Vec::new(), addr(0)),
+            crate::db::Db::in_memory(),
+        );
+        let mut state = node.state.lock().await;
+        state.init_mutex_timeout(100);
+        let now = std::time::Instant::now();
+
+        let mut queue = wave_queue(&[("A", 1, LockScope::All)]);
+        queue.waiting = true;
+        state.mutex = Box::new(queue);
+        let (done, outcome) = tokio::sync::oneshot::channel();
+        state
+            .pending_commands
+            .push_back(crate::control::PendingCommand::new(
+                &node.db,
+                crate::control::CriticalCommands::FileSnapshot,
+                Some(done),
+            ));
+        state.mutex_requested_at = Some(now);
+        assert!(!state.mutex_request_expired(now + std::time::Duration::from_millis(50)));
+        assert!(state.mutex_request_expired(now + std::time::Duration::from_millis(100)));
+
+        state.abort_mutex_request(&node).await.unwrap();
+        assert!(outcome.await.unwrap().unwrap_err().contains("Timed out"));
+        assert!(state.pending_commands.is_empty());
+        assert_eq!(state.mutex_status(), crate::mutex::MutexStatus::Idle);
+        assert!(!state.get_global_mutex_fifo().contains_key("A"));
+        assert!(!state.mutex_request_expired(now + std::time::Duration::from_secs(1)));
+    }
 }
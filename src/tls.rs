@@ -0,0 +1,279 @@
+//! Mutually authenticated TLS between sites
+//!
+//! When a certificate authority, a certificate and a private key are given on
+//! the command line, every peer stream is wrapped in TLS. Both ends present a
+//! certificate and check that it chains to the local CA, so a site whose
+//! certificate was not issued by this CA can neither connect to us nor accept
+//! our connections. The client checks the server certificate against the IP
+//! address it connects to, node certificates must therefore carry their IP
+//! address as a subject alternative name.
+
+#![cfg(feature = "server")]
+
+/// Errors raised while loading the TLS configuration
+#[derive(Debug)]
+pub enum TlsError {
+    /// A PEM file could not be read or parsed
+    Pem { path: String, reason: String },
+    /// A PEM file does not contain the expected item
+    Missing { path: String, what: &'static str },
+    /// The certificates or the key were rejected by rustls
+    Config(String),
+}
+
+impl std::fmt::Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsError::Pem { path, reason } => write!(f, "cannot read {}: {}", path, reason),
+            TlsError::Missing { path, what } => write!(f, "no {} found in {}", what, path),
+            TlsError::Config(reason) => write!(f, "invalid TLS configuration: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+/// Stream type of a TLS connection opened by this site
+pub type ClientStream = tokio_rustls::client::TlsStream<tokio::net::TcpStream>;
+
+/// Stream type of a TLS connection accepted by this site
+pub type ServerStream = tokio_rustls::server::TlsStream<tokio::net::TcpStream>;
+
+/// Acceptor and connector sharing the same CA and node identity
+#[derive(Clone)]
+pub struct TlsContext {
+    /// Used on incoming connections, requires a client certificate
+    acceptor: tokio_rustls::TlsAcceptor,
+    /// Used on outgoing connections, presents our certificate
+    connector: tokio_rustls::TlsConnector,
+}
+
+impl TlsContext {
+    /// Loads the CA certificate, the node certificate chain and its key from PEM files
+    pub fn from_pem_files(
+        ca_path: &str,
+        cert_path: &str,
+        key_path: &str,
+    ) -> Result<Self, TlsError> {
+        let read = |path: &str| {
+            std::fs::read(path).map_err(|e| TlsError::Pem {
+                path: path.to_string(),
+                reason: e.to_string(),
+            })
+        };
+        Self::build(
+            (&read(ca_path)?, ca_path),
+            (&read(cert_path)?, cert_path),
+            (&read(key_path)?, key_path),
+        )
+    }
+
+    /// Builds the context, each PEM content comes with a name used in errors
+    fn build(
+        (ca_pem, ca_name): (&[u8], &str),
+        (cert_pem, cert_name): (&[u8], &str),
+        (key_pem, key_name): (&[u8], &str),
+    ) -> Result<Self, TlsError> {
+        use rustls::pki_types::pem::PemObject;
+        use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+        use std::sync::Arc;
+
+        let parse_certs = |pem: &[u8], name: &str| {
+            let certs = CertificateDer::pem_slice_iter(pem)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| TlsError::Pem {
+                    path: name.to_string(),
+                    reason: e.to_string(),
+                })?;
+            if certs.is_empty() {
+                return Err(TlsError::Missing {
+                    path: name.to_string(),
+                    what: "certificate",
+                });
+            }
+            Ok(certs)
+        };
+
+        let ca_certs = parse_certs(ca_pem, ca_name)?;
+        let certs = parse_certs(cert_pem, cert_name)?;
+        let key = PrivateKeyDer::from_pem_slice(key_pem).map_err(|_| TlsError::Missing {
+            path: key_name.to_string(),
+            what: "private key",
+        })?;
+
+        let mut roots = rustls::RootCertStore::empty();
+        for ca in ca_certs {
+            roots.add(ca).map_err(|e| TlsError::Config(e.to_string()))?;
+        }
+        let roots = Arc::new(roots);
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+        let client_verifier = rustls::server::WebPkiClientVerifier::builder_with_provider(
+            roots.clone(),
+            provider.clone(),
+        )
+        .build()
+        .map_err(|e| TlsError::Config(e.to_string()))?;
+
+        let server_config = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| TlsError::Config(e.to_string()))?
+            .with_client_cert_verifier(client_verifier)
+            .with_single_cert(certs.clone(), key.clone_key())
+            .map_err(|e| TlsError::Config(e.to_string()))?;
+
+        let client_config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|e| TlsError::Config(e.to_string()))?
+            .with_root_certificates(roots)
+            .with_client_auth_cert(certs, key)
+            .map_err(|e| TlsError::Config(e.to_string()))?;
+
+        Ok(Self {
+            acceptor: tokio_rustls::TlsAcceptor::from(Arc::new(server_config)),
+            connector: tokio_rustls::TlsConnector::from(Arc::new(client_config)),
+        })
+    }
+
+    /// Runs the client handshake on a stream opened to `addr`
+    pub async fn connect(
+        &self,
+        addr: std::net::SocketAddr,
+        stream: tokio::net::TcpStream,
+    ) -> std::io::Result<ClientStream> {
+        let server_name = rustls::pki_types::ServerName::IpAddress(addr.ip().into());
+        self.connector.connect(server_name, stream).await
+    }
+
+    /// Runs the server handshake on an accepted stream
+    pub async fn accept(&self, stream: tokio::net::TcpStream) -> std::io::Result<ServerStream> {
+        self.acceptor.accept(stream).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn from_pem(ca: &[u8], cert: &[u8], key: &[u8]) -> Result<TlsContext, TlsError> {
+        TlsContext::build((ca, "CA"), (cert, "certificate"), (key, "key"))
+    }
+
+    /// Certificate authority able to issue node certificates
+    struct TestCa {
+        cert: rcgen::Certificate,
+        key: rcgen::KeyPair,
+    }
+
+    impl TestCa {
+        fn new(name: &str) -> Self {
+            let mut params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+            params
+                .distinguished_name
+                .push(rcgen::DnType::CommonName, name);
+            params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+            let key = rcgen::KeyPair::generate().unwrap();
+            let cert = params.self_signed(&key).unwrap();
+            Self { cert, key }
+        }
+
+        /// Issues a certificate valid for 127.0.0.1, as server and as client
+        fn issue(&self) -> (String, String) {
+            let mut params = rcgen::CertificateParams::new(vec!["127.0.0.1".to_string()]).unwrap();
+            params.extended_key_usages = vec![
+                rcgen::ExtendedKeyUsagePurpose::ServerAuth,
+                rcgen::ExtendedKeyUsagePurpose::ClientAuth,
+            ];
+            let key = rcgen::KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+            (cert.pem(), key.serialize_pem())
+        }
+
+        fn context(&self) -> TlsContext {
+            let (cert, key) = self.issue();
+            from_pem(self.cert.pem().as_bytes(), cert.as_bytes(), key.as_bytes()).unwrap()
+        }
+    }
+
+    /// Accepts one connection with `server` and opens it with `client`
+    async fn handshake(
+        server: TlsContext,
+        client: TlsContext,
+    ) -> (std::io::Result<Vec<u8>>, std::io::Result<()>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server_task = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut tls = server.accept(stream).await?;
+            let mut buf = vec![0u8; 5];
+            tls.read_exact(&mut buf).await?;
+            Ok(buf)
+        });
+
+        let client_result = async {
+            let stream = tokio::net::TcpStream::connect(addr).await?;
+            let mut tls = client.connect(addr, stream).await?;
+            tls.write_all(b"hello").await?;
+            tls.flush().await?;
+            // Wait for the server to read or reject before closing
+            let mut buf = [0u8; 1];
+            let _ = tls.read(&mut buf).await;
+            Ok(())
+        };
+
+        let client_result = tokio::time::timeout(std::time::Duration::from_secs(5), client_result)
+            .await
+            .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()));
+        let server_result = server_task.await.unwrap();
+        (server_result, client_result)
+    }
+
+    #[tokio::test]
+    async fn peers_sharing_the_ca_can_talk() {
+        let ca = TestCa::new("peillute test ca");
+        let (server, client) = handshake(ca.context(), ca.context()).await;
+        assert_eq!(server.unwrap(), b"hello");
+        assert!(client.is_ok());
+    }
+
+    #[tokio::test]
+    async fn client_from_another_ca_is_rejected() {
+        let ca = TestCa::new("peillute test ca");
+        let rogue = TestCa::new("rogue ca");
+
+        // The rogue client trusts our CA but its own certificate does not chain to it
+        let (cert, key) = rogue.issue();
+        let client = from_pem(ca.cert.pem().as_bytes(), cert.as_bytes(), key.as_bytes()).unwrap();
+
+        let (server, _) = handshake(ca.context(), client).await;
+        assert!(server.is_err());
+    }
+
+    #[tokio::test]
+    async fn server_from_another_ca_is_rejected() {
+        let ca = TestCa::new("peillute test ca");
+        let rogue = TestCa::new("rogue ca");
+
+        let (server, client) = handshake(rogue.context(), ca.context()).await;
+        assert!(client.is_err());
+        assert!(server.is_err());
+    }
+
+    #[test]
+    fn missing_key_is_reported() {
+        let ca = TestCa::new("peillute test ca");
+        let (cert, _) = ca.issue();
+        let err = from_pem(ca.cert.pem().as_bytes(), cert.as_bytes(), b"")
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            TlsError::Missing {
+                what: "private key",
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,368 @@
+//! Transports carrying the streams between sites
+//!
+//! The network code only needs two things from the underlying network: opening
+//! an outgoing stream to a site, on which the writer task sends the frames,
+//! and receiving the incoming streams of the other sites, read by
+//! `handle_network_message`. The [`Transport`] trait covers exactly this.
+//!
+//! [`TcpTransport`] is the real one, optionally wrapped in mutual TLS.
+//! `MemoryTransport`, only built for the tests, connects transports living in
+//! the same process through in-memory pipes.
+//!
+//! The handlers run on a `crate::node::Node`, so several full sites bound on
+//! the same `MemoryNetwork` can run in a single test, see the tests of `node`.
+
+#![cfg(feature = "server")]
+
+/// Stream on which a site receives the frames of a peer
+pub type PeerReader = Box<dyn tokio::io::AsyncRead + Send + Unpin>;
+
+/// Stream on which a site sends its frames to a peer
+pub type PeerWriter = Box<dyn tokio::io::AsyncWrite + Send + Unpin>;
+
+/// Future returned by the transport operations
+pub type TransportFuture<'a, T> =
+    std::pin::Pin<Box<dyn std::future::Future<Output = std::io::Result<T>> + Send + 'a>>;
+
+/// Incoming stream with the address of the remote socket
+type Incoming = (PeerReader, std::net::SocketAddr);
+
+/// Number of incoming streams waiting to be accepted
+const INCOMING_BACKLOG: usize = 128;
+
+/// Way of connecting the sites together
+pub trait Transport: Send + Sync {
+    /// Address the other sites use to reach this one
+    fn local_addr(&self) -> std::net::SocketAddr;
+
+    /// Opens a stream to send frames to the site listening on `addr`
+    fn connect(&self, addr: std::net::SocketAddr) -> TransportFuture<'_, PeerWriter>;
+
+    /// Waits for the next stream opened by another site
+    ///
+    /// Cancelling the returned future never loses a stream, so it can be used
+    /// in a `select!` loop.
+    fn accept(&self) -> TransportFuture<'_, Incoming>;
+}
+
+/// TCP transport, with mutual TLS when a context is given
+pub struct TcpTransport {
+    /// Address of the listener
+    local_addr: std::net::SocketAddr,
+    /// TLS configuration, plain TCP when absent
+    tls: Option<crate::tls::TlsContext>,
+    /// Streams accepted by the listener task, handshake done
+    incoming: tokio::sync::Mutex<tokio::sync::mpsc::Receiver<Incoming>>,
+}
+
+impl TcpTransport {
+    /// Listens on `addr` and starts accepting the other sites
+    ///
+    /// The TLS handshakes are run in their own tasks, so a slow peer does not
+    /// delay the others.
+    pub async fn bind(
+        addr: std::net::SocketAddr,
+        tls: Option<crate::tls::TlsContext>,
+    ) -> std::io::Result<Self> {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let (tx, rx) = tokio::sync::mpsc::channel(INCOMING_BACKLOG);
+
+        let acceptor_tls = tls.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, remote) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        log::error!("Failed to accept a connection: {}", e);
+                        continue;
+                    }
+                };
+                log::debug!("Accepted connection from: {}", remote);
+
+                let tx = tx.clone();
+                let tls = acceptor_tls.clone();
+                tokio::spawn(async move {
+                    let reader: PeerReader = match tls {
+                        Some(tls) => match tls.accept(stream).await {
+                            Ok(stream) => Box::new(stream),
+                            Err(e) => {
+                                log::warn!("TLS handshake with {} rejected: {}", remote, e);
+                                return;
+                            }
+                        },
+                        None => Box::new(stream),
+                    };
+                    let _ = tx.send((reader, remote)).await;
+                });
+            }
+        });
+
+        Ok(Self {
+            local_addr,
+            tls,
+            incoming: tokio::sync::Mutex::new(rx),
+        })
+    }
+}
+
+impl Transport for TcpTransport {
+    fn local_addr(&self) -> std::net::SocketAddr {
+        self.local_addr
+    }
+
+    fn connect(&self, addr: std::net::SocketAddr) -> TransportFuture<'_, PeerWriter> {
+        Box::pin(async move {
+            let stream = tokio::net::TcpStream::connect(addr).await?;
+            let writer: PeerWriter = match &self.tls {
+                Some(tls) => Box::new(tls.connect(addr, stream).await?),
+                None => Box::new(stream),
+            };
+            Ok(writer)
+        })
+    }
+
+    fn accept(&self) -> TransportFuture<'_, Incoming> {
+        Box::pin(async move {
+            let mut incoming = self.incoming.lock().await;
+            incoming
+                .recv()
+                .await
+                .ok_or_else(|| std::io::Error::other("listener task stopped"))
+        })
+    }
+}
+
+/// In-process network on which memory transports are bound
+#[cfg(test)]
+pub struct MemoryNetwork {
+    /// Incoming queue of every bound transport
+    listeners: std::sync::Mutex<
+        std::collections::HashMap<std::net::SocketAddr, tokio::sync::mpsc::Sender<Incoming>>,
+    >,
+    /// Port given to the next connecting socket, as an ephemeral TCP port
+    next_port: std::sync::atomic::AtomicU16,
+    /// Capacity of the pipes, in bytes
+    pipe_capacity: usize,
+}
+
+#[cfg(test)]
+impl MemoryNetwork {
+    /// Creates an empty network
+    pub fn new() -> std::sync::Arc<Self> {
+        std::sync::Arc::new(Self {
+            listeners: std::sync::Mutex::new(std::collections::HashMap::new()),
+            next_port: std::sync::atomic::AtomicU16::new(40000),
+            pipe_capacity: 64 * 1024,
+        })
+    }
+
+    /// Binds a transport on `addr`, which must not be used yet
+    pub fn bind(
+        self: &std::sync::Arc<Self>,
+        addr: std::net::SocketAddr,
+    ) -> std::io::Result<MemoryTransport> {
+        let mut listeners = self.listeners.lock().unwrap();
+        if listeners.contains_key(&addr) {
+            return Err(std::io::ErrorKind::AddrInUse.into());
+        }
+        let (tx, rx) = tokio::sync::mpsc::channel(INCOMING_BACKLOG);
+        listeners.insert(addr, tx);
+        Ok(MemoryTransport {
+            network: self.clone(),
+            local_addr: addr,
+            incoming: tokio::sync::Mutex::new(rx),
+        })
+    }
+}
+
+/// Transport bound on a [`MemoryNetwork`]
+///
+/// Dropping the transport unbinds it: the other sites can no longer connect,
+/// as if the site had crashed.
+#[cfg(test)]
+pub struct MemoryTransport {
+    /// Network the transport is bound on
+    network: std::sync::Arc<MemoryNetwork>,
+    /// Address the transport is bound on
+    local_addr: std::net::SocketAddr,
+    /// Streams opened by the other transports
+    incoming: tokio::sync::Mutex<tokio::sync::mpsc::Receiver<Incoming>>,
+}
+
+#[cfg(test)]
+impl Transport for MemoryTransport {
+    fn local_addr(&self) -> std::net::SocketAddr {
+        self.local_addr
+    }
+
+    fn connect(&self, addr: std::net::SocketAddr) -> TransportFuture<'_, PeerWriter> {
+        Box::pin(async move {
+            let listener = {
+                let listeners = self.network.listeners.lock().unwrap();
+                listeners.get(&addr).cloned()
+            }
+            .ok_or(std::io::ErrorKind::ConnectionRefused)?;
+
+            let port = self
+                .network
+                .next_port
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let remote = std::net::SocketAddr::new(self.local_addr.ip(), port);
+
+            let (client, server) = tokio::io::duplex(self.network.pipe_capacity);
+            listener
+                .send((Box::new(server), remote))
+                .await
+                .map_err(|_| std::io::Error::from(std::io::ErrorKind::ConnectionRefused))?;
+            let writer: PeerWriter = Box::new(client);
+            Ok(writer)
+        })
+    }
+
+    fn accept(&self) -> TransportFuture<'_, Incoming> {
+        Box::pin(async move {
+            let mut incoming = self.incoming.lock().await;
+            incoming
+                .recv()
+                .await
+                .ok_or_else(|| std::io::Error::other("memory network dropped"))
+        })
+    }
+}
+
+#[cfg(test)]
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        if let Ok(mut listeners) = self.network.listeners.lock() {
+            listeners.remove(&self.local_addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{DEFAULT_MAX_FRAME_SIZE, FrameCodec};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn addr(port: u16) -> std::net::SocketAddr {
+        format!("10.0.0.1:{}", port).parse().unwrap()
+    }
+
+    /// Reads the frames of an incoming stream until it is closed
+    async fn read_frames(mut reader: PeerReader) -> Vec<Vec<u8>> {
+        let mut codec = FrameCodec::new(DEFAULT_MAX_FRAME_SIZE);
+        let mut frames = Vec::new();
+        let mut buf = vec![0u8; 1024];
+        loop {
+            let n = reader.read(&mut buf).await.unwrap();
+            if n == 0 {
+                return frames;
+            }
+            codec.extend(&buf[..n]);
+            while let Some(frame) = codec.decode().unwrap() {
+                frames.push(frame);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_transport_carries_bytes() {
+        let network = MemoryNetwork::new();
+        let a = network.bind(addr(1)).unwrap();
+        let b = network.bind(addr(2)).unwrap();
+
+        let mut writer = a.connect(b.local_addr()).await.unwrap();
+        writer.write_all(b"hello").await.unwrap();
+        drop(writer);
+
+        let (mut reader, remote) = b.accept().await.unwrap();
+        assert_eq!(remote.ip(), addr(1).ip());
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+    }
+
+    #[tokio::test]
+    async fn memory_transport_refuses_unbound_addresses() {
+        let network = MemoryNetwork::new();
+        let a = network.bind(addr(1)).unwrap();
+        assert!(network.bind(addr(1)).is_err());
+
+        let err = a.connect(addr(2)).await.err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+
+        // A dropped transport behaves as a crashed site
+        let b = network.bind(addr(2)).unwrap();
+        drop(b);
+        assert!(a.connect(addr(2)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn memory_transports_exchange_frames() {
+        let network = MemoryNetwork::new();
+        let sites: Vec<std::sync::Arc<dyn Transport>> = (1..=4)
+            .map(|port| {
+                std::sync::Arc::new(network.bind(addr(port)).unwrap())
+                    as std::sync::Arc<dyn Transport>
+            })
+            .collect();
+
+        // Every site sends one frame to every other site through a writer task
+        for site in &sites {
+            for peer in &sites {
+                if site.local_addr() == peer.local_addr() {
+                    continue;
+                }
+                let writer = site.connect(peer.local_addr()).await.unwrap();
+                let (tx, rx) = tokio::sync::mpsc::channel(4);
+                crate::network::spawn_writer_task(writer, rx, DEFAULT_MAX_FRAME_SIZE).await;
+                tx.send(site.local_addr().to_string().into_bytes())
+                    .await
+                    .unwrap();
+            }
+        }
+
+        for site in &sites {
+            let mut senders = Vec::new();
+            for _ in 0..sites.len() - 1 {
+                let (reader, _) = site.accept().await.unwrap();
+                let frames = read_frames(reader).await;
+                assert_eq!(frames.len(), 1);
+                senders.push(String::from_utf8(frames[0].clone()).unwrap());
+            }
+            senders.sort();
+            let mut expected: Vec<_> = sites
+                .iter()
+                .filter(|s| s.local_addr() != site.local_addr())
+                .map(|s| s.local_addr().to_string())
+                .collect();
+            expected.sort();
+            assert_eq!(senders, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn tcp_transport_carries_frames() {
+        let a = TcpTransport::bind("127.0.0.1:0".parse().unwrap(), None)
+            .await
+            .unwrap();
+        let b = TcpTransport::bind("127.0.0.1:0".parse().unwrap(), None)
+            .await
+            .unwrap();
+
+        let writer = a.connect(b.local_addr()).await.unwrap();
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        crate::network::spawn_writer_task(writer, rx, DEFAULT_MAX_FRAME_SIZE).await;
+        tx.send(b"first".to_vec()).await.unwrap();
+        tx.send(b"second".to_vec()).await.unwrap();
+        drop(tx);
+
+        let (reader, _) = b.accept().await.unwrap();
+        assert_eq!(
+            read_frames(reader).await,
+            vec![b"first".to_vec(), b"second".to_vec()]
+        );
+    }
+}
@@ -15,9 +15,11 @@ pub fn get_mac_address() -> Option<String> {
     None
 }
 #[cfg(feature = "server")]
-pub async fn reload_existing_site() -> Result<(String, crate::clock::Clock), String> {
+pub async fn reload_existing_site(
+    db: &crate::db::Db,
+) -> Result<(String, crate::clock::Clock), String> {
     use log::info;
-    match crate::db::get_local_state() {
+    match crate::db::get_local_state(db) {
         Ok((site_id, clock)) => {
             info!("Existing site state reloaded");
             Ok((site_id, clock))
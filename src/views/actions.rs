@@ -18,7 +18,7 @@ pub fn History(name: String) -> Element {
 
     let transactions_resource = use_resource(move || {
         let name_clone = name_for_future.clone();
-        async move { get_transactions_for_user_server(name_clone.to_string()).await }
+        async move { get_history_for_user_server(name_clone.to_string()).await }
     });
 
     rsx! {
@@ -35,10 +35,13 @@ pub fn History(name: String) -> Element {
                     } else {
                         rsx! {
                             ul { class: "transactions-list",
-                                for transaction in transactions.iter() {
+                                for (transaction, concurrent) in transactions.iter() {
                                     li {
                                         key: "{transaction.lamport_time}-{transaction.source_node}",
                                         class: "transaction-card",
+                                        if *concurrent {
+                                            p { class: "concurrent-tag", "⚡ Concurrent with an earlier transaction" }
+                                        }
                                         p {
                                             strong { "From:" }
                                             " {transaction.from_user}"
@@ -49,7 +52,13 @@ pub fn History(name: String) -> Element {
                                         }
                                         p {
                                             strong { "Amount:" }
-                                            " {transaction.amount:.2}"
+                                            " {transaction.amount}"
+                                        }
+                                        if transaction.hlc.physical_ms > 0 {
+                                            p {
+                                                strong { "When:" }
+                                                " {transaction.hlc}"
+                                            }
                                         }
                                         if let Some(msg) = &transaction.optional_msg {
                                             if !msg.is_empty() {
@@ -80,7 +89,7 @@ pub fn History(name: String) -> Element {
 /// validation to ensure positive amounts and sufficient funds.
 #[component]
 pub fn Withdraw(name: String) -> Element {
-    let mut withdraw_amount = use_signal(|| 0f64);
+    let mut withdraw_amount = use_signal(|| crate::money::Money::ZERO);
     let name = std::rc::Rc::new(name);
 
     let mut error_signal = use_signal(|| None::<String>);
@@ -98,8 +107,12 @@ pub fn Withdraw(name: String) -> Element {
                     step: 0.01,
                     value: "{withdraw_amount}",
                     oninput: move |event| {
-                        if let Ok(as_number) = event.value().parse::<f64>() {
-                            withdraw_amount.set(as_number);
+                        match event.value().parse::<crate::money::Money>() {
+                            Ok(as_number) => {
+                                withdraw_amount.set(as_number);
+                                error_signal.set(None);
+                            }
+                            Err(e) => error_signal.set(Some(e.to_string())),
                         }
                     },
                 }
@@ -109,9 +122,9 @@ pub fn Withdraw(name: String) -> Element {
                         let name = name_for_future.clone();
                         let amount = *withdraw_amount.read();
                         async move {
-                            if amount >= 0.0 {
+                            if !amount.is_negative() {
                                 if let Ok(_) = withdraw_for_user_server(name.to_string(), amount).await {
-                                    withdraw_amount.set(0.0);
+                                    withdraw_amount.set(crate::money::Money::ZERO);
                                     error_signal.set(None);
                                 }
                             } else {
@@ -139,13 +152,27 @@ const CHIPS_IMG: Asset = asset!("/assets/images/chips.png");
 const SANDWICH_IMG: Asset = asset!("/assets/images/sandwich.png");
 const COFFEE_IMG: Asset = asset!("/assets/images/coffee.png");
 
-const PRODUCTS: &[(&str, f64, Asset)] = &[
-    ("Coca", 1.50, COCA_IMG),
-    ("Chips", 2.00, CHIPS_IMG),
-    ("Sandwich", 4.50, SANDWICH_IMG),
-    ("Coffee", 1.20, COFFEE_IMG),
+const PRODUCTS: &[(&str, crate::money::Money, Asset)] = &[
+    ("Coca", crate::money::Money::from_cents(150), COCA_IMG),
+    ("Chips", crate::money::Money::from_cents(200), CHIPS_IMG),
+    (
+        "Sandwich",
+        crate::money::Money::from_cents(450),
+        SANDWICH_IMG,
+    ),
+    ("Coffee", crate::money::Money::from_cents(120), COFFEE_IMG),
 ];
 
+/// Price of the products selected, or an error if it does not fit in the ledger
+fn order_total(quantities: &[u32]) -> Result<crate::money::Money, crate::money::MoneyError> {
+    let prices = PRODUCTS
+        .iter()
+        .zip(quantities)
+        .map(|(&(_, price, _), &quantity)| price.checked_mul(quantity))
+        .collect::<Result<Vec<_>, _>>()?;
+    crate::money::Money::total(prices)
+}
+
 // take the username and collect the an amount (float from form) to make a payment
 /// Payment component
 ///
@@ -163,15 +190,17 @@ pub fn Pay(name: String) -> Element {
         let current_quantities = product_quantities.read().clone();
         let name_clone = name_for_payment.clone();
 
-        let mut total_amount = 0.0;
-        for (i, &(_, price, _)) in PRODUCTS.iter().enumerate() {
-            if let Some(&quantity) = current_quantities.get(i) {
-                total_amount += price * quantity as f64;
-            }
-        }
+        let total_amount = order_total(&current_quantities);
 
         spawn(async move {
-            if total_amount > 0.0 {
+            let total_amount = match total_amount {
+                Ok(total_amount) => total_amount,
+                Err(e) => {
+                    error_signal.set(Some(e.to_string()));
+                    return;
+                }
+            };
+            if total_amount.is_positive() {
                 if let Ok(_) = pay_for_user_server(name_clone.to_string(), total_amount).await {
                     log::info!("Payment successful.");
                     product_quantities.set(vec![0u32; PRODUCTS.len()]);
@@ -186,16 +215,7 @@ pub fn Pay(name: String) -> Element {
         });
     };
 
-    let current_total_display = use_memo(move || {
-        let mut total = 0.0;
-        let quantities_read = product_quantities.read();
-        for (i, &(_, price, _)) in PRODUCTS.iter().enumerate() {
-            if let Some(&quantity) = quantities_read.get(i) {
-                total += price * quantity as f64;
-            }
-        }
-        total
-    });
+    let current_total_display = use_memo(move || order_total(&product_quantities.read()));
 
     rsx! {
         div { id: "pay-page",
@@ -205,7 +225,7 @@ pub fn Pay(name: String) -> Element {
                         img { src: "{image_path}", alt: "{product_name}" }
                         div { class: "product-info",
                             h3 { "{product_name}" }
-                            p { "€{price:.2}" }
+                            p { "€{price}" }
                             div {
                                 label { r#for: "qty-{index}", "Quantity:" }
                                 input {
@@ -236,11 +256,11 @@ pub fn Pay(name: String) -> Element {
 
             div { class: "cart-summary",
                 h2 { "Order Summary" }
-                h3 { "Total: €{current_total_display():.2}" }
+                h3 { "Total: €{current_total_display().unwrap_or_default()}" }
                 form {
                     button {
                         r#type: "button",
-                        disabled: current_total_display() == 0.0,
+                        disabled: !current_total_display().is_ok_and(|total| total.is_positive()),
                         onclick: handle_pay,
                         "Pay Now"
                     }
@@ -302,7 +322,13 @@ pub fn Refund(name: String) -> Element {
                                         }
                                         p {
                                             strong { "Amount:" }
-                                            " {transaction.amount:.2}"
+                                            " {transaction.amount}"
+                                        }
+                                        if transaction.hlc.physical_ms > 0 {
+                                            p {
+                                                strong { "When:" }
+                                                " {transaction.hlc}"
+                                            }
                                         }
                                         if let Some(msg) = &transaction.optional_msg {
                                             if !msg.is_empty() {
@@ -374,7 +400,7 @@ pub fn Refund(name: String) -> Element {
 /// - Generating random messages for fun
 #[component]
 pub fn Transfer(name: String) -> Element {
-    let mut transfer_amount = use_signal(|| 0f64);
+    let mut transfer_amount = use_signal(|| crate::money::Money::ZERO);
     let mut transfer_message = use_signal(String::new);
     let mut selected_user = use_signal(String::new);
     let name = std::rc::Rc::new(name);
@@ -426,8 +452,12 @@ pub fn Transfer(name: String) -> Element {
                             step: 0.01,
                             value: "{transfer_amount}",
                             oninput: move |evt| {
-                                if let Ok(val) = evt.value().parse::<f64>() {
-                                    transfer_amount.set(val);
+                                match evt.value().parse::<crate::money::Money>() {
+                                    Ok(val) => {
+                                        transfer_amount.set(val);
+                                        error_signal.set(None);
+                                    }
+                                    Err(e) => error_signal.set(Some(e.to_string())),
                                 }
                             },
                         }
@@ -459,7 +489,7 @@ pub fn Transfer(name: String) -> Element {
                                 let message = transfer_message.read().clone();
                                 let from_user = name.clone();
                                 async move {
-                                    if !to_user.is_empty() && amount > 0.0 {
+                                    if !to_user.is_empty() && amount.is_positive() {
                                         if let Ok(_) = transfer_from_user_to_user_server(
                                                 from_user.to_string(),
                                                 to_user,
@@ -468,7 +498,7 @@ pub fn Transfer(name: String) -> Element {
                                             )
                                             .await
                                         {
-                                            transfer_amount.set(0.0);
+                                            transfer_amount.set(crate::money::Money::ZERO);
                                             transfer_message.set(String::new());
                                             selected_user.set(String::new());
                                             error_signal.set(None);
@@ -503,7 +533,7 @@ pub fn Transfer(name: String) -> Element {
 /// validation to ensure positive amounts.
 #[component]
 pub fn Deposit(name: String) -> Element {
-    let mut deposit_amount = use_signal(|| 0f64);
+    let mut deposit_amount = use_signal(|| crate::money::Money::ZERO);
     let name = std::rc::Rc::new(name);
 
     let mut error_signal = use_signal(|| None::<String>);
@@ -516,7 +546,7 @@ pub fn Deposit(name: String) -> Element {
                 h1 { "💰 Deposit Money" }
                 p { "Add funds to your account" }
             }
-            
+
             div { class: "transaction-form",
                 form {
                     div { class: "form-group",
@@ -530,59 +560,64 @@ pub fn Deposit(name: String) -> Element {
                                 step: 0.01,
                                 min: "0.01",
                                 placeholder: "0.00",
-                                value: if *deposit_amount.read() > 0.0 { "{deposit_amount}" } else { "" },
+                                value: if deposit_amount.read().is_positive() { "{deposit_amount}" } else { "" },
                                 oninput: move |event| {
-                                    if let Ok(as_number) = event.value().parse::<f64>() {
-                                        deposit_amount.set(as_number);
-                                    } else if event.value().is_empty() {
-                                        deposit_amount.set(0.0);
+                                    match event.value().parse::<crate::money::Money>() {
+                                        Ok(as_number) => {
+                                            deposit_amount.set(as_number);
+                                            error_signal.set(None);
+                                        }
+                                        Err(_) if event.value().is_empty() => {
+                                            deposit_amount.set(crate::money::Money::ZERO);
+                                        }
+                                        Err(e) => error_signal.set(Some(e.to_string())),
                                     }
                                 },
                             }
                         }
                     }
-                    
+
                     div { class: "quick-amounts",
                         span { class: "quick-label", "Quick amounts:" }
                         div { class: "quick-buttons",
                             button {
                                 r#type: "button",
                                 class: "quick-amount",
-                                onclick: move |_| deposit_amount.set(10.0),
+                                onclick: move |_| deposit_amount.set(crate::money::Money::from_cents(1000)),
                                 "€10"
                             }
                             button {
                                 r#type: "button",
                                 class: "quick-amount",
-                                onclick: move |_| deposit_amount.set(25.0),
+                                onclick: move |_| deposit_amount.set(crate::money::Money::from_cents(2500)),
                                 "€25"
                             }
                             button {
                                 r#type: "button",
                                 class: "quick-amount",
-                                onclick: move |_| deposit_amount.set(50.0),
+                                onclick: move |_| deposit_amount.set(crate::money::Money::from_cents(5000)),
                                 "€50"
                             }
                             button {
                                 r#type: "button",
                                 class: "quick-amount",
-                                onclick: move |_| deposit_amount.set(100.0),
+                                onclick: move |_| deposit_amount.set(crate::money::Money::from_cents(10000)),
                                 "€100"
                             }
                         }
                     }
-                    
+
                     button {
                         r#type: "button",
                         class: "submit-button",
-                        disabled: *deposit_amount.read() <= 0.0,
+                        disabled: !deposit_amount.read().is_positive(),
                         onclick: move |_| {
                             let name = name_for_future.clone();
                             let amount = *deposit_amount.read();
                             async move {
-                                if amount > 0.0 {
+                                if amount.is_positive() {
                                     if let Ok(_) = deposit_for_user_server(name.to_string(), amount).await {
-                                        deposit_amount.set(0.0);
+                                        deposit_amount.set(crate::money::Money::ZERO);
                                         error_signal.set(None);
                                     }
                                 } else {
@@ -595,10 +630,10 @@ pub fn Deposit(name: String) -> Element {
                                 }
                             }
                         },
-                        "💰 Deposit €{deposit_amount:.2}"
+                        "💰 Deposit €{deposit_amount}"
                     }
                 }
-                
+
                 if let Some(error) = &*error_signal.read() {
                     div { class: "error-message", "{error}" }
                 }
@@ -667,20 +702,26 @@ async fn get_random_message_server() -> Result<String, ServerFnError> {
 #[server]
 async fn get_users_server() -> Result<Vec<String>, ServerFnError> {
     use crate::db;
-    let users = db::get_users()?;
+    let users = db::get_users(&crate::node::Node::global().db)?;
     Ok(users)
 }
 
 #[server]
-async fn deposit_for_user_server(user: String, amount: f64) -> Result<(), ServerFnError> {
-    if amount < 0.0 {
+async fn deposit_for_user_server(
+    user: String,
+    amount: crate::money::Money,
+) -> Result<(), ServerFnError> {
+    if amount.is_negative() {
         return Err(ServerFnError::new("Amount cannot be negative."));
     }
 
-    if let Err(e) = crate::control::enqueue_critical(crate::control::CriticalCommands::Deposit {
-        name: user,
-        amount: amount,
-    })
+    if let Err(e) = crate::control::run_critical(
+        crate::node::Node::global(),
+        crate::control::CriticalCommands::Deposit {
+            name: user,
+            amount: amount,
+        },
+    )
     .await
     {
         return Err(ServerFnError::new(format!(
@@ -692,15 +733,21 @@ async fn deposit_for_user_server(user: String, amount: f64) -> Result<(), Server
 }
 
 #[server]
-async fn withdraw_for_user_server(user: String, amount: f64) -> Result<(), ServerFnError> {
-    if amount < 0.0 {
+async fn withdraw_for_user_server(
+    user: String,
+    amount: crate::money::Money,
+) -> Result<(), ServerFnError> {
+    if amount.is_negative() {
         return Err(ServerFnError::new("Amount cannot be negative."));
     }
 
-    if let Err(e) = crate::control::enqueue_critical(crate::control::CriticalCommands::Withdraw {
-        name: user,
-        amount: amount,
-    })
+    if let Err(e) = crate::control::run_critical(
+        crate::node::Node::global(),
+        crate::control::CriticalCommands::Withdraw {
+            name: user,
+            amount: amount,
+        },
+    )
     .await
     {
         return Err(ServerFnError::new(format!(
@@ -712,15 +759,21 @@ async fn withdraw_for_user_server(user: String, amount: f64) -> Result<(), Serve
 }
 
 #[server]
-async fn pay_for_user_server(user: String, amount: f64) -> Result<(), ServerFnError> {
-    if amount < 0.0 {
+async fn pay_for_user_server(
+    user: String,
+    amount: crate::money::Money,
+) -> Result<(), ServerFnError> {
+    if amount.is_negative() {
         return Err(ServerFnError::new("Amount cannot be negative."));
     }
 
-    if let Err(e) = crate::control::enqueue_critical(crate::control::CriticalCommands::Pay {
-        name: user,
-        amount: amount,
-    })
+    if let Err(e) = crate::control::run_critical(
+        crate::node::Node::global(),
+        crate::control::CriticalCommands::Pay {
+            name: user,
+            amount: amount,
+        },
+    )
     .await
     {
         return Err(ServerFnError::new(format!("[SERVER] Failed to pay : {e}")));
@@ -733,18 +786,21 @@ async fn pay_for_user_server(user: String, amount: f64) -> Result<(), ServerFnEr
 async fn transfer_from_user_to_user_server(
     from_user: String,
     to_user: String,
-    amount: f64,
+    amount: crate::money::Money,
     _optional_message: String,
 ) -> Result<(), ServerFnError> {
-    if amount < 0.0 {
+    if amount.is_negative() {
         return Err(ServerFnError::new("Amount cannot be negative."));
     }
 
-    if let Err(e) = crate::control::enqueue_critical(crate::control::CriticalCommands::Transfer {
-        from: from_user,
-        to: to_user,
-        amount: amount,
-    })
+    if let Err(e) = crate::control::run_critical(
+        crate::node::Node::global(),
+        crate::control::CriticalCommands::Transfer {
+            from: from_user,
+            to: to_user,
+            amount: amount,
+        },
+    )
     .await
     {
         return Err(ServerFnError::new(format!(
@@ -759,24 +815,65 @@ async fn transfer_from_user_to_user_server(
 async fn get_transactions_for_user_server(
     name: String,
 ) -> Result<Vec<crate::db::Transaction>, ServerFnError> {
-    if let Ok(data) = crate::db::get_transactions_for_user(&name) {
+    if let Ok(data) = crate::db::get_transactions_for_user(&crate::node::Node::global().db, &name) {
         Ok(data)
     } else {
         Err(ServerFnError::new("User not found."))
     }
 }
 
+/// Returns the transactions of a user in causal order, each one flagged if it
+/// is concurrent with a transaction listed before it
+#[server]
+async fn get_history_for_user_server(
+    name: String,
+) -> Result<Vec<(crate::db::Transaction, bool)>, ServerFnError> {
+    use crate::clock::Clock;
+
+    let Ok(mut remaining) =
+        crate::db::get_transactions_for_user(&crate::node::Node::global().db, &name)
+    else {
+        return Err(ServerFnError::new("User not found."));
+    };
+    remaining
+        .sort_by(|a, b| (a.lamport_time, &a.source_node).cmp(&(b.lamport_time, &b.source_node)));
+    let clock =
+        |tx: &crate::db::Transaction| Clock::from_parts(tx.lamport_time, tx.vector_clock.clone());
+
+    let mut history: Vec<(crate::db::Transaction, bool)> = Vec::new();
+    while !remaining.is_empty() {
+        // the first transaction that no remaining one happened before
+        let next = remaining
+            .iter()
+            .position(|tx| {
+                !remaining
+                    .iter()
+                    .any(|other| clock(other).happened_before(&clock(tx)))
+            })
+            .unwrap_or(0);
+        let tx = remaining.remove(next);
+        let concurrent = history
+            .iter()
+            .any(|(earlier, _)| clock(earlier).concurrent_with(&clock(&tx)));
+        history.push((tx, concurrent));
+    }
+    Ok(history)
+}
+
 #[server]
 async fn refund_transaction_server(
     name: String,
     lamport_time: i64,
     transac_node: String,
 ) -> Result<(), ServerFnError> {
-    if let Err(e) = crate::control::enqueue_critical(crate::control::CriticalCommands::Refund {
-        name: name,
-        lamport: lamport_time,
-        node: transac_node,
-    })
+    if let Err(e) = crate::control::run_critical(
+        crate::node::Node::global(),
+        crate::control::CriticalCommands::Refund {
+            name: name,
+            lamport: lamport_time,
+            node: transac_node,
+        },
+    )
     .await
     {
         return Err(ServerFnError::new(format!(
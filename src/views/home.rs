@@ -29,14 +29,14 @@ pub fn Home() -> Element {
                 h1 { "User Management" }
                 p { class: "page-description", "Manage users and their transactions in the Peillute system" }
             }
-            
+
             div { class: "main-content",
                 div { class: "users-section",
                     div { class: "section-header",
                         h2 { "Current Users" }
                         span { class: "user-count", "{users.read().len()} user(s)" }
                     }
-                    
+
                     div { id: "users-list",
                         if users.read().is_empty() {
                             div { class: "empty-users",
@@ -53,7 +53,7 @@ pub fn Home() -> Element {
                                                 name: item.to_string(),
                                             },
                                             div { class: "user-info",
-                                                div { class: "user-avatar", 
+                                                div { class: "user-avatar",
                                                     span { class: "avatar-text", "{item.chars().next().unwrap_or('U').to_uppercase()}" }
                                                 }
                                                 span { class: "user-name", "{item}" }
@@ -86,13 +86,13 @@ pub fn Home() -> Element {
                         }
                     }
                 }
-                
+
                 div { class: "add-user-section",
                     div { class: "section-header",
                         h2 { "Add New User" }
                         p { "Create a new user account to start managing transactions" }
                     }
-                    
+
                     div { id: "add-user-form",
                         form {
                             div { class: "form-group",
@@ -132,7 +132,7 @@ pub fn Home() -> Element {
 #[server]
 async fn get_users() -> Result<Vec<String>, ServerFnError> {
     use crate::db;
-    let users = db::get_users()?;
+    let users = db::get_users(&crate::node::Node::global().db)?;
     Ok(users)
 }
 
@@ -146,9 +146,10 @@ async fn add_user(name: String) -> Result<(), ServerFnError> {
         return Err(ServerFnError::new("User name cannot be empty."));
     }
 
-    if let Err(e) = crate::control::enqueue_critical(crate::control::CriticalCommands::CreateUser {
-        name: name,
-    })
+    if let Err(e) = crate::control::run_critical(
+        crate::node::Node::global(),
+        crate::control::CriticalCommands::CreateUser { name: name },
+    )
     .await
     {
         return Err(ServerFnError::new(format!(
@@ -165,6 +166,6 @@ async fn add_user(name: String) -> Result<(), ServerFnError> {
 #[server]
 async fn delete_user(name: String) -> Result<(), ServerFnError> {
     use crate::db;
-    db::delete_user(&name)?;
+    db::delete_user(&crate::node::Node::global().db, &name)?;
     Ok(())
 }
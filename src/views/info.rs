@@ -92,11 +92,39 @@ async fn get_peer_addrs() -> Result<Vec<String>, ServerFnError> {
     Ok(state.get_cli_peers_addrs_as_string())
 }
 
+/// Server function to retrieve the suspicion level of each connected neighbour
+#[server]
+async fn get_suspicion_levels() -> Result<Vec<(String, f64)>, ServerFnError> {
+    use crate::state::LOCAL_APP_STATE;
+    let state = LOCAL_APP_STATE.lock().await;
+    Ok(state
+        .get_suspicion_levels()
+        .into_iter()
+        .map(|(addr, phi)| (addr.to_string(), phi))
+        .collect())
+}
+
+/// Server function to retrieve the number of transactions held by the causal buffer,
+/// its peak and the number of transactions delivered without their dependencies
+#[server]
+async fn get_causal_buffer() -> Result<(usize, usize, u64), ServerFnError> {
+    use crate::state::LOCAL_APP_STATE;
+    let state = LOCAL_APP_STATE.lock().await;
+    Ok((
+        state.causal.len(),
+        state.causal.peak(),
+        state.causal.timed_out(),
+    ))
+}
+
 /// Ask for a snapshot
 #[server]
 async fn ask_for_snapshot() -> Result<(), ServerFnError> {
-    if let Err(e) =
-        crate::control::enqueue_critical(crate::control::CriticalCommands::FileSnapshot).await
+    if let Err(e) = crate::control::run_critical(
+        crate::node::Node::global(),
+        crate::control::CriticalCommands::FileSnapshot,
+    )
+    .await
     {
         return Err(ServerFnError::new(format!(
             "[SERVER] Failed make the local snapshot: {e}"
@@ -105,6 +133,34 @@ async fn ask_for_snapshot() -> Result<(), ServerFnError> {
     Ok(())
 }
 
+/// Rebuild the site from a snapshot file, returns the number of transactions restored
+#[server]
+async fn restore_snapshot(path: String) -> Result<usize, ServerFnError> {
+    crate::snapshot::restore(crate::node::Node::global(), &path)
+        .await
+        .map_err(|e| ServerFnError::new(format!("[SERVER] Failed to restore {path}: {e}")))
+}
+
+/// Get the name, size in bytes and date of the snapshots saved, latest first
+#[server]
+async fn get_snapshot_index() -> Result<Vec<(String, u64, String)>, ServerFnError> {
+    use crate::snapshot::LOCAL_SNAPSHOT_MANAGER;
+    let mgr = LOCAL_SNAPSHOT_MANAGER.lock().await;
+    Ok(mgr
+        .index
+        .entries()
+        .iter()
+        .rev()
+        .map(|entry| {
+            (
+                entry.path.display().to_string(),
+                entry.size,
+                entry.taken_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+            )
+        })
+        .collect())
+}
+
 /// Get the latest snapshot content if any
 #[server]
 async fn get_snapshot_content() -> Result<Option<String>, ServerFnError> {
@@ -114,7 +170,7 @@ async fn get_snapshot_content() -> Result<Option<String>, ServerFnError> {
 
     let maybe_filename = {
         let state = LOCAL_SNAPSHOT_MANAGER.lock().await;
-        state.path.clone()
+        state.index.latest().map(|entry| entry.path.clone())
     };
 
     if let Some(filename) = maybe_filename {
@@ -137,19 +193,27 @@ async fn get_snapshot_content() -> Result<Option<String>, ServerFnError> {
 /// - Vector clock state
 /// - Number of connected sites
 /// - List of connected peers
+/// - Suspicion level of each neighbour
 /// - Snapshot button
+/// - Snapshots saved
+/// - Snapshot restoration
 #[component]
 pub fn Info() -> Element {
     let mut local_addr = use_signal(|| "".to_string());
     let mut site_id = use_signal(|| "".to_string());
     let mut peers_addr = use_signal(|| Vec::new());
     let mut connected_neighbours = use_signal(|| Vec::new());
+    let mut suspicion_levels = use_signal(Vec::<(String, f64)>::new);
+    let mut causal_buffer = use_signal(|| (0usize, 0usize, 0u64));
     let mut lamport = use_signal(|| 0i64);
     let mut vector_clock = use_signal(|| "".to_string());
     let mut nb_neighbours = use_signal(|| 0i64);
     let mut nb_peers = use_signal(|| 0i64);
     let mut db_path = use_signal(|| "".to_string());
+    let mut snapshot_index = use_signal(Vec::<(String, u64, String)>::new);
     let mut snapshot_content = use_signal(|| None::<String>);
+    let mut restore_path = use_signal(|| "".to_string());
+    let mut restore_status = use_signal(|| None::<String>);
 
     use_future(move || async move {
         // Fetch local address
@@ -177,6 +241,16 @@ pub fn Info() -> Element {
             connected_neighbours.set(data);
         } // else: connected_neighbours remains empty or you could set an error state if needed
 
+        // Fetch suspicion levels
+        if let Ok(data) = get_suspicion_levels().await {
+            suspicion_levels.set(data);
+        } // else: suspicion_levels remains empty or handle error
+
+        // Fetch causal buffer sizes
+        if let Ok(data) = get_causal_buffer().await {
+            causal_buffer.set(data);
+        } // else: causal_buffer remains empty or handle error
+
         // Fetch Lamport clock
         if let Ok(data) = get_lamport().await {
             lamport.set(data);
@@ -202,6 +276,11 @@ pub fn Info() -> Element {
             db_path.set(data);
         } // else: db_path remains "" or handle error
 
+        // Fetch the snapshots saved
+        if let Ok(data) = get_snapshot_index().await {
+            snapshot_index.set(data);
+        } // else: snapshot_index remains empty or handle error
+
         // Fetch snapshot content
         if let Ok(data) = get_snapshot_content().await {
             snapshot_content.set(data);
@@ -251,6 +330,26 @@ pub fn Info() -> Element {
                 }
             }
 
+            div { class: "info-item",
+                strong { "🩺 Neighbour suspicion (φ): " }
+                if suspicion_levels.read().is_empty() {
+                    span { "No neighbour monitored." }
+                } else {
+                    ul { class: "peer-list",
+                        for (adr, phi) in suspicion_levels.read().iter() {
+                            li { key: "{adr}", "{adr} : {phi:.2}" }
+                        }
+                    }
+                }
+            }
+
+            div { class: "info-item",
+                strong { "📬 Causal buffer: " }
+                span {
+                    "{causal_buffer.read().0} held (peak {causal_buffer.read().1}), {causal_buffer.read().2} delivered without their dependencies"
+                }
+            }
+
             div { class: "info-item",
                 strong { "🌍 Number of CLI peers: " }
                 span { "{nb_peers}" }
@@ -288,6 +387,51 @@ pub fn Info() -> Element {
                 }
             }
 
+            div { class: "info-item",
+                strong { "🗂️ Saved snapshots: " }
+                if snapshot_index.read().is_empty() {
+                    span { "No snapshot saved." }
+                } else {
+                    ul { class: "peer-list",
+                        for (path, size, taken_at) in snapshot_index.read().iter() {
+                            li { key: "{path}", "{taken_at} : {path} ({size} bytes)" }
+                        }
+                    }
+                }
+            }
+
+            div { class: "info-item",
+                strong { "♻️ Restore a snapshot: " }
+                form {
+                    input {
+                        r#type: "text",
+                        placeholder: "Snapshot file",
+                        value: "{restore_path}",
+                        oninput: move |evt| restore_path.set(evt.value()),
+                    }
+                    button {
+                        class: "snapshot",
+                        r#type: "button",
+                        onclick: move |_| {
+                            async move {
+                                let path = restore_path.read().clone();
+                                match restore_snapshot(path.clone()).await {
+                                    Ok(n) => {
+                                        restore_status
+                                            .set(Some(format!("{n} transactions restored from {path}")))
+                                    }
+                                    Err(e) => restore_status.set(Some(format!("{e}"))),
+                                }
+                            }
+                        },
+                        "Restore"
+                    }
+                }
+                if let Some(status) = restore_status.read().as_ref() {
+                    span { "{status}" }
+                }
+            }
+
             div { class: "info-item",
                 strong { "📄 Last Snapshot Content:" }
                 if let Some(content) = snapshot_content.read().as_ref() {
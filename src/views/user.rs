@@ -19,7 +19,7 @@ use dioxus::prelude::*;
 /// - Making deposits
 #[component]
 pub fn User(name: String) -> Element {
-    let mut solde = use_signal(|| 0f64);
+    let mut solde = use_signal(|| crate::money::Money::ZERO);
 
     let name = std::rc::Rc::new(name);
     let name_for_future = name.clone();
@@ -61,11 +61,11 @@ pub fn User(name: String) -> Element {
                     h1 { "Welcome back, {name}!" }
                     div { class: "balance-display",
                         span { class: "balance-label", "Current Balance" }
-                        h2 { class: "balance-amount", "€{solde():.2}" }
+                        h2 { class: "balance-amount", "€{solde()}" }
                     }
                 }
             }
-            
+
             div { class: "dashboard-content",
                 div { class: "quick-actions",
                     div { class: "section-header",
@@ -73,22 +73,22 @@ pub fn User(name: String) -> Element {
                         p { "Most common transactions" }
                     }
                     div { class: "action-grid primary",
-                        Link { 
-                            to: deposit_route, 
+                        Link {
+                            to: deposit_route,
                             class: "action-card primary",
                             div { class: "action-icon", "💰" }
                             span { class: "action-label", "Deposit" }
                             span { class: "action-desc", "Add money" }
                         }
-                        Link { 
-                            to: withdraw_route, 
+                        Link {
+                            to: withdraw_route,
                             class: "action-card primary",
                             div { class: "action-icon", "💸" }
                             span { class: "action-label", "Withdraw" }
                             span { class: "action-desc", "Take money out" }
                         }
-                        Link { 
-                            to: pay_route, 
+                        Link {
+                            to: pay_route,
                             class: "action-card primary",
                             div { class: "action-icon", "🛒" }
                             span { class: "action-label", "Pay" }
@@ -96,27 +96,27 @@ pub fn User(name: String) -> Element {
                         }
                     }
                 }
-                
+
                 div { class: "more-actions",
                     div { class: "section-header",
                         h3 { "More Actions" }
                         p { "Additional transaction options" }
                     }
                     div { class: "action-grid secondary",
-                        Link { 
-                            to: history_route, 
+                        Link {
+                            to: history_route,
                             class: "action-card secondary",
                             div { class: "action-icon", "📊" }
                             span { class: "action-label", "History" }
                         }
-                        Link { 
-                            to: transfer_route, 
+                        Link {
+                            to: transfer_route,
                             class: "action-card secondary",
                             div { class: "action-icon", "💸" }
                             span { class: "action-label", "Transfer" }
                         }
-                        Link { 
-                            to: refund_route, 
+                        Link {
+                            to: refund_route,
                             class: "action-card secondary",
                             div { class: "action-icon", "🔄" }
                             span { class: "action-label", "Refund" }
@@ -131,8 +131,8 @@ pub fn User(name: String) -> Element {
 
 /// Server function to retrieve a user's current balance
 #[server]
-async fn get_solde(name: String) -> Result<f64, ServerFnError> {
+async fn get_solde(name: String) -> Result<crate::money::Money, ServerFnError> {
     use crate::db;
-    let solde = db::calculate_solde(&name)?;
+    let solde = db::calculate_solde(&crate::node::Node::global().db, &name)?;
     Ok(solde)
 }
@@ -0,0 +1,370 @@
+//! Echo waves carrying the distributed operations
+//!
+//! A wave goes down from its initiator to every site as blue messages, each
+//! site adopting as parent the neighbour it first heard the wave from, then
+//! back up as red messages: a site answers its parent once all its other
+//! neighbours have answered, and answers right away a neighbour bringing the
+//! wave a second time. When every neighbour of the initiator has answered, the
+//! wave has reached the whole network.
+//!
+//! The traversal is the same for every distributed operation, only what the
+//! sites do with the wave changes. A [`WaveHandler`] describes it: what a site
+//! does when the wave reaches it, what it sends back up and what the initiator
+//! does with the aggregate of the whole network. A new operation implements
+//! the trait, adds its handler to [`HANDLERS`] and starts its waves with
+//! [`start_wave`]; `handle_message` hands the messages of every registered
+//! handler to [`handle_wave_message`].
+
+#![cfg(feature = "server")]
+
+/// Result of the wave operations
+pub type WaveResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// Future returned by the wave handlers
+pub type WaveFuture<'a, T> =
+    std::pin::Pin<Box<dyn std::future::Future<Output = WaveResult<T>> + Send + 'a>>;
+
+/// What a wave does on the sites it goes through
+pub trait WaveHandler: Send + Sync {
+    /// Code of the blue messages taking the wave down
+    fn request_code(&self) -> crate::message::NetworkMessageCode;
+
+    /// Code of the red messages taking the answers up
+    fn answer_code(&self) -> crate::message::NetworkMessageCode;
+
+    /// Dependencies carried by a wave started from this site
+    ///
+    /// Called before the wave starts, even if the site has no neighbour.
+    fn stamp(
+        &self,
+        _node: &crate::node::Node,
+        _state: &mut crate::state::AppState,
+    ) -> Option<crate::causal::Dependencies> {
+        None
+    }
+
+    /// Whether the initiator signs what the wave diffuses
+    ///
+    /// The signature is then checked by every site before [`WaveHandler::visit`].
+    fn signed_by_initiator(&self) -> bool {
+        false
+    }
+
+    /// Runs on a site the first time the wave reaches it
+    ///
+    /// `forwarded` tells whether the wave goes on to other neighbours, whose
+    /// answers will be aggregated here.
+    fn visit<'a>(
+        &'a self,
+        _node: &'a crate::node::Node,
+        _message: &'a crate::message::Message,
+        _forwarded: bool,
+    ) -> WaveFuture<'a, ()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Payload answered by a leaf of the wave, or by a site reached twice
+    fn leaf_answer<'a>(
+        &'a self,
+        _node: &'a crate::node::Node,
+        _message: &'a crate::message::Message,
+    ) -> WaveFuture<'a, crate::message::MessageInfo> {
+        Box::pin(async { Ok(crate::message::MessageInfo::None) })
+    }
+
+    /// Folds the answer of a neighbour into the aggregate of the site
+    ///
+    /// `last` is set for the last expected answer: the returned payload is
+    /// then the aggregate of the subtree, sent to the parent or, on the
+    /// initiator, given to [`WaveHandler::complete`]. By default the last
+    /// answer goes up as it is.
+    fn aggregate<'a>(
+        &'a self,
+        _node: &'a crate::node::Node,
+        _state: &'a crate::state::AppState,
+        answer: &'a crate::message::Message,
+        _last: bool,
+    ) -> WaveFuture<'a, crate::message::MessageInfo> {
+        Box::pin(async move { Ok(answer.info.clone()) })
+    }
+
+    /// Runs on the initiator once every site has answered
+    fn complete<'a>(
+        &'a self,
+        node: &'a crate::node::Node,
+        state: &'a mut crate::state::AppState,
+        aggregate: crate::message::MessageInfo,
+    ) -> WaveFuture<'a, ()>;
+}
+
+/// Handlers of the waves run by the sites
+pub static HANDLERS: &[&dyn WaveHandler] = &[
+    &crate::state::MutexRequestWave,
+    &crate::state::MutexReleaseWave,
+    &crate::control::TransactionWave,
+    &crate::snapshot::SnapshotWave,
+];
+
+/// Returns the handler of a message code, and whether the code takes the wave down
+pub fn handler_for(
+    code: &crate::message::NetworkMessageCode,
+) -> Option<(&'static dyn WaveHandler, bool)> {
+    HANDLERS.iter().find_map(|handler| {
+        if handler.request_code() == *code {
+            Some((*handler, true))
+        } else if handler.answer_code() == *code {
+            Some((*handler, false))
+        } else {
+            None
+        }
+    })
+}
+
+/// Starts a wave from this site
+///
+/// The aggregate of the whole network is given to the `complete` method of
+/// the handler. Returns the identifier of the wave, or `None` if the site has
+/// no neighbour: nothing is sent then, and the caller carries on alone.
+pub async fn start_wave(
+    node: &crate::node::Node,
+    state: &mut crate::state::AppState,
+    handler: &dyn WaveHandler,
+    info: crate::message::MessageInfo,
+    command: Option<crate::control::Command>,
+    clock: crate::clock::Clock,
+) -> WaveResult<Option<crate::message::WaveId>> {
+    let deps = handler.stamp(node, state);
+    let wave = state.next_wave_id();
+    if !state.start_wave(&wave) {
+        return Ok(None);
+    }
+
+    let mut message = crate::message::Message {
+        sender_id: state.get_site_id(),
+        sender_addr: state.get_site_addr(),
+        message_initiator_id: state.get_site_id(),
+        message_initiator_addr: state.get_site_addr(),
+        wave_seq: wave.seq,
+        clock,
+        command,
+        info,
+        code: handler.request_code(),
+        deps,
+        initiator_signature: None,
+    };
+    if handler.signed_by_initiator() {
+        message.initiator_signature =
+            Some(crate::network::sign_as_initiator(node, &message).await?);
+    }
+
+    log::info!("Début de la diffusion de la vague {}", wave);
+    crate::network::diffuse_message_without_lock(
+        node,
+        &message,
+        state.get_site_addr(),
+        &state.get_site_id(),
+        state.get_connected_nei_addr(),
+        state.get_parent_addr_for_wave(&wave),
+    )
+    .await?;
+    Ok(Some(wave))
+}
+
+/// Handles a blue or red message of a wave
+pub async fn handle_wave_message(
+    node: &crate::node::Node,
+    message: &crate::message::Message,
+) -> WaveResult<()> {
+    let Some((handler, down)) = handler_for(&message.code) else {
+        return Err(format!("No wave handler for {:?} messages", message.code).into());
+    };
+    if down {
+        receive_request(node, handler, message).await
+    } else {
+        receive_answer(node, handler, message).await
+    }
+}
+
+/// Blue message: visit the site, then go further or answer
+async fn receive_request(
+    node: &crate::node::Node,
+    handler: &dyn WaveHandler,
+    message: &crate::message::Message,
+) -> WaveResult<()> {
+    let wave = message.wave_id();
+    let (first_visit, forward, site_id, site_addr) = {
+        let mut state = node.state.lock().await;
+        let first_visit = !state.knows_wave(&wave);
+        // une vague falsifiée n'est ni appliquée ni acquittée
+        if first_visit
+            && handler.signed_by_initiator()
+            && let Err(e) = state.key_ring.verify_initiator(message)
+        {
+            crate::network::reject_message(message, e);
+            return Ok(());
+        }
+        let forward = state.receive_wave(&wave, message.sender_addr);
+        (
+            first_visit,
+            forward,
+            state.get_site_id(),
+            state.get_site_addr(),
+        )
+    };
+
+    if first_visit {
+        handler.visit(node, message, forward).await?;
+    }
+
+    if forward {
+        let mut snd_msg = message.clone();
+        snd_msg.sender_id = site_id;
+        snd_msg.sender_addr = site_addr;
+        crate::network::diffuse_message(node, &snd_msg).await?;
+    } else {
+        log::debug!(
+            "Réception de la vague {}, on est sur une feuille ou déjà visité, on acquitte {}",
+            wave,
+            message.sender_addr
+        );
+        let info = handler.leaf_answer(node, message).await?;
+        crate::network::send_message(
+            node,
+            message.sender_addr,
+            info,
+            handler.answer_code(),
+            site_addr,
+            &site_id,
+            message.origin(),
+            message.clock.clone(),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Red message: aggregate, then answer the parent or complete the wave
+async fn receive_answer(
+    node: &crate::node::Node,
+    handler: &dyn WaveHandler,
+    message: &crate::message::Message,
+) -> WaveResult<()> {
+    let mut state = node.state.lock().await;
+
+    let wave = message.wave_id();
+    let parent_addr = state.receive_wave_answer(&wave);
+    let aggregate = handler
+        .aggregate(node, &state, message, parent_addr.is_some())
+        .await?;
+    let Some(parent_addr) = parent_addr else {
+        return Ok(());
+    };
+
+    if parent_addr == state.get_site_addr() {
+        // on est chez l'initiateur : diffusion terminée
+        println!("\x1b[1;31mDiffusion terminée et réussie !\x1b[0m");
+        handler.complete(node, &mut state, aggregate).await?;
+    } else {
+        log::debug!(
+            "On a reçu un rouge de tous nos fils pour la vague {}: on acquitte au parent {}",
+            wave,
+            parent_addr
+        );
+        crate::network::send_message(
+            node,
+            parent_addr,
+            aggregate,
+            handler.answer_code(),
+            state.get_site_addr(),
+            &state.get_site_id(),
+            message.origin(),
+            state.get_clock(),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::NetworkMessageCode;
+
+    #[test]
+    fn every_wave_code_has_one_handler() {
+        let mut codes = Vec::new();
+        for handler in HANDLERS {
+            codes.push(handler.request_code());
+            codes.push(handler.answer_code());
+        }
+        for code in &codes {
+            assert_eq!(codes.iter().filter(|c| *c == code).count(), 1);
+        }
+    }
+
+    #[test]
+    fn handlers_are_found_in_both_directions() {
+        let (request, down) = handler_for(&NetworkMessageCode::AcquireMutex).unwrap();
+        assert!(down);
+        let (answer, up) = handler_for(&NetworkMessageCode::AckGlobalMutex).unwrap();
+        assert!(!up);
+        assert_eq!(request.answer_code(), answer.answer_code());
+
+        assert!(handler_for(&NetworkMessageCode::Heartbeat).is_none());
+        assert!(handler_for(&NetworkMessageCode::Discovery).is_none());
+    }
+
+    #[tokio::test]
+    async fn default_handler_passes_the_last_answer_up() {
+        struct Echo;
+        impl WaveHandler for Echo {
+            fn request_code(&self) -> NetworkMessageCode {
+                NetworkMessageCode::Error
+            }
+            fn answer_code(&self) -> NetworkMessageCode {
+                NetworkMessageCode::Error
+            }
+            fn complete<'a>(
+                &'a self,
+                _node: &'a crate::node::Node,
+                _state: &'a mut crate::state::AppState,
+                _aggregate: crate::message::MessageInfo,
+            ) -> WaveFuture<'a, ()> {
+                Box::pin(async { Ok(()) })
+            }
+        }
+
+        let node = crate::node::Node::new(
+            crate::state::AppState::new(
+                "A".to_string(),
+                Vec::new(),
+                "127.0.0.1:8080".parse().unwrap(),
+            ),
+            crate::db::Db::in_memory(),
+        );
+        let answer = crate::message::Message {
+            sender_id: "B".to_string(),
+            sender_addr: "127.0.0.1:8081".parse().unwrap(),
+            message_initiator_id: "A".to_string(),
+            message_initiator_addr: "127.0.0.1:8080".parse().unwrap(),
+            wave_seq: 1,
+            clock: crate::clock::Clock::new(),
+            command: None,
+            info: crate::message::MessageInfo::AckMutex(crate::message::AckMutexPayload {
+                clock: 7,
+            }),
+            code: NetworkMessageCode::Error,
+            deps: None,
+            initiator_signature: None,
+        };
+
+        let leaf = Echo.leaf_answer(&node, &answer).await.unwrap();
+        assert!(matches!(leaf, crate::message::MessageInfo::None));
+        let state = node.state.lock().await;
+        let up = Echo.aggregate(&node, &state, &answer, true).await.unwrap();
+        assert!(matches!(
+            up,
+            crate::message::MessageInfo::AckMutex(crate::message::AckMutexPayload { clock: 7 })
+        ));
+    }
+}